@@ -818,6 +818,7 @@ CREATE OR REPLACE FUNCTION _prom_catalog.label_jsonb_each_text(js jsonb, OUT key
     RETURNS SETOF record
     LANGUAGE INTERNAL
 IMMUTABLE PARALLEL SAFE STRICT ROWS 10
+SUPPORT _prom_ext.label_rows_support
 AS $function$jsonb_each_text$function$;
 GRANT EXECUTE ON FUNCTION _prom_catalog.label_jsonb_each_text(jsonb) to prom_reader;
 
@@ -826,9 +827,23 @@ CREATE OR REPLACE FUNCTION _prom_catalog.label_unnest(label_array anyarray)
     RETURNS SETOF anyelement
     LANGUAGE INTERNAL
 IMMUTABLE PARALLEL SAFE STRICT ROWS 10
+SUPPORT _prom_ext.label_rows_support
 AS $function$array_unnest$function$;
 GRANT EXECUTE ON FUNCTION _prom_catalog.label_unnest(anyarray) to prom_reader;
 
+-- range_start returns the earliest sample time considered by a vector selector or
+-- a prom_rate/prom_delta/prom_increase aggregate starting at start_time, given its
+-- lookback or range in milliseconds. It is used by the planner hook injecting time
+-- bounds into queries using these aggregates.
+CREATE OR REPLACE FUNCTION _prom_catalog.range_start(start_time TIMESTAMPTZ, range BIGINT)
+    RETURNS TIMESTAMPTZ
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT start_time OPERATOR(pg_catalog.-) (range OPERATOR(pg_catalog.*) interval '1 millisecond')
+$func$
+LANGUAGE SQL STABLE PARALLEL SAFE;
+GRANT EXECUTE ON FUNCTION _prom_catalog.range_start(TIMESTAMPTZ, BIGINT) TO prom_reader;
+
 -- safe_approximate_row_count returns the approximate row count of a hypertable if timescaledb is installed
 -- else returns the approximate row count in the normal table. This prevents errors in approximate count calculation
 -- if timescaledb is not installed, which is the case in plain postgres support.
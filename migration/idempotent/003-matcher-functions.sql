@@ -106,6 +106,13 @@ $func$
 LANGUAGE SQL IMMUTABLE PARALLEL SAFE;
 GRANT EXECUTE ON FUNCTION _prom_catalog.label_match(prom_api.label_array, prom_api.matcher_negative) TO prom_reader;
 
+-- The operators are planned as such only when label_match isn't inlined,
+-- otherwise the built-in estimator of && applies.
+ALTER OPERATOR prom_api.? (prom_api.label_array, prom_api.matcher_positive)
+    SET (RESTRICT = _prom_ext.label_matcher_sel);
+ALTER OPERATOR prom_api.? (prom_api.label_array, prom_api.matcher_negative)
+    SET (RESTRICT = _prom_ext.label_matcher_negative_sel);
+
 --------------------- op == !== ==~ !=~ ------------------------
 
 CREATE OR REPLACE FUNCTION _prom_catalog.label_find_key_equal(key_to_match prom_api.label_key, pat prom_api.pattern)
@@ -52,6 +52,87 @@ COMMENT ON OPERATOR ps_trace.-> (ps_trace.tag_map, pg_catalog.text)
 IS 'This operator is a part of custom ps_trace.tag_map type which is a wrapper for
 the built-in jsonb. It is the same as its jsonb_ namesake, but returns _ps_trace.tag_v.';
 
+-------------------------------------------------------------------------------
+-- the ->> operator
+-------------------------------------------------------------------------------
+CREATE OR REPLACE FUNCTION ps_trace.tag_map_object_field_text(ps_trace.tag_map, pg_catalog.text)
+    RETURNS _ps_trace.tag_v_text
+    LANGUAGE internal
+        IMMUTABLE
+        STRICT
+        PARALLEL SAFE
+    AS 'jsonb_object_field_text';
+GRANT EXECUTE ON FUNCTION ps_trace.tag_map_object_field_text(ps_trace.tag_map, pg_catalog.text) TO prom_reader;
+COMMENT ON FUNCTION ps_trace.tag_map_object_field_text
+IS 'This function is a part of custom ps_trace.tag_map type which is a wrapper for
+the built-in jsonb. It is the same as its jsonb_ namesake, but returns _ps_trace.tag_v_text.';
+
+DO $do$
+BEGIN
+	CREATE OPERATOR ps_trace.->> (
+	    FUNCTION = ps_trace.tag_map_object_field_text,
+	    LEFTARG  = ps_trace.tag_map,
+	    RIGHTARG = pg_catalog.text
+	);
+EXCEPTION
+    WHEN SQLSTATE '42723' THEN -- operator already exists
+        EXECUTE format($q$ALTER OPERATOR ps_trace.->>(ps_trace.tag_map, pg_catalog.text) OWNER TO %I$q$, current_user);
+END;
+$do$;
+COMMENT ON OPERATOR ps_trace.->> (ps_trace.tag_map, pg_catalog.text)
+IS 'This operator is a part of custom ps_trace.tag_map type which is a wrapper for
+the built-in jsonb. It is the same as its jsonb_ namesake, but returns _ps_trace.tag_v_text.';
+
+-------------------------------------------------------------------------------
+-- the ? operator
+-------------------------------------------------------------------------------
+CREATE OR REPLACE FUNCTION ps_trace.tag_map_exists(ps_trace.tag_map, pg_catalog.text)
+    RETURNS pg_catalog.bool
+    LANGUAGE internal
+        IMMUTABLE
+        STRICT
+        PARALLEL SAFE
+        SUPPORT _prom_ext.tag_map_rewrite
+    AS 'jsonb_exists';
+GRANT EXECUTE ON FUNCTION ps_trace.tag_map_exists(ps_trace.tag_map, pg_catalog.text) TO prom_reader;
+COMMENT ON FUNCTION ps_trace.tag_map_exists
+IS 'This function is a part of custom ps_trace.tag_map type which is a wrapper for
+the built-in jsonb. It is the same as its jsonb_ namesake, but has a support function attached.';
+
+CREATE OR REPLACE FUNCTION _ps_trace.tag_map_exists_matching_tags(_tag_key pg_catalog.text)
+    RETURNS pg_catalog.jsonb[]
+    LANGUAGE sql STABLE
+    -- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+    PARALLEL SAFE AS
+$fnc$
+    SELECT coalesce(pg_catalog.array_agg(pg_catalog.jsonb_build_object(a.key_id, a.id)), array[]::pg_catalog.jsonb[])
+    FROM _ps_trace.tag a
+    WHERE a.key OPERATOR(pg_catalog.=) _tag_key
+$fnc$;
+GRANT EXECUTE ON FUNCTION _ps_trace.tag_map_exists_matching_tags(_tag_key pg_catalog.text) TO prom_reader;
+COMMENT ON FUNCTION _ps_trace.tag_map_exists_matching_tags
+IS 'This function is a part of custom ps_trace.tag_map type which is a wrapper for
+the built-in jsonb. The tag_map_rewrite support function, attached to tag_map_exists,
+will use this function instead, if it can.';
+
+DO $do$
+BEGIN
+	CREATE OPERATOR ps_trace.? (
+	    FUNCTION = ps_trace.tag_map_exists,
+	    LEFTARG  = ps_trace.tag_map,
+	    RIGHTARG = pg_catalog.text,
+	    RESTRICT = contsel,
+	    JOIN     = contjoinsel
+	);
+EXCEPTION
+    WHEN SQLSTATE '42723' THEN -- operator already exists
+        EXECUTE format($q$ALTER OPERATOR ps_trace.?(ps_trace.tag_map, pg_catalog.text) OWNER TO %I$q$, current_user);
+END;
+$do$;
+COMMENT ON OPERATOR ps_trace.? (ps_trace.tag_map, pg_catalog.text)
+IS 'This operator is a part of custom ps_trace.tag_map type which is a wrapper for
+the built-in jsonb. It is the same as its jsonb_ namesake, but relies on tag_map_* functions.';
+
 -------------------------------------------------------------------------------
 -- equals
 -------------------------------------------------------------------------------
@@ -100,6 +181,46 @@ IS 'This function is a part of custom _ps_trace.tag_v type which is a wrapper fo
 the built-in jsonb. The tag_map_rewrite support function, attached to tag_v_eq,
 will use this function instead, if it can.';
 
+/* PostgreSQL never passes `IN (...)` and `= ANY(...)` predicates to support functions,
+ * hence `span_tags -> 'key' IN (...)` can't be made index-friendly. This function
+ * is the explicit equivalent of such a predicate, which tag_map_rewrite can process.
+ */
+CREATE OR REPLACE FUNCTION ps_trace.tag_v_eq_any(_tag_v _ps_trace.tag_v, _values pg_catalog.jsonb[])
+    RETURNS pg_catalog.bool
+    LANGUAGE sql IMMUTABLE
+    -- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+    PARALLEL SAFE
+    SUPPORT _prom_ext.tag_map_rewrite AS
+$fnc$
+    SELECT _tag_v::pg_catalog.jsonb OPERATOR(pg_catalog.=) ANY(_values)
+$fnc$;
+GRANT EXECUTE ON FUNCTION ps_trace.tag_v_eq_any(_ps_trace.tag_v, pg_catalog.jsonb[]) TO prom_reader;
+COMMENT ON FUNCTION ps_trace.tag_v_eq_any
+IS 'This function is a part of custom _ps_trace.tag_v type which is a wrapper for
+the built-in jsonb. It is the same as tag_v = ANY(jsonb[]) and tag_v IN (...),
+but has a support function attached. The promscale planner hook replaces those
+with calls to this function.';
+
+CREATE OR REPLACE FUNCTION _ps_trace.tag_v_eq_any_matching_tags(_tag_key pg_catalog.text, _values pg_catalog.jsonb[])
+    RETURNS pg_catalog.jsonb[]
+    LANGUAGE sql STABLE
+    SET search_path = pg_catalog, pg_temp
+    PARALLEL SAFE AS
+$fnc$
+    SELECT coalesce(pg_catalog.array_agg(m.tag), array[]::pg_catalog.jsonb[])
+    FROM
+    (
+        SELECT _ps_trace.tag_v_eq_matching_tags(_tag_key, v) AS tag
+        FROM unnest(_values) AS v
+    ) AS m
+    WHERE m.tag IS NOT NULL;
+$fnc$;
+GRANT EXECUTE ON FUNCTION _ps_trace.tag_v_eq_any_matching_tags(_tag_key pg_catalog.text, _values pg_catalog.jsonb[]) TO prom_reader;
+COMMENT ON FUNCTION _ps_trace.tag_v_eq_any_matching_tags
+IS 'This function is a part of custom _ps_trace.tag_v type which is a wrapper for
+the built-in jsonb. The tag_map_rewrite support function, attached to tag_v_eq_any,
+will use this function instead, if it can.';
+
 CREATE OR REPLACE FUNCTION _ps_trace.text_matches(_value pg_catalog.text)
     RETURNS pg_catalog.jsonb[]
     --note: to_jsonb is stable, not immutable because of timestamptz handling which does not apply here.
@@ -148,6 +269,68 @@ IS 'This function is a part of custom _ps_trace.tag_v type which is a wrapper fo
 the built-in jsonb. This function returns all the jsonb { tag_key_id, tag_value_id } pairs where
 the tag key matches _tag_key and the textual output of tag value matches _tag_value_text';
 
+CREATE OR REPLACE FUNCTION ps_trace.tag_v_text_eq(_ps_trace.tag_v_text, pg_catalog.text)
+    RETURNS pg_catalog.bool
+    LANGUAGE internal
+        IMMUTABLE
+        STRICT
+        PARALLEL SAFE
+        SUPPORT _prom_ext.tag_map_rewrite
+    AS 'texteq';
+GRANT EXECUTE ON FUNCTION ps_trace.tag_v_text_eq(_ps_trace.tag_v_text, pg_catalog.text) TO prom_reader;
+COMMENT ON FUNCTION ps_trace.tag_v_text_eq(_ps_trace.tag_v_text, pg_catalog.text)
+IS 'This function is a part of custom _ps_trace.tag_v_text type which is a wrapper for
+the built-in text. It is the same as its text namesake, but has a support function attached.';
+
+CREATE OR REPLACE FUNCTION ps_trace.tag_v_text_eq(_ps_trace.tag_v_text, _ps_trace.tag_v_text)
+    RETURNS pg_catalog.bool
+    LANGUAGE internal
+        IMMUTABLE
+        STRICT
+        PARALLEL SAFE
+        SUPPORT _prom_ext.tag_map_rewrite
+    AS 'texteq';
+GRANT EXECUTE ON FUNCTION ps_trace.tag_v_text_eq(_ps_trace.tag_v_text, _ps_trace.tag_v_text) TO prom_reader;
+COMMENT ON FUNCTION ps_trace.tag_v_text_eq(_ps_trace.tag_v_text, _ps_trace.tag_v_text)
+IS 'This function is a part of custom _ps_trace.tag_v_text type which is a wrapper for
+the built-in text. It is the same as its text namesake, but has a support function attached.';
+
+DO $do$
+BEGIN
+	CREATE OPERATOR ps_trace.= (
+	    FUNCTION       = ps_trace.tag_v_text_eq,
+	    LEFTARG        = _ps_trace.tag_v_text,
+	    RIGHTARG       = pg_catalog.text,
+	    RESTRICT       = eqsel,
+	    JOIN           = eqjoinsel
+	);
+EXCEPTION
+    WHEN SQLSTATE '42723' THEN -- operator already exists
+        EXECUTE format($q$ALTER OPERATOR ps_trace.=(_ps_trace.tag_v_text, pg_catalog.text) OWNER TO %I$q$, current_user);
+END;
+$do$;
+COMMENT ON OPERATOR ps_trace.= (_ps_trace.tag_v_text, pg_catalog.text)
+IS 'This operator is a part of custom ps_trace.tag_map type which is a wrapper for
+the built-in jsonb. It is the same as its text namesake, but relies on tag_map_* functions.';
+
+/* This one is picked when the right-hand side is an untyped literal, e.g. span_tags ->> 'key' = 'value' */
+DO $do$
+BEGIN
+	CREATE OPERATOR ps_trace.= (
+	    FUNCTION       = ps_trace.tag_v_text_eq,
+	    LEFTARG        = _ps_trace.tag_v_text,
+	    RIGHTARG       = _ps_trace.tag_v_text,
+	    RESTRICT       = eqsel,
+	    JOIN           = eqjoinsel
+	);
+EXCEPTION
+    WHEN SQLSTATE '42723' THEN -- operator already exists
+        EXECUTE format($q$ALTER OPERATOR ps_trace.=(_ps_trace.tag_v_text, _ps_trace.tag_v_text) OWNER TO %I$q$, current_user);
+END;
+$do$;
+COMMENT ON OPERATOR ps_trace.= (_ps_trace.tag_v_text, _ps_trace.tag_v_text)
+IS 'This operator is a part of custom ps_trace.tag_map type which is a wrapper for
+the built-in jsonb. It is the same as its text namesake, but relies on tag_map_* functions.';
 
 DO $do$
 BEGIN
@@ -1 +1,52 @@
-GRANT EXECUTE ON FUNCTION _prom_ext.re2_match(TEXT, TEXT) TO prom_reader;
\ No newline at end of file
+GRANT EXECUTE ON FUNCTION _prom_ext.re2_match(TEXT, TEXT) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.label_matcher_sel(internal, oid, internal, integer) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.label_matcher_negative_sel(internal, oid, internal, integer) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.label_rows_support(internal) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.relabel(jsonb, jsonb) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.decode_remote_write(bytea) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.encode_remote_read_transition(internal, jsonb, timestamptz[], double precision[]) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.encode_remote_read_transition(internal, integer, jsonb, timestamptz[], double precision[]) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.encode_remote_read_final(internal) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.encode_remote_read_chunked(jsonb, timestamptz[], double precision[], bigint) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.parse_exposition(text, timestamptz) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.to_exposition_transition(internal, text, jsonb, double precision, timestamptz) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.to_openmetrics_transition(internal, text, jsonb, double precision, timestamptz) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.to_exposition_final(internal) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.decode_otlp_metrics(bytea) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.decode_otlp_spans(bytea) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.decode_otlp_span_events(bytea) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.decode_otlp_span_links(bytea) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.decode_zipkin_json(jsonb) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.decode_jaeger_json(jsonb) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.trace_to_otlp_json(uuid) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.trace_to_jaeger_json(uuid) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.parse_line_protocol(text, text, timestamptz) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.to_prom_matrix_json_transition(internal, jsonb, timestamptz[], double precision[]) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.to_prom_matrix_json_final(internal) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.to_prom_vector_json_transition(internal, jsonb, timestamptz, double precision) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.to_prom_vector_json_final(internal) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.promql_parse(text) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.promql_selectors(text) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.promql_query_range(text, timestamptz, timestamptz, bigint) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.parse_selector(text) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.select_series(text, timestamptz, timestamptz) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.tsdb_block_meta(text) TO prom_writer;
+GRANT EXECUTE ON FUNCTION _prom_ext.read_tsdb_block(text, bigint, bigint) TO prom_writer;
+GRANT EXECUTE ON FUNCTION _prom_ext.write_tsdb_block_transition(internal, text, jsonb, timestamptz[], double precision[]) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.write_tsdb_block_final(internal) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.export_metric_parquet(text, timestamptz, timestamptz, text) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.export_spans_parquet(timestamptz, timestamptz, text) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.sample_block_transition(internal, timestamptz, double precision) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.quantile_sketch_transition(internal, double precision) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.quantile_sketch_combine(internal, internal) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.quantile_sketch_serialize(internal) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.quantile_sketch_deserialize(bytea, internal) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.hyperloglog_transition(internal, bigint) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.hyperloglog_text_transition(internal, text) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.hyperloglog_combine(internal, internal) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.hyperloglog_serialize(internal) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.hyperloglog_deserialize(bytea, internal) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.counter_summary_transition(internal, timestamptz, timestamptz, timestamptz, double precision) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.counter_summary_rollup_combine(internal, internal) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.counter_summary_rollup_serialize(internal) TO prom_reader;
+GRANT EXECUTE ON FUNCTION _prom_ext.counter_summary_rollup_deserialize(bytea, internal) TO prom_reader;
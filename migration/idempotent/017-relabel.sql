@@ -0,0 +1,10 @@
+CREATE OR REPLACE FUNCTION prom_api.relabel(labels jsonb, rules jsonb)
+RETURNS jsonb
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT _prom_ext.relabel(labels, rules)
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.relabel(jsonb, jsonb)
+IS 'applies Prometheus relabel_configs, given as a JSON array of rules, to a label set. Returns NULL if the label set is dropped';
+GRANT EXECUTE ON FUNCTION prom_api.relabel(jsonb, jsonb) TO prom_reader;
@@ -0,0 +1,124 @@
+CREATE OR REPLACE FUNCTION prom_api.decode_remote_write(payload BYTEA)
+RETURNS TABLE (kind TEXT, metric_name TEXT, labels JSONB, "time" TIMESTAMPTZ, value DOUBLE PRECISION, exemplar_labels JSONB, metric_type TEXT, unit TEXT, help TEXT)
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT * FROM _prom_ext.decode_remote_write(payload)
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.decode_remote_write(BYTEA)
+IS 'decodes a snappy-compressed Prometheus remote write request into sample, exemplar and metadata rows';
+GRANT EXECUTE ON FUNCTION prom_api.decode_remote_write(BYTEA) TO prom_reader;
+
+-- Inserts a remote write request the same way the connector would.
+-- Like with the connector, newly created metrics still need to be finalized
+-- by _prom_catalog.finalize_metric_creation() afterwards. It can't be called
+-- from here as it does its own transaction control.
+CREATE OR REPLACE PROCEDURE prom_api.insert_remote_write(payload BYTEA)
+    SET search_path = pg_catalog, pg_temp
+AS $proc$
+DECLARE
+    r RECORD;
+    _kinds TEXT[];
+    _metric_names TEXT[];
+    _labels JSONB[];
+    _times TIMESTAMPTZ[];
+    _values DOUBLE PRECISION[];
+    _exemplar_labels JSONB[];
+    _metric_types TEXT[];
+    _units TEXT[];
+    _helps TEXT[];
+BEGIN
+    -- the request is decoded only once, each of the steps below reads the rows back with unnest
+    SELECT
+        array_agg(d.kind), array_agg(d.metric_name), array_agg(d.labels), array_agg(d.time), array_agg(d.value),
+        array_agg(d.exemplar_labels), array_agg(d.metric_type), array_agg(d.unit), array_agg(d.help)
+    INTO _kinds, _metric_names, _labels, _times, _values, _exemplar_labels, _metric_types, _units, _helps
+    FROM _prom_ext.decode_remote_write(payload) d;
+
+    FOR r IN
+        WITH sample AS MATERIALIZED (
+            SELECT d.metric_name, d.labels, d.time, d.value
+            FROM unnest(_kinds, _metric_names, _labels, _times, _values) d(kind, metric_name, labels, time, value)
+            WHERE d.kind = 'sample'
+        ), series AS MATERIALIZED (
+            SELECT l.labels, s.table_name, s.series_id
+            FROM (SELECT DISTINCT sample.metric_name, sample.labels FROM sample ORDER BY 1, 2) l,
+            LATERAL (
+                SELECT array_agg(e.key ORDER BY e.key) AS keys, array_agg(e.value ORDER BY e.key) AS vals
+                FROM jsonb_each_text(l.labels) e
+            ) kv,
+            LATERAL _prom_catalog.get_or_create_series_id_for_kv_array(l.metric_name, kv.keys, kv.vals) s
+        )
+        SELECT series.table_name, array_agg(sample.time) AS times, array_agg(sample.value) AS vals, array_agg(series.series_id) AS series_ids
+        FROM sample
+        INNER JOIN series USING (labels)
+        GROUP BY series.table_name
+    LOOP
+        PERFORM _prom_catalog.insert_metric_row(r.table_name, r.times, r.vals, r.series_ids);
+    END LOOP;
+
+    FOR r IN
+        WITH exemplar AS MATERIALIZED (
+            SELECT d.metric_name, d.labels, d.time, d.value, d.exemplar_labels
+            FROM unnest(_kinds, _metric_names, _labels, _times, _values, _exemplar_labels)
+                d(kind, metric_name, labels, time, value, exemplar_labels)
+            WHERE d.kind = 'exemplar'
+        ), series AS MATERIALIZED (
+            SELECT l.labels, s.table_name, s.series_id
+            FROM (SELECT DISTINCT exemplar.metric_name, exemplar.labels FROM exemplar ORDER BY 1, 2) l,
+            LATERAL (
+                SELECT array_agg(e.key ORDER BY e.key) AS keys, array_agg(e.value ORDER BY e.key) AS vals
+                FROM jsonb_each_text(l.labels) e
+            ) kv,
+            LATERAL _prom_catalog.get_or_create_series_id_for_kv_array(l.metric_name, kv.keys, kv.vals) s
+        )
+        SELECT
+            exemplar.metric_name,
+            series.table_name,
+            array_agg(exemplar.time) AS times,
+            array_agg(exemplar.value) AS vals,
+            array_agg(series.series_id) AS series_ids,
+            array_agg(exemplar.exemplar_labels) AS exemplar_labels
+        FROM exemplar
+        INNER JOIN series USING (labels)
+        GROUP BY exemplar.metric_name, series.table_name
+    LOOP
+        PERFORM _prom_catalog.create_exemplar_table_if_not_exists(r.metric_name);
+        PERFORM _prom_catalog.get_new_pos_for_key(
+            r.metric_name,
+            r.table_name,
+            ARRAY(SELECT DISTINCT jsonb_object_keys(l) FROM unnest(r.exemplar_labels) l ORDER BY 1),
+            true
+        );
+        -- exemplar label values are stored by key position, with a placeholder for absent keys
+        PERFORM _prom_catalog.insert_exemplar_row(
+            r.table_name,
+            r.times,
+            r.series_ids,
+            ARRAY(
+                SELECT
+                    ARRAY(
+                        SELECT coalesce(u.exemplar_labels->>p.key, '__promscale_no_value__')
+                        FROM _prom_catalog.exemplar_label_key_position p
+                        WHERE p.metric_name = r.metric_name
+                        ORDER BY p.pos
+                    )::prom_api.label_value_array
+                FROM unnest(r.exemplar_labels) WITH ORDINALITY u(exemplar_labels, ord)
+                ORDER BY u.ord
+            ),
+            r.vals
+        );
+    END LOOP;
+
+    PERFORM _prom_catalog.insert_metric_metadatas(
+        array_agg(now()), array_agg(d.metric_name), array_agg(d.metric_type), array_agg(d.unit), array_agg(d.help)
+    )
+    FROM unnest(_kinds, _metric_names, _metric_types, _units, _helps) d(kind, metric_name, metric_type, unit, help)
+    WHERE d.kind = 'metadata'
+    HAVING count(*) > 0;
+END;
+$proc$
+LANGUAGE PLPGSQL;
+COMMENT ON PROCEDURE prom_api.insert_remote_write(BYTEA)
+IS 'inserts the samples, exemplars and metadata of a snappy-compressed Prometheus remote write request';
+GRANT EXECUTE ON PROCEDURE prom_api.insert_remote_write(BYTEA) TO prom_writer;
@@ -0,0 +1,30 @@
+CREATE OR REPLACE AGGREGATE prom_api.encode_remote_read(labels JSONB, sample_times TIMESTAMPTZ[], sample_values DOUBLE PRECISION[])
+(
+    sfunc = _prom_ext.encode_remote_read_transition,
+    stype = internal,
+    finalfunc = _prom_ext.encode_remote_read_final
+);
+COMMENT ON AGGREGATE prom_api.encode_remote_read(JSONB, TIMESTAMPTZ[], DOUBLE PRECISION[])
+IS 'encodes the aggregated series as a snappy-compressed Prometheus remote read response';
+GRANT EXECUTE ON FUNCTION prom_api.encode_remote_read(JSONB, TIMESTAMPTZ[], DOUBLE PRECISION[]) TO prom_reader;
+
+CREATE OR REPLACE AGGREGATE prom_api.encode_remote_read(query_index INTEGER, labels JSONB, sample_times TIMESTAMPTZ[], sample_values DOUBLE PRECISION[])
+(
+    sfunc = _prom_ext.encode_remote_read_transition,
+    stype = internal,
+    finalfunc = _prom_ext.encode_remote_read_final
+);
+COMMENT ON AGGREGATE prom_api.encode_remote_read(INTEGER, JSONB, TIMESTAMPTZ[], DOUBLE PRECISION[])
+IS 'encodes the aggregated series as a snappy-compressed Prometheus remote read response, with one result per query_index';
+GRANT EXECUTE ON FUNCTION prom_api.encode_remote_read(INTEGER, JSONB, TIMESTAMPTZ[], DOUBLE PRECISION[]) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION prom_api.encode_remote_read_chunked(labels JSONB, sample_times TIMESTAMPTZ[], sample_values DOUBLE PRECISION[], query_index BIGINT = 0)
+RETURNS BYTEA
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT _prom_ext.encode_remote_read_chunked(labels, sample_times, sample_values, query_index)
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.encode_remote_read_chunked(JSONB, TIMESTAMPTZ[], DOUBLE PRECISION[], BIGINT)
+IS 'encodes a series as a frame of a streamed (XOR chunked) Prometheus remote read response';
+GRANT EXECUTE ON FUNCTION prom_api.encode_remote_read_chunked(JSONB, TIMESTAMPTZ[], DOUBLE PRECISION[], BIGINT) TO prom_reader;
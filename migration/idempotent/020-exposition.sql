@@ -0,0 +1,10 @@
+CREATE OR REPLACE FUNCTION prom_api.parse_exposition(input TEXT, default_ts TIMESTAMPTZ = now())
+RETURNS TABLE (metric_family TEXT, metric_type TEXT, help TEXT, unit TEXT, labels JSONB, "time" TIMESTAMPTZ, value DOUBLE PRECISION, exemplar_labels JSONB, exemplar_value DOUBLE PRECISION, exemplar_time TIMESTAMPTZ)
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT * FROM _prom_ext.parse_exposition(input, default_ts)
+$func$
+LANGUAGE SQL IMMUTABLE PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.parse_exposition(TEXT, TIMESTAMPTZ)
+IS 'parses Prometheus text exposition format or OpenMetrics input into samples. default_ts is used for samples without a timestamp';
+GRANT EXECUTE ON FUNCTION prom_api.parse_exposition(TEXT, TIMESTAMPTZ) TO prom_reader;
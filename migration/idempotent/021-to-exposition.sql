@@ -0,0 +1,19 @@
+CREATE OR REPLACE AGGREGATE prom_api.to_exposition(metric_name TEXT, labels JSONB, value DOUBLE PRECISION, ts TIMESTAMPTZ)
+(
+    sfunc = _prom_ext.to_exposition_transition,
+    stype = internal,
+    finalfunc = _prom_ext.to_exposition_final
+);
+COMMENT ON AGGREGATE prom_api.to_exposition(TEXT, JSONB, DOUBLE PRECISION, TIMESTAMPTZ)
+IS 'renders the aggregated samples in Prometheus text exposition format, with HELP and TYPE lines from the metric metadata';
+GRANT EXECUTE ON FUNCTION prom_api.to_exposition(TEXT, JSONB, DOUBLE PRECISION, TIMESTAMPTZ) TO prom_reader;
+
+CREATE OR REPLACE AGGREGATE prom_api.to_openmetrics(metric_name TEXT, labels JSONB, value DOUBLE PRECISION, ts TIMESTAMPTZ)
+(
+    sfunc = _prom_ext.to_openmetrics_transition,
+    stype = internal,
+    finalfunc = _prom_ext.to_exposition_final
+);
+COMMENT ON AGGREGATE prom_api.to_openmetrics(TEXT, JSONB, DOUBLE PRECISION, TIMESTAMPTZ)
+IS 'renders the aggregated samples in OpenMetrics text format, with HELP, TYPE and UNIT lines from the metric metadata and the latest exemplar of each series from its exemplar table';
+GRANT EXECUTE ON FUNCTION prom_api.to_openmetrics(TEXT, JSONB, DOUBLE PRECISION, TIMESTAMPTZ) TO prom_reader;
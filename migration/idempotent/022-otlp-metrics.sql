@@ -0,0 +1,10 @@
+CREATE OR REPLACE FUNCTION prom_api.decode_otlp_metrics(payload BYTEA)
+RETURNS TABLE (kind TEXT, metric_name TEXT, labels JSONB, "time" TIMESTAMPTZ, value DOUBLE PRECISION, exemplar_labels JSONB, metric_type TEXT, unit TEXT, help TEXT)
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT * FROM _prom_ext.decode_otlp_metrics(payload)
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.decode_otlp_metrics(BYTEA)
+IS 'decodes a protobuf OTLP metrics export request into Prometheus sample, exemplar and metadata rows';
+GRANT EXECUTE ON FUNCTION prom_api.decode_otlp_metrics(BYTEA) TO prom_reader;
@@ -0,0 +1,325 @@
+CREATE OR REPLACE FUNCTION ps_trace.decode_otlp_spans(payload BYTEA)
+RETURNS TABLE (
+    trace_id ps_trace.trace_id,
+    span_id BIGINT,
+    parent_span_id BIGINT,
+    trace_state TEXT,
+    name TEXT,
+    span_kind ps_trace.span_kind,
+    start_time TIMESTAMPTZ,
+    end_time TIMESTAMPTZ,
+    status_code ps_trace.status_code,
+    status_message TEXT,
+    span_tags JSONB,
+    dropped_tags_count INT,
+    dropped_events_count INT,
+    dropped_link_count INT,
+    resource_tags JSONB,
+    resource_dropped_tags_count INT,
+    resource_schema_url TEXT,
+    instrumentation_lib_name TEXT,
+    instrumentation_lib_version TEXT,
+    instrumentation_lib_schema_url TEXT
+)
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT
+        d.trace_id::ps_trace.trace_id,
+        d.span_id,
+        d.parent_span_id,
+        d.trace_state,
+        d.name,
+        d.span_kind::ps_trace.span_kind,
+        d.start_time,
+        d.end_time,
+        d.status_code::ps_trace.status_code,
+        d.status_message,
+        d.span_tags,
+        d.dropped_tags_count,
+        d.dropped_events_count,
+        d.dropped_link_count,
+        d.resource_tags,
+        d.resource_dropped_tags_count,
+        d.resource_schema_url,
+        d.instrumentation_lib_name,
+        d.instrumentation_lib_version,
+        d.instrumentation_lib_schema_url
+    FROM _prom_ext.decode_otlp_spans(payload) d
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION ps_trace.decode_otlp_spans(BYTEA)
+IS 'decodes a protobuf OTLP traces export request into span rows, with the tags as JSON objects';
+GRANT EXECUTE ON FUNCTION ps_trace.decode_otlp_spans(BYTEA) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION ps_trace.decode_otlp_span_events(payload BYTEA)
+RETURNS TABLE (
+    "time" TIMESTAMPTZ,
+    trace_id ps_trace.trace_id,
+    span_id BIGINT,
+    event_nbr INT,
+    name TEXT,
+    tags JSONB,
+    dropped_tags_count INT
+)
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT d.time, d.trace_id::ps_trace.trace_id, d.span_id, d.event_nbr, d.name, d.tags, d.dropped_tags_count
+    FROM _prom_ext.decode_otlp_span_events(payload) d
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION ps_trace.decode_otlp_span_events(BYTEA)
+IS 'decodes a protobuf OTLP traces export request into span event rows, with the tags as JSON objects';
+GRANT EXECUTE ON FUNCTION ps_trace.decode_otlp_span_events(BYTEA) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION ps_trace.decode_otlp_span_links(payload BYTEA)
+RETURNS TABLE (
+    trace_id ps_trace.trace_id,
+    span_id BIGINT,
+    span_start_time TIMESTAMPTZ,
+    linked_trace_id ps_trace.trace_id,
+    linked_span_id BIGINT,
+    link_nbr INT,
+    trace_state TEXT,
+    tags JSONB,
+    dropped_tags_count INT
+)
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT
+        d.trace_id::ps_trace.trace_id,
+        d.span_id,
+        d.span_start_time,
+        d.linked_trace_id::ps_trace.trace_id,
+        d.linked_span_id,
+        d.link_nbr,
+        d.trace_state,
+        d.tags,
+        d.dropped_tags_count
+    FROM _prom_ext.decode_otlp_span_links(payload) d
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION ps_trace.decode_otlp_span_links(BYTEA)
+IS 'decodes a protobuf OTLP traces export request into span link rows, with the tags as JSON objects';
+GRANT EXECUTE ON FUNCTION ps_trace.decode_otlp_span_links(BYTEA) TO prom_reader;
+
+CREATE OR REPLACE PROCEDURE ps_trace.insert_otlp_traces(payload BYTEA)
+    SET search_path = pg_catalog, pg_temp
+AS $proc$
+DECLARE
+    _trace_ids ps_trace.trace_id[];
+    _span_ids BIGINT[];
+    _parent_span_ids BIGINT[];
+    _trace_states TEXT[];
+    _names TEXT[];
+    _span_kinds ps_trace.span_kind[];
+    _start_times TIMESTAMPTZ[];
+    _end_times TIMESTAMPTZ[];
+    _status_codes ps_trace.status_code[];
+    _status_messages TEXT[];
+    _span_tags JSONB[];
+    _dropped_tags_counts INT[];
+    _dropped_events_counts INT[];
+    _dropped_link_counts INT[];
+    _resource_tags JSONB[];
+    _resource_dropped_tags_counts INT[];
+    _resource_schema_urls TEXT[];
+    _instrumentation_lib_names TEXT[];
+    _instrumentation_lib_versions TEXT[];
+    _instrumentation_lib_schema_urls TEXT[];
+    _event_times TIMESTAMPTZ[];
+    _event_trace_ids ps_trace.trace_id[];
+    _event_span_ids BIGINT[];
+    _event_nbrs INT[];
+    _event_names TEXT[];
+    _event_tags JSONB[];
+    _event_dropped_tags_counts INT[];
+    _link_trace_ids ps_trace.trace_id[];
+    _link_span_ids BIGINT[];
+    _link_span_start_times TIMESTAMPTZ[];
+    _linked_trace_ids ps_trace.trace_id[];
+    _linked_span_ids BIGINT[];
+    _link_nbrs INT[];
+    _link_trace_states TEXT[];
+    _link_tags JSONB[];
+    _link_dropped_tags_counts INT[];
+BEGIN
+    -- the request is decoded only once per row kind, each of the steps below reads the rows back with unnest
+    SELECT
+        array_agg(s.trace_id), array_agg(s.span_id), array_agg(s.parent_span_id), array_agg(s.trace_state),
+        array_agg(s.name), array_agg(s.span_kind), array_agg(s.start_time), array_agg(s.end_time),
+        array_agg(s.status_code), array_agg(s.status_message), array_agg(s.span_tags),
+        array_agg(s.dropped_tags_count), array_agg(s.dropped_events_count), array_agg(s.dropped_link_count),
+        array_agg(s.resource_tags), array_agg(s.resource_dropped_tags_count), array_agg(s.resource_schema_url),
+        array_agg(s.instrumentation_lib_name), array_agg(s.instrumentation_lib_version),
+        array_agg(s.instrumentation_lib_schema_url)
+    INTO
+        _trace_ids, _span_ids, _parent_span_ids, _trace_states,
+        _names, _span_kinds, _start_times, _end_times,
+        _status_codes, _status_messages, _span_tags,
+        _dropped_tags_counts, _dropped_events_counts, _dropped_link_counts,
+        _resource_tags, _resource_dropped_tags_counts, _resource_schema_urls,
+        _instrumentation_lib_names, _instrumentation_lib_versions,
+        _instrumentation_lib_schema_urls
+    FROM ps_trace.decode_otlp_spans(payload) s;
+
+    SELECT
+        array_agg(e.time), array_agg(e.trace_id), array_agg(e.span_id), array_agg(e.event_nbr),
+        array_agg(e.name), array_agg(e.tags), array_agg(e.dropped_tags_count)
+    INTO
+        _event_times, _event_trace_ids, _event_span_ids, _event_nbrs,
+        _event_names, _event_tags, _event_dropped_tags_counts
+    FROM ps_trace.decode_otlp_span_events(payload) e;
+
+    SELECT
+        array_agg(l.trace_id), array_agg(l.span_id), array_agg(l.span_start_time), array_agg(l.linked_trace_id),
+        array_agg(l.linked_span_id), array_agg(l.link_nbr), array_agg(l.trace_state), array_agg(l.tags),
+        array_agg(l.dropped_tags_count)
+    INTO
+        _link_trace_ids, _link_span_ids, _link_span_start_times, _linked_trace_ids,
+        _linked_span_ids, _link_nbrs, _link_trace_states, _link_tags,
+        _link_dropped_tags_counts
+    FROM ps_trace.decode_otlp_span_links(payload) l;
+
+    -- Tag keys and tags have to exist before the tag maps can be looked up.
+    -- Well-known tag keys already exist for all tag types and are left as is.
+    PERFORM ps_trace.put_tag(t.key, t.value, t.tag_type)
+    FROM (
+        WITH tag AS MATERIALIZED (
+            SELECT DISTINCT e.key, e.value, t.tag_type
+            FROM (
+                SELECT u.tags, ps_trace.span_tag_type() AS tag_type
+                FROM unnest(_span_tags) u(tags)
+                UNION ALL
+                SELECT u.tags, ps_trace.resource_tag_type()
+                FROM unnest(_resource_tags) u(tags)
+                UNION ALL
+                SELECT u.tags, ps_trace.event_tag_type()
+                FROM unnest(_event_tags) u(tags)
+                UNION ALL
+                SELECT u.tags, ps_trace.link_tag_type()
+                FROM unnest(_link_tags) u(tags)
+            ) t, jsonb_each(t.tags) e
+        ), tag_key AS MATERIALIZED (
+            SELECT k.key, k.tag_type, ps_trace.put_tag_key(k.key, k.tag_type) AS key_id
+            FROM (SELECT DISTINCT tag.key, tag.tag_type FROM tag ORDER BY 1, 2) k
+        )
+        -- joining on tag_key makes sure the key of a tag is put first
+        SELECT tag.key, tag.value, tag.tag_type
+        FROM tag
+        INNER JOIN tag_key USING (key, tag_type)
+        ORDER BY 1, 2, 3
+    ) t;
+
+    INSERT INTO _ps_trace.span (
+        trace_id,
+        span_id,
+        parent_span_id,
+        operation_id,
+        start_time,
+        end_time,
+        duration_ms,
+        instrumentation_lib_id,
+        resource_schema_url_id,
+        event_time,
+        dropped_tags_count,
+        dropped_events_count,
+        dropped_link_count,
+        resource_dropped_tags_count,
+        status_code,
+        trace_state,
+        span_tags,
+        status_message,
+        resource_tags
+    )
+    SELECT
+        s.trace_id,
+        s.span_id,
+        s.parent_span_id,
+        ps_trace.put_operation(coalesce(s.resource_tags->>'service.name', ''), s.name, s.span_kind),
+        s.start_time,
+        s.end_time,
+        extract(epoch FROM (s.end_time - s.start_time)) * 1000.0,
+        CASE WHEN s.instrumentation_lib_name IS NOT NULL THEN
+            ps_trace.put_instrumentation_lib(
+                s.instrumentation_lib_name,
+                coalesce(s.instrumentation_lib_version, ''),
+                ps_trace.put_schema_url(s.instrumentation_lib_schema_url)
+            )
+        END,
+        ps_trace.put_schema_url(s.resource_schema_url),
+        e.event_time,
+        s.dropped_tags_count,
+        s.dropped_events_count,
+        s.dropped_link_count,
+        s.resource_dropped_tags_count,
+        s.status_code,
+        s.trace_state,
+        ps_trace.get_tag_map(s.span_tags),
+        s.status_message,
+        ps_trace.get_tag_map(s.resource_tags)
+    FROM unnest(
+        _trace_ids, _span_ids, _parent_span_ids, _trace_states,
+        _names, _span_kinds, _start_times, _end_times,
+        _status_codes, _status_messages, _span_tags,
+        _dropped_tags_counts, _dropped_events_counts, _dropped_link_counts,
+        _resource_tags, _resource_dropped_tags_counts, _resource_schema_urls,
+        _instrumentation_lib_names, _instrumentation_lib_versions,
+        _instrumentation_lib_schema_urls
+    ) s(
+        trace_id, span_id, parent_span_id, trace_state,
+        name, span_kind, start_time, end_time,
+        status_code, status_message, span_tags,
+        dropped_tags_count, dropped_events_count, dropped_link_count,
+        resource_tags, resource_dropped_tags_count, resource_schema_url,
+        instrumentation_lib_name, instrumentation_lib_version,
+        instrumentation_lib_schema_url
+    )
+    LEFT JOIN (
+        SELECT e.trace_id, e.span_id, tstzrange(min(e.time), max(e.time), '[]') AS event_time
+        FROM unnest(_event_trace_ids, _event_span_ids, _event_times) e(trace_id, span_id, time)
+        GROUP BY e.trace_id, e.span_id
+    ) e ON (e.trace_id = s.trace_id AND e.span_id = s.span_id);
+
+    INSERT INTO _ps_trace.event (time, trace_id, span_id, event_nbr, dropped_tags_count, name, tags)
+    SELECT e.time, e.trace_id, e.span_id, e.event_nbr, e.dropped_tags_count, e.name, ps_trace.get_tag_map(e.tags)
+    FROM unnest(
+        _event_times, _event_trace_ids, _event_span_ids, _event_nbrs,
+        _event_names, _event_tags, _event_dropped_tags_counts
+    ) e(time, trace_id, span_id, event_nbr, name, tags, dropped_tags_count);
+
+    INSERT INTO _ps_trace.link (
+        trace_id,
+        span_id,
+        span_start_time,
+        linked_trace_id,
+        linked_span_id,
+        link_nbr,
+        dropped_tags_count,
+        trace_state,
+        tags
+    )
+    SELECT
+        l.trace_id,
+        l.span_id,
+        l.span_start_time,
+        l.linked_trace_id,
+        l.linked_span_id,
+        l.link_nbr,
+        l.dropped_tags_count,
+        l.trace_state,
+        ps_trace.get_tag_map(l.tags)
+    FROM unnest(
+        _link_trace_ids, _link_span_ids, _link_span_start_times, _linked_trace_ids,
+        _linked_span_ids, _link_nbrs, _link_trace_states, _link_tags,
+        _link_dropped_tags_counts
+    ) l(
+        trace_id, span_id, span_start_time, linked_trace_id,
+        linked_span_id, link_nbr, trace_state, tags,
+        dropped_tags_count
+    );
+END;
+$proc$
+LANGUAGE PLPGSQL;
+COMMENT ON PROCEDURE ps_trace.insert_otlp_traces(BYTEA)
+IS 'inserts the spans, events and links of a protobuf OTLP traces export request, registering their tags, operations, schema URLs and instrumentation libraries';
+GRANT EXECUTE ON PROCEDURE ps_trace.insert_otlp_traces(BYTEA) TO prom_writer;
@@ -0,0 +1,87 @@
+CREATE OR REPLACE FUNCTION ps_trace.decode_zipkin_json(spans JSONB)
+RETURNS TABLE (
+    trace_id ps_trace.trace_id,
+    span_id BIGINT,
+    parent_span_id BIGINT,
+    trace_state TEXT,
+    name TEXT,
+    span_kind ps_trace.span_kind,
+    start_time TIMESTAMPTZ,
+    end_time TIMESTAMPTZ,
+    status_code ps_trace.status_code,
+    status_message TEXT,
+    span_tags JSONB,
+    resource_tags JSONB,
+    instrumentation_lib_name TEXT,
+    instrumentation_lib_version TEXT,
+    events JSONB
+)
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT
+        d.trace_id::ps_trace.trace_id,
+        d.span_id,
+        d.parent_span_id,
+        d.trace_state,
+        d.name,
+        d.span_kind::ps_trace.span_kind,
+        d.start_time,
+        d.end_time,
+        d.status_code::ps_trace.status_code,
+        d.status_message,
+        d.span_tags,
+        d.resource_tags,
+        d.instrumentation_lib_name,
+        d.instrumentation_lib_version,
+        d.events
+    FROM _prom_ext.decode_zipkin_json(spans) d
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION ps_trace.decode_zipkin_json(JSONB)
+IS 'decodes Zipkin v2 JSON spans into span rows, with the annotations as an array of events';
+GRANT EXECUTE ON FUNCTION ps_trace.decode_zipkin_json(JSONB) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION ps_trace.decode_jaeger_json(traces JSONB)
+RETURNS TABLE (
+    trace_id ps_trace.trace_id,
+    span_id BIGINT,
+    parent_span_id BIGINT,
+    trace_state TEXT,
+    name TEXT,
+    span_kind ps_trace.span_kind,
+    start_time TIMESTAMPTZ,
+    end_time TIMESTAMPTZ,
+    status_code ps_trace.status_code,
+    status_message TEXT,
+    span_tags JSONB,
+    resource_tags JSONB,
+    instrumentation_lib_name TEXT,
+    instrumentation_lib_version TEXT,
+    events JSONB,
+    links JSONB
+)
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT
+        d.trace_id::ps_trace.trace_id,
+        d.span_id,
+        d.parent_span_id,
+        d.trace_state,
+        d.name,
+        d.span_kind::ps_trace.span_kind,
+        d.start_time,
+        d.end_time,
+        d.status_code::ps_trace.status_code,
+        d.status_message,
+        d.span_tags,
+        d.resource_tags,
+        d.instrumentation_lib_name,
+        d.instrumentation_lib_version,
+        d.events,
+        d.links
+    FROM _prom_ext.decode_jaeger_json(traces) d
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION ps_trace.decode_jaeger_json(JSONB)
+IS 'decodes Jaeger JSON traces into span rows, with the logs as an array of events and the references other than the parent as an array of links';
+GRANT EXECUTE ON FUNCTION ps_trace.decode_jaeger_json(JSONB) TO prom_reader;
@@ -0,0 +1,21 @@
+CREATE OR REPLACE FUNCTION ps_trace.trace_to_otlp_json(_trace_id ps_trace.trace_id)
+RETURNS JSONB
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT _prom_ext.trace_to_otlp_json(_trace_id::uuid)
+$func$
+LANGUAGE SQL STABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION ps_trace.trace_to_otlp_json(ps_trace.trace_id)
+IS 'serializes the spans, events and links of a trace as an OTLP JSON traces export request, grouped by resource and scope';
+GRANT EXECUTE ON FUNCTION ps_trace.trace_to_otlp_json(ps_trace.trace_id) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION ps_trace.trace_to_jaeger_json(_trace_id ps_trace.trace_id)
+RETURNS JSONB
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT _prom_ext.trace_to_jaeger_json(_trace_id::uuid)
+$func$
+LANGUAGE SQL STABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION ps_trace.trace_to_jaeger_json(ps_trace.trace_id)
+IS 'serializes the spans of a trace in the JSON model of the Jaeger query API';
+GRANT EXECUTE ON FUNCTION ps_trace.trace_to_jaeger_json(ps_trace.trace_id) TO prom_reader;
@@ -0,0 +1,10 @@
+CREATE OR REPLACE FUNCTION prom_api.parse_line_protocol(input TEXT, "precision" TEXT = 'ns', default_ts TIMESTAMPTZ = now())
+RETURNS TABLE (measurement TEXT, field TEXT, metric_name TEXT, labels JSONB, "time" TIMESTAMPTZ, value DOUBLE PRECISION)
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT * FROM _prom_ext.parse_line_protocol(input, "precision", default_ts)
+$func$
+LANGUAGE SQL IMMUTABLE PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.parse_line_protocol(TEXT, TEXT, TIMESTAMPTZ)
+IS 'parses InfluxDB line protocol into a Prometheus sample per numeric field, named <measurement>_<field> and labeled with the tags. Timestamps are in units of precision (ns, us, ms or s), default_ts is used for points without a timestamp';
+GRANT EXECUTE ON FUNCTION prom_api.parse_line_protocol(TEXT, TEXT, TIMESTAMPTZ) TO prom_reader;
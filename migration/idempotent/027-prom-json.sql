@@ -0,0 +1,19 @@
+CREATE OR REPLACE AGGREGATE prom_api.to_prom_matrix_json(labels JSONB, eval_times TIMESTAMPTZ[], "values" DOUBLE PRECISION[])
+(
+    sfunc = _prom_ext.to_prom_matrix_json_transition,
+    stype = internal,
+    finalfunc = _prom_ext.to_prom_matrix_json_final
+);
+COMMENT ON AGGREGATE prom_api.to_prom_matrix_json(JSONB, TIMESTAMPTZ[], DOUBLE PRECISION[])
+IS 'renders series labels with their values at the evaluation times, e.g. of vector_selector or prom_rate, as a Prometheus HTTP API range query response with resultType matrix';
+GRANT EXECUTE ON FUNCTION prom_api.to_prom_matrix_json(JSONB, TIMESTAMPTZ[], DOUBLE PRECISION[]) TO prom_reader;
+
+CREATE OR REPLACE AGGREGATE prom_api.to_prom_vector_json(labels JSONB, eval_time TIMESTAMPTZ, value DOUBLE PRECISION)
+(
+    sfunc = _prom_ext.to_prom_vector_json_transition,
+    stype = internal,
+    finalfunc = _prom_ext.to_prom_vector_json_final
+);
+COMMENT ON AGGREGATE prom_api.to_prom_vector_json(JSONB, TIMESTAMPTZ, DOUBLE PRECISION)
+IS 'renders series labels with their value at the evaluation time as a Prometheus HTTP API instant query response with resultType vector';
+GRANT EXECUTE ON FUNCTION prom_api.to_prom_vector_json(JSONB, TIMESTAMPTZ, DOUBLE PRECISION) TO prom_reader;
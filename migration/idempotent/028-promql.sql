@@ -0,0 +1,21 @@
+CREATE OR REPLACE FUNCTION prom_api.promql_parse(query TEXT)
+RETURNS JSONB
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT _prom_ext.promql_parse(query)
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.promql_parse(TEXT)
+IS 'parses a PromQL query into its syntax tree, in the JSON format of Prometheus'' /api/v1/parse_query with the byte offsets of every node in posRange';
+GRANT EXECUTE ON FUNCTION prom_api.promql_parse(TEXT) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION prom_api.promql_selectors(query TEXT)
+RETURNS TABLE (kind TEXT, metric_name TEXT, matchers JSONB, range_ms BIGINT, offset_ms BIGINT, start_pos INTEGER, end_pos INTEGER)
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT * FROM _prom_ext.promql_selectors(query)
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.promql_selectors(TEXT)
+IS 'lists the vector and matrix selectors of a PromQL query with their matchers, range and offset in milliseconds, and byte offsets in the query';
+GRANT EXECUTE ON FUNCTION prom_api.promql_selectors(TEXT) TO prom_reader;
@@ -0,0 +1,21 @@
+CREATE OR REPLACE FUNCTION prom_api.promql_query_range(query TEXT, start TIMESTAMPTZ, "end" TIMESTAMPTZ, step INTERVAL)
+RETURNS TABLE (labels JSONB, "values" DOUBLE PRECISION[])
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT * FROM _prom_ext.promql_query_range(query, start, "end", (extract(epoch FROM step) * 1000)::bigint)
+$func$
+LANGUAGE SQL STABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.promql_query_range(TEXT, TIMESTAMPTZ, TIMESTAMPTZ, INTERVAL)
+IS 'evaluates a PromQL query at every step from start to end, returning the labels of each resulting series with its values at these times. Unsupported PromQL features raise an error';
+GRANT EXECUTE ON FUNCTION prom_api.promql_query_range(TEXT, TIMESTAMPTZ, TIMESTAMPTZ, INTERVAL) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION prom_api.promql_query(query TEXT, "time" TIMESTAMPTZ = now())
+RETURNS TABLE (labels JSONB, value DOUBLE PRECISION)
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT r.labels, r."values"[1] FROM _prom_ext.promql_query_range(query, "time", "time", 1) r
+$func$
+LANGUAGE SQL STABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.promql_query(TEXT, TIMESTAMPTZ)
+IS 'evaluates a PromQL query at a single time, returning the labels and value of each resulting series. Unsupported PromQL features raise an error';
+GRANT EXECUTE ON FUNCTION prom_api.promql_query(TEXT, TIMESTAMPTZ) TO prom_reader;
@@ -0,0 +1,21 @@
+CREATE OR REPLACE FUNCTION prom_api.parse_selector(selector TEXT)
+RETURNS JSONB
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT _prom_ext.parse_selector(selector)
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.parse_selector(TEXT)
+IS 'parses a Prometheus series selector, returning its metric name and label matchers as JSON';
+GRANT EXECUTE ON FUNCTION prom_api.parse_selector(TEXT) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION prom_api.select_series(selector TEXT, start TIMESTAMPTZ = NULL, "end" TIMESTAMPTZ = NULL)
+RETURNS TABLE (series_id BIGINT, metric_name TEXT, labels JSONB)
+-- Note: no explicit `SET SCHEMA` because we want this function to be inlined
+AS $func$
+    SELECT * FROM _prom_ext.select_series(selector, start, "end")
+$func$
+LANGUAGE SQL STABLE PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.select_series(TEXT, TIMESTAMPTZ, TIMESTAMPTZ)
+IS 'returns the series matching a Prometheus series selector, with Prometheus semantics for matchers of the empty string. With start or end, only series with samples in between are returned';
+GRANT EXECUTE ON FUNCTION prom_api.select_series(TEXT, TIMESTAMPTZ, TIMESTAMPTZ) TO prom_reader;
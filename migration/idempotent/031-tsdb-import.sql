@@ -0,0 +1,98 @@
+CREATE OR REPLACE FUNCTION _prom_catalog.import_tsdb_block_batch(path TEXT, batch_size INT)
+RETURNS _ps_catalog.tsdb_block_import
+    SET search_path = pg_catalog, pg_temp
+AS $func$
+DECLARE
+    meta JSONB;
+    progress _ps_catalog.tsdb_block_import;
+    r RECORD;
+    s RECORD;
+    batch_series BIGINT := 0;
+    batch_samples BIGINT := 0;
+    batch_resume_offset BIGINT;
+BEGIN
+    IF batch_size < 1 THEN
+        RAISE EXCEPTION 'batch_size must be positive, got %', batch_size;
+    END IF;
+
+    meta := _prom_ext.tsdb_block_meta(path);
+    INSERT INTO _ps_catalog.tsdb_block_import AS i (ulid, path, min_time, max_time, num_series)
+    VALUES (
+        meta->>'ulid',
+        path,
+        to_timestamp((meta->>'minTime')::BIGINT / 1000.0),
+        to_timestamp((meta->>'maxTime')::BIGINT / 1000.0),
+        nullif((meta->'stats'->>'numSeries')::BIGINT, 0)
+    )
+    -- the block may have been moved since an interrupted import
+    ON CONFLICT (ulid) DO UPDATE SET path = excluded.path
+    RETURNING * INTO progress;
+
+    IF progress.completed_at IS NOT NULL THEN
+        RETURN progress;
+    END IF;
+
+    FOR r IN
+        SELECT b.next_series_offset, b.metric_name, kv.keys, kv.vals, b.times, b."values"
+        FROM _prom_ext.read_tsdb_block(path, progress.resume_offset, batch_size) b,
+        LATERAL (
+            SELECT array_agg(e.key ORDER BY e.key) AS keys, array_agg(e.value ORDER BY e.key) AS vals
+            FROM jsonb_each_text(b.labels) e
+        ) kv
+        ORDER BY b.series_offset
+    LOOP
+        batch_series := batch_series + 1;
+        batch_resume_offset := r.next_series_offset;
+        -- series without a metric name or float samples can't be stored
+        CONTINUE WHEN r.metric_name IS NULL OR cardinality(r.times) = 0;
+        SELECT * INTO STRICT s
+        FROM _prom_catalog.get_or_create_series_id_for_kv_array(r.metric_name, r.keys, r.vals);
+        batch_samples := batch_samples + _prom_catalog.insert_metric_row(
+            s.table_name, r.times, r."values", array_fill(s.series_id, ARRAY[cardinality(r.times)])
+        );
+    END LOOP;
+
+    UPDATE _ps_catalog.tsdb_block_import i
+    SET imported_series = i.imported_series + batch_series,
+        imported_samples = i.imported_samples + batch_samples,
+        resume_offset = coalesce(batch_resume_offset, i.resume_offset),
+        completed_at = CASE WHEN batch_series < batch_size THEN now() END
+    WHERE i.ulid = progress.ulid
+    RETURNING * INTO progress;
+    RETURN progress;
+END;
+$func$
+LANGUAGE PLPGSQL;
+COMMENT ON FUNCTION _prom_catalog.import_tsdb_block_batch(TEXT, INT)
+IS 'imports the next batch of series of a Prometheus TSDB block, returning the progress of its import';
+GRANT EXECUTE ON FUNCTION _prom_catalog.import_tsdb_block_batch(TEXT, INT) TO prom_writer;
+
+-- Note: no `SET search_path` because procedures with it can't COMMIT
+CREATE OR REPLACE PROCEDURE prom_api.import_tsdb_block(path TEXT, batch_size INT = 1000)
+AS $proc$
+DECLARE
+    progress _ps_catalog.tsdb_block_import;
+BEGIN
+    SELECT * INTO progress
+    FROM _ps_catalog.tsdb_block_import i
+    WHERE i.ulid = _prom_ext.tsdb_block_meta(path) OPERATOR(pg_catalog.->>) 'ulid';
+    IF progress.completed_at IS NOT NULL THEN
+        RAISE NOTICE 'block % was already imported at %', progress.ulid, progress.completed_at;
+        RETURN;
+    ELSIF progress.imported_series > 0 THEN
+        RAISE NOTICE 'resuming import of block % after % series', progress.ulid, progress.imported_series;
+    END IF;
+
+    LOOP
+        progress := _prom_catalog.import_tsdb_block_batch(path, batch_size);
+        RAISE NOTICE 'block %: imported % of % series, % samples',
+            progress.ulid, progress.imported_series, coalesce(progress.num_series::TEXT, '?'), progress.imported_samples;
+        COMMIT;
+        EXIT WHEN progress.completed_at IS NOT NULL;
+    END LOOP;
+END;
+$proc$
+LANGUAGE PLPGSQL;
+COMMENT ON PROCEDURE prom_api.import_tsdb_block(TEXT, INT)
+IS 'imports the samples of a Prometheus TSDB block directory on the database server, committing after every batch of series. Interrupted imports resume by block ULID. Requires superuser or pg_read_server_files';
+GRANT EXECUTE ON PROCEDURE prom_api.import_tsdb_block(TEXT, INT) TO prom_writer;
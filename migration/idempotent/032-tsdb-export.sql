@@ -0,0 +1,43 @@
+CREATE OR REPLACE AGGREGATE prom_api.write_tsdb_block(path TEXT, labels JSONB, sample_times TIMESTAMPTZ[], sample_values DOUBLE PRECISION[])
+(
+    sfunc = _prom_ext.write_tsdb_block_transition,
+    stype = internal,
+    finalfunc = _prom_ext.write_tsdb_block_final
+);
+COMMENT ON AGGREGATE prom_api.write_tsdb_block(TEXT, JSONB, TIMESTAMPTZ[], DOUBLE PRECISION[])
+IS 'writes the aggregated series as a Prometheus TSDB block into a new directory under path on the database server, returning its meta.json. Requires superuser or pg_write_server_files';
+GRANT EXECUTE ON FUNCTION prom_api.write_tsdb_block(TEXT, JSONB, TIMESTAMPTZ[], DOUBLE PRECISION[]) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION prom_api.export_tsdb_block(path TEXT, metric_names TEXT[], start TIMESTAMPTZ, "end" TIMESTAMPTZ)
+RETURNS JSONB
+    SET search_path = pg_catalog, pg_temp
+AS $func$
+DECLARE
+    series_query TEXT;
+    meta JSONB;
+BEGIN
+    SELECT string_agg(format(
+        'SELECT d.series_id, array_agg(d.time ORDER BY d.time) AS times, array_agg(d.value ORDER BY d.time) AS vals
+        FROM prom_data.%I d
+        WHERE d.time >= $1 AND d.time < $2
+        GROUP BY d.series_id', m.table_name), ' UNION ALL ')
+    INTO series_query
+    FROM _prom_catalog.metric m
+    WHERE m.table_schema = 'prom_data' AND m.metric_name = ANY(metric_names);
+
+    IF series_query IS NULL THEN
+        RETURN NULL;
+    END IF;
+
+    EXECUTE format(
+        'SELECT prom_api.write_tsdb_block(%L, prom_api.jsonb(prom_api.labels(s.series_id)), s.times, s.vals)
+        FROM (%s) s', path, series_query)
+    INTO meta
+    USING start, "end";
+    RETURN meta;
+END;
+$func$
+LANGUAGE PLPGSQL;
+COMMENT ON FUNCTION prom_api.export_tsdb_block(TEXT, TEXT[], TIMESTAMPTZ, TIMESTAMPTZ)
+IS 'exports the samples of the given metrics in [start, end) as a Prometheus TSDB block into a new directory under path on the database server, returning its meta.json or NULL when there are no samples. Requires superuser or pg_write_server_files';
+GRANT EXECUTE ON FUNCTION prom_api.export_tsdb_block(TEXT, TEXT[], TIMESTAMPTZ, TIMESTAMPTZ) TO prom_reader;
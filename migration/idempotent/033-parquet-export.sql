@@ -0,0 +1,21 @@
+CREATE OR REPLACE FUNCTION prom_api.export_parquet(metric_name TEXT, start TIMESTAMPTZ, "end" TIMESTAMPTZ, path TEXT)
+RETURNS BIGINT
+    SET search_path = pg_catalog, pg_temp
+AS $func$
+    SELECT _prom_ext.export_metric_parquet(metric_name, start, "end", path)
+$func$
+LANGUAGE SQL VOLATILE STRICT;
+COMMENT ON FUNCTION prom_api.export_parquet(TEXT, TIMESTAMPTZ, TIMESTAMPTZ, TEXT)
+IS 'writes the samples of a metric in [start, end) as a Parquet file on the database server, with a row group per chunk, returning the number of rows. Requires superuser or pg_write_server_files';
+GRANT EXECUTE ON FUNCTION prom_api.export_parquet(TEXT, TIMESTAMPTZ, TIMESTAMPTZ, TEXT) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION ps_trace.export_parquet(start TIMESTAMPTZ, "end" TIMESTAMPTZ, path TEXT)
+RETURNS BIGINT
+    SET search_path = pg_catalog, pg_temp
+AS $func$
+    SELECT _prom_ext.export_spans_parquet(start, "end", path)
+$func$
+LANGUAGE SQL VOLATILE STRICT;
+COMMENT ON FUNCTION ps_trace.export_parquet(TIMESTAMPTZ, TIMESTAMPTZ, TEXT)
+IS 'writes the spans starting in [start, end) as a Parquet file on the database server, with a row group per chunk, returning the number of rows. Requires superuser or pg_write_server_files';
+GRANT EXECUTE ON FUNCTION ps_trace.export_parquet(TIMESTAMPTZ, TIMESTAMPTZ, TEXT) TO prom_reader;
@@ -0,0 +1,94 @@
+-- the functions taking or returning prom_api.sample_block are declared here
+-- rather than generated by pgx, as the type is defined by the migrations
+CREATE OR REPLACE FUNCTION _prom_ext.sample_block_final(internal)
+RETURNS prom_api.sample_block
+    IMMUTABLE PARALLEL SAFE
+    LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'sample_block_final_wrapper';
+GRANT EXECUTE ON FUNCTION _prom_ext.sample_block_final(internal) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION _prom_ext.sample_block_unnest(block prom_api.sample_block)
+RETURNS TABLE(time TIMESTAMPTZ, value DOUBLE PRECISION)
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'sample_block_unnest_wrapper';
+GRANT EXECUTE ON FUNCTION _prom_ext.sample_block_unnest(prom_api.sample_block) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION _prom_ext.vector_selector_block_transition(
+    state internal,
+    start_time TIMESTAMPTZ,
+    end_time TIMESTAMPTZ,
+    bucket_width BIGINT,
+    lookback BIGINT,
+    samples prom_api.sample_block)
+RETURNS internal
+    IMMUTABLE PARALLEL SAFE
+    LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'vector_selector_block_transition_wrapper';
+GRANT EXECUTE ON FUNCTION _prom_ext.vector_selector_block_transition(internal, TIMESTAMPTZ, TIMESTAMPTZ, BIGINT, BIGINT, prom_api.sample_block) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION _prom_ext.prom_rate_block_transition(
+    state internal,
+    lowest_time TIMESTAMPTZ,
+    greatest_time TIMESTAMPTZ,
+    step_size BIGINT,
+    range BIGINT,
+    samples prom_api.sample_block)
+RETURNS internal
+    IMMUTABLE PARALLEL SAFE
+    LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'prom_rate_block_transition_wrapper';
+GRANT EXECUTE ON FUNCTION _prom_ext.prom_rate_block_transition(internal, TIMESTAMPTZ, TIMESTAMPTZ, BIGINT, BIGINT, prom_api.sample_block) TO prom_reader;
+
+CREATE OR REPLACE AGGREGATE prom_api.sample_block_agg(sample_time TIMESTAMPTZ, sample_value DOUBLE PRECISION)
+(
+    sfunc = _prom_ext.sample_block_transition,
+    stype = internal,
+    finalfunc = _prom_ext.sample_block_final
+);
+COMMENT ON AGGREGATE prom_api.sample_block_agg(TIMESTAMPTZ, DOUBLE PRECISION)
+IS 'compresses the aggregated samples into a sample block, in time order. Samples with a NULL time or value are skipped';
+GRANT EXECUTE ON FUNCTION prom_api.sample_block_agg(TIMESTAMPTZ, DOUBLE PRECISION) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION prom_api.unnest(samples prom_api.sample_block)
+RETURNS TABLE(time TIMESTAMPTZ, value DOUBLE PRECISION)
+    SET search_path = pg_catalog, pg_temp
+AS $func$
+    SELECT * FROM _prom_ext.sample_block_unnest(samples)
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.unnest(prom_api.sample_block)
+IS 'expands a sample block into its samples, in time order';
+GRANT EXECUTE ON FUNCTION prom_api.unnest(prom_api.sample_block) TO prom_reader;
+
+-- the sample block overloads skip the samples outside of the evaluated range,
+-- prom_rate expects the blocks to be ordered by time and not to overlap
+CREATE OR REPLACE AGGREGATE _prom_ext.vector_selector(
+    start_time TIMESTAMPTZ,
+    end_time TIMESTAMPTZ,
+    bucket_width BIGINT,
+    lookback BIGINT,
+    samples prom_api.sample_block)
+(
+    sfunc = _prom_ext.vector_selector_block_transition,
+    stype = internal,
+    finalfunc = _prom_ext.vector_selector_final,
+    combinefunc = _prom_ext.vector_selector_combine,
+    serialfunc = _prom_ext.vector_selector_serialize,
+    deserialfunc = _prom_ext.vector_selector_deserialize,
+    parallel = safe
+);
+GRANT EXECUTE ON FUNCTION _prom_ext.vector_selector(TIMESTAMPTZ, TIMESTAMPTZ, BIGINT, BIGINT, prom_api.sample_block) TO prom_reader;
+
+CREATE OR REPLACE AGGREGATE _prom_ext.prom_rate(
+    lowest_time TIMESTAMPTZ,
+    greatest_time TIMESTAMPTZ,
+    step_size BIGINT,
+    range BIGINT,
+    samples prom_api.sample_block)
+(
+    sfunc = _prom_ext.prom_rate_block_transition,
+    stype = internal,
+    finalfunc = _prom_ext.prom_extrapolate_final
+);
+GRANT EXECUTE ON FUNCTION _prom_ext.prom_rate(TIMESTAMPTZ, TIMESTAMPTZ, BIGINT, BIGINT, prom_api.sample_block) TO prom_reader;
@@ -0,0 +1,61 @@
+-- the functions taking or returning prom_api.quantile_sketch are declared here
+-- rather than generated by pgx, as the type is defined by the migrations
+CREATE OR REPLACE FUNCTION _prom_ext.quantile_sketch_final(internal)
+RETURNS prom_api.quantile_sketch
+    IMMUTABLE PARALLEL SAFE
+    LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'quantile_sketch_final_wrapper';
+GRANT EXECUTE ON FUNCTION _prom_ext.quantile_sketch_final(internal) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION _prom_ext.quantile_sketch_rollup_transition(state internal, sketch prom_api.quantile_sketch)
+RETURNS internal
+    IMMUTABLE PARALLEL SAFE
+    LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'quantile_sketch_rollup_transition_wrapper';
+GRANT EXECUTE ON FUNCTION _prom_ext.quantile_sketch_rollup_transition(internal, prom_api.quantile_sketch) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION _prom_ext.quantile_sketch_quantile(sketch prom_api.quantile_sketch, quantile DOUBLE PRECISION)
+RETURNS DOUBLE PRECISION
+    IMMUTABLE PARALLEL SAFE STRICT
+    LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'quantile_sketch_quantile_wrapper';
+GRANT EXECUTE ON FUNCTION _prom_ext.quantile_sketch_quantile(prom_api.quantile_sketch, DOUBLE PRECISION) TO prom_reader;
+
+CREATE OR REPLACE AGGREGATE prom_api.quantile_sketch_agg(value DOUBLE PRECISION)
+(
+    sfunc = _prom_ext.quantile_sketch_transition,
+    stype = internal,
+    finalfunc = _prom_ext.quantile_sketch_final,
+    combinefunc = _prom_ext.quantile_sketch_combine,
+    serialfunc = _prom_ext.quantile_sketch_serialize,
+    deserialfunc = _prom_ext.quantile_sketch_deserialize,
+    parallel = safe
+);
+COMMENT ON AGGREGATE prom_api.quantile_sketch_agg(DOUBLE PRECISION)
+IS 'summarizes the aggregated values in a sketch, from which quantiles can be estimated with 1% relative accuracy. NaN and infinite values are skipped';
+GRANT EXECUTE ON FUNCTION prom_api.quantile_sketch_agg(DOUBLE PRECISION) TO prom_reader;
+
+CREATE OR REPLACE AGGREGATE prom_api.quantile_sketch_rollup(sketch prom_api.quantile_sketch)
+(
+    sfunc = _prom_ext.quantile_sketch_rollup_transition,
+    stype = internal,
+    finalfunc = _prom_ext.quantile_sketch_final,
+    combinefunc = _prom_ext.quantile_sketch_combine,
+    serialfunc = _prom_ext.quantile_sketch_serialize,
+    deserialfunc = _prom_ext.quantile_sketch_deserialize,
+    parallel = safe
+);
+COMMENT ON AGGREGATE prom_api.quantile_sketch_rollup(prom_api.quantile_sketch)
+IS 'merges the aggregated quantile sketches into one, as if all of their values had been aggregated with prom_api.quantile_sketch_agg';
+GRANT EXECUTE ON FUNCTION prom_api.quantile_sketch_rollup(prom_api.quantile_sketch) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION prom_api.approx_quantile(sketch prom_api.quantile_sketch, quantile DOUBLE PRECISION)
+RETURNS DOUBLE PRECISION
+    SET search_path = pg_catalog, pg_temp
+AS $func$
+    SELECT _prom_ext.quantile_sketch_quantile(sketch, quantile)
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.approx_quantile(prom_api.quantile_sketch, DOUBLE PRECISION)
+IS 'estimates the given quantile, between 0 and 1, of the values in a quantile sketch, or returns NULL for a sketch without values';
+GRANT EXECUTE ON FUNCTION prom_api.approx_quantile(prom_api.quantile_sketch, DOUBLE PRECISION) TO prom_reader;
@@ -0,0 +1,114 @@
+-- the functions taking or returning prom_api.hyperloglog are declared here
+-- rather than generated by pgx, as the type is defined by the migrations
+CREATE OR REPLACE FUNCTION _prom_ext.hyperloglog_final(internal)
+RETURNS prom_api.hyperloglog
+    IMMUTABLE PARALLEL SAFE
+    LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'hyperloglog_final_wrapper';
+GRANT EXECUTE ON FUNCTION _prom_ext.hyperloglog_final(internal) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION _prom_ext.hyperloglog_rollup_transition(state internal, sketch prom_api.hyperloglog)
+RETURNS internal
+    IMMUTABLE PARALLEL SAFE
+    LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'hyperloglog_rollup_transition_wrapper';
+GRANT EXECUTE ON FUNCTION _prom_ext.hyperloglog_rollup_transition(internal, prom_api.hyperloglog) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION _prom_ext.hyperloglog_count(sketch prom_api.hyperloglog)
+RETURNS BIGINT
+    IMMUTABLE PARALLEL SAFE STRICT
+    LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'hyperloglog_count_wrapper';
+GRANT EXECUTE ON FUNCTION _prom_ext.hyperloglog_count(prom_api.hyperloglog) TO prom_reader;
+
+CREATE OR REPLACE AGGREGATE prom_api.hyperloglog_agg(value BIGINT)
+(
+    sfunc = _prom_ext.hyperloglog_transition,
+    stype = internal,
+    finalfunc = _prom_ext.hyperloglog_final,
+    combinefunc = _prom_ext.hyperloglog_combine,
+    serialfunc = _prom_ext.hyperloglog_serialize,
+    deserialfunc = _prom_ext.hyperloglog_deserialize,
+    parallel = safe
+);
+COMMENT ON AGGREGATE prom_api.hyperloglog_agg(BIGINT)
+IS 'summarizes the distinct aggregated values in a HyperLogLog sketch, from which their number can be estimated with a standard error of about 0.8%. NULL values are skipped';
+GRANT EXECUTE ON FUNCTION prom_api.hyperloglog_agg(BIGINT) TO prom_reader;
+
+CREATE OR REPLACE AGGREGATE prom_api.hyperloglog_agg(value TEXT)
+(
+    sfunc = _prom_ext.hyperloglog_text_transition,
+    stype = internal,
+    finalfunc = _prom_ext.hyperloglog_final,
+    combinefunc = _prom_ext.hyperloglog_combine,
+    serialfunc = _prom_ext.hyperloglog_serialize,
+    deserialfunc = _prom_ext.hyperloglog_deserialize,
+    parallel = safe
+);
+COMMENT ON AGGREGATE prom_api.hyperloglog_agg(TEXT)
+IS 'summarizes the distinct aggregated values in a HyperLogLog sketch, from which their number can be estimated with a standard error of about 0.8%. NULL values are skipped';
+GRANT EXECUTE ON FUNCTION prom_api.hyperloglog_agg(TEXT) TO prom_reader;
+
+CREATE OR REPLACE AGGREGATE prom_api.hyperloglog_rollup(sketch prom_api.hyperloglog)
+(
+    sfunc = _prom_ext.hyperloglog_rollup_transition,
+    stype = internal,
+    finalfunc = _prom_ext.hyperloglog_final,
+    combinefunc = _prom_ext.hyperloglog_combine,
+    serialfunc = _prom_ext.hyperloglog_serialize,
+    deserialfunc = _prom_ext.hyperloglog_deserialize,
+    parallel = safe
+);
+COMMENT ON AGGREGATE prom_api.hyperloglog_rollup(prom_api.hyperloglog)
+IS 'merges the aggregated HyperLogLog sketches into one, as if all of their values had been aggregated with prom_api.hyperloglog_agg';
+GRANT EXECUTE ON FUNCTION prom_api.hyperloglog_rollup(prom_api.hyperloglog) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION prom_api.approx_count_distinct(sketch prom_api.hyperloglog)
+RETURNS BIGINT
+    SET search_path = pg_catalog, pg_temp
+AS $func$
+    SELECT _prom_ext.hyperloglog_count(sketch)
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.approx_count_distinct(prom_api.hyperloglog)
+IS 'estimates the number of distinct values in a HyperLogLog sketch';
+GRANT EXECUTE ON FUNCTION prom_api.approx_count_distinct(prom_api.hyperloglog) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION prom_api.approx_active_series(
+    start TIMESTAMPTZ,
+    "end" TIMESTAMPTZ,
+    bucket_width INTERVAL,
+    metric_names TEXT[] DEFAULT NULL)
+RETURNS TABLE(metric_name TEXT, bucket TIMESTAMPTZ, series BIGINT)
+    SET search_path = pg_catalog, pg_temp
+AS $func$
+DECLARE
+    _query TEXT;
+BEGIN
+    IF bucket_width <= interval '0' THEN
+        RAISE EXCEPTION 'bucket_width must be positive, got %', bucket_width;
+    END IF;
+
+    SELECT string_agg(
+        format(
+            'SELECT %L::TEXT, $1 + floor(extract(epoch FROM d.time - $1) / extract(epoch FROM $3)) * $3, '
+            'prom_api.approx_count_distinct(prom_api.hyperloglog_agg(d.series_id)) '
+            'FROM prom_data.%I d WHERE d.time >= $1 AND d.time < $2 GROUP BY 2',
+            m.metric_name, m.table_name),
+        ' UNION ALL ' ORDER BY m.metric_name)
+    INTO _query
+    FROM _prom_catalog.metric m
+    WHERE m.table_schema = 'prom_data'
+      AND (approx_active_series.metric_names IS NULL OR m.metric_name = ANY(approx_active_series.metric_names));
+
+    IF _query IS NULL THEN
+        RETURN;
+    END IF;
+
+    RETURN QUERY EXECUTE _query USING start, "end", bucket_width;
+END;
+$func$
+LANGUAGE PLPGSQL STABLE;
+COMMENT ON FUNCTION prom_api.approx_active_series(TIMESTAMPTZ, TIMESTAMPTZ, INTERVAL, TEXT[])
+IS 'estimates the number of series with samples of each metric in time buckets of the given width between start (inclusive) and end (exclusive)';
+GRANT EXECUTE ON FUNCTION prom_api.approx_active_series(TIMESTAMPTZ, TIMESTAMPTZ, INTERVAL, TEXT[]) TO prom_reader;
@@ -0,0 +1,91 @@
+-- the functions taking or returning prom_api.counter_summary are declared here
+-- rather than generated by pgx, as the type is defined by the migrations
+CREATE OR REPLACE FUNCTION _prom_ext.counter_summary_final(internal)
+RETURNS prom_api.counter_summary
+    IMMUTABLE PARALLEL SAFE
+    LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'counter_summary_final_wrapper';
+GRANT EXECUTE ON FUNCTION _prom_ext.counter_summary_final(internal) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION _prom_ext.counter_summary_rollup_transition(state internal, summary prom_api.counter_summary)
+RETURNS internal
+    IMMUTABLE PARALLEL SAFE
+    LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'counter_summary_rollup_transition_wrapper';
+GRANT EXECUTE ON FUNCTION _prom_ext.counter_summary_rollup_transition(internal, prom_api.counter_summary) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION _prom_ext.counter_summary_rollup_final(internal)
+RETURNS prom_api.counter_summary
+    IMMUTABLE PARALLEL SAFE
+    LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'counter_summary_rollup_final_wrapper';
+GRANT EXECUTE ON FUNCTION _prom_ext.counter_summary_rollup_final(internal) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION _prom_ext.counter_summary_extrapolate(summary prom_api.counter_summary, is_counter BOOLEAN, is_rate BOOLEAN)
+RETURNS DOUBLE PRECISION
+    IMMUTABLE PARALLEL SAFE STRICT
+    LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'counter_summary_extrapolate_wrapper';
+GRANT EXECUTE ON FUNCTION _prom_ext.counter_summary_extrapolate(prom_api.counter_summary, BOOLEAN, BOOLEAN) TO prom_reader;
+
+-- the samples must be aggregated in ascending time order, like for prom_rate
+CREATE OR REPLACE AGGREGATE prom_api.counter_summary_agg(
+    bucket_start TIMESTAMPTZ,
+    bucket_end TIMESTAMPTZ,
+    sample_time TIMESTAMPTZ,
+    sample_value DOUBLE PRECISION)
+(
+    sfunc = _prom_ext.counter_summary_transition,
+    stype = internal,
+    finalfunc = _prom_ext.counter_summary_final
+);
+COMMENT ON AGGREGATE prom_api.counter_summary_agg(TIMESTAMPTZ, TIMESTAMPTZ, TIMESTAMPTZ, DOUBLE PRECISION)
+IS 'summarizes the samples of a series between bucket_start and bucket_end, aggregated in ascending time order, for prom_api.rate, prom_api.increase and prom_api.delta. Stale markers are skipped';
+GRANT EXECUTE ON FUNCTION prom_api.counter_summary_agg(TIMESTAMPTZ, TIMESTAMPTZ, TIMESTAMPTZ, DOUBLE PRECISION) TO prom_reader;
+
+CREATE OR REPLACE AGGREGATE prom_api.counter_summary_rollup(summary prom_api.counter_summary)
+(
+    sfunc = _prom_ext.counter_summary_rollup_transition,
+    stype = internal,
+    finalfunc = _prom_ext.counter_summary_rollup_final,
+    combinefunc = _prom_ext.counter_summary_rollup_combine,
+    serialfunc = _prom_ext.counter_summary_rollup_serialize,
+    deserialfunc = _prom_ext.counter_summary_rollup_deserialize,
+    parallel = safe
+);
+COMMENT ON AGGREGATE prom_api.counter_summary_rollup(prom_api.counter_summary)
+IS 'merges the aggregated counter summaries of a series, in any order, into one spanning all of their buckets, as if all of their samples had been aggregated with prom_api.counter_summary_agg';
+GRANT EXECUTE ON FUNCTION prom_api.counter_summary_rollup(prom_api.counter_summary) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION prom_api.rate(summary prom_api.counter_summary)
+RETURNS DOUBLE PRECISION
+    SET search_path = pg_catalog, pg_temp
+AS $func$
+    SELECT _prom_ext.counter_summary_extrapolate(summary, true, true)
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.rate(prom_api.counter_summary)
+IS 'calculates the per-second rate of a counter over the bounds of a summary like prom_rate, or returns NULL for a summary of less than two samples';
+GRANT EXECUTE ON FUNCTION prom_api.rate(prom_api.counter_summary) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION prom_api.increase(summary prom_api.counter_summary)
+RETURNS DOUBLE PRECISION
+    SET search_path = pg_catalog, pg_temp
+AS $func$
+    SELECT _prom_ext.counter_summary_extrapolate(summary, true, false)
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.increase(prom_api.counter_summary)
+IS 'calculates the increase of a counter over the bounds of a summary like prom_increase, or returns NULL for a summary of less than two samples';
+GRANT EXECUTE ON FUNCTION prom_api.increase(prom_api.counter_summary) TO prom_reader;
+
+CREATE OR REPLACE FUNCTION prom_api.delta(summary prom_api.counter_summary)
+RETURNS DOUBLE PRECISION
+    SET search_path = pg_catalog, pg_temp
+AS $func$
+    SELECT _prom_ext.counter_summary_extrapolate(summary, false, false)
+$func$
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+COMMENT ON FUNCTION prom_api.delta(prom_api.counter_summary)
+IS 'calculates the difference of a gauge over the bounds of a summary like prom_delta, or returns NULL for a summary of less than two samples';
+GRANT EXECUTE ON FUNCTION prom_api.delta(prom_api.counter_summary) TO prom_reader;
@@ -0,0 +1,6 @@
+/* A textual counterpart of _ps_trace.tag_v, returned by the ->> operator
+ * on ps_trace.tag_map. Having a distinct type allows us to attach
+ * tag_map_rewrite to the comparison operators without affecting text.
+ */
+CREATE DOMAIN _ps_trace.tag_v_text AS pg_catalog.text;
+GRANT USAGE ON DOMAIN _ps_trace.tag_v_text TO prom_reader;
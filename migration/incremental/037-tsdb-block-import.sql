@@ -0,0 +1,16 @@
+-- The progress of prom_api.import_tsdb_block, by block
+CREATE TABLE _ps_catalog.tsdb_block_import (
+    ulid             TEXT NOT NULL PRIMARY KEY,
+    path             TEXT NOT NULL,
+    min_time         TIMESTAMPTZ NOT NULL,
+    max_time         TIMESTAMPTZ NOT NULL,
+    num_series       BIGINT,
+    imported_series  BIGINT NOT NULL DEFAULT 0,
+    -- the offset into the index of the series to continue with
+    resume_offset    BIGINT NOT NULL DEFAULT 0,
+    imported_samples BIGINT NOT NULL DEFAULT 0,
+    started_at       TIMESTAMPTZ NOT NULL DEFAULT now(),
+    completed_at     TIMESTAMPTZ
+);
+GRANT SELECT ON TABLE _ps_catalog.tsdb_block_import TO prom_reader;
+GRANT SELECT, INSERT, UPDATE, DELETE ON TABLE _ps_catalog.tsdb_block_import TO prom_writer;
@@ -0,0 +1,43 @@
+/* Define prom_api.sample_block, a Gorilla-compressed block of samples, see
+ * src/sample_block.rs. It is stored like bytea and printed like bytea, but its
+ * input and receive functions reject anything that isn't a valid block. There
+ * are deliberately no casts from or to bytea.
+ */
+CREATE TYPE prom_api.sample_block;
+
+CREATE OR REPLACE FUNCTION prom_api.sample_block_in(cstring)
+RETURNS prom_api.sample_block
+IMMUTABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'sample_block_in_wrapper';
+
+CREATE OR REPLACE FUNCTION prom_api.sample_block_out(prom_api.sample_block)
+RETURNS cstring
+LANGUAGE internal
+IMMUTABLE PARALLEL SAFE STRICT
+AS $function$byteaout$function$
+;
+
+CREATE OR REPLACE FUNCTION prom_api.sample_block_send(prom_api.sample_block)
+RETURNS bytea
+LANGUAGE internal
+IMMUTABLE PARALLEL SAFE STRICT
+AS $function$byteasend$function$
+;
+
+CREATE OR REPLACE FUNCTION prom_api.sample_block_recv(internal)
+RETURNS prom_api.sample_block
+IMMUTABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'sample_block_recv_wrapper';
+
+CREATE TYPE prom_api.sample_block (
+    INPUT = prom_api.sample_block_in,
+    OUTPUT = prom_api.sample_block_out,
+    SEND = prom_api.sample_block_send,
+    RECEIVE = prom_api.sample_block_recv,
+    INTERNALLENGTH = VARIABLE,
+    STORAGE = extended
+);
+
+GRANT USAGE ON TYPE prom_api.sample_block TO prom_reader;
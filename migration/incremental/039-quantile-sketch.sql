@@ -0,0 +1,27 @@
+/* Define prom_api.quantile_sketch, the mergeable state of the quantile sketch
+ * aggregates, see src/aggregates/quantile_sketch.rs. It's the pgx type
+ * QuantileSketch, whose text form is JSON, and its input function rejects
+ * sketches whose buckets don't add up.
+ */
+CREATE TYPE prom_api.quantile_sketch;
+
+CREATE OR REPLACE FUNCTION prom_api.quantile_sketch_in(cstring)
+RETURNS prom_api.quantile_sketch
+IMMUTABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'quantilesketch_in_wrapper';
+
+CREATE OR REPLACE FUNCTION prom_api.quantile_sketch_out(prom_api.quantile_sketch)
+RETURNS cstring
+IMMUTABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'quantilesketch_out_wrapper';
+
+CREATE TYPE prom_api.quantile_sketch (
+    INPUT = prom_api.quantile_sketch_in,
+    OUTPUT = prom_api.quantile_sketch_out,
+    INTERNALLENGTH = VARIABLE,
+    STORAGE = extended
+);
+
+GRANT USAGE ON TYPE prom_api.quantile_sketch TO prom_reader;
@@ -0,0 +1,27 @@
+/* Define prom_api.hyperloglog, the mergeable state of the HyperLogLog
+ * aggregates, see src/aggregates/hyperloglog.rs. It's the pgx type
+ * HyperLogLog, whose text form is JSON, and its registers are checked
+ * whenever a sketch is read.
+ */
+CREATE TYPE prom_api.hyperloglog;
+
+CREATE OR REPLACE FUNCTION prom_api.hyperloglog_in(cstring)
+RETURNS prom_api.hyperloglog
+IMMUTABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'hyperloglog_in_wrapper';
+
+CREATE OR REPLACE FUNCTION prom_api.hyperloglog_out(prom_api.hyperloglog)
+RETURNS cstring
+IMMUTABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'hyperloglog_out_wrapper';
+
+CREATE TYPE prom_api.hyperloglog (
+    INPUT = prom_api.hyperloglog_in,
+    OUTPUT = prom_api.hyperloglog_out,
+    INTERNALLENGTH = VARIABLE,
+    STORAGE = extended
+);
+
+GRANT USAGE ON TYPE prom_api.hyperloglog TO prom_reader;
@@ -0,0 +1,27 @@
+/* Define prom_api.counter_summary, the storable summary of the samples of a counter
+ * in a time bucket, see src/aggregates/counter_summary.rs. It's the pgx type
+ * CounterSummary, whose text form is JSON, and its input function rejects
+ * summaries whose samples lie outside of their bounds.
+ */
+CREATE TYPE prom_api.counter_summary;
+
+CREATE OR REPLACE FUNCTION prom_api.counter_summary_in(cstring)
+RETURNS prom_api.counter_summary
+IMMUTABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'countersummary_in_wrapper';
+
+CREATE OR REPLACE FUNCTION prom_api.counter_summary_out(prom_api.counter_summary)
+RETURNS cstring
+IMMUTABLE PARALLEL SAFE STRICT
+LANGUAGE c
+AS '$libdir/promscale-{{extension_version}}', 'countersummary_out_wrapper';
+
+CREATE TYPE prom_api.counter_summary (
+    INPUT = prom_api.counter_summary_in,
+    OUTPUT = prom_api.counter_summary_out,
+    INTERNALLENGTH = VARIABLE,
+    STORAGE = extended
+);
+
+GRANT USAGE ON TYPE prom_api.counter_summary TO prom_reader;
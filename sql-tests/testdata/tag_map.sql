@@ -95,7 +95,7 @@ SELECT span_tags -> 'pwlen' AS tagv FROM span  GROUP BY tagv;
 \set QUIET 1
 \i 'testdata/scripts/pgtap-1.2.0.sql'
 
-SELECT * FROM plan(10);
+SELECT * FROM plan(19);
 
 SELECT is(_ps_trace.text_matches(input), expected, 'test text_matches')
 FROM
@@ -134,6 +134,60 @@ explain_jsonb(
        SELECT * FROM _ps_trace.span WHERE span_tags @> ANY((SELECT _ps_trace.tag_v_text_eq_matching_tags('pwlen', 'does not exist'))::jsonb[]);
     $$) j;
 
+SELECT is(
+    (SELECT count(*) FROM ps_trace.span WHERE span_tags ? 'pwlen'),
+    2::bigint,
+    'key existence returns correct result');
+SELECT
+  ok(j @? '$.**."Index Cond" ? (@ like_regex ".*span_tags.* @> ANY .*\\\$0.*")', 'key existence uses index')
+FROM
+explain_jsonb(
+    $$
+        SELECT * FROM ps_trace.span WHERE span_tags ? 'pwlen'
+    $$) j;
+
+SELECT is(
+    (SELECT count(*) FROM ps_trace.span WHERE span_tags ->> 'pwlen' = '25'),
+    1::bigint,
+    'text equality returns correct result');
+SELECT
+  ok(j @? '$.**."Index Cond" ? (@ like_regex ".*span_tags.* @> ANY .*\\\$0.*")', 'text equality uses index')
+FROM
+explain_jsonb(
+    $$
+        SELECT * FROM ps_trace.span WHERE span_tags ->> 'pwlen' = '25'
+    $$) j;
+
+SELECT is(
+    (SELECT count(*) FROM ps_trace.span WHERE span_tags -> 'pwlen' IN ('18', '25', '42')),
+    2::bigint,
+    'IN list returns correct result');
+SELECT
+  ok(j @? '$.**."Index Cond" ? (@ like_regex ".*span_tags.* @> ANY .*\\\$0.*")', 'IN list uses index')
+FROM
+explain_jsonb(
+    $$
+        SELECT * FROM ps_trace.span WHERE span_tags -> 'pwlen' IN ('18', '25', '42')
+    $$) j;
+SELECT
+  ok(j @? '$.**."Index Cond" ? (@ like_regex ".*span_tags.* @> ANY .*\\\$0.*")', '= ANY uses index')
+FROM
+explain_jsonb(
+    $$
+        SELECT * FROM ps_trace.span WHERE span_tags -> 'pwlen' = ANY(array['18', '25', '42']::jsonb[])
+    $$) j;
+SELECT is(
+    (SELECT count(*) FROM ps_trace.span WHERE ps_trace.tag_v_eq_any(span_tags -> 'pwlen', array['18', '25', '42']::jsonb[])),
+    2::bigint,
+    'tag_v_eq_any returns correct result');
+SELECT
+  ok(j @? '$.**."Index Cond" ? (@ like_regex ".*span_tags.* @> ANY .*\\\$0.*")', 'tag_v_eq_any uses index')
+FROM
+explain_jsonb(
+    $$
+        SELECT * FROM ps_trace.span WHERE ps_trace.tag_v_eq_any(span_tags -> 'pwlen', array['18', '25', '42']::jsonb[])
+    $$) j;
+
 RESET enable_seqscan;
 
 SELECT * FROM finish(true);
\ No newline at end of file
@@ -0,0 +1,114 @@
+\unset ECHO
+\set QUIET 1
+\i 'testdata/scripts/pgtap-1.2.0.sql'
+
+CREATE FUNCTION explain_jsonb(_query pg_catalog.text, OUT _result pg_catalog.jsonb)
+    RETURNS pg_catalog.jsonb
+    LANGUAGE plpgsql VOLATILE AS
+$fnc$
+BEGIN
+    EXECUTE 'explain (format json, analyze) ' || _query INTO _result;
+    RETURN;
+END;
+$fnc$;
+
+CREATE FUNCTION scanned_chunks(_plan pg_catalog.jsonb)
+    RETURNS pg_catalog.int4
+    LANGUAGE sql IMMUTABLE AS
+$fnc$
+    SELECT pg_catalog.jsonb_array_length(
+        pg_catalog.jsonb_path_query_array(_plan, '$.**."Relation Name" ? (@ like_regex "^_hyper_.*_chunk$")')
+    )
+$fnc$;
+
+-- The planner hook is only installed once the extension's library is loaded
+SELECT _prom_ext.num_cpus() > 0;
+
+SELECT * FROM plan(4);
+
+SELECT _prom_catalog.get_or_create_metric_table_name('time_bounds');
+CALL _prom_catalog.finalize_metric_creation();
+SELECT prom_api.set_metric_chunk_interval('time_bounds', '1 hour'::INTERVAL);
+
+-- A sample per minute over a day, i.e. 24 chunks
+INSERT INTO prom_data.time_bounds(time, value, series_id)
+SELECT timestamptz '2000-01-02 00:00:00 UTC' + interval '1 minute' * g, g, s.id
+FROM generate_series(0, 24 * 60 - 1) g,
+     _prom_catalog.get_or_create_series_id('{"__name__": "time_bounds", "job": "test"}') s(id);
+
+-- Would've raised "input time less than expected" without the time bounds
+SELECT is(
+    (
+        SELECT _prom_ext.vector_selector(
+              '2000-01-02 12:00:00 UTC'::TIMESTAMPTZ
+            , '2000-01-02 12:10:00 UTC'::TIMESTAMPTZ
+            , 5 * 60 * 1000
+            , 5 * 60 * 1000
+            , time
+            , value)
+        FROM prom_data.time_bounds
+    ),
+    array[720, 725, 730]::float8[],
+    'vector_selector returns correct result without a WHERE clause');
+
+-- [11:55, 12:10] spans the 11:00 and 12:00 chunks
+SELECT is(
+    scanned_chunks(j),
+    2,
+    'vector_selector excludes chunks outside of [start - lookback, end]')
+FROM explain_jsonb(
+    $$
+        SELECT _prom_ext.vector_selector(
+              '2000-01-02 12:00:00 UTC'::TIMESTAMPTZ
+            , '2000-01-02 12:10:00 UTC'::TIMESTAMPTZ
+            , 5 * 60 * 1000
+            , 5 * 60 * 1000
+            , time
+            , value)
+        FROM prom_data.time_bounds
+    $$) j;
+
+-- prom_rate rejects samples prior to its start, only the upper bound is left out
+SELECT is(
+    (
+        SELECT _prom_ext.prom_rate(
+              '2000-01-02 12:00:00 UTC'::TIMESTAMPTZ
+            , '2000-01-02 12:10:00 UTC'::TIMESTAMPTZ
+            , 5 * 60 * 1000
+            , 5 * 60 * 1000
+            , time
+            , value ORDER BY time)
+        FROM prom_data.time_bounds
+        WHERE time >= '2000-01-02 12:00:00 UTC'
+    ),
+    (
+        SELECT _prom_ext.prom_rate(
+              '2000-01-02 12:00:00 UTC'::TIMESTAMPTZ
+            , '2000-01-02 12:10:00 UTC'::TIMESTAMPTZ
+            , 5 * 60 * 1000
+            , 5 * 60 * 1000
+            , time
+            , value ORDER BY time)
+        FROM prom_data.time_bounds
+        WHERE time BETWEEN '2000-01-02 12:00:00 UTC' AND '2000-01-02 12:10:00 UTC'
+    ),
+    'prom_rate returns the same result with the time bounds injected');
+
+SELECT is(
+    scanned_chunks(j),
+    1,
+    'prom_rate excludes chunks after end')
+FROM explain_jsonb(
+    $$
+        SELECT _prom_ext.prom_rate(
+              '2000-01-02 12:00:00 UTC'::TIMESTAMPTZ
+            , '2000-01-02 12:10:00 UTC'::TIMESTAMPTZ
+            , 5 * 60 * 1000
+            , 5 * 60 * 1000
+            , time
+            , value ORDER BY time)
+        FROM prom_data.time_bounds
+        WHERE time >= '2000-01-02 12:00:00 UTC'
+    $$) j;
+
+SELECT * FROM finish(true);
@@ -0,0 +1,576 @@
+//! # Counter Summary
+//! The `counter_summary_agg` aggregate summarizes the samples of a series in a time bucket, so that
+//! counters can be downsampled without losing the ability to compute their rate. `prom_rate` needs
+//! all raw samples of a range in a single pass, but its result only depends on the first and last
+//! sample in the range, the sum of the values before counter resets, the number of samples and
+//! the bounds of the range. A `CounterSummary` records exactly these.
+//!
+//! `counter_summary_rollup` merges summaries of adjacent buckets: a reset between two of them is
+//! detected by comparing the last value of one with the first value of the next, just like between
+//! two consecutive samples. The merged summary is thus the same as if the raw samples of all of the
+//! buckets had been summarized at once, and `rate`, `increase` and `delta` of it extrapolate like
+//! `prom_rate`, `prom_increase` and `prom_delta` over the merged bounds do. Only resets within a
+//! bucket that wouldn't be visible in the raw samples either are lost.
+//!
+//! Stale markers are skipped. The samples of a bucket must be aggregated in ascending time order,
+//! while the summaries can be rolled up in any order, as long as their samples don't overlap.
+//!
+//! The result is a `prom_api.counter_summary`, whose text form is the summary as JSON. Its input
+//! function checks that the samples lie within the bounds of the summary. As the type is defined
+//! by the migrations, so are the functions taking or returning it.
+//!
+//! ## Example SQL query
+//!
+//! ```sql
+//! CREATE TABLE requests_hourly AS
+//! SELECT series_id, hour,
+//!     prom_api.counter_summary_agg(hour, hour + '1 hour', time, value ORDER BY time) AS summary
+//! FROM prom_data.http_requests_total, time_bucket('1 hour', time) AS hour
+//! GROUP BY 1, 2;
+//!
+//! SELECT series_id, prom_api.rate(prom_api.counter_summary_rollup(summary))
+//! FROM requests_hourly
+//! WHERE hour >= now() - interval '30 days'
+//! GROUP BY 1;
+//! ```
+use pgx::*;
+
+#[pg_schema]
+mod _prom_ext {
+    use pgx::*;
+    use serde::{Deserialize, Serialize};
+
+    use crate::aggregate_utils::in_aggregate_context;
+    use crate::aggregates::{STALE_NAN, USECS_PER_SEC};
+    use crate::palloc::{Inner, InternalAsValue, ToInternal};
+    use crate::raw::bytea;
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn counter_summary_transition(
+        state: Internal,
+        bucket_start: TimestampWithTimeZone,
+        bucket_end: TimestampWithTimeZone,
+        sample_time: Option<TimestampWithTimeZone>,
+        sample_value: Option<f64>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        counter_summary_transition_inner(
+            unsafe { state.to_inner() },
+            bucket_start.into(),
+            bucket_end.into(),
+            sample_time.map(Into::into),
+            sample_value,
+            fcinfo,
+        )
+        .internal()
+    }
+
+    fn counter_summary_transition_inner(
+        state: Option<Inner<CounterSummary>>,
+        bucket_start: i64,
+        bucket_end: i64,
+        sample_time: Option<i64>,
+        sample_value: Option<f64>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<CounterSummary>> {
+        unsafe {
+            in_aggregate_context(fcinfo, || {
+                if bucket_start >= bucket_end {
+                    error!(
+                        "bucket start {} must be before bucket end {}",
+                        bucket_start, bucket_end
+                    )
+                }
+                let (time, value) = match (sample_time, sample_value) {
+                    (Some(time), Some(value)) => (time, value),
+                    _ => return state,
+                };
+                if value.to_bits() == STALE_NAN {
+                    return state;
+                }
+                if time < bucket_start || time > bucket_end {
+                    error!(
+                        "input time {} not in bounds [{}, {}]",
+                        time, bucket_start, bucket_end
+                    )
+                }
+                match state {
+                    None => Some(CounterSummary::new(bucket_start, bucket_end, time, value).into()),
+                    Some(mut state) => {
+                        if state.bucket_start != bucket_start || state.bucket_end != bucket_end {
+                            error!(
+                                "the bucket bounds must be the same for all samples of a summary"
+                            )
+                        }
+                        state.add_sample(time, value);
+                        Some(state)
+                    }
+                }
+            })
+        }
+    }
+
+    #[pg_extern(immutable, parallel_safe, sql = false)]
+    pub fn counter_summary_final(
+        state: Internal, /* Option<Inner<CounterSummary>> */
+    ) -> Option<CounterSummary> {
+        let state: Inner<CounterSummary> = unsafe { state.to_inner() }?;
+        Some(state.clone())
+    }
+
+    /// The rollup only collects the summaries, they are merged in time order by its final function.
+    #[pg_extern(immutable, parallel_safe, sql = false)]
+    pub fn counter_summary_rollup_transition(
+        state: Internal,
+        summary: Option<CounterSummary>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        counter_summary_rollup_transition_inner(unsafe { state.to_inner() }, summary, fcinfo)
+            .internal()
+    }
+
+    fn counter_summary_rollup_transition_inner(
+        state: Option<Inner<Vec<CounterSummary>>>,
+        summary: Option<CounterSummary>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<Vec<CounterSummary>>> {
+        unsafe {
+            in_aggregate_context(fcinfo, || {
+                let summary = match summary {
+                    None => return state,
+                    Some(summary) => summary,
+                };
+                let mut state = state.unwrap_or_else(|| Vec::new().into());
+                state.push(summary);
+                Some(state)
+            })
+        }
+    }
+
+    #[pg_extern(immutable, parallel_safe, sql = false)]
+    pub fn counter_summary_rollup_final(
+        state: Internal, /* Option<Inner<Vec<CounterSummary>>> */
+    ) -> Option<CounterSummary> {
+        let mut state: Inner<Vec<CounterSummary>> = unsafe { state.to_inner() }?;
+        merge_summaries(&mut state)
+    }
+
+    #[pg_extern(immutable, parallel_safe, strict, create_or_replace)]
+    pub fn counter_summary_rollup_serialize(state: Internal) -> bytea {
+        let state: &mut Vec<CounterSummary> = unsafe {
+            // This is safe as long as this function is defined as `strict`, in
+            // which case PG knows that NULL -> NULL and so it will not call this
+            // function with NULL values
+            state.get_mut().unwrap()
+        };
+        crate::do_serialize!(state)
+    }
+
+    #[pg_extern(immutable, parallel_safe, strict, create_or_replace)]
+    pub fn counter_summary_rollup_deserialize(bytes: bytea, _internal: Internal) -> Internal {
+        let summaries: Vec<CounterSummary> = crate::do_deserialize!(bytes, Vec<CounterSummary>);
+        Inner::from(summaries).internal()
+    }
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn counter_summary_rollup_combine(
+        state1: Internal,
+        state2: Internal,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        counter_summary_rollup_combine_inner(
+            unsafe { state1.to_inner() },
+            unsafe { state2.to_inner() },
+            fcinfo,
+        )
+        .internal()
+    }
+
+    fn counter_summary_rollup_combine_inner(
+        state1: Option<Inner<Vec<CounterSummary>>>,
+        state2: Option<Inner<Vec<CounterSummary>>>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<Vec<CounterSummary>>> {
+        unsafe {
+            in_aggregate_context(fcinfo, || match (state1, state2) {
+                (None, None) => None,
+                (None, Some(state2)) => Some(state2.clone().into()),
+                (Some(state1), None) => Some(state1.clone().into()),
+                (Some(state1), Some(state2)) => {
+                    let mut s1 = state1.clone();
+                    s1.extend(state2.iter().cloned());
+                    Some(s1.into())
+                }
+            })
+        }
+    }
+
+    /// Extrapolates the change of the summarized samples to the bounds of the summary,
+    /// like `prom_rate`, `prom_increase` and `prom_delta` do for their range.
+    #[pg_extern(immutable, parallel_safe, strict, sql = false)]
+    pub fn counter_summary_extrapolate(
+        summary: CounterSummary,
+        is_counter: bool,
+        is_rate: bool,
+    ) -> Option<f64> {
+        summary.extrapolate(is_counter, is_rate)
+    }
+
+    /// Merges the summaries in the time order of their samples, which must not overlap.
+    fn merge_summaries(summaries: &mut [CounterSummary]) -> Option<CounterSummary> {
+        summaries.sort_by_key(|s| s.first_time);
+        let (first, rest) = summaries.split_first()?;
+        let mut merged = first.clone();
+        for summary in rest {
+            if summary.first_time < merged.last_time {
+                error!("counter summaries must not overlap in time")
+            }
+            merged.append(summary);
+        }
+        Some(merged)
+    }
+
+    /// JSON has no NaN or infinity, so the text form writes those as strings,
+    /// like the text form of `double precision` does.
+    mod float_text {
+        use serde::{de, Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+            if serializer.is_human_readable() && !value.is_finite() {
+                serializer.serialize_str(&value.to_string())
+            } else {
+                serializer.serialize_f64(*value)
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+            #[derive(Deserialize)]
+            #[serde(untagged)]
+            enum Float {
+                Number(f64),
+                Text(String),
+            }
+
+            if !deserializer.is_human_readable() {
+                return f64::deserialize(deserializer);
+            }
+            match Float::deserialize(deserializer)? {
+                Float::Number(value) => Ok(value),
+                Float::Text(text) => text.parse().map_err(de::Error::custom),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, PostgresType)]
+    #[pgx(sql = false)]
+    #[inoutfuncs]
+    pub struct CounterSummary {
+        first_time: i64,
+        #[serde(with = "float_text")]
+        first_value: f64,
+        last_time: i64,
+        #[serde(with = "float_text")]
+        last_value: f64,
+        /// The sum of the values before each counter reset
+        #[serde(with = "float_text")]
+        reset_correction: f64,
+        count: u64,
+        bucket_start: i64,
+        bucket_end: i64,
+    }
+
+    impl InOutFuncs for CounterSummary {
+        fn input(input: &cstr_core::CStr) -> Self {
+            let input = input
+                .to_str()
+                .unwrap_or_else(|e| error!("invalid counter summary: {}", e));
+            let summary: CounterSummary = serde_json::from_str(input)
+                .unwrap_or_else(|e| error!("invalid counter summary: {}", e));
+            summary
+                .validate()
+                .unwrap_or_else(|e| error!("invalid counter summary: {}", e));
+            summary
+        }
+
+        fn output(&self, buffer: &mut StringInfo) {
+            let output =
+                serde_json::to_string(self).unwrap_or_else(|e| error!("serialization error {}", e));
+            buffer.push_str(&output);
+        }
+    }
+
+    impl CounterSummary {
+        pub fn new(bucket_start: i64, bucket_end: i64, time: i64, value: f64) -> Self {
+            CounterSummary {
+                first_time: time,
+                first_value: value,
+                last_time: time,
+                last_value: value,
+                reset_correction: 0.0,
+                count: 1,
+                bucket_start,
+                bucket_end,
+            }
+        }
+
+        /// Checks the invariants that the functions on summaries rely on.
+        fn validate(&self) -> Result<(), String> {
+            if self.bucket_start >= self.bucket_end
+                || self.bucket_end.checked_sub(self.bucket_start).is_none()
+            {
+                return Err(format!(
+                    "bucket start {} must be before bucket end {}",
+                    self.bucket_start, self.bucket_end
+                ));
+            }
+            if !(self.bucket_start <= self.first_time
+                && self.first_time <= self.last_time
+                && self.last_time <= self.bucket_end)
+            {
+                return Err(format!(
+                    "sample times [{}, {}] not in bounds [{}, {}]",
+                    self.first_time, self.last_time, self.bucket_start, self.bucket_end
+                ));
+            }
+            if self.count == 0 || (self.count == 1 && self.first_time != self.last_time) {
+                return Err(format!(
+                    "a count of {} doesn't match the sample times",
+                    self.count
+                ));
+            }
+            Ok(())
+        }
+
+        pub fn add_sample(&mut self, time: i64, value: f64) {
+            if time < self.last_time {
+                error!("inputs must be in ascending time order")
+            }
+            if value < self.last_value {
+                self.reset_correction += self.last_value;
+            }
+            self.last_time = time;
+            self.last_value = value;
+            self.count += 1;
+        }
+
+        /// Appends the summary of the samples following the ones of `self`.
+        fn append(&mut self, next: &CounterSummary) {
+            if next.first_value < self.last_value {
+                self.reset_correction += self.last_value;
+            }
+            self.reset_correction += next.reset_correction;
+            self.last_time = next.last_time;
+            self.last_value = next.last_value;
+            self.count += next.count;
+            self.bucket_start = self.bucket_start.min(next.bucket_start);
+            self.bucket_end = self.bucket_end.max(next.bucket_end);
+        }
+
+        // see GapfillDeltaTransition::add_delta_for_current_window, which this mirrors
+        pub fn extrapolate(&self, is_counter: bool, is_rate: bool) -> Option<f64> {
+            if self.count < 2 {
+                return None;
+            }
+
+            let mut result_val = self.last_value - self.first_value;
+            if is_counter {
+                result_val += self.reset_correction;
+            }
+
+            // all calculated durations and interval are in seconds
+            let mut duration_to_start =
+                (self.first_time - self.bucket_start) as f64 / USECS_PER_SEC as f64;
+            let duration_to_end = (self.bucket_end - self.last_time) as f64 / USECS_PER_SEC as f64;
+
+            let sampled_interval = (self.last_time - self.first_time) as f64 / USECS_PER_SEC as f64;
+            let avg_duration_between_samples = sampled_interval / (self.count - 1) as f64;
+
+            if is_counter && result_val > 0.0 && self.first_value >= 0.0 {
+                // Counters cannot be negative, don't extrapolate beyond their zero point.
+                let duration_to_zero = sampled_interval * (self.first_value / result_val);
+                if duration_to_zero < duration_to_start {
+                    duration_to_start = duration_to_zero
+                }
+            }
+
+            let extrapolation_threshold = avg_duration_between_samples * 1.1;
+            let mut extrapolate_to_interval = sampled_interval;
+
+            if duration_to_start < extrapolation_threshold {
+                extrapolate_to_interval += duration_to_start;
+            } else {
+                extrapolate_to_interval += avg_duration_between_samples / 2.0;
+            }
+
+            if duration_to_end < extrapolation_threshold {
+                extrapolate_to_interval += duration_to_end;
+            } else {
+                extrapolate_to_interval += avg_duration_between_samples / 2.0;
+            }
+
+            result_val *= extrapolate_to_interval / sampled_interval;
+
+            if is_rate {
+                result_val /= ((self.bucket_end - self.bucket_start) / USECS_PER_SEC) as f64;
+            }
+
+            Some(result_val)
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+
+    fn setup() {
+        Spi::run(
+            r#"
+            CREATE TABLE cs_test_table(t TIMESTAMPTZ, v DOUBLE PRECISION);
+            INSERT INTO cs_test_table
+            SELECT '2000-01-02 15:00:00 UTC'::TIMESTAMPTZ + i * interval '65 seconds',
+                   -- resets within the second hour and at the start of the fifth one
+                   CASE WHEN i < 80 THEN i * 3 WHEN i < 222 THEN (i - 80) * 2 ELSE i - 222 END
+            FROM generate_series(3, 400) i;
+        "#,
+        );
+    }
+
+    #[pg_test]
+    fn test_counter_summary_matches_prom_rate() {
+        setup();
+        let result = Spi::get_one::<bool>(
+            r#"
+            WITH summary AS (
+                SELECT prom_api.counter_summary_agg(
+                    '2000-01-02 16:00:00 UTC', '2000-01-02 18:00:00 UTC', t, v ORDER BY t) AS s
+                FROM cs_test_table
+                WHERE t BETWEEN '2000-01-02 16:00:00 UTC' AND '2000-01-02 18:00:00 UTC'
+            ), raw AS (
+                SELECT prom_rate('2000-01-02 16:00:00 UTC', '2000-01-02 18:00:00 UTC',
+                            2 * 3600 * 1000, 2 * 3600 * 1000, t, v ORDER BY t) AS rate,
+                       prom_increase('2000-01-02 16:00:00 UTC', '2000-01-02 18:00:00 UTC',
+                            2 * 3600 * 1000, 2 * 3600 * 1000, t, v ORDER BY t) AS increase,
+                       prom_delta('2000-01-02 16:00:00 UTC', '2000-01-02 18:00:00 UTC',
+                            2 * 3600 * 1000, 2 * 3600 * 1000, t, v ORDER BY t) AS delta
+                FROM cs_test_table
+                WHERE t BETWEEN '2000-01-02 16:00:00 UTC' AND '2000-01-02 18:00:00 UTC'
+            )
+            SELECT prom_api.rate(s) = rate[1]
+               AND prom_api.increase(s) = increase[1]
+               AND prom_api.delta(s) = delta[1]
+            FROM summary, raw;
+        "#,
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test]
+    fn test_counter_summary_rollup() {
+        setup();
+        let result = Spi::get_one::<bool>(
+            r#"
+            WITH hourly AS (
+                SELECT time_bucket('1 hour', t) AS hour, prom_api.counter_summary_agg(
+                    time_bucket('1 hour', t), time_bucket('1 hour', t) + '1 hour', t, v ORDER BY t) AS s
+                FROM cs_test_table
+                GROUP BY 1
+            ), rollup AS (
+                -- in reverse order, which doesn't matter
+                SELECT prom_api.counter_summary_rollup(s ORDER BY hour DESC) AS s
+                FROM hourly
+            ), raw AS (
+                SELECT prom_rate('2000-01-02 15:00:00 UTC', '2000-01-02 23:00:00 UTC',
+                            8 * 3600 * 1000, 8 * 3600 * 1000, t, v ORDER BY t) AS rate,
+                       prom_increase('2000-01-02 15:00:00 UTC', '2000-01-02 23:00:00 UTC',
+                            8 * 3600 * 1000, 8 * 3600 * 1000, t, v ORDER BY t) AS increase
+                FROM cs_test_table
+            )
+            SELECT abs(prom_api.rate(s) - rate[1]) < 1e-9
+               AND abs(prom_api.increase(s) - increase[1]) < 1e-6
+            FROM rollup, raw;
+        "#,
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test]
+    fn test_counter_summary_single_sample() {
+        let result = Spi::get_one::<bool>(
+            r#"
+            SELECT prom_api.rate(prom_api.counter_summary_agg(
+                       '2000-01-02 15:00:00 UTC', '2000-01-02 16:00:00 UTC', t, v)) IS NULL
+            FROM (VALUES ('2000-01-02 15:30:00 UTC'::TIMESTAMPTZ, 1.0::DOUBLE PRECISION)) AS s(t, v);
+        "#,
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test]
+    fn test_counter_summary_text_round_trip() {
+        setup();
+        let result = Spi::get_one::<bool>(
+            r#"
+            WITH hourly AS (
+                SELECT time_bucket('1 hour', t) AS hour, prom_api.counter_summary_agg(
+                    time_bucket('1 hour', t), time_bucket('1 hour', t) + '1 hour', t, v ORDER BY t) AS s
+                FROM cs_test_table
+                GROUP BY 1
+            )
+            SELECT prom_api.rate(prom_api.counter_summary_rollup(s::text::prom_api.counter_summary))
+                = prom_api.rate(prom_api.counter_summary_rollup(s))
+            FROM hourly;
+        "#,
+        );
+        assert_eq!(result, Some(true));
+
+        // NaN has no JSON representation
+        let result = Spi::get_one::<bool>(
+            r#"
+            SELECT prom_api.delta(s::text::prom_api.counter_summary) = 'NaN'
+            FROM (
+                SELECT prom_api.counter_summary_agg(
+                    '2000-01-02 15:00:00 UTC', '2000-01-02 16:00:00 UTC', t, v ORDER BY t) AS s
+                FROM (VALUES ('2000-01-02 15:10:00 UTC'::TIMESTAMPTZ, 1.0::DOUBLE PRECISION),
+                             ('2000-01-02 15:20:00 UTC', 'NaN')) AS samples(t, v)
+            ) summary;
+        "#,
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test(error = "invalid counter summary: sample times [0, 100] not in bounds [0, 10]")]
+    fn test_counter_summary_invalid_input() {
+        Spi::run(
+            r#"
+            SELECT prom_api.rate('{
+                "first_time": 0,
+                "first_value": 1,
+                "last_time": 100,
+                "last_value": 2,
+                "reset_correction": 0,
+                "count": 2,
+                "bucket_start": 0,
+                "bucket_end": 10
+            }'::prom_api.counter_summary);
+        "#,
+        );
+    }
+
+    #[pg_test(error = "counter summaries must not overlap in time")]
+    fn test_counter_summary_rollup_overlap() {
+        setup();
+        Spi::get_one::<bool>(
+            r#"
+            WITH summaries AS (
+                SELECT prom_api.counter_summary_agg(
+                    '2000-01-02 15:00:00 UTC', '2000-01-02 23:00:00 UTC', t, v ORDER BY t) AS s
+                FROM cs_test_table
+                GROUP BY extract(minute FROM t)::INT % 2
+            )
+            SELECT prom_api.counter_summary_rollup(s) IS NOT NULL FROM summaries;
+        "#,
+        );
+    }
+}
@@ -0,0 +1,582 @@
+//! # HyperLogLog
+//! The `hyperloglog_agg` aggregate estimates the number of distinct values, e.g. of the series
+//! with samples in a time range, with a [HyperLogLog] sketch of 2^14 registers. Its standard
+//! error is about 0.8%, and small cardinalities are close to exact.
+//!
+//! Each value is hashed to 64 bits: the first 14 bits select a register, which keeps the highest
+//! position of the first set bit among the remaining ones. Sketches are merged by taking the
+//! maximum of each register, which makes the aggregate parallelizable and its result storable:
+//! `hyperloglog_rollup` merges stored sketches, so that the distinct series of any range of
+//! buckets can be estimated from per-bucket sketches. The registers are stored sparsely while
+//! few of them are set, as most sketches of a cardinality dashboard are small.
+//!
+//! The hash function is part of the stored format, so it must never change: FNV-1a followed by
+//! the MurmurHash3 finalizer, over the little-endian bytes of a `bigint` or the UTF-8 of a `text`.
+//!
+//! The result is a `prom_api.hyperloglog`, whose text form is the sketch as JSON. The registers
+//! are checked whenever a sketch is deserialized, so that a sketch typed in by hand cannot index
+//! past them. As the type is defined by the migrations, so are the functions taking or returning
+//! it.
+//!
+//! ## Example SQL query
+//!
+//! ```sql
+//! SELECT prom_api.approx_count_distinct(prom_api.hyperloglog_agg(series_id))
+//! FROM prom_data.up
+//! WHERE time >= now() - interval '1 day';
+//! ```
+//!
+//! [HyperLogLog]: http://algo.inria.fr/flajolet/Publications/FlFuGaMe07.pdf
+use pgx::*;
+
+#[pg_schema]
+mod _prom_ext {
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+
+    use pgx::*;
+    use serde::{Deserialize, Serialize};
+
+    use crate::aggregate_utils::in_aggregate_context;
+    use crate::palloc::{Inner, InternalAsValue, ToInternal};
+    use crate::raw::bytea;
+
+    const PRECISION: u32 = 14;
+    const NUM_REGISTERS: usize = 1 << PRECISION;
+    /// Beyond this many set registers, the sparse representation is larger than the dense one
+    const MAX_SPARSE_REGISTERS: usize = NUM_REGISTERS / 3;
+    /// The sentinel bit caps the rank at 64 - PRECISION + 1
+    const MAX_RANK: u8 = 64 - PRECISION as u8 + 1;
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn hyperloglog_transition(
+        state: Internal,
+        value: Option<i64>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        let value = value.map(i64::to_le_bytes);
+        hyperloglog_transition_inner(
+            unsafe { state.to_inner() },
+            value.as_ref().map(|v| &v[..]),
+            fcinfo,
+        )
+        .internal()
+    }
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn hyperloglog_text_transition(
+        state: Internal,
+        value: Option<&str>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        hyperloglog_transition_inner(
+            unsafe { state.to_inner() },
+            value.map(str::as_bytes),
+            fcinfo,
+        )
+        .internal()
+    }
+
+    fn hyperloglog_transition_inner(
+        state: Option<Inner<HyperLogLog>>,
+        value: Option<&[u8]>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<HyperLogLog>> {
+        unsafe {
+            in_aggregate_context(fcinfo, || {
+                let value = match value {
+                    None => return state,
+                    Some(value) => value,
+                };
+                let mut state = state.unwrap_or_else(|| HyperLogLog::new().into());
+                state.add(value);
+                Some(state)
+            })
+        }
+    }
+
+    #[pg_extern(immutable, parallel_safe, sql = false)]
+    pub fn hyperloglog_rollup_transition(
+        state: Internal,
+        sketch: Option<HyperLogLog>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        hyperloglog_rollup_transition_inner(unsafe { state.to_inner() }, sketch, fcinfo).internal()
+    }
+
+    fn hyperloglog_rollup_transition_inner(
+        state: Option<Inner<HyperLogLog>>,
+        sketch: Option<HyperLogLog>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<HyperLogLog>> {
+        unsafe {
+            in_aggregate_context(fcinfo, || {
+                let sketch = match sketch {
+                    None => return state,
+                    Some(sketch) => sketch,
+                };
+                match state {
+                    None => Some(sketch.into()),
+                    Some(mut state) => {
+                        state.combine(&sketch);
+                        Some(state)
+                    }
+                }
+            })
+        }
+    }
+
+    /// The final function of all the aggregates.
+    #[pg_extern(immutable, parallel_safe, sql = false)]
+    pub fn hyperloglog_final(
+        state: Internal, /* Option<Inner<HyperLogLog>> */
+    ) -> Option<HyperLogLog> {
+        let state: Inner<HyperLogLog> = unsafe { state.to_inner() }?;
+        Some(state.clone())
+    }
+
+    #[pg_extern(immutable, parallel_safe, strict, create_or_replace)]
+    pub fn hyperloglog_serialize(state: Internal) -> bytea {
+        let state: &mut HyperLogLog = unsafe {
+            // This is safe as long as this function is defined as `strict`, in
+            // which case PG knows that NULL -> NULL and so it will not call this
+            // function with NULL values
+            state.get_mut().unwrap()
+        };
+        crate::do_serialize!(state)
+    }
+
+    #[pg_extern(immutable, parallel_safe, strict, create_or_replace)]
+    pub fn hyperloglog_deserialize(bytes: bytea, _internal: Internal) -> Internal {
+        let sketch: HyperLogLog = crate::do_deserialize!(bytes, HyperLogLog);
+        Inner::from(sketch).internal()
+    }
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn hyperloglog_combine(
+        state1: Internal,
+        state2: Internal,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        hyperloglog_combine_inner(
+            unsafe { state1.to_inner() },
+            unsafe { state2.to_inner() },
+            fcinfo,
+        )
+        .internal()
+    }
+
+    fn hyperloglog_combine_inner(
+        state1: Option<Inner<HyperLogLog>>,
+        state2: Option<Inner<HyperLogLog>>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<HyperLogLog>> {
+        unsafe {
+            in_aggregate_context(fcinfo, || match (state1, state2) {
+                (None, None) => None,
+                (None, Some(state2)) => Some(state2.clone().into()),
+                (Some(state1), None) => Some(state1.clone().into()),
+                (Some(state1), Some(state2)) => {
+                    let mut s1 = state1.clone();
+                    s1.combine(&state2);
+                    Some(s1.into())
+                }
+            })
+        }
+    }
+
+    #[pg_extern(immutable, parallel_safe, strict, sql = false)]
+    pub fn hyperloglog_count(sketch: HyperLogLog) -> i64 {
+        sketch.estimate()
+    }
+
+    /// FNV-1a, with the MurmurHash3 finalizer to spread the bits of similar inputs.
+    fn hash(bytes: &[u8]) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for &b in bytes {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+        hash ^ (hash >> 33)
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(try_from = "UncheckedRegisters")]
+    enum Registers {
+        /// The registers that are set, by index
+        Sparse(BTreeMap<u16, u8>),
+        Dense(Vec<u8>),
+    }
+
+    /// The serialized form of [`Registers`], which indexes and ranks are
+    /// checked on before they are used.
+    #[derive(Deserialize)]
+    #[serde(rename = "Registers")]
+    enum UncheckedRegisters {
+        Sparse(BTreeMap<u16, u8>),
+        Dense(Vec<u8>),
+    }
+
+    impl TryFrom<UncheckedRegisters> for Registers {
+        type Error = String;
+
+        fn try_from(registers: UncheckedRegisters) -> Result<Self, Self::Error> {
+            let check_rank = |rank: u8| {
+                if rank > MAX_RANK {
+                    Err(format!("register rank {} above {}", rank, MAX_RANK))
+                } else {
+                    Ok(())
+                }
+            };
+            match registers {
+                UncheckedRegisters::Sparse(registers) => {
+                    if registers.len() > MAX_SPARSE_REGISTERS {
+                        return Err(format!(
+                            "{} sparse registers, at most {} are allowed",
+                            registers.len(),
+                            MAX_SPARSE_REGISTERS
+                        ));
+                    }
+                    for (&index, &rank) in &registers {
+                        if index as usize >= NUM_REGISTERS {
+                            return Err(format!("register index {} out of range", index));
+                        }
+                        if rank == 0 {
+                            return Err(format!("sparse register {} is not set", index));
+                        }
+                        check_rank(rank)?;
+                    }
+                    Ok(Registers::Sparse(registers))
+                }
+                UncheckedRegisters::Dense(registers) => {
+                    if registers.len() != NUM_REGISTERS {
+                        return Err(format!(
+                            "{} dense registers, expected {}",
+                            registers.len(),
+                            NUM_REGISTERS
+                        ));
+                    }
+                    registers.iter().try_for_each(|&rank| check_rank(rank))?;
+                    Ok(Registers::Dense(registers))
+                }
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, PostgresType)]
+    #[pgx(sql = false)]
+    #[inoutfuncs]
+    pub struct HyperLogLog {
+        registers: Registers,
+    }
+
+    impl InOutFuncs for HyperLogLog {
+        fn input(input: &cstr_core::CStr) -> Self {
+            let input = input
+                .to_str()
+                .unwrap_or_else(|e| error!("invalid hyperloglog: {}", e));
+            // going through a Value keeps the input position out of the register errors
+            serde_json::from_str::<serde_json::Value>(input)
+                .and_then(serde_json::from_value)
+                .unwrap_or_else(|e| error!("invalid hyperloglog: {}", e))
+        }
+
+        fn output(&self, buffer: &mut StringInfo) {
+            let output =
+                serde_json::to_string(self).unwrap_or_else(|e| error!("serialization error {}", e));
+            buffer.push_str(&output);
+        }
+    }
+
+    impl Default for HyperLogLog {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl HyperLogLog {
+        pub fn new() -> Self {
+            HyperLogLog {
+                registers: Registers::Sparse(BTreeMap::new()),
+            }
+        }
+
+        pub fn add(&mut self, value: &[u8]) {
+            let hash = hash(value);
+            let index = (hash >> (64 - PRECISION)) as u16;
+            // the sentinel bit caps the rank at MAX_RANK
+            let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+            self.set_register(index, rank);
+        }
+
+        fn set_register(&mut self, index: u16, rank: u8) {
+            match &mut self.registers {
+                Registers::Sparse(registers) => {
+                    let register = registers.entry(index).or_insert(0);
+                    *register = (*register).max(rank);
+                    if registers.len() > MAX_SPARSE_REGISTERS {
+                        let mut dense = vec![0; NUM_REGISTERS];
+                        for (&index, &rank) in registers.iter() {
+                            dense[index as usize] = rank;
+                        }
+                        self.registers = Registers::Dense(dense);
+                    }
+                }
+                Registers::Dense(registers) => {
+                    let register = &mut registers[index as usize];
+                    *register = (*register).max(rank);
+                }
+            }
+        }
+
+        pub fn combine(&mut self, other: &HyperLogLog) {
+            match &other.registers {
+                Registers::Sparse(registers) => {
+                    for (&index, &rank) in registers {
+                        self.set_register(index, rank);
+                    }
+                }
+                Registers::Dense(registers) => {
+                    for (index, &rank) in registers.iter().enumerate() {
+                        if rank > 0 {
+                            self.set_register(index as u16, rank);
+                        }
+                    }
+                }
+            }
+        }
+
+        /// Ertl's improved estimator, see "New cardinality estimation algorithms for
+        /// HyperLogLog sketches", which is unbiased from small to large cardinalities
+        /// without the empirical corrections of HyperLogLog++.
+        pub fn estimate(&self) -> i64 {
+            let m = NUM_REGISTERS as f64;
+            let q = 64 - PRECISION as usize;
+            // the number of registers of each rank, from 0 (not set) to q + 1
+            let mut histogram = vec![0u32; q + 2];
+            match &self.registers {
+                Registers::Sparse(registers) => {
+                    registers
+                        .values()
+                        .for_each(|&rank| histogram[rank as usize] += 1);
+                    histogram[0] += (NUM_REGISTERS - registers.len()) as u32;
+                }
+                Registers::Dense(registers) => registers
+                    .iter()
+                    .for_each(|&rank| histogram[rank as usize] += 1),
+            }
+
+            let mut z = m * tau(1.0 - histogram[q + 1] as f64 / m);
+            for &count in histogram[1..=q].iter().rev() {
+                z = 0.5 * (z + count as f64);
+            }
+            z += m * sigma(histogram[0] as f64 / m);
+            (m * m / (2.0 * std::f64::consts::LN_2 * z)).round() as i64
+        }
+    }
+
+    fn sigma(mut x: f64) -> f64 {
+        if x == 1.0 {
+            return f64::INFINITY;
+        }
+        let (mut y, mut z) = (1.0, x);
+        loop {
+            x *= x;
+            let previous = z;
+            z += x * y;
+            y += y;
+            if z == previous {
+                return z;
+            }
+        }
+    }
+
+    fn tau(mut x: f64) -> f64 {
+        if x == 0.0 || x == 1.0 {
+            return 0.0;
+        }
+        let (mut y, mut z) = (1.0, 1.0 - x);
+        loop {
+            x = x.sqrt();
+            let previous = z;
+            y *= 0.5;
+            z -= (1.0 - x).powi(2) * y;
+            if z == previous {
+                return z / 3.0;
+            }
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+
+    use super::_prom_ext::HyperLogLog;
+
+    #[pg_test]
+    fn test_hyperloglog_accuracy() {
+        for &cardinality in [1, 10, 1_000, 10_000, 100_000, 1_000_000].iter() {
+            let mut sketch = HyperLogLog::new();
+            // duplicates don't count
+            (0..cardinality)
+                .chain(0..cardinality / 2)
+                .for_each(|v: i64| sketch.add(&v.to_le_bytes()));
+            let estimate = sketch.estimate();
+            assert!(
+                (estimate - cardinality).abs() as f64 <= cardinality as f64 * 0.02,
+                "cardinality {} estimated as {}",
+                cardinality,
+                estimate
+            );
+        }
+        assert_eq!(HyperLogLog::new().estimate(), 0);
+    }
+
+    #[pg_test]
+    fn test_hyperloglog_agg() {
+        let result = Spi::get_one::<bool>(
+            r#"
+            SELECT abs(prom_api.approx_count_distinct(prom_api.hyperloglog_agg(i % 50000)) - 50000) < 1000
+               AND abs(prom_api.approx_count_distinct(prom_api.hyperloglog_agg((i % 50000)::TEXT)) - 50000) < 1000
+               AND abs(prom_api.approx_count_distinct(prom_api.hyperloglog_agg(i % 100)) - 100) <= 2
+            FROM generate_series(1, 200000) i;
+        "#,
+        );
+        assert_eq!(result, Some(true));
+
+        let result = Spi::get_one::<bool>(
+            "SELECT prom_api.hyperloglog_agg(NULL::BIGINT) IS NULL FROM generate_series(1, 10);",
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test]
+    fn test_hyperloglog_rollup() {
+        let result = Spi::get_one::<bool>(
+            r#"
+            WITH buckets AS (
+                SELECT i / 10000 AS bucket, prom_api.hyperloglog_agg(i % 30000) AS sketch
+                FROM generate_series(1, 100000) i
+                GROUP BY 1
+            )
+            SELECT prom_api.approx_count_distinct(prom_api.hyperloglog_rollup(sketch))
+                = (SELECT prom_api.approx_count_distinct(prom_api.hyperloglog_agg(i % 30000))
+                   FROM generate_series(1, 100000) i)
+            FROM buckets;
+        "#,
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test]
+    fn test_hyperloglog_text_round_trip() {
+        let result = Spi::get_one::<bool>(
+            r#"
+            SELECT bool_and(prom_api.approx_count_distinct(sketch::text::prom_api.hyperloglog)
+                            = prom_api.approx_count_distinct(sketch))
+            FROM (
+                SELECT prom_api.hyperloglog_agg(i % n) AS sketch
+                FROM generate_series(1, 100000) i, (VALUES (100), (50000)) v(n)
+                GROUP BY n
+            ) s;
+        "#,
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test(error = "invalid hyperloglog: 3 dense registers, expected 16384")]
+    fn test_hyperloglog_invalid_dense_registers() {
+        Spi::run(
+            r#"SELECT prom_api.approx_count_distinct('{"registers": {"Dense": [1, 2, 3]}}'::prom_api.hyperloglog);"#,
+        );
+    }
+
+    #[pg_test(error = "invalid hyperloglog: register index 20000 out of range")]
+    fn test_hyperloglog_invalid_sparse_registers() {
+        Spi::run(
+            r#"SELECT prom_api.approx_count_distinct('{"registers": {"Sparse": {"20000": 1}}}'::prom_api.hyperloglog);"#,
+        );
+    }
+
+    #[pg_test]
+    fn test_hyperloglog_parallel_execution() {
+        Spi::run(
+            r#"
+            CREATE TABLE hll_test_table(series_id BIGINT);
+            INSERT INTO hll_test_table SELECT i % 20000 FROM generate_series(1, 100000) i;
+            ANALYZE hll_test_table;
+            "#,
+        );
+
+        // Force parallel execution
+        Spi::run(
+            r#"
+            SET max_parallel_workers = 6;
+            SET max_parallel_workers_per_gather = 6;
+            SET parallel_leader_participation = off;
+            SET parallel_tuple_cost = 0;
+            SET parallel_setup_cost = 0;
+            SET min_parallel_table_scan_size = 0;
+            "#,
+        );
+
+        let query = r#"
+            SELECT prom_api.approx_count_distinct(prom_api.hyperloglog_agg(series_id))
+            FROM hll_test_table
+            ;"#;
+
+        let parallel_plan =
+            Spi::get_one::<Json>(format!("EXPLAIN (COSTS OFF, FORMAT JSON) {}", query).as_str())
+                .expect("SQL query failed");
+
+        // Assert that we're running in parallel mode.
+        let top_level_plan = parallel_plan.0[0]["Plan"].clone();
+        assert_eq!(
+            top_level_plan
+                .pointer("/Plans/0/Node Type")
+                .and_then(|v| v.as_str()),
+            Some("Gather")
+        );
+
+        let parallel = Spi::get_one::<i64>(query).expect("SQL query failed");
+        Spi::run("SET max_parallel_workers_per_gather = 0;");
+        let serial = Spi::get_one::<i64>(query).expect("SQL query failed");
+        assert_eq!(parallel, serial);
+    }
+
+    #[pg_test]
+    fn test_approx_active_series() {
+        Spi::run(
+            r#"SELECT _prom_catalog.get_or_create_metric_table_name('up');
+            INSERT INTO prom_data.up(time, value, series_id)
+            SELECT to_timestamp(1600000000 + 60 * i), 1, s.id
+            FROM generate_series(0, 179) i,
+            (VALUES (_prom_catalog.get_or_create_series_id('{"__name__": "up", "job": "api", "instance": "a"}'), 0),
+                    (_prom_catalog.get_or_create_series_id('{"__name__": "up", "job": "api", "instance": "b"}'), 60),
+                    (_prom_catalog.get_or_create_series_id('{"__name__": "up", "job": "api", "instance": "c"}'), 120))
+                AS s(id, first)
+            WHERE i >= s.first;"#,
+        );
+        let result = Spi::get_one::<Json>(
+            r#"
+            SELECT json_agg(json_build_array(metric_name, extract(epoch FROM bucket)::BIGINT, series) ORDER BY bucket)
+            FROM prom_api.approx_active_series(to_timestamp(1600000000), to_timestamp(1600010800), '1 hour');
+        "#,
+        )
+        .expect("SQL query failed");
+        assert_eq!(
+            result.0,
+            serde_json::json!([
+                ["up", 1600000000, 1],
+                ["up", 1600003600, 2],
+                ["up", 1600007200, 3],
+            ])
+        );
+    }
+}
@@ -1,9 +1,12 @@
 use crate::aggregates::gapfill_delta::_prom_ext::GapfillDeltaTransition;
 
+mod counter_summary;
 mod gapfill_delta;
+mod hyperloglog;
 mod prom_delta;
 mod prom_increase;
 mod prom_rate;
+mod quantile_sketch;
 mod vector_selector;
 
 pub type Milliseconds = i64;
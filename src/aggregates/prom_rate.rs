@@ -9,6 +9,7 @@ mod _prom_ext {
     use crate::aggregate_utils::in_aggregate_context;
     use crate::aggregates::{GapfillDeltaTransition, Milliseconds};
     use crate::palloc::{Inner, InternalAsValue, ToInternal};
+    use crate::sample_block::decode_sample_block;
 
     #[allow(clippy::too_many_arguments)]
     #[pg_extern(immutable, parallel_safe, create_or_replace)]
@@ -75,6 +76,73 @@ mod _prom_ext {
         }
     }
 
+    /// Transition function of the `prom_rate` overload over sample blocks.
+    /// Samples outside of [`lowest_time`, `greatest_time`] are skipped, as a
+    /// block may cover more than the evaluated range.
+    #[allow(clippy::too_many_arguments)]
+    #[pg_extern(immutable, parallel_safe, sql = false)]
+    pub fn prom_rate_block_transition(
+        state: Internal,
+        lowest_time: TimestampWithTimeZone,
+        greatest_time: TimestampWithTimeZone,
+        step_size: Milliseconds,
+        range: Milliseconds, // the size of a window to calculate over
+        samples: Option<&[u8]>,
+        fc: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        prom_rate_block_transition_inner(
+            unsafe { state.to_inner() },
+            lowest_time.into(),
+            greatest_time.into(),
+            step_size,
+            range,
+            samples,
+            fc,
+        )
+        .internal()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn prom_rate_block_transition_inner(
+        state: Option<Inner<GapfillDeltaTransition>>,
+        lowest_time: i64,
+        greatest_time: i64,
+        step_size: Milliseconds,
+        range: Milliseconds, // the size of a window to calculate over
+        samples: Option<&[u8]>,
+        fc: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<GapfillDeltaTransition>> {
+        unsafe {
+            in_aggregate_context(fc, || {
+                let mut state = state.unwrap_or_else(|| {
+                    let state: Inner<_> = GapfillDeltaTransition::new(
+                        lowest_time,
+                        greatest_time,
+                        range,
+                        step_size,
+                        true,
+                        true,
+                    )
+                    .into();
+                    state
+                });
+
+                let samples = samples
+                    .map(decode_sample_block)
+                    .transpose()
+                    .unwrap_or_else(|e| error!("invalid sample block: {}", e))
+                    .unwrap_or_default();
+                for (time, value) in samples {
+                    if lowest_time <= time && time <= greatest_time {
+                        state.add_data_point(time, value);
+                    }
+                }
+
+                Some(state)
+            })
+        }
+    }
+
     /// Backwards compatibility
     #[no_mangle]
     pub extern "C" fn pg_finfo_gapfill_rate_transition() -> &'static pg_sys::Pg_finfo_record {
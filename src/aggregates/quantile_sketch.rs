@@ -0,0 +1,558 @@
+//! # Quantile Sketch
+//! The `quantile_sketch_agg` aggregate summarizes values in a [DDSketch], from which quantiles can
+//! be estimated with a relative error of at most 1%, regardless of their distribution.
+//!
+//! Values are counted in logarithmically sized buckets: the bucket with index `i` holds the values
+//! in `(gamma^(i-1), gamma^i]`, where `gamma = (1 + accuracy) / (1 - accuracy)`. Negative values
+//! are counted by their magnitude in buckets of their own. Sketches of the same accuracy can be
+//! merged by adding up the counts of their buckets, which makes the aggregate parallelizable and
+//! its result storable: `quantile_sketch_rollup` merges stored sketches, so that e.g. a continuous
+//! aggregate can hold a sketch per hour and any quantile be queried over any range of hours later.
+//!
+//! The number of buckets is limited to `MAX_BUCKETS`, beyond which the buckets of the smallest
+//! magnitudes are collapsed into each other. At 1% accuracy this only affects sketches spanning
+//! more than 17 orders of magnitude, for which the accuracy of the lowest quantiles is lost.
+//!
+//! NaN and infinite values are skipped, the former including Prometheus' stale markers.
+//!
+//! The result is a `prom_api.quantile_sketch`, whose text form is the sketch as JSON. Its input
+//! function checks that the buckets add up to the count of the sketch, so that a sketch typed in
+//! by hand cannot break the estimates. As the type is defined by the migrations, so are the
+//! functions taking or returning it.
+//!
+//! ## Example SQL query
+//!
+//! ```sql
+//! CREATE TABLE latency_hourly AS
+//! SELECT time_bucket('1 hour', time) AS hour, prom_api.quantile_sketch_agg(value) AS sketch
+//! FROM prom_data.request_latency_seconds
+//! GROUP BY 1;
+//!
+//! SELECT prom_api.approx_quantile(prom_api.quantile_sketch_rollup(sketch), 0.99)
+//! FROM latency_hourly
+//! WHERE hour >= now() - interval '7 days';
+//! ```
+//!
+//! [DDSketch]: https://arxiv.org/abs/1908.10693
+use pgx::*;
+
+#[pg_schema]
+mod _prom_ext {
+    use std::collections::BTreeMap;
+
+    use pgx::*;
+    use serde::{Deserialize, Serialize};
+
+    use crate::aggregate_utils::in_aggregate_context;
+    use crate::palloc::{Inner, InternalAsValue, ToInternal};
+    use crate::raw::bytea;
+
+    const RELATIVE_ACCURACY: f64 = 0.01;
+    const MAX_BUCKETS: usize = 2048;
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn quantile_sketch_transition(
+        state: Internal,
+        value: Option<f64>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        quantile_sketch_transition_inner(unsafe { state.to_inner() }, value, fcinfo).internal()
+    }
+
+    fn quantile_sketch_transition_inner(
+        state: Option<Inner<QuantileSketch>>,
+        value: Option<f64>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<QuantileSketch>> {
+        unsafe {
+            in_aggregate_context(fcinfo, || {
+                let value = match value {
+                    None => return state,
+                    Some(value) => value,
+                };
+                let mut state =
+                    state.unwrap_or_else(|| QuantileSketch::new(RELATIVE_ACCURACY).into());
+                state.add(value);
+                Some(state)
+            })
+        }
+    }
+
+    #[pg_extern(immutable, parallel_safe, sql = false)]
+    pub fn quantile_sketch_rollup_transition(
+        state: Internal,
+        sketch: Option<QuantileSketch>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        quantile_sketch_rollup_transition_inner(unsafe { state.to_inner() }, sketch, fcinfo)
+            .internal()
+    }
+
+    fn quantile_sketch_rollup_transition_inner(
+        state: Option<Inner<QuantileSketch>>,
+        sketch: Option<QuantileSketch>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<QuantileSketch>> {
+        unsafe {
+            in_aggregate_context(fcinfo, || {
+                let sketch = match sketch {
+                    None => return state,
+                    Some(sketch) => sketch,
+                };
+                match state {
+                    None => Some(sketch.into()),
+                    Some(mut state) => {
+                        state.combine(&sketch);
+                        Some(state)
+                    }
+                }
+            })
+        }
+    }
+
+    /// The final function of both aggregates.
+    #[pg_extern(immutable, parallel_safe, sql = false)]
+    pub fn quantile_sketch_final(
+        state: Internal, /* Option<Inner<QuantileSketch>> */
+    ) -> Option<QuantileSketch> {
+        let state: Inner<QuantileSketch> = unsafe { state.to_inner() }?;
+        Some(state.clone())
+    }
+
+    #[pg_extern(immutable, parallel_safe, strict, create_or_replace)]
+    pub fn quantile_sketch_serialize(state: Internal) -> bytea {
+        let state: &mut QuantileSketch = unsafe {
+            // This is safe as long as this function is defined as `strict`, in
+            // which case PG knows that NULL -> NULL and so it will not call this
+            // function with NULL values
+            state.get_mut().unwrap()
+        };
+        crate::do_serialize!(state)
+    }
+
+    #[pg_extern(immutable, parallel_safe, strict, create_or_replace)]
+    pub fn quantile_sketch_deserialize(bytes: bytea, _internal: Internal) -> Internal {
+        let sketch: QuantileSketch = crate::do_deserialize!(bytes, QuantileSketch);
+        Inner::from(sketch).internal()
+    }
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn quantile_sketch_combine(
+        state1: Internal,
+        state2: Internal,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        quantile_sketch_combine_inner(
+            unsafe { state1.to_inner() },
+            unsafe { state2.to_inner() },
+            fcinfo,
+        )
+        .internal()
+    }
+
+    fn quantile_sketch_combine_inner(
+        state1: Option<Inner<QuantileSketch>>,
+        state2: Option<Inner<QuantileSketch>>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<QuantileSketch>> {
+        unsafe {
+            in_aggregate_context(fcinfo, || match (state1, state2) {
+                (None, None) => None,
+                (None, Some(state2)) => Some(state2.clone().into()),
+                (Some(state1), None) => Some(state1.clone().into()),
+                (Some(state1), Some(state2)) => {
+                    let mut s1 = state1.clone();
+                    s1.combine(&state2);
+                    Some(s1.into())
+                }
+            })
+        }
+    }
+
+    /// Estimates the given quantile of the values in the sketch,
+    /// or returns `NULL` if it doesn't hold any.
+    #[pg_extern(immutable, parallel_safe, strict, sql = false)]
+    pub fn quantile_sketch_quantile(sketch: QuantileSketch, quantile: f64) -> Option<f64> {
+        if !(0.0..=1.0).contains(&quantile) {
+            error!("quantile must be between 0 and 1, got {}", quantile)
+        }
+        sketch.quantile(quantile)
+    }
+
+    /// The counts of the buckets of one sign, by bucket index.
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    struct Buckets {
+        counts: BTreeMap<i32, u64>,
+    }
+
+    impl Buckets {
+        fn add(&mut self, index: i32, count: u64) {
+            *self.counts.entry(index).or_insert(0) += count;
+        }
+
+        /// Merges the lowest buckets into the next one, until at most `MAX_BUCKETS` are left.
+        fn collapse(&mut self) {
+            while self.counts.len() > MAX_BUCKETS {
+                let (&lowest, &count) = self.counts.iter().next().unwrap();
+                self.counts.remove(&lowest);
+                *self.counts.values_mut().next().unwrap() += count;
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, PostgresType)]
+    #[pgx(sql = false)]
+    #[inoutfuncs]
+    pub struct QuantileSketch {
+        relative_accuracy: f64,
+        /// ln(gamma), cached as it's needed for every value
+        ln_gamma: f64,
+        positive: Buckets,
+        negative: Buckets,
+        /// Values too close to zero to have a bucket
+        zero_count: u64,
+        count: u64,
+        /// The extremes of the values, `None` for a sketch without any
+        min: Option<f64>,
+        max: Option<f64>,
+    }
+
+    impl InOutFuncs for QuantileSketch {
+        fn input(input: &cstr_core::CStr) -> Self {
+            let input = input
+                .to_str()
+                .unwrap_or_else(|e| error!("invalid quantile sketch: {}", e));
+            let mut sketch: QuantileSketch = serde_json::from_str(input)
+                .unwrap_or_else(|e| error!("invalid quantile sketch: {}", e));
+            sketch
+                .validate()
+                .unwrap_or_else(|e| error!("invalid quantile sketch: {}", e));
+            // not worth checking, as it's derived from the accuracy anyway
+            sketch.ln_gamma = QuantileSketch::new(sketch.relative_accuracy).ln_gamma;
+            sketch
+        }
+
+        fn output(&self, buffer: &mut StringInfo) {
+            let output =
+                serde_json::to_string(self).unwrap_or_else(|e| error!("serialization error {}", e));
+            buffer.push_str(&output);
+        }
+    }
+
+    impl QuantileSketch {
+        pub fn new(relative_accuracy: f64) -> Self {
+            let gamma = (1.0 + relative_accuracy) / (1.0 - relative_accuracy);
+            QuantileSketch {
+                relative_accuracy,
+                ln_gamma: gamma.ln(),
+                positive: Buckets::default(),
+                negative: Buckets::default(),
+                zero_count: 0,
+                count: 0,
+                min: None,
+                max: None,
+            }
+        }
+
+        /// Checks the invariants that the functions on sketches rely on.
+        fn validate(&self) -> Result<(), String> {
+            if !(self.relative_accuracy > 0.0 && self.relative_accuracy < 1.0) {
+                return Err(format!(
+                    "relative accuracy must be between 0 and 1, got {}",
+                    self.relative_accuracy
+                ));
+            }
+            let mut count = self.zero_count;
+            for buckets in [&self.positive, &self.negative] {
+                if buckets.counts.len() > MAX_BUCKETS {
+                    return Err(format!("more than {} buckets", MAX_BUCKETS));
+                }
+                for &bucket_count in buckets.counts.values() {
+                    count = count
+                        .checked_add(bucket_count)
+                        .ok_or_else(|| "count out of range".to_string())?;
+                }
+            }
+            if count != self.count {
+                return Err(format!(
+                    "the buckets hold {} values, but the count is {}",
+                    count, self.count
+                ));
+            }
+            match (self.count, self.min, self.max) {
+                (0, None, None) => Ok(()),
+                (0, _, _) => Err("min and max of an empty sketch must be null".to_string()),
+                (_, Some(min), Some(max)) if min.is_finite() && max.is_finite() && min <= max => {
+                    Ok(())
+                }
+                _ => Err("min and max must be finite, with min <= max".to_string()),
+            }
+        }
+
+        pub fn add(&mut self, value: f64) {
+            if !value.is_finite() {
+                return;
+            }
+            if value >= f64::MIN_POSITIVE {
+                self.positive.add(self.index(value), 1);
+                self.positive.collapse();
+            } else if value <= -f64::MIN_POSITIVE {
+                self.negative.add(self.index(-value), 1);
+                self.negative.collapse();
+            } else {
+                self.zero_count += 1;
+            }
+            self.count += 1;
+            self.min = Some(self.min.map_or(value, |min| min.min(value)));
+            self.max = Some(self.max.map_or(value, |max| max.max(value)));
+        }
+
+        pub fn combine(&mut self, other: &QuantileSketch) {
+            if self.relative_accuracy != other.relative_accuracy {
+                error!("cannot combine quantile sketches of different accuracy")
+            }
+            for (&index, &count) in &other.positive.counts {
+                self.positive.add(index, count);
+            }
+            self.positive.collapse();
+            for (&index, &count) in &other.negative.counts {
+                self.negative.add(index, count);
+            }
+            self.negative.collapse();
+            self.zero_count += other.zero_count;
+            self.count += other.count;
+            self.min = match (self.min, other.min) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            self.max = match (self.max, other.max) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            };
+        }
+
+        /// Returns a value whose rank in the sketch is `quantile * (count - 1)`, rounded down,
+        /// within the relative accuracy of the sketch.
+        pub fn quantile(&self, quantile: f64) -> Option<f64> {
+            // only a sketch without values has no extremes
+            let (min, max) = (self.min?, self.max?);
+            let rank = quantile * (self.count - 1) as f64;
+            // values in ascending order: negative ones by descending magnitude, zeros, positive ones
+            let buckets = self
+                .negative
+                .counts
+                .iter()
+                .rev()
+                .map(|(&index, &count)| (-self.value(index), count))
+                .chain(std::iter::once((0.0, self.zero_count)))
+                .chain(
+                    self.positive
+                        .counts
+                        .iter()
+                        .map(|(&index, &count)| (self.value(index), count)),
+                );
+            let mut seen = 0;
+            for (value, count) in buckets {
+                seen += count;
+                if seen as f64 > rank {
+                    // the exact extremes are known, and tighter than the bucket's estimate
+                    return Some(value.max(min).min(max));
+                }
+            }
+            Some(max)
+        }
+
+        fn index(&self, magnitude: f64) -> i32 {
+            (magnitude.ln() / self.ln_gamma).ceil() as i32
+        }
+
+        /// The estimate of the values in the bucket `index`, with the same
+        /// relative error towards both of its bounds.
+        fn value(&self, index: i32) -> f64 {
+            let gamma = self.ln_gamma.exp();
+            2.0 * (index as f64 * self.ln_gamma).exp() / (1.0 + gamma)
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+
+    use super::_prom_ext::QuantileSketch;
+
+    fn setup() {
+        Spi::run(
+            r#"
+            CREATE TABLE qs_test_table(hour INT, v DOUBLE PRECISION);
+            INSERT INTO qs_test_table
+            SELECT i % 24, exp(i % 1000 / 100.0) - 50
+            FROM generate_series(1, 100000) i;
+            INSERT INTO qs_test_table VALUES (0, NULL), (1, 'NaN'), (2, 'Infinity');
+        "#,
+        );
+    }
+
+    #[pg_test]
+    fn test_quantile_sketch_accuracy() {
+        let mut values: Vec<f64> = (0..20_000)
+            .map(|i| match i % 4 {
+                0 => 0.0,
+                1 => -(i as f64).powf(1.5),
+                _ => ((i % 700) as f64 * 0.05).exp(),
+            })
+            .collect();
+        let mut sketch = QuantileSketch::new(0.01);
+        values.iter().for_each(|&v| sketch.add(v));
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for q in [0.0, 0.01, 0.1, 0.25, 0.5, 0.75, 0.9, 0.99, 0.999, 1.0].iter() {
+            let expected = values[(q * (values.len() - 1) as f64) as usize];
+            let actual = sketch.quantile(*q).unwrap();
+            assert!(
+                (actual - expected).abs() <= expected.abs() * 0.01,
+                "quantile {}: expected {} got {}",
+                q,
+                expected,
+                actual
+            );
+        }
+        assert_eq!(QuantileSketch::new(0.01).quantile(0.5), None);
+    }
+
+    #[pg_test]
+    fn test_quantile_sketch_agg() {
+        setup();
+        let result = Spi::get_one::<bool>(
+            r#"
+            SELECT bool_and(abs(prom_api.approx_quantile(s.sketch, q) - e.value) <= abs(e.value) * 0.01)
+            FROM (SELECT prom_api.quantile_sketch_agg(v) AS sketch FROM qs_test_table) s,
+                 unnest(ARRAY[0, 0.1, 0.5, 0.9, 0.99, 1]) q,
+                 LATERAL (
+                     SELECT v AS value
+                     FROM qs_test_table
+                     WHERE v <> 'NaN' AND v <> 'Infinity'
+                     ORDER BY v
+                     OFFSET floor(q * 99999)::BIGINT
+                     LIMIT 1
+                 ) e;
+        "#,
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test]
+    fn test_quantile_sketch_rollup() {
+        setup();
+        let result = Spi::get_one::<bool>(
+            r#"
+            WITH hourly AS (
+                SELECT hour, prom_api.quantile_sketch_agg(v) AS sketch
+                FROM qs_test_table
+                GROUP BY hour
+            )
+            SELECT array_agg(prom_api.approx_quantile(r.sketch, q))
+                = array_agg(prom_api.approx_quantile(a.sketch, q))
+            FROM (SELECT prom_api.quantile_sketch_rollup(sketch) AS sketch FROM hourly) r,
+                 (SELECT prom_api.quantile_sketch_agg(v) AS sketch FROM qs_test_table) a,
+                 unnest(ARRAY[0, 0.1, 0.5, 0.9, 0.99, 1]) q;
+        "#,
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test]
+    fn test_quantile_sketch_empty() {
+        let result = Spi::get_one::<bool>(
+            r#"
+            SELECT prom_api.quantile_sketch_agg(v) IS NULL
+            FROM (VALUES (NULL::DOUBLE PRECISION)) t(v);
+        "#,
+        );
+        assert_eq!(result, Some(true));
+        let result = Spi::get_one::<f64>(
+            r#"
+            SELECT prom_api.approx_quantile(prom_api.quantile_sketch_agg(v), 0.5)
+            FROM (VALUES ('NaN'::DOUBLE PRECISION)) t(v);
+        "#,
+        );
+        assert_eq!(result, None);
+    }
+
+    #[pg_test]
+    fn test_quantile_sketch_text_round_trip() {
+        setup();
+        let result = Spi::get_one::<bool>(
+            r#"
+            SELECT array_agg(prom_api.approx_quantile(s.sketch::text::prom_api.quantile_sketch, q))
+                = array_agg(prom_api.approx_quantile(s.sketch, q))
+            FROM (SELECT prom_api.quantile_sketch_agg(v) AS sketch FROM qs_test_table) s,
+                 unnest(ARRAY[0, 0.1, 0.5, 0.9, 0.99, 1]) q;
+        "#,
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test(error = "invalid quantile sketch: the buckets hold 2 values, but the count is 3")]
+    fn test_quantile_sketch_invalid_input() {
+        Spi::run(
+            r#"
+            SELECT prom_api.approx_quantile('{
+                "relative_accuracy": 0.01,
+                "ln_gamma": 0.02,
+                "positive": {"counts": {"1": 1}},
+                "negative": {"counts": {}},
+                "zero_count": 1,
+                "count": 3,
+                "min": 0,
+                "max": 1
+            }'::prom_api.quantile_sketch, 0.5);
+        "#,
+        );
+    }
+
+    #[pg_test(error = "quantile must be between 0 and 1, got 1.5")]
+    fn test_quantile_sketch_invalid_quantile() {
+        Spi::run("SELECT prom_api.approx_quantile(prom_api.quantile_sketch_agg(1), 1.5);");
+    }
+
+    #[pg_test]
+    fn test_quantile_sketch_parallel_execution() {
+        setup();
+
+        // Force parallel execution
+        Spi::run(
+            r#"
+            SET max_parallel_workers = 6;
+            SET max_parallel_workers_per_gather = 6;
+            SET parallel_leader_participation = off;
+            SET parallel_tuple_cost = 0;
+            SET parallel_setup_cost = 0;
+            SET min_parallel_table_scan_size = 0;
+            "#,
+        );
+
+        let query = r#"
+            SELECT prom_api.approx_quantile(prom_api.quantile_sketch_agg(v), 0.5)
+            FROM qs_test_table
+            ;"#;
+
+        let parallel_plan =
+            Spi::get_one::<Json>(format!("EXPLAIN (COSTS OFF, FORMAT JSON) {}", query).as_str())
+                .expect("SQL query failed");
+
+        // Assert that we're running in parallel mode.
+        let top_level_plan = parallel_plan.0[0]["Plan"].clone();
+        assert_eq!(
+            top_level_plan
+                .pointer("/Plans/0/Node Type")
+                .and_then(|v| v.as_str()),
+            Some("Gather")
+        );
+
+        let parallel = Spi::get_one::<f64>(query).expect("SQL query failed");
+        Spi::run("SET max_parallel_workers_per_gather = 0;");
+        let serial = Spi::get_one::<f64>(query).expect("SQL query failed");
+        assert_eq!(parallel, serial);
+    }
+}
@@ -71,7 +71,9 @@
 //!
 //! Note: The `vector_selector` aggregate expects to be evaluated over time series data in the range
 //! [`start_time` - `lookback`, `end_time`]. If any of the values of `sample_time` is _outside_ of
-//! this range, the aggregate will raise a Postgres ERROR.
+//! this range, the aggregate will raise a Postgres ERROR. When `start_time`, `end_time` and
+//! `lookback` are constant for the duration of the query and `sample_time` is a plain column,
+//! the corresponding `WHERE` clause is added automatically (see `crate::planner`).
 //!
 //! ## Example SQL query
 //!
@@ -100,7 +102,8 @@
 //! Here we define that we want to determine the `Instant Vector`s for the range
 //! [2000-01-02T15:00:00+00:00, 2000-01-02T15:10:00+00:00], in 10 minute buckets, and looking back
 //! over 10 minutes of data. Note that we restrict the data being aggregated over to correspond to
-//! the arguments which we have passed to `vector_selector`.
+//! the arguments which we have passed to `vector_selector`. In this case the `WHERE` clause is
+//! redundant, as it would've been derived from the arguments anyway.
 //!
 //! If `test_table` contained the following values:
 //! ```text
@@ -150,6 +153,7 @@ mod _prom_ext {
 
     use crate::palloc::{Inner, InternalAsValue, ToInternal};
     use crate::raw::bytea;
+    use crate::sample_block::decode_sample_block;
 
     /// Note that for performance, this aggregate is parallel-izable, combinable, and does not expect
     /// ordered inputs.
@@ -204,6 +208,67 @@ mod _prom_ext {
         }
     }
 
+    /// Transition function of the `vector_selector` overload over sample
+    /// blocks. Samples outside of [`start_time` - `lookback`, `end_time`] are
+    /// skipped, as a block may cover more than the evaluated range.
+    #[allow(clippy::too_many_arguments)]
+    #[pg_extern(immutable, parallel_safe, sql = false)]
+    pub fn vector_selector_block_transition(
+        state: Internal,
+        start_time: TimestampWithTimeZone,
+        end_time: TimestampWithTimeZone,
+        bucket_width: Milliseconds,
+        lookback: Milliseconds,
+        samples: Option<&[u8]>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        vector_selector_block_transition_inner(
+            unsafe { state.to_inner() },
+            start_time.into(),
+            end_time.into(),
+            bucket_width,
+            lookback,
+            samples,
+            fcinfo,
+        )
+        .internal()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn vector_selector_block_transition_inner(
+        state: Option<Inner<VectorSelector>>,
+        start_time: i64,
+        end_time: i64,
+        bucket_width: Milliseconds,
+        lookback: Milliseconds,
+        samples: Option<&[u8]>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<VectorSelector>> {
+        unsafe {
+            in_aggregate_context(fcinfo, || {
+                let mut state = state.unwrap_or_else(|| {
+                    let state: Inner<VectorSelector> =
+                        VectorSelector::new(start_time, end_time, bucket_width, lookback).into();
+                    state
+                });
+
+                let samples = samples
+                    .map(decode_sample_block)
+                    .transpose()
+                    .unwrap_or_else(|e| error!("invalid sample block: {}", e))
+                    .unwrap_or_default();
+                let lowest_time = start_time - lookback * USECS_PER_MS;
+                for (time, value) in samples {
+                    if lowest_time <= time && time <= end_time {
+                        state.insert(time, value);
+                    }
+                }
+
+                Some(state)
+            })
+        }
+    }
+
     #[pg_extern(immutable, parallel_safe, create_or_replace)]
     pub fn vector_selector_final(
         state: Internal, /* Option<Inner<VectorSelector>> */
@@ -0,0 +1,269 @@
+//! Prometheus TSDB chunk encodings, see
+//! [`tsdb/chunkenc`](https://github.com/prometheus/prometheus/tree/main/tsdb/chunkenc).
+//!
+//! Only the Gorilla-style XOR encoding of float samples is implemented.
+
+/// The number of samples Prometheus puts into a single chunk.
+pub(crate) const SAMPLES_PER_CHUNK: usize = 120;
+
+/// A stream of bits, written most significant bit first.
+#[derive(Debug, Clone, Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits still free in the last byte.
+    free: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.free == 0 {
+            self.bytes.push(0);
+            self.free = 8;
+        }
+        self.free -= 1;
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 1 << self.free;
+        }
+    }
+
+    /// Writes the `nbits` least significant bits of `value`.
+    fn write_bits(&mut self, value: u64, nbits: u8) {
+        for i in (0..nbits).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.write_bits(byte as u64, 8);
+    }
+}
+
+/// Reads a stream of bits written by [`BitWriter`].
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    /// Position in bits.
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn read_bit(&mut self) -> Result<bool, String> {
+        let byte = self
+            .bytes
+            .get(self.pos / 8)
+            .ok_or_else(|| "XOR chunk: unexpected end of data".to_string())?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, nbits: u8) -> Result<u64, String> {
+        let mut value = 0;
+        for _ in 0..nbits {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
+
+    fn read_uvarint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.read_bits(8)?;
+            value |= (b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("XOR chunk: varint too long".to_string())
+    }
+}
+
+/// An XOR chunk under construction.
+#[derive(Debug, Clone)]
+pub(crate) struct XorChunkBuilder {
+    stream: BitWriter,
+    num_samples: u16,
+    t: i64,
+    v: f64,
+    t_delta: u64,
+    leading: u8,
+    trailing: u8,
+}
+
+impl Default for XorChunkBuilder {
+    fn default() -> Self {
+        Self {
+            stream: BitWriter::default(),
+            num_samples: 0,
+            t: 0,
+            v: 0.0,
+            t_delta: 0,
+            // 0xff marks the absence of a previous leading zero count
+            leading: 0xff,
+            trailing: 0,
+        }
+    }
+}
+
+impl XorChunkBuilder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a sample. Timestamps are in milliseconds and must not decrease.
+    pub(crate) fn append(&mut self, t: i64, v: f64) {
+        match self.num_samples {
+            0 => {
+                let mut buf = vec![];
+                crate::protobuf::write_varint(&mut buf, zigzag(t));
+                buf.into_iter().for_each(|b| self.stream.write_byte(b));
+                self.stream.write_bits(v.to_bits(), 64);
+            }
+            1 => {
+                let t_delta = t.wrapping_sub(self.t) as u64;
+                let mut buf = vec![];
+                crate::protobuf::write_varint(&mut buf, t_delta);
+                buf.into_iter().for_each(|b| self.stream.write_byte(b));
+                self.write_value_delta(v);
+                self.t_delta = t_delta;
+            }
+            _ => {
+                let t_delta = t.wrapping_sub(self.t) as u64;
+                let dod = t_delta.wrapping_sub(self.t_delta) as i64;
+                if dod == 0 {
+                    self.stream.write_bit(false);
+                } else if bit_range(dod, 14) {
+                    self.stream.write_bits(0b10, 2);
+                    self.stream.write_bits(dod as u64, 14);
+                } else if bit_range(dod, 17) {
+                    self.stream.write_bits(0b110, 3);
+                    self.stream.write_bits(dod as u64, 17);
+                } else if bit_range(dod, 20) {
+                    self.stream.write_bits(0b1110, 4);
+                    self.stream.write_bits(dod as u64, 20);
+                } else {
+                    self.stream.write_bits(0b1111, 4);
+                    self.stream.write_bits(dod as u64, 64);
+                }
+                self.write_value_delta(v);
+                self.t_delta = t_delta;
+            }
+        }
+        self.t = t;
+        self.v = v;
+        self.num_samples += 1;
+    }
+
+    fn write_value_delta(&mut self, v: f64) {
+        let delta = v.to_bits() ^ self.v.to_bits();
+        if delta == 0 {
+            self.stream.write_bit(false);
+            return;
+        }
+        self.stream.write_bit(true);
+
+        // the leading zero count is stored in 5 bits
+        let leading = (delta.leading_zeros() as u8).min(31);
+        let trailing = delta.trailing_zeros() as u8;
+        if self.leading != 0xff && leading >= self.leading && trailing >= self.trailing {
+            // the meaningful bits fit into the previous window
+            self.stream.write_bit(false);
+            self.stream
+                .write_bits(delta >> self.trailing, 64 - self.leading - self.trailing);
+        } else {
+            self.leading = leading;
+            self.trailing = trailing;
+            let significant = 64 - leading - trailing;
+            self.stream.write_bit(true);
+            self.stream.write_bits(leading as u64, 5);
+            // 64 significant bits overflow into 0, which is unambiguous as 0 is impossible
+            self.stream.write_bits(significant as u64, 6);
+            self.stream.write_bits(delta >> trailing, significant);
+        }
+    }
+
+    /// The chunk's bytes: a big-endian sample count followed by the bit stream.
+    pub(crate) fn finish(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 + self.stream.bytes.len());
+        bytes.extend_from_slice(&self.num_samples.to_be_bytes());
+        bytes.extend_from_slice(&self.stream.bytes);
+        bytes
+    }
+}
+
+/// Decodes the samples of an XOR chunk, as returned by
+/// [`XorChunkBuilder::finish`].
+pub(crate) fn decode_xor_chunk(bytes: &[u8]) -> Result<Vec<(i64, f64)>, String> {
+    if bytes.len() < 2 {
+        return Err("XOR chunk: missing sample count".to_string());
+    }
+    let num_samples = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+    let mut stream = BitReader {
+        bytes: &bytes[2..],
+        pos: 0,
+    };
+    let mut samples = Vec::with_capacity(num_samples);
+    let (mut t, mut v, mut t_delta) = (0i64, 0u64, 0u64);
+    let (mut leading, mut trailing) = (0u8, 0u8);
+    for i in 0..num_samples {
+        match i {
+            0 => {
+                t = unzigzag(stream.read_uvarint()?);
+                v = stream.read_bits(64)?;
+            }
+            1 => {
+                t_delta = stream.read_uvarint()?;
+                t = t.wrapping_add(t_delta as i64);
+            }
+            _ => {
+                // the number of leading one bits selects the size of the delta of delta
+                let mut prefix = 0;
+                while prefix < 4 && stream.read_bit()? {
+                    prefix += 1;
+                }
+                let dod = match prefix {
+                    0 => 0,
+                    4 => stream.read_bits(64)? as i64,
+                    _ => {
+                        let nbits = [14, 17, 20][prefix - 1];
+                        let bits = stream.read_bits(nbits)?;
+                        if bits > 1 << (nbits - 1) {
+                            bits as i64 - (1 << nbits)
+                        } else {
+                            bits as i64
+                        }
+                    }
+                };
+                t_delta = t_delta.wrapping_add(dod as u64);
+                t = t.wrapping_add(t_delta as i64);
+            }
+        }
+        if i > 0 && stream.read_bit()? {
+            if stream.read_bit()? {
+                leading = stream.read_bits(5)? as u8;
+                let significant = match stream.read_bits(6)? as u8 {
+                    0 => 64,
+                    significant => significant,
+                };
+                trailing = 64u8
+                    .checked_sub(leading + significant)
+                    .ok_or_else(|| "XOR chunk: invalid value width".to_string())?;
+            }
+            v ^= stream.read_bits(64 - leading - trailing)? << trailing;
+        }
+        samples.push((t, f64::from_bits(v)));
+    }
+    Ok(samples)
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    (v >> 1) as i64 ^ -((v & 1) as i64)
+}
+
+/// Whether `x` can be stored in `nbits` bits, using Prometheus' asymmetric range.
+fn bit_range(x: i64, nbits: u8) -> bool {
+    -((1 << (nbits - 1)) - 1) <= x && x <= 1 << (nbits - 1)
+}
@@ -0,0 +1,1202 @@
+//! # Text exposition format
+//!
+//! Parses metric dumps in the Prometheus [text exposition format][text]
+//! (version 0.0.4) and in [OpenMetrics][om] 1.0 text format, e.g. the output
+//! of `curl /metrics`, node-exporter textfile collector files or federation:
+//!
+//! ```sql
+//! SELECT metric_family, labels, time, value
+//! FROM prom_api.parse_exposition(pg_read_file('node.prom'), now());
+//! ```
+//!
+//! Input ending with a `# EOF` line is parsed as OpenMetrics, anything else
+//! as the Prometheus text format. The formats differ in the set of metric
+//! types, the unit of timestamps (milliseconds vs seconds), and OpenMetrics
+//! additionally supports `# UNIT` lines and exemplars.
+//!
+//! Every sample yields a row. `labels` includes the sample's `__name__`, which
+//! for histograms, summaries and OpenMetrics counters carries a suffix like
+//! `_bucket` or `_total`, while `metric_family` is the name used by `# HELP`
+//! and `# TYPE`. Samples without a timestamp get `default_ts`. `metric_type`,
+//! `help` and `unit` are `NULL` if the family wasn't described.
+//!
+//! Malformed input is rejected with an error pointing at the offending line
+//! and column.
+//!
+//! The family descriptions can be stored as metric metadata with:
+//!
+//! ```sql
+//! SELECT _prom_catalog.insert_metric_metadatas(
+//!     array_agg(now()), array_agg(metric_family), array_agg(metric_type),
+//!     array_agg(coalesce(unit, '')), array_agg(coalesce(help, '')))
+//! FROM (
+//!     SELECT DISTINCT metric_family, metric_type, unit, help
+//!     FROM prom_api.parse_exposition(pg_read_file('node.prom'))
+//!     WHERE metric_type IS NOT NULL
+//! ) families;
+//! ```
+//!
+//! The `prom_api.to_exposition` aggregate goes the other way and renders
+//! samples for federation. Samples are grouped by metric family in order of
+//! first appearance, preceded by `# HELP` and `# TYPE` lines taken from the
+//! most recent `_prom_catalog.metadata` of the family:
+//!
+//! ```sql
+//! SELECT prom_api.to_exposition('up', prom_api.jsonb(s.labels), d.value, d.time)
+//! FROM prom_data_series.up s
+//! INNER JOIN prom_data.up d ON d.series_id = s.id
+//! WHERE d.time > now() - interval '5 minutes';
+//! ```
+//!
+//! `prom_api.to_openmetrics`, taking the same arguments, renders OpenMetrics
+//! instead, with `# UNIT` lines and a final `# EOF`. Each sample gets the most
+//! recent exemplar of its series (not newer than the sample) from the exemplar
+//! table of the metric, if any. Rows with a `NULL` name or value are skipped;
+//! without any rows the result is `NULL`.
+//!
+//! [text]: https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format
+//! [om]: https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md
+use pgx::*;
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde_json::{Map, Value};
+
+#[pg_schema]
+mod _prom_ext {
+    use pgx::*;
+
+    use serde_json::Value;
+    use std::collections::HashMap;
+
+    use super::{
+        parse, render, ExpositionRow, Family, Format, RenderExemplar, RenderSample, RenderState,
+        SUFFIXES,
+    };
+    use crate::aggregate_utils::in_aggregate_context;
+    use crate::palloc::{Inner, InternalAsValue, ToInternal};
+    use crate::prompb::from_pg_timestamp;
+    use crate::util::quote_identifier;
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn parse_exposition(
+        input: Option<&str>,
+        default_ts: Option<TimestampWithTimeZone>,
+    ) -> TableIterator<
+        'static,
+        (
+            name!(metric_family, String),
+            name!(metric_type, Option<String>),
+            name!(help, Option<String>),
+            name!(unit, Option<String>),
+            name!(labels, JsonB),
+            name!(time, Option<TimestampWithTimeZone>),
+            name!(value, f64),
+            name!(exemplar_labels, Option<JsonB>),
+            name!(exemplar_value, Option<f64>),
+            name!(exemplar_time, Option<TimestampWithTimeZone>),
+        ),
+    > {
+        let rows = match input {
+            Some(input) => parse(input, default_ts.map(|ts| ts.into()))
+                .unwrap_or_else(|e| error!("invalid exposition format at {}", e)),
+            None => vec![],
+        };
+        TableIterator::new(rows.into_iter().map(|row: ExpositionRow| {
+            (
+                row.metric_family,
+                row.metric_type,
+                row.help,
+                row.unit,
+                JsonB(row.labels),
+                row.time.map(TimestampWithTimeZone::from),
+                row.value,
+                row.exemplar.as_ref().map(|e| JsonB(e.labels.clone())),
+                row.exemplar.as_ref().map(|e| e.value),
+                row.exemplar
+                    .and_then(|e| e.time)
+                    .map(TimestampWithTimeZone::from),
+            )
+        }))
+    }
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn to_exposition_transition(
+        state: Internal,
+        metric_name: Option<String>,
+        labels: Option<JsonB>,
+        value: Option<f64>,
+        ts: Option<TimestampWithTimeZone>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        to_exposition_transition_inner(
+            unsafe { state.to_inner() },
+            Format::Prometheus,
+            metric_name,
+            labels,
+            value,
+            ts,
+            fcinfo,
+        )
+        .internal()
+    }
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn to_openmetrics_transition(
+        state: Internal,
+        metric_name: Option<String>,
+        labels: Option<JsonB>,
+        value: Option<f64>,
+        ts: Option<TimestampWithTimeZone>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        to_exposition_transition_inner(
+            unsafe { state.to_inner() },
+            Format::OpenMetrics,
+            metric_name,
+            labels,
+            value,
+            ts,
+            fcinfo,
+        )
+        .internal()
+    }
+
+    fn to_exposition_transition_inner(
+        state: Option<Inner<RenderState>>,
+        format: Format,
+        metric_name: Option<String>,
+        labels: Option<JsonB>,
+        value: Option<f64>,
+        ts: Option<TimestampWithTimeZone>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<RenderState>> {
+        unsafe {
+            in_aggregate_context(fcinfo, || {
+                let mut state = state.unwrap_or_else(|| {
+                    RenderState {
+                        format,
+                        samples: vec![],
+                    }
+                    .into()
+                });
+                // like other aggregates, rows with a NULL metric name or value are skipped
+                if let (Some(name), Some(value)) = (metric_name, value) {
+                    state.samples.push(RenderSample {
+                        name,
+                        labels: labels_of(labels.map(|l| l.0)),
+                        value,
+                        timestamp: ts.map(|ts| from_pg_timestamp(ts.into())),
+                        exemplar: None,
+                    });
+                }
+                Some(state)
+            })
+        }
+    }
+
+    /// Label values as strings, without the metric name.
+    fn labels_of(labels: Option<Value>) -> Vec<(String, String)> {
+        match labels {
+            None | Some(Value::Null) => vec![],
+            Some(Value::Object(map)) => map
+                .into_iter()
+                .filter(|(name, _)| name != "__name__")
+                .map(|(name, value)| match value {
+                    Value::String(value) => (name, value),
+                    _ => error!("value of label \"{}\" must be a string", name),
+                })
+                .collect(),
+            Some(_) => error!("labels must be a JSON object"),
+        }
+    }
+
+    #[pg_extern(stable, parallel_safe, create_or_replace)]
+    pub fn to_exposition_final(
+        state: Internal, /* Option<Inner<RenderState>> */
+    ) -> Option<String> {
+        let state: Option<Inner<RenderState>> = unsafe { state.to_inner() };
+        state.map(|mut state| {
+            if state.format == Format::OpenMetrics {
+                fetch_exemplars(&mut state.samples);
+            }
+            let families = fetch_metadata(&state.samples);
+            render(&state, &families)
+        })
+    }
+
+    /// Attaches to every sample the most recent exemplar of its series, which
+    /// isn't newer than the sample itself, from the exemplar table of the metric.
+    fn fetch_exemplars(samples: &mut [RenderSample]) {
+        let mut names: Vec<String> = samples.iter().map(|s| s.name.clone()).collect();
+        names.sort();
+        names.dedup();
+        let tables = Spi::get_one_with_args::<JsonB>(
+            "SELECT jsonb_object_agg(e.metric_name, e.table_name)
+             FROM _prom_catalog.exemplar e
+             WHERE e.metric_name = ANY($1)",
+            vec![(PgBuiltInOids::TEXTARRAYOID.oid(), names.into_datum())],
+        );
+        let tables = match tables {
+            Some(JsonB(Value::Object(map))) => map,
+            _ => return,
+        };
+
+        for (metric_name, table_name) in tables {
+            let table_name = match table_name {
+                Value::String(table_name) => table_name,
+                _ => continue,
+            };
+            let positions: Vec<usize> = samples
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| s.name == metric_name)
+                .map(|(i, _)| i)
+                .collect();
+            let lookups = Value::Array(
+                positions
+                    .iter()
+                    .map(|&i| {
+                        let sample = &samples[i];
+                        serde_json::json!({
+                            "labels": sample
+                                .labels
+                                .iter()
+                                .map(|(name, value)| (name.clone(), Value::String(value.clone())))
+                                .collect::<serde_json::Map<_, _>>(),
+                            "ts": sample.timestamp,
+                        })
+                    })
+                    .collect(),
+            );
+            let exemplars = Spi::get_one_with_args::<JsonB>(
+                &format!(
+                    "SELECT jsonb_agg(e.exemplar ORDER BY u.ord)
+                     FROM jsonb_array_elements($2) WITH ORDINALITY u(sample, ord)
+                     LEFT JOIN LATERAL (
+                        SELECT jsonb_build_object(
+                            'labels', (
+                                SELECT coalesce(jsonb_object_agg(p.key, x.exemplar_label_values[p.pos]), '{{}}')
+                                FROM _prom_catalog.exemplar_label_key_position p
+                                WHERE p.metric_name = $1
+                                AND x.exemplar_label_values[p.pos] <> '__promscale_no_value__'
+                            ),
+                            'value', x.value,
+                            'ts', (extract(epoch FROM x.time) * 1000)::bigint
+                        ) AS exemplar
+                        FROM _prom_catalog.series s
+                        INNER JOIN prom_data_exemplar.{} x ON (x.series_id = s.id)
+                        WHERE s.metric_id = (
+                            SELECT m.id FROM _prom_catalog.metric m
+                            WHERE m.metric_name = $1 AND m.table_schema = 'prom_data'
+                        )
+                        AND prom_api.eq(s.labels, u.sample->'labels')
+                        AND (u.sample->>'ts' IS NULL OR x.time <= to_timestamp((u.sample->>'ts')::bigint / 1000.0))
+                        ORDER BY x.time DESC
+                        LIMIT 1
+                     ) e ON true",
+                    quote_identifier(&table_name)
+                ),
+                vec![
+                    (PgBuiltInOids::TEXTOID.oid(), metric_name.into_datum()),
+                    (PgBuiltInOids::JSONBOID.oid(), JsonB(lookups).into_datum()),
+                ],
+            );
+            let exemplars = match exemplars {
+                Some(JsonB(Value::Array(exemplars))) => exemplars,
+                _ => continue,
+            };
+            for (i, exemplar) in positions.into_iter().zip(exemplars) {
+                samples[i].exemplar = match exemplar {
+                    Value::Object(exemplar) => Some(RenderExemplar {
+                        labels: labels_of(exemplar.get("labels").cloned()),
+                        value: exemplar
+                            .get("value")
+                            .and_then(Value::as_f64)
+                            .unwrap_or(f64::NAN),
+                        timestamp: exemplar.get("ts").and_then(Value::as_i64),
+                    }),
+                    _ => None,
+                };
+            }
+        }
+    }
+
+    /// Looks up the most recent metadata of every family the samples could belong to.
+    fn fetch_metadata(samples: &[RenderSample]) -> HashMap<String, Family> {
+        let mut candidates: Vec<String> = samples
+            .iter()
+            .flat_map(|s| {
+                std::iter::once(s.name.clone()).chain(
+                    SUFFIXES
+                        .iter()
+                        .filter_map(move |(suffix, _)| s.name.strip_suffix(suffix))
+                        .map(str::to_string),
+                )
+            })
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+
+        let metadata = Spi::get_one_with_args::<JsonB>(
+            "SELECT jsonb_object_agg(m.metric_family, jsonb_build_object('type', m.type, 'unit', m.unit, 'help', m.help))
+             FROM (
+                SELECT DISTINCT ON (metric_family) metric_family, type, unit, help
+                FROM _prom_catalog.metadata
+                WHERE metric_family = ANY($1)
+                ORDER BY metric_family, last_seen DESC
+             ) m",
+            vec![(PgBuiltInOids::TEXTARRAYOID.oid(), candidates.into_datum())],
+        );
+        let get = |v: &Value, key: &str| v.get(key).and_then(Value::as_str).map(str::to_string);
+        match metadata {
+            Some(JsonB(Value::Object(map))) => map
+                .into_iter()
+                .map(|(name, m)| {
+                    let family = Family {
+                        metric_type: get(&m, "type"),
+                        help: get(&m, "help"),
+                        unit: get(&m, "unit"),
+                    };
+                    (name, family)
+                })
+                .collect(),
+            _ => HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct ExpositionRow {
+    metric_family: String,
+    metric_type: Option<String>,
+    help: Option<String>,
+    unit: Option<String>,
+    labels: Value,
+    /// Postgres timestamp (microseconds since 2000-01-01)
+    time: Option<i64>,
+    value: f64,
+    exemplar: Option<Exemplar>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Exemplar {
+    labels: Value,
+    value: f64,
+    time: Option<i64>,
+}
+
+/// A syntax error, also used by the line protocol parser.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ParseError {
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Prometheus,
+    OpenMetrics,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Family {
+    metric_type: Option<String>,
+    help: Option<String>,
+    unit: Option<String>,
+}
+
+/// Parses a whole exposition. `default_ts` is a Postgres timestamp.
+fn parse(input: &str, default_ts: Option<i64>) -> Result<Vec<ExpositionRow>, ParseError> {
+    let format = if input.lines().any(|l| l.trim_end() == "# EOF") {
+        Format::OpenMetrics
+    } else {
+        Format::Prometheus
+    };
+
+    let mut families: HashMap<String, Family> = HashMap::new();
+    let mut rows = vec![];
+    let mut seen_eof = false;
+    for (idx, line) in input.lines().enumerate() {
+        let mut cursor = Cursor {
+            line,
+            line_no: idx + 1,
+            pos: 0,
+        };
+        cursor.skip_blanks();
+        if cursor.at_end() {
+            continue;
+        }
+        if seen_eof {
+            return Err(cursor.error("unexpected content after # EOF"));
+        }
+        if cursor.peek() == Some('#') {
+            seen_eof = parse_comment(&mut cursor, format, &mut families)?;
+        } else {
+            let mut row = parse_sample(&mut cursor, format)?;
+            let (family_name, family) = find_family(&row.metric_family, &families);
+            row.metric_family = family_name;
+            if let Some(family) = family {
+                row.metric_type = family.metric_type.clone();
+                row.help = family.help.clone();
+                row.unit = family.unit.clone();
+            }
+            row.time = row.time.or(default_ts);
+            rows.push(row);
+        }
+    }
+    Ok(rows)
+}
+
+/// Parses a `#` line. Returns whether it was the OpenMetrics `# EOF` marker.
+fn parse_comment(
+    cursor: &mut Cursor,
+    format: Format,
+    families: &mut HashMap<String, Family>,
+) -> Result<bool, ParseError> {
+    cursor.expect('#')?;
+    cursor.skip_blanks();
+    let keyword_start = cursor.pos;
+    let keyword = cursor.take_while(|c| !is_blank(c));
+    match (keyword, format) {
+        ("EOF", Format::OpenMetrics) => {
+            cursor.skip_blanks();
+            if !cursor.at_end() {
+                return Err(cursor.error("unexpected content after # EOF"));
+            }
+            Ok(true)
+        }
+        ("HELP", _) | ("TYPE", _) | ("UNIT", Format::OpenMetrics) => {
+            cursor.expect_blank()?;
+            let name = cursor.metric_name()?;
+            let family = families.entry(name.to_string()).or_default();
+            let already_set = match keyword {
+                "HELP" => {
+                    // the docstring is the rest of the line, it may be empty
+                    if !cursor.at_end() {
+                        cursor.expect_blank()?;
+                    }
+                    let help = cursor.escaped_until_end(format)?;
+                    family.help.replace(help).is_some()
+                }
+                "TYPE" => {
+                    cursor.expect_blank()?;
+                    let type_start = cursor.pos;
+                    let metric_type = cursor.take_while(|c| !is_blank(c));
+                    let valid = match format {
+                        Format::Prometheus => PROMETHEUS_TYPES.contains(&metric_type),
+                        Format::OpenMetrics => OPENMETRICS_TYPES.contains(&metric_type),
+                    };
+                    if !valid {
+                        return Err(cursor.error_at(
+                            type_start,
+                            format!("unknown metric type \"{}\"", metric_type),
+                        ));
+                    }
+                    cursor.expect_end()?;
+                    family
+                        .metric_type
+                        .replace(metric_type.to_string())
+                        .is_some()
+                }
+                _ => {
+                    cursor.expect_blank()?;
+                    let unit = cursor.take_while(|c| !is_blank(c));
+                    cursor.expect_end()?;
+                    family.unit.replace(unit.to_string()).is_some()
+                }
+            };
+            if already_set {
+                return Err(cursor.error_at(
+                    keyword_start,
+                    format!("second {} line for metric family \"{}\"", keyword, name),
+                ));
+            }
+            Ok(false)
+        }
+        // any other comment is ignored
+        _ => Ok(false),
+    }
+}
+
+const PROMETHEUS_TYPES: &[&str] = &["counter", "gauge", "histogram", "summary", "untyped"];
+
+const OPENMETRICS_TYPES: &[&str] = &[
+    "counter",
+    "gauge",
+    "histogram",
+    "gaugehistogram",
+    "stateset",
+    "info",
+    "summary",
+    "unknown",
+];
+
+/// Parses `name{labels} value [timestamp] [# exemplar]`. The returned row's
+/// `metric_family` is the sample name, to be resolved by the caller.
+fn parse_sample(cursor: &mut Cursor, format: Format) -> Result<ExpositionRow, ParseError> {
+    let name = cursor.metric_name()?.to_string();
+    let mut labels = Map::new();
+    labels.insert("__name__".to_string(), Value::String(name.clone()));
+    let name_end = cursor.pos;
+    cursor.skip_blanks();
+    if cursor.peek() == Some('{') {
+        cursor.label_set(&mut labels)?;
+        cursor.expect_blank()?;
+    } else if cursor.pos == name_end {
+        cursor.expect_blank()?;
+    }
+
+    let value = cursor.float("value")?;
+    cursor.skip_blanks();
+
+    let mut time = None;
+    if !cursor.at_end() && cursor.peek() != Some('#') {
+        time = Some(cursor.timestamp(format)?);
+        cursor.skip_blanks();
+    }
+
+    let mut exemplar = None;
+    if format == Format::OpenMetrics && cursor.peek() == Some('#') {
+        cursor.expect('#')?;
+        cursor.expect_blank()?;
+        let mut exemplar_labels = Map::new();
+        cursor.label_set(&mut exemplar_labels)?;
+        cursor.expect_blank()?;
+        let value = cursor.float("exemplar value")?;
+        cursor.skip_blanks();
+        let time = if cursor.at_end() {
+            None
+        } else {
+            Some(cursor.timestamp(format)?)
+        };
+        exemplar = Some(Exemplar {
+            labels: Value::Object(exemplar_labels),
+            value,
+            time,
+        });
+    }
+    cursor.expect_end()?;
+
+    Ok(ExpositionRow {
+        metric_family: name,
+        metric_type: None,
+        help: None,
+        unit: None,
+        labels: Value::Object(labels),
+        time,
+        value,
+        exemplar,
+    })
+}
+
+/// Maps a sample name to its family, taking the type-specific suffixes of
+/// histograms, summaries, counters and info metrics into account.
+fn find_family<'a>(
+    sample_name: &str,
+    families: &'a HashMap<String, Family>,
+) -> (String, Option<&'a Family>) {
+    if let Some(family) = families.get(sample_name) {
+        return (sample_name.to_string(), Some(family));
+    }
+    for (suffix, types) in SUFFIXES {
+        if let Some(base) = sample_name.strip_suffix(suffix) {
+            if let Some(family) = families.get(base) {
+                if matches!(&family.metric_type, Some(t) if types.contains(&t.as_str())) {
+                    return (base.to_string(), Some(family));
+                }
+            }
+        }
+    }
+    (sample_name.to_string(), None)
+}
+
+const SUFFIXES: &[(&str, &[&str])] = &[
+    ("_total", &["counter"]),
+    ("_created", &["counter", "histogram", "summary"]),
+    ("_bucket", &["histogram", "gaugehistogram"]),
+    ("_count", &["histogram", "summary"]),
+    ("_sum", &["histogram", "summary"]),
+    ("_gcount", &["gaugehistogram"]),
+    ("_gsum", &["gaugehistogram"]),
+    ("_info", &["info"]),
+];
+
+fn is_blank(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+/// Position within a single line, tracked in bytes.
+struct Cursor<'a> {
+    line: &'a str,
+    line_no: usize,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn rest(&self) -> &'a str {
+        &self.line[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.line.len()
+    }
+
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        self.error_at(self.pos, message)
+    }
+
+    /// Columns are 1-based and counted in characters.
+    fn error_at(&self, pos: usize, message: impl Into<String>) -> ParseError {
+        ParseError {
+            line: self.line_no,
+            column: self.line[..pos].chars().count() + 1,
+            message: message.into(),
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn skip_blanks(&mut self) {
+        self.take_while(is_blank);
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += c.len_utf8();
+                Ok(())
+            }
+            Some(c) => Err(self.error(format!("expected '{}', found '{}'", expected, c))),
+            None => Err(self.error(format!("expected '{}', found end of line", expected))),
+        }
+    }
+
+    /// Requires at least one blank and skips all of them.
+    fn expect_blank(&mut self) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(c) if is_blank(c) => {
+                self.skip_blanks();
+                Ok(())
+            }
+            Some(c) => Err(self.error(format!("expected whitespace, found '{}'", c))),
+            None => Err(self.error("unexpected end of line")),
+        }
+    }
+
+    fn expect_end(&mut self) -> Result<(), ParseError> {
+        self.skip_blanks();
+        match self.peek() {
+            None => Ok(()),
+            Some(c) => Err(self.error(format!("unexpected '{}'", c))),
+        }
+    }
+
+    fn metric_name(&mut self) -> Result<&'a str, ParseError> {
+        let start = self.pos;
+        let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            self.pos = start;
+            return Err(self.error("invalid metric name"));
+        }
+        Ok(name)
+    }
+
+    /// Parses `{name="value",...}` into `labels`.
+    fn label_set(&mut self, labels: &mut Map<String, Value>) -> Result<(), ParseError> {
+        self.expect('{')?;
+        loop {
+            self.skip_blanks();
+            if self.peek() == Some('}') {
+                self.pos += 1;
+                return Ok(());
+            }
+            let start = self.pos;
+            let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+            if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+                self.pos = start;
+                return Err(self.error("invalid label name"));
+            }
+            self.skip_blanks();
+            self.expect('=')?;
+            self.skip_blanks();
+            self.expect('"')?;
+            let value = self.label_value()?;
+            if labels
+                .insert(name.to_string(), Value::String(value))
+                .is_some()
+            {
+                return Err(self.error_at(start, format!("duplicate label \"{}\"", name)));
+            }
+            self.skip_blanks();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {}
+                Some(c) => return Err(self.error(format!("expected ',' or '}}', found '{}'", c))),
+                None => return Err(self.error("unterminated label set")),
+            }
+        }
+    }
+
+    /// Parses the rest of a quoted label value, including the closing quote.
+    fn label_value(&mut self) -> Result<String, ParseError> {
+        let mut value = String::new();
+        let mut chars = self.rest().char_indices();
+        let start = self.pos;
+        while let Some((offset, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos = start + offset + 1;
+                    return Ok(value);
+                }
+                '\\' => match chars.next() {
+                    Some((_, '\\')) => value.push('\\'),
+                    Some((_, '"')) => value.push('"'),
+                    Some((_, 'n')) => value.push('\n'),
+                    _ => {
+                        return Err(self.error_at(start + offset, "invalid escape sequence"));
+                    }
+                },
+                c => value.push(c),
+            }
+        }
+        self.pos = self.line.len();
+        Err(self.error("unterminated label value"))
+    }
+
+    /// The rest of the line with `\\` and `\n` (and `\"` in OpenMetrics) unescaped.
+    fn escaped_until_end(&mut self, format: Format) -> Result<String, ParseError> {
+        let mut text = String::new();
+        let mut chars = self.rest().char_indices();
+        let start = self.pos;
+        while let Some((offset, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, '\\')) => text.push('\\'),
+                    Some((_, 'n')) => text.push('\n'),
+                    Some((_, '"')) if format == Format::OpenMetrics => text.push('"'),
+                    _ => {
+                        return Err(self.error_at(start + offset, "invalid escape sequence"));
+                    }
+                },
+                c => text.push(c),
+            }
+        }
+        self.pos = self.line.len();
+        Ok(text)
+    }
+
+    fn token(&mut self) -> (usize, &'a str) {
+        let start = self.pos;
+        (start, self.take_while(|c| !is_blank(c)))
+    }
+
+    fn float(&mut self, what: &str) -> Result<f64, ParseError> {
+        let (start, token) = self.token();
+        parse_float(token)
+            .ok_or_else(|| self.error_at(start, format!("invalid {} \"{}\"", what, token)))
+    }
+
+    /// Milliseconds in the Prometheus format, (fractional) seconds in
+    /// OpenMetrics. Returns a Postgres timestamp.
+    fn timestamp(&mut self, format: Format) -> Result<i64, ParseError> {
+        let (start, token) = self.token();
+        let ts = match format {
+            Format::Prometheus => token
+                .parse::<i64>()
+                .ok()
+                .and_then(|ms| crate::prompb::to_pg_timestamp(ms).ok()),
+            Format::OpenMetrics => parse_float(token)
+                .filter(|s| s.is_finite())
+                .map(|s| (s * 1_000_000.0).round())
+                .filter(|us| us.abs() < i64::MAX as f64)
+                .and_then(|us| (us as i64).checked_sub(PG_EPOCH_OFFSET_US)),
+        };
+        ts.ok_or_else(|| self.error_at(start, format!("invalid timestamp \"{}\"", token)))
+    }
+}
+
+/// Aggregation state of `to_exposition`.
+#[derive(Debug)]
+struct RenderState {
+    format: Format,
+    samples: Vec<RenderSample>,
+}
+
+#[derive(Debug)]
+struct RenderSample {
+    name: String,
+    labels: Vec<(String, String)>,
+    value: f64,
+    /// Milliseconds since the Unix epoch.
+    timestamp: Option<i64>,
+    exemplar: Option<RenderExemplar>,
+}
+
+#[derive(Debug)]
+struct RenderExemplar {
+    labels: Vec<(String, String)>,
+    value: f64,
+    timestamp: Option<i64>,
+}
+
+/// Renders the samples grouped by metric family, in order of first appearance,
+/// each family preceded by its `# HELP`, `# TYPE` (and `# UNIT`) lines.
+fn render(state: &RenderState, families: &HashMap<String, Family>) -> String {
+    let mut order: Vec<String> = vec![];
+    let mut groups: HashMap<String, (Option<&Family>, Vec<&RenderSample>)> = HashMap::new();
+    for sample in &state.samples {
+        let (name, family) = find_family(&sample.name, families);
+        groups
+            .entry(name.clone())
+            .or_insert_with(|| {
+                order.push(name);
+                (family, vec![])
+            })
+            .1
+            .push(sample);
+    }
+
+    let mut out = String::new();
+    for name in order {
+        let (family, samples) = &groups[&name];
+        if let Some(family) = family {
+            render_family_header(&mut out, state.format, &name, family);
+        }
+        for sample in samples {
+            render_sample(&mut out, state.format, sample);
+        }
+    }
+    if state.format == Format::OpenMetrics {
+        out.push_str("# EOF\n");
+    }
+    out
+}
+
+fn render_family_header(out: &mut String, format: Format, name: &str, family: &Family) {
+    let metric_type = family.metric_type.as_deref().map(|t| match format {
+        Format::Prometheus if PROMETHEUS_TYPES.contains(&t) => t,
+        Format::Prometheus => "untyped",
+        Format::OpenMetrics if OPENMETRICS_TYPES.contains(&t) => t,
+        Format::OpenMetrics => "unknown",
+    });
+    // OpenMetrics counter families are named without the suffix of their samples
+    let name = match (format, metric_type) {
+        (Format::OpenMetrics, Some("counter")) => name.strip_suffix("_total").unwrap_or(name),
+        _ => name,
+    };
+    if let Some(help) = family.help.as_deref().filter(|h| !h.is_empty()) {
+        out.push_str(&format!(
+            "# HELP {} {}\n",
+            name,
+            escape(help, format, false)
+        ));
+    }
+    if let Some(metric_type) = metric_type {
+        out.push_str(&format!("# TYPE {} {}\n", name, metric_type));
+    }
+    if format == Format::OpenMetrics {
+        if let Some(unit) = family.unit.as_deref().filter(|u| !u.is_empty()) {
+            out.push_str(&format!("# UNIT {} {}\n", name, unit));
+        }
+    }
+}
+
+fn render_sample(out: &mut String, format: Format, sample: &RenderSample) {
+    out.push_str(&sample.name);
+    render_labels(out, &sample.labels, false);
+    out.push(' ');
+    out.push_str(&format_float(sample.value));
+    if let Some(ts) = sample.timestamp {
+        out.push(' ');
+        out.push_str(&format_timestamp(ts, format));
+    }
+    if let (Format::OpenMetrics, Some(exemplar)) = (format, &sample.exemplar) {
+        out.push_str(" # ");
+        render_labels(out, &exemplar.labels, true);
+        out.push(' ');
+        out.push_str(&format_float(exemplar.value));
+        if let Some(ts) = exemplar.timestamp {
+            out.push(' ');
+            out.push_str(&format_timestamp(ts, format));
+        }
+    }
+    out.push('\n');
+}
+
+/// Renders `{name="value",...}`. An empty label set is omitted, unless
+/// `always` is set, as it is required for exemplars.
+fn render_labels(out: &mut String, labels: &[(String, String)], always: bool) {
+    if labels.is_empty() && !always {
+        return;
+    }
+    out.push('{');
+    for (i, (name, value)) in labels.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(&format!(
+            "{}=\"{}\"",
+            name,
+            escape(value, Format::OpenMetrics, true)
+        ));
+    }
+    out.push('}');
+}
+
+/// Escapes backslashes and newlines and, in label values and OpenMetrics
+/// help texts, double quotes.
+fn escape(text: &str, format: Format, label_value: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' if label_value || format == Format::OpenMetrics => escaped.push_str("\\\""),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_float(v: f64) -> String {
+    if v.is_nan() {
+        "NaN".to_string()
+    } else if v == f64::INFINITY {
+        "+Inf".to_string()
+    } else if v == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        v.to_string()
+    }
+}
+
+/// Milliseconds in the Prometheus format, seconds in OpenMetrics.
+fn format_timestamp(ms: i64, format: Format) -> String {
+    match format {
+        Format::Prometheus => ms.to_string(),
+        Format::OpenMetrics if ms % 1000 == 0 => (ms / 1000).to_string(),
+        Format::OpenMetrics => {
+            let sign = if ms < 0 { "-" } else { "" };
+            let abs = ms.unsigned_abs();
+            format!("{}{}.{:03}", sign, abs / 1000, abs % 1000)
+        }
+    }
+}
+
+/// Microseconds between the Unix epoch and the Postgres epoch (2000-01-01).
+const PG_EPOCH_OFFSET_US: i64 = 946_684_800_000_000;
+
+/// Like Go's `strconv.ParseFloat`, accepts `NaN`, `Inf` and `+Inf`/`-Inf`
+/// in any case.
+fn parse_float(token: &str) -> Option<f64> {
+    token.parse::<f64>().ok()
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+
+    /// Parses `input` and returns the rows, projected by `columns`, as a JSON array.
+    fn parse(input: &str, columns: &str) -> serde_json::Value {
+        Spi::get_one_with_args::<Json>(
+            &format!(
+                "SELECT json_agg(json_build_array({}))
+                 FROM prom_api.parse_exposition($1, '2000-01-01 00:00:00+00')",
+                columns
+            ),
+            vec![(PgBuiltInOids::TEXTOID.oid(), input.into_datum())],
+        )
+        .expect("SQL query failed")
+        .0
+    }
+
+    #[pg_test]
+    fn test_parse_exposition_prometheus() {
+        let input = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{method="post",code="400",path="C:\\dir\"x\"\n"}    3 1395066363000
+
+# a comment
+# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.5"} NaN
+rpc_duration_seconds_count 2693
+untyped_metric -Inf
+"#;
+        assert_eq!(
+            parse(
+                input,
+                "metric_family, metric_type, help, labels, (extract(epoch FROM time) * 1000)::bigint, value::text"
+            ),
+            serde_json::json!([
+                ["http_requests_total", "counter", "The total number of HTTP requests.",
+                 {"__name__": "http_requests_total", "method": "post", "code": "200"}, 1395066363000_i64, "1027"],
+                ["http_requests_total", "counter", "The total number of HTTP requests.",
+                 {"__name__": "http_requests_total", "method": "post", "code": "400", "path": "C:\\dir\"x\"\n"}, 1395066363000_i64, "3"],
+                ["rpc_duration_seconds", "summary", null,
+                 {"__name__": "rpc_duration_seconds", "quantile": "0.5"}, 946684800000_i64, "NaN"],
+                ["rpc_duration_seconds", "summary", null,
+                 {"__name__": "rpc_duration_seconds_count"}, 946684800000_i64, "2693"],
+                ["untyped_metric", null, null,
+                 {"__name__": "untyped_metric"}, 946684800000_i64, "-Infinity"],
+            ])
+        );
+    }
+
+    #[pg_test]
+    fn test_parse_exposition_openmetrics() {
+        let input = r#"# TYPE foo_seconds histogram
+# UNIT foo_seconds seconds
+# HELP foo_seconds A \"histogram\" \\ with help.
+foo_seconds_bucket{le="0.1"} 8 1520879607.789 # {trace_id="KOO5S4vxi0o"} 0.067 1520879602.123
+foo_seconds_count 17 1520879607.789
+# EOF
+"#;
+        assert_eq!(
+            parse(
+                input,
+                "metric_family, metric_type, unit, help, labels, (extract(epoch FROM time) * 1000)::bigint, value, \
+                 exemplar_labels, exemplar_value, (extract(epoch FROM exemplar_time) * 1000)::bigint"
+            ),
+            serde_json::json!([
+                ["foo_seconds", "histogram", "seconds", "A \"histogram\" \\ with help.",
+                 {"__name__": "foo_seconds_bucket", "le": "0.1"}, 1520879607789_i64, 8,
+                 {"trace_id": "KOO5S4vxi0o"}, 0.067, 1520879602123_i64],
+                ["foo_seconds", "histogram", "seconds", "A \"histogram\" \\ with help.",
+                 {"__name__": "foo_seconds_count"}, 1520879607789_i64, 17,
+                 null, null, null],
+            ])
+        );
+    }
+
+    #[pg_test(
+        error = "invalid exposition format at line 2, column 13: expected ',' or '}', found 'b'"
+    )]
+    fn test_parse_exposition_error_position() {
+        parse("foo 1\nbar{a=\"x\"   b=\"y\"} 2\n", "value");
+    }
+
+    #[pg_test(
+        error = "invalid exposition format at line 1, column 12: unknown metric type \"gaugehistogram\""
+    )]
+    fn test_parse_exposition_openmetrics_type_in_text_format() {
+        parse("# TYPE foo gaugehistogram\n", "value");
+    }
+
+    #[pg_test(
+        error = "invalid exposition format at line 3, column 1: unexpected content after # EOF"
+    )]
+    fn test_parse_exposition_content_after_eof() {
+        parse("foo 1\n# EOF\nbar 2\n", "value");
+    }
+
+    #[pg_test]
+    fn test_to_exposition() {
+        Spi::run(
+            r#"SELECT _prom_catalog.insert_metric_metadatas(
+                   ARRAY[now() - interval '1 hour', now(), now()],
+                   ARRAY['http_requests_total', 'http_requests_total', 'rpc_duration_seconds'],
+                   ARRAY['gauge', 'counter', 'histogram'],
+                   ARRAY['', '', 'seconds'],
+                   ARRAY['outdated', 'Requests "handled"' || chr(10) || 'so far.', ''])"#,
+        );
+        let result = Spi::get_one::<String>(
+            r#"SELECT prom_api.to_exposition(name, labels, value, ts)
+               FROM (VALUES
+                   ('http_requests_total', '{"__name__": "http_requests_total", "path": "C:\\dir"}'::jsonb, 3::float8, '1970-01-01 00:00:01.5+00'::timestamptz),
+                   ('rpc_duration_seconds_bucket', '{"le": "+Inf"}', 'Infinity', NULL),
+                   ('other', '{}', 'NaN', NULL),
+                   ('http_requests_total', '{"path": "a\"b"}', 0.25, NULL),
+                   ('skipped', '{}', NULL, NULL)
+               ) AS v(name, labels, value, ts)"#,
+        )
+        .expect("SQL query failed");
+        assert_eq!(
+            result,
+            r#"# HELP http_requests_total Requests "handled"\nso far.
+# TYPE http_requests_total counter
+http_requests_total{path="C:\\dir"} 3 1500
+http_requests_total{path="a\"b"} 0.25
+# TYPE rpc_duration_seconds histogram
+rpc_duration_seconds_bucket{le="+Inf"} +Inf
+other NaN
+"#
+        );
+    }
+
+    #[pg_test]
+    fn test_to_exposition_openmetrics() {
+        Spi::run(
+            r#"SELECT _prom_catalog.insert_metric_metadatas(
+                   ARRAY[now()], ARRAY['http_requests_total'], ARRAY['counter'], ARRAY[''], ARRAY['Requests.'])"#,
+        );
+        Spi::run(
+            r#"DO $$
+               DECLARE
+                   s RECORD;
+               BEGIN
+                   SELECT * INTO s FROM _prom_catalog.get_or_create_series_id_for_kv_array(
+                       'http_requests_total', ARRAY['__name__', 'code'], ARRAY['http_requests_total', '200']);
+                   PERFORM _prom_catalog.create_exemplar_table_if_not_exists('http_requests_total');
+                   PERFORM _prom_catalog.get_new_pos_for_key('http_requests_total', s.table_name, ARRAY['trace_id'], true);
+                   PERFORM _prom_catalog.insert_exemplar_row(
+                       s.table_name,
+                       ARRAY['1970-01-01 00:00:00.5+00', '1970-01-01 00:00:01+00', '1970-01-01 00:00:03+00']::timestamptz[],
+                       ARRAY[s.series_id, s.series_id, s.series_id],
+                       ARRAY['{old}', '{abc}', '{newer}']::prom_api.label_value_array[],
+                       ARRAY[0.5, 1, 2]);
+               END
+               $$"#,
+        );
+        // the sample of code="200" gets the latest exemplar not newer than
+        // itself, code="500" has none
+        let result = Spi::get_one::<String>(
+            r#"SELECT prom_api.to_openmetrics(name, labels, value, ts)
+               FROM (VALUES
+                   ('http_requests_total', '{"code": "200"}'::jsonb, 3::float8, '1970-01-01 00:00:01.5+00'::timestamptz),
+                   ('http_requests_total', '{"code": "500"}', 1, '1970-01-01 00:00:02+00')
+               ) AS v(name, labels, value, ts)"#,
+        )
+        .expect("SQL query failed");
+        assert_eq!(
+            result,
+            r#"# HELP http_requests Requests.
+# TYPE http_requests counter
+http_requests_total{code="200"} 3 1.500 # {trace_id="abc"} 1 1
+http_requests_total{code="500"} 1 2
+# EOF
+"#
+        );
+    }
+
+    #[pg_test]
+    fn test_to_exposition_no_rows() {
+        let result = Spi::get_one::<String>(
+            "SELECT prom_api.to_exposition('up', '{}', 1, now()) WHERE false",
+        );
+        assert_eq!(result, None);
+    }
+}
@@ -0,0 +1,367 @@
+//! # Jaeger JSON decoding
+//!
+//! Decodes traces in the JSON model of the Jaeger query API (the response of
+//! `/api/traces`, `{"data": [trace, ...]}`, a single trace or an array of
+//! traces) into span rows, following the OpenTelemetry collector's Jaeger
+//! translator, so that spans look the same whether they arrive as Jaeger or
+//! as OTLP:
+//!
+//! * the `span.kind` tag becomes the span kind, `unspecified` when absent,
+//! * the process' service name becomes the `service.name` resource tag, and
+//!   its tags the other resource tags,
+//! * the first `CHILD_OF` reference within the trace becomes the parent, the
+//!   other references become links with an `opentracing.ref_type` tag,
+//! * logs become events, named by their `event` field,
+//! * the `otel.status_code`, `otel.status_description`, `error`,
+//!   `otel.scope.*` and `w3c.tracestate` tags become the corresponding span
+//!   fields. Without them, an `http.status_code` tag of 5xx (or of 4xx
+//!   for spans other than server spans) sets the error status.
+//!
+//! Events and links of a span are returned as JSON arrays of objects with the
+//! columns of `_ps_trace.event` and `_ps_trace.link`.
+use pgx::*;
+
+use serde_json::{Map, Value};
+
+use crate::otlp::{micros_to_pg_timestamp, SPAN_KINDS};
+use crate::otlp_traces::{
+    apply_otel_tags, events_to_json, int_field, links_to_json, span_id_from_hex, str_field,
+    trace_id_from_hex, EventRow, LinkRow, SpanRow,
+};
+
+#[pg_schema]
+mod _prom_ext {
+    use pgx::*;
+    use serde_json::Value;
+
+    use super::{decode, events_to_json, links_to_json};
+
+    #[pg_extern(immutable, strict, parallel_safe, create_or_replace)]
+    pub fn decode_jaeger_json(
+        traces: JsonB,
+    ) -> TableIterator<
+        'static,
+        (
+            name!(trace_id, Uuid),
+            name!(span_id, i64),
+            name!(parent_span_id, Option<i64>),
+            name!(trace_state, Option<String>),
+            name!(name, String),
+            name!(span_kind, String),
+            name!(start_time, TimestampWithTimeZone),
+            name!(end_time, TimestampWithTimeZone),
+            name!(status_code, String),
+            name!(status_message, Option<String>),
+            name!(span_tags, JsonB),
+            name!(resource_tags, JsonB),
+            name!(instrumentation_lib_name, Option<String>),
+            name!(instrumentation_lib_version, Option<String>),
+            name!(events, JsonB),
+            name!(links, JsonB),
+        ),
+    > {
+        let spans = decode(&traces.0);
+        TableIterator::new(spans.into_iter().map(|(span, events, links)| {
+            (
+                Uuid::from_bytes(span.trace_id),
+                span.span_id,
+                span.parent_span_id,
+                span.trace_state,
+                span.name,
+                span.span_kind.to_string(),
+                TimestampWithTimeZone::from(span.start_time),
+                TimestampWithTimeZone::from(span.end_time),
+                span.status_code.to_string(),
+                span.status_message,
+                JsonB(Value::Object(span.span_tags)),
+                JsonB(Value::Object(span.resource_tags)),
+                span.instrumentation_lib_name,
+                span.instrumentation_lib_version,
+                JsonB(events_to_json(events)),
+                JsonB(links_to_json(links)),
+            )
+        }))
+    }
+}
+
+type Span = (SpanRow, Vec<EventRow>, Vec<LinkRow>);
+
+fn decode(traces: &Value) -> Vec<Span> {
+    to_rows(traces).unwrap_or_else(|e| error!("invalid Jaeger traces: {}", e))
+}
+
+fn to_rows(traces: &Value) -> Result<Vec<Span>, String> {
+    let traces = match traces {
+        Value::Array(traces) => traces.iter().collect(),
+        Value::Object(o) => match o.get("data") {
+            Some(Value::Array(traces)) => traces.iter().collect(),
+            Some(other) => return Err(format!("invalid data {}", other)),
+            None => vec![traces],
+        },
+        other => return Err(format!("expected a trace object, got {}", other)),
+    };
+    let mut rows = vec![];
+    for trace in traces {
+        let trace = trace
+            .as_object()
+            .ok_or_else(|| format!("expected a trace object, got {}", trace))?;
+        let processes = match trace.get("processes") {
+            None | Some(Value::Null) => None,
+            Some(Value::Object(processes)) => Some(processes),
+            Some(other) => return Err(format!("invalid processes {}", other)),
+        };
+        for span in array_field(trace, "spans")? {
+            let span = span
+                .as_object()
+                .ok_or_else(|| format!("expected a span object, got {}", span))?;
+            // the process is either embedded or referenced by id
+            let process = match (span.get("process"), str_field(span, "processID")?) {
+                (Some(process), _) => Some(process),
+                (None, Some(id)) => Some(
+                    processes
+                        .and_then(|p| p.get(id))
+                        .ok_or_else(|| format!("unknown process {:?}", id))?,
+                ),
+                (None, None) => None,
+            };
+            rows.push(to_row(span, process)?);
+        }
+    }
+    Ok(rows)
+}
+
+fn to_row(span: &Map<String, Value>, process: Option<&Value>) -> Result<Span, String> {
+    let trace_id = trace_id_from_hex(str_field(span, "traceID")?.unwrap_or_default())?;
+    let span_id = span_id_from_hex(str_field(span, "spanID")?.unwrap_or_default())?;
+    let start = int_field(span, "startTime")?.unwrap_or(0);
+    let start_time = micros_to_pg_timestamp(start);
+    let mut row = SpanRow {
+        trace_id,
+        span_id,
+        name: str_field(span, "operationName")?
+            .unwrap_or_default()
+            .to_string(),
+        span_kind: "unspecified",
+        start_time,
+        end_time: micros_to_pg_timestamp(start + int_field(span, "duration")?.unwrap_or(0)),
+        status_code: "unset",
+        span_tags: tags(array_field(span, "tags")?)?,
+        ..Default::default()
+    };
+
+    if let Some(process) = process {
+        let process = process
+            .as_object()
+            .ok_or_else(|| format!("invalid process {}", process))?;
+        row.resource_tags = tags(array_field(process, "tags")?)?;
+        if let Some(service) = str_field(process, "serviceName")? {
+            row.resource_tags.insert(
+                "service.name".to_string(),
+                Value::String(service.to_string()),
+            );
+        }
+    }
+
+    if let Some(kind) = row.span_tags.remove("span.kind") {
+        row.span_kind = kind
+            .as_str()
+            .and_then(|kind| {
+                SPAN_KINDS
+                    .iter()
+                    .copied()
+                    .find(|&k| k != "unspecified" && k == kind)
+            })
+            .ok_or_else(|| format!("invalid span kind {}", kind))?;
+    }
+    let has_status = row.span_tags.contains_key("otel.status_code")
+        || row.span_tags.get("error") == Some(&Value::Bool(true));
+    apply_otel_tags(&mut row);
+    if !has_status {
+        let http_status = row
+            .span_tags
+            .get("http.status_code")
+            .and_then(|code| match code {
+                Value::String(s) => s.parse::<i64>().ok(),
+                code => code.as_i64(),
+            });
+        let is_error = match http_status {
+            Some(100..=399) | None => false,
+            Some(400..=499) => row.span_kind != "server",
+            Some(_) => true,
+        };
+        if is_error {
+            row.status_code = "error";
+        }
+    }
+
+    let mut links = vec![];
+    for reference in array_field(span, "references")? {
+        let reference = reference
+            .as_object()
+            .ok_or_else(|| format!("invalid reference {}", reference))?;
+        let ref_type = str_field(reference, "refType")?.unwrap_or("CHILD_OF");
+        let linked_trace_id =
+            trace_id_from_hex(str_field(reference, "traceID")?.unwrap_or_default())?;
+        let linked_span_id = span_id_from_hex(str_field(reference, "spanID")?.unwrap_or_default())?;
+        if row.parent_span_id.is_none() && ref_type == "CHILD_OF" && linked_trace_id == trace_id {
+            row.parent_span_id = Some(linked_span_id);
+            continue;
+        }
+        let mut tags = Map::new();
+        tags.insert(
+            "opentracing.ref_type".to_string(),
+            Value::String(ref_type.to_ascii_lowercase()),
+        );
+        links.push(LinkRow {
+            trace_id,
+            span_id,
+            span_start_time: start_time,
+            linked_trace_id,
+            linked_span_id,
+            link_nbr: links.len() as i32,
+            trace_state: None,
+            tags,
+            dropped_tags_count: 0,
+        });
+    }
+
+    let mut events = vec![];
+    for log in array_field(span, "logs")? {
+        let log = log
+            .as_object()
+            .ok_or_else(|| format!("invalid log {}", log))?;
+        let mut tags = tags(array_field(log, "fields")?)?;
+        let name = match tags.remove("event") {
+            Some(Value::String(name)) => name,
+            Some(other) => other.to_string(),
+            None => String::new(),
+        };
+        events.push(EventRow {
+            time: micros_to_pg_timestamp(int_field(log, "timestamp")?.unwrap_or(0)),
+            trace_id,
+            span_id,
+            event_nbr: events.len() as i32,
+            name,
+            tags,
+            dropped_tags_count: 0,
+        });
+    }
+    Ok((row, events, links))
+}
+
+/// Jaeger key-values (`{"key", "type", "value"}`) as a JSON object.
+fn tags(key_values: &[Value]) -> Result<Map<String, Value>, String> {
+    key_values
+        .iter()
+        .map(|kv| {
+            let kv = kv
+                .as_object()
+                .ok_or_else(|| format!("invalid tag {}", kv))?;
+            let key = str_field(kv, "key")?.unwrap_or_default().to_string();
+            let value = kv.get("value").cloned().unwrap_or(Value::Null);
+            // 64-bit integers may be sent as strings to not lose precision
+            let value = match (str_field(kv, "type")?, value) {
+                (Some("int64"), Value::String(s)) => s
+                    .parse::<i64>()
+                    .map(Value::from)
+                    .map_err(|_| format!("invalid int64 tag {} {:?}", key, s))?,
+                (_, value) => value,
+            };
+            Ok((key, value))
+        })
+        .collect()
+}
+
+fn array_field<'a>(object: &'a Map<String, Value>, field: &str) -> Result<&'a [Value], String> {
+    match object.get(field) {
+        None | Some(Value::Null) => Ok(&[]),
+        Some(Value::Array(values)) => Ok(values),
+        Some(other) => Err(format!("invalid {} {}", field, other)),
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+
+    #[pg_test]
+    fn test_decode_jaeger_json() {
+        let spans = Spi::get_one::<Json>(
+            r#"SELECT json_agg(json_build_array(
+                trace_id::text, span_id, parent_span_id, name, span_kind,
+                (extract(epoch FROM end_time - start_time) * 1000)::bigint, status_code,
+                span_tags, resource_tags, events, links
+               ))
+               FROM ps_trace.decode_jaeger_json('{"data": [{
+                "traceID": "0102030405060708090a0b0c0d0e0f10",
+                "spans": [{
+                    "traceID": "0102030405060708090a0b0c0d0e0f10",
+                    "spanID": "0000000000000002",
+                    "operationName": "SELECT users",
+                    "references": [
+                        {"refType": "CHILD_OF", "traceID": "0102030405060708090a0b0c0d0e0f10", "spanID": "0000000000000001"},
+                        {"refType": "FOLLOWS_FROM", "traceID": "ffffffffffffffffffffffffffffffff", "spanID": "0000000000000009"}
+                    ],
+                    "startTime": 1672531200020000,
+                    "duration": 100000,
+                    "tags": [
+                        {"key": "span.kind", "type": "string", "value": "client"},
+                        {"key": "http.status_code", "type": "int64", "value": 404}
+                    ],
+                    "logs": [{
+                        "timestamp": 1672531200030000,
+                        "fields": [
+                            {"key": "event", "type": "string", "value": "retry"},
+                            {"key": "attempt", "type": "int64", "value": 2}
+                        ]
+                    }],
+                    "processID": "p1"
+                }],
+                "processes": {
+                    "p1": {"serviceName": "api", "tags": [{"key": "hostname", "type": "string", "value": "host-1"}]}
+                }
+               }]}')"#,
+        )
+        .expect("SQL query failed")
+        .0;
+        assert_eq!(
+            spans,
+            serde_json::json!([[
+                "01020304-0506-0708-090a-0b0c0d0e0f10",
+                2,
+                1,
+                "SELECT users",
+                "client",
+                100,
+                "error",
+                {"http.status_code": 404},
+                {"hostname": "host-1", "service.name": "api"},
+                [{
+                    "time": "2023-01-01T00:00:00.030000Z",
+                    "event_nbr": 0,
+                    "name": "retry",
+                    "tags": {"attempt": 2},
+                    "dropped_tags_count": 0
+                }],
+                [{
+                    "linked_trace_id": "ffffffff-ffff-ffff-ffff-ffffffffffff",
+                    "linked_span_id": 9,
+                    "link_nbr": 0,
+                    "trace_state": null,
+                    "tags": {"opentracing.ref_type": "follows_from"},
+                    "dropped_tags_count": 0
+                }]
+            ]])
+        );
+    }
+
+    #[pg_test(error = "invalid Jaeger traces: unknown process \"p2\"")]
+    fn test_decode_jaeger_json_unknown_process() {
+        Spi::run(
+            r#"SELECT * FROM ps_trace.decode_jaeger_json('{"spans": [
+                {"traceID": "01", "spanID": "01", "processID": "p2"}
+               ], "processes": {}}')"#,
+        );
+    }
+}
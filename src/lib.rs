@@ -3,19 +3,47 @@ use pgx::*;
 
 mod aggregate_utils;
 mod aggregates;
+mod chunkenc;
+mod exposition;
 mod iterable_jsonb;
+mod jaeger;
 mod jsonb_digest;
+mod line_protocol;
+mod otlp;
+mod otlp_metrics;
+mod otlp_traces;
 mod palloc;
+mod parquet;
 mod pg_imports;
+mod planner;
+mod prom_json;
+mod prompb;
+mod promql;
+mod protobuf;
 mod raw;
 mod regex;
+mod relabel;
+mod remote_read;
+mod remote_write;
+mod sample_block;
 mod schema;
+mod selectivity;
+mod snappy;
 mod support;
+mod trace_export;
+mod tsdb;
 mod type_builder;
 mod util;
+mod zipkin;
 
 pg_module_magic!();
 
+#[allow(non_snake_case)]
+#[pg_guard]
+pub extern "C" fn _PG_init() {
+    unsafe { planner::init() };
+}
+
 /// A helper function for building [`pgx::PgList`] out of
 /// iterable collection of `str`.
 ///
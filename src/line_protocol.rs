@@ -0,0 +1,542 @@
+//! # InfluxDB line protocol
+//!
+//! Parses points in the InfluxDB [line protocol][lp], as written by Telegraf
+//! and many IoT devices, into Prometheus samples:
+//!
+//! ```sql
+//! SELECT metric_name, labels, time, value
+//! FROM prom_api.parse_line_protocol(pg_read_file('sensors.lp'), 'ms');
+//! ```
+//!
+//! Every line is a point of the form
+//! `measurement[,tag=value...] field=value[,field=value...] [timestamp]`.
+//! Commas and spaces in measurements, and commas, spaces and `=` in tag keys,
+//! tag values and field keys are escaped with a backslash. Field values are
+//! floats (`1.5`), integers (`1i`), unsigned integers (`1u`), booleans (`t`,
+//! `true`, `F`, `FALSE` etc.) or double-quoted strings, in which `\"` and `\\`
+//! are escaped. Timestamps are integers in units of `precision` (`ns`, `us`,
+//! `ms` or `s`). Points without a timestamp get `default_ts`. Empty lines and
+//! lines starting with `#` are ignored.
+//!
+//! Fields are mapped to series the way Telegraf's Prometheus serializer does
+//! it:
+//!
+//! * The metric name is `<measurement>_<field>`, or just the measurement for
+//!   a field called `value`.
+//! * Tags become labels.
+//! * Metric and label names are sanitized: invalid characters are replaced
+//!   by `_`, leading and trailing underscores are trimmed, and names starting
+//!   with a digit get a `_` prefix. Fields and tags whose name ends up empty
+//!   are skipped.
+//! * Booleans are 1 or 0. String fields have no numeric value and are
+//!   skipped.
+//!
+//! The rows can be inserted the same way `prom_api.insert_remote_write` does,
+//! through `_prom_catalog.get_or_create_series_id_for_kv_array` and
+//! `_prom_catalog.insert_metric_row`.
+//!
+//! Malformed input is rejected with an error pointing at the offending line
+//! and column.
+//!
+//! [lp]: https://docs.influxdata.com/influxdb/v2/reference/syntax/line-protocol/
+use pgx::*;
+
+use serde_json::{Map, Value};
+
+use crate::exposition::ParseError;
+use crate::otlp::PG_EPOCH_OFFSET_US;
+
+#[pg_schema]
+mod _prom_ext {
+    use pgx::*;
+
+    use serde_json::Value;
+
+    use super::{parse, to_samples, Precision};
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn parse_line_protocol(
+        input: Option<&str>,
+        precision: Option<&str>,
+        default_ts: Option<TimestampWithTimeZone>,
+    ) -> TableIterator<
+        'static,
+        (
+            name!(measurement, String),
+            name!(field, String),
+            name!(metric_name, String),
+            name!(labels, JsonB),
+            name!(time, Option<TimestampWithTimeZone>),
+            name!(value, f64),
+        ),
+    > {
+        let precision = match precision.unwrap_or("ns") {
+            "ns" => Precision::Nanoseconds,
+            "us" => Precision::Microseconds,
+            "ms" => Precision::Milliseconds,
+            "s" => Precision::Seconds,
+            other => error!(
+                "invalid precision \"{}\", expected \"ns\", \"us\", \"ms\" or \"s\"",
+                other
+            ),
+        };
+        let points = match input {
+            Some(input) => {
+                parse(input, precision).unwrap_or_else(|e| error!("invalid line protocol at {}", e))
+            }
+            None => vec![],
+        };
+        let default_ts: Option<i64> = default_ts.map(|ts| ts.into());
+        TableIterator::new(to_samples(points).into_iter().map(move |sample| {
+            (
+                sample.measurement,
+                sample.field,
+                sample.metric_name,
+                JsonB(Value::Object(sample.labels)),
+                sample.time.or(default_ts).map(TimestampWithTimeZone::from),
+                sample.value,
+            )
+        }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+#[derive(Debug, PartialEq)]
+enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    Unsigned(u64),
+    Boolean(bool),
+    String(String),
+}
+
+#[derive(Debug, PartialEq)]
+struct Point {
+    measurement: String,
+    tags: Vec<(String, String)>,
+    fields: Vec<(String, FieldValue)>,
+    /// Postgres timestamp (microseconds since 2000-01-01)
+    time: Option<i64>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Sample {
+    measurement: String,
+    field: String,
+    metric_name: String,
+    labels: Map<String, Value>,
+    time: Option<i64>,
+    value: f64,
+}
+
+fn parse(input: &str, precision: Precision) -> Result<Vec<Point>, ParseError> {
+    let mut points = vec![];
+    for (idx, line) in input.lines().enumerate() {
+        let mut cursor = Cursor {
+            line,
+            line_no: idx + 1,
+            pos: 0,
+        };
+        cursor.skip_blanks();
+        if cursor.at_end() || cursor.peek() == Some('#') {
+            continue;
+        }
+        points.push(parse_point(&mut cursor, precision)?);
+    }
+    Ok(points)
+}
+
+fn parse_point(cursor: &mut Cursor, precision: Precision) -> Result<Point, ParseError> {
+    let measurement = cursor.identifier(MEASUREMENT_SPECIAL);
+    if measurement.is_empty() {
+        return Err(cursor.error("missing measurement"));
+    }
+
+    let mut tags = vec![];
+    while cursor.peek() == Some(',') {
+        cursor.pos += 1;
+        let key_start = cursor.pos;
+        let key = cursor.identifier(KEY_SPECIAL);
+        if key.is_empty() {
+            return Err(cursor.error("missing tag key"));
+        }
+        cursor.expect('=')?;
+        let value = cursor.identifier(KEY_SPECIAL);
+        if value.is_empty() {
+            return Err(cursor.error(format!("missing value of tag \"{}\"", key)));
+        }
+        if tags.iter().any(|(k, _)| *k == key) {
+            return Err(cursor.error_at(key_start, format!("duplicate tag \"{}\"", key)));
+        }
+        tags.push((key, value));
+    }
+
+    cursor.expect_blank()?;
+    let mut fields = vec![];
+    loop {
+        let key = cursor.identifier(KEY_SPECIAL);
+        if key.is_empty() {
+            return Err(cursor.error("missing field key"));
+        }
+        cursor.expect('=')?;
+        let value = cursor.field_value()?;
+        fields.push((key, value));
+        if cursor.peek() != Some(',') {
+            break;
+        }
+        cursor.pos += 1;
+    }
+
+    cursor.skip_blanks();
+    let time = if cursor.at_end() {
+        None
+    } else {
+        Some(cursor.timestamp(precision)?)
+    };
+    cursor.expect_end()?;
+
+    Ok(Point {
+        measurement,
+        tags,
+        fields,
+        time,
+    })
+}
+
+fn to_samples(points: Vec<Point>) -> Vec<Sample> {
+    let mut samples = vec![];
+    for point in points {
+        let mut labels = Map::new();
+        for (key, value) in &point.tags {
+            if let Some(name) = sanitize(key, false) {
+                labels.insert(name, Value::String(value.clone()));
+            }
+        }
+        for (field, value) in point.fields {
+            let value = match value {
+                FieldValue::Float(v) => v,
+                FieldValue::Integer(v) => v as f64,
+                FieldValue::Unsigned(v) => v as f64,
+                FieldValue::Boolean(v) => {
+                    if v {
+                        1.0
+                    } else {
+                        0.0
+                    }
+                }
+                FieldValue::String(_) => continue,
+            };
+            let name = if field == "value" {
+                point.measurement.clone()
+            } else {
+                format!("{}_{}", point.measurement, field)
+            };
+            let metric_name = match sanitize(&name, true) {
+                Some(name) => name,
+                None => continue,
+            };
+            let mut labels = labels.clone();
+            labels.insert("__name__".to_string(), Value::String(metric_name.clone()));
+            samples.push(Sample {
+                measurement: point.measurement.clone(),
+                field,
+                metric_name,
+                labels,
+                time: point.time,
+                value,
+            });
+        }
+    }
+    samples
+}
+
+/// Telegraf's sanitization of metric names (which may contain `:`) and label
+/// names. `None` if nothing is left.
+fn sanitize(name: &str, metric_name: bool) -> Option<String> {
+    let sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || (metric_name && c == ':') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let sanitized = sanitized.trim_matches('_');
+    if sanitized.is_empty() {
+        None
+    } else if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        Some(format!("_{}", sanitized))
+    } else {
+        Some(sanitized.to_string())
+    }
+}
+
+/// Characters that have to be escaped in measurements.
+const MEASUREMENT_SPECIAL: &[char] = &[',', ' '];
+
+/// Characters that have to be escaped in tag keys, tag values and field keys.
+const KEY_SPECIAL: &[char] = &[',', '=', ' '];
+
+/// Position within a single line, tracked in bytes.
+struct Cursor<'a> {
+    line: &'a str,
+    line_no: usize,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn rest(&self) -> &'a str {
+        &self.line[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.line.len()
+    }
+
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        self.error_at(self.pos, message)
+    }
+
+    /// Columns are 1-based and counted in characters.
+    fn error_at(&self, pos: usize, message: impl Into<String>) -> ParseError {
+        ParseError {
+            line: self.line_no,
+            column: self.line[..pos].chars().count() + 1,
+            message: message.into(),
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn skip_blanks(&mut self) {
+        self.take_while(|c| c == ' ');
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += c.len_utf8();
+                Ok(())
+            }
+            Some(c) => Err(self.error(format!("expected '{}', found '{}'", expected, c))),
+            None => Err(self.error(format!("expected '{}', found end of line", expected))),
+        }
+    }
+
+    /// Requires at least one space and skips all of them.
+    fn expect_blank(&mut self) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(' ') => {
+                self.skip_blanks();
+                Ok(())
+            }
+            Some(c) => Err(self.error(format!("expected whitespace, found '{}'", c))),
+            None => Err(self.error("missing fields")),
+        }
+    }
+
+    fn expect_end(&mut self) -> Result<(), ParseError> {
+        self.skip_blanks();
+        match self.peek() {
+            None => Ok(()),
+            Some(c) => Err(self.error(format!("unexpected '{}'", c))),
+        }
+    }
+
+    /// Reads up to the first unescaped `special` character. A backslash only
+    /// escapes `special` characters, any other backslash is taken literally.
+    fn identifier(&mut self, special: &[char]) -> String {
+        let mut text = String::new();
+        let mut chars = self.rest().char_indices().peekable();
+        let start = self.pos;
+        while let Some((offset, c)) = chars.next() {
+            match c {
+                '\\' => match chars.peek() {
+                    Some(&(_, next)) if special.contains(&next) => {
+                        text.push(next);
+                        chars.next();
+                    }
+                    _ => text.push('\\'),
+                },
+                c if special.contains(&c) => {
+                    self.pos = start + offset;
+                    return text;
+                }
+                c => text.push(c),
+            }
+        }
+        self.pos = self.line.len();
+        text
+    }
+
+    fn field_value(&mut self) -> Result<FieldValue, ParseError> {
+        if self.peek() == Some('"') {
+            self.pos += 1;
+            return self.string_value().map(FieldValue::String);
+        }
+        let start = self.pos;
+        let token = self.take_while(|c| c != ',' && c != ' ');
+        let value = match token {
+            "t" | "T" | "true" | "True" | "TRUE" => Some(FieldValue::Boolean(true)),
+            "f" | "F" | "false" | "False" | "FALSE" => Some(FieldValue::Boolean(false)),
+            _ if token.ends_with('i') => token[..token.len() - 1]
+                .parse()
+                .ok()
+                .map(FieldValue::Integer),
+            _ if token.ends_with('u') => token[..token.len() - 1]
+                .parse()
+                .ok()
+                .map(FieldValue::Unsigned),
+            // Rust also accepts `inf` and `NaN`, the line protocol doesn't
+            _ if token.starts_with(|c: char| c.is_ascii_digit() || "+-.".contains(c)) => token
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .map(FieldValue::Float),
+            _ => None,
+        };
+        value.ok_or_else(|| self.error_at(start, format!("invalid field value \"{}\"", token)))
+    }
+
+    /// Parses the rest of a quoted string field value, including the closing
+    /// quote.
+    fn string_value(&mut self) -> Result<String, ParseError> {
+        let mut value = String::new();
+        let mut chars = self.rest().char_indices().peekable();
+        let start = self.pos;
+        while let Some((offset, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos = start + offset + 1;
+                    return Ok(value);
+                }
+                '\\' => match chars.peek() {
+                    Some(&(_, next)) if next == '"' || next == '\\' => {
+                        value.push(next);
+                        chars.next();
+                    }
+                    _ => value.push('\\'),
+                },
+                c => value.push(c),
+            }
+        }
+        self.pos = self.line.len();
+        Err(self.error("unterminated string field value"))
+    }
+
+    /// An integer in units of `precision`. Returns a Postgres timestamp.
+    fn timestamp(&mut self, precision: Precision) -> Result<i64, ParseError> {
+        let start = self.pos;
+        let token = self.take_while(|c| c != ' ');
+        let ts = token.parse::<i64>().ok().and_then(|ts| {
+            match precision {
+                Precision::Nanoseconds => Some(ts.div_euclid(1_000)),
+                Precision::Microseconds => Some(ts),
+                Precision::Milliseconds => ts.checked_mul(1_000),
+                Precision::Seconds => ts.checked_mul(1_000_000),
+            }
+            .and_then(|us| us.checked_sub(PG_EPOCH_OFFSET_US))
+        });
+        ts.ok_or_else(|| self.error_at(start, format!("invalid timestamp \"{}\"", token)))
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+
+    /// Parses `input` and returns the rows, projected by `columns`, as a JSON array.
+    fn parse(input: &str, precision: &str, columns: &str) -> serde_json::Value {
+        Spi::get_one_with_args::<Json>(
+            &format!(
+                "SELECT json_agg(json_build_array({}))
+                 FROM prom_api.parse_line_protocol($1, $2, '2000-01-01 00:00:00+00')",
+                columns
+            ),
+            vec![
+                (PgBuiltInOids::TEXTOID.oid(), input.into_datum()),
+                (PgBuiltInOids::TEXTOID.oid(), precision.into_datum()),
+            ],
+        )
+        .expect("SQL query failed")
+        .0
+    }
+
+    #[pg_test]
+    fn test_parse_line_protocol() {
+        let input = r#"# sensors
+weather,location=us-midwest,sensor\ id=a\,b temperature=82.5,humidity=71i,ok=t 1465839830100400200
+
+cpu\ load value=0.64,note="say \"hi\" \\ \o/",count=3u,up=FALSE
+net.bytes,host=server\=1 rx=-1.5e3 -1000
+"#;
+        assert_eq!(
+            parse(
+                input,
+                "ns",
+                "measurement, field, metric_name, labels, (extract(epoch FROM time) * 1000000)::bigint, value"
+            ),
+            serde_json::json!([
+                ["weather", "temperature", "weather_temperature",
+                 {"__name__": "weather_temperature", "location": "us-midwest", "sensor_id": "a,b"},
+                 1465839830100400_i64, 82.5],
+                ["weather", "humidity", "weather_humidity",
+                 {"__name__": "weather_humidity", "location": "us-midwest", "sensor_id": "a,b"},
+                 1465839830100400_i64, 71],
+                ["weather", "ok", "weather_ok",
+                 {"__name__": "weather_ok", "location": "us-midwest", "sensor_id": "a,b"},
+                 1465839830100400_i64, 1],
+                ["cpu load", "value", "cpu_load", {"__name__": "cpu_load"}, 946684800000000_i64, 0.64],
+                ["cpu load", "count", "cpu_load_count", {"__name__": "cpu_load_count"}, 946684800000000_i64, 3],
+                ["cpu load", "up", "cpu_load_up", {"__name__": "cpu_load_up"}, 946684800000000_i64, 0],
+                ["net.bytes", "rx", "net_bytes_rx", {"__name__": "net_bytes_rx", "host": "server=1"}, -1_i64, -1500],
+            ])
+        );
+    }
+
+    #[pg_test]
+    fn test_parse_line_protocol_precision() {
+        assert_eq!(
+            parse(
+                "m v=1 1672531200\nm v=2 1672531201",
+                "s",
+                "(extract(epoch FROM time))::bigint, value"
+            ),
+            serde_json::json!([[1672531200_i64, 1], [1672531201_i64, 2]])
+        );
+    }
+
+    #[pg_test(error = "invalid line protocol at line 2, column 21: invalid field value \"1.5.0\"")]
+    fn test_parse_line_protocol_invalid_field_value() {
+        parse("m v=1\nm,host=a x=1i,value=1.5.0", "ns", "value");
+    }
+
+    #[pg_test(error = "invalid line protocol at line 1, column 6: missing fields")]
+    fn test_parse_line_protocol_missing_fields() {
+        parse("m,t=a", "ns", "value");
+    }
+
+    #[pg_test(error = "invalid precision \"h\", expected \"ns\", \"us\", \"ms\" or \"s\"")]
+    fn test_parse_line_protocol_invalid_precision() {
+        parse("m v=1", "h", "value");
+    }
+}
@@ -0,0 +1,890 @@
+//! Hand-rolled bindings for the subset of the [OpenTelemetry protocol][otlp]
+//! messages we decode: the common and resource types, metrics and traces.
+//!
+//! Deprecated fields (like `instrumentation_library_metrics`) are not
+//! supported.
+//!
+//! [otlp]: https://github.com/open-telemetry/opentelemetry-proto/tree/main/opentelemetry/proto
+
+use serde_json::{Map, Value};
+
+use crate::protobuf::Reader;
+
+/// `AnyValue`, the value of an attribute.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum AnyValue {
+    Empty,
+    String(String),
+    Bool(bool),
+    Int(i64),
+    Double(f64),
+    Array(Vec<AnyValue>),
+    KeyValueList(Vec<KeyValue>),
+    Bytes(Vec<u8>),
+}
+
+/// How deep array and key-value list values may nest. Decoding, converting
+/// and dropping values recurses once per level, so without a bound a small
+/// payload could overflow the stack.
+pub(crate) const MAX_ANY_VALUE_DEPTH: usize = 32;
+
+impl AnyValue {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        AnyValue::decode_nested(buf, 0)
+    }
+
+    fn decode_nested(buf: &[u8], depth: usize) -> Result<Self, String> {
+        if depth >= MAX_ANY_VALUE_DEPTH {
+            return Err(format!(
+                "AnyValue nested deeper than {} levels",
+                MAX_ANY_VALUE_DEPTH
+            ));
+        }
+        let mut value = AnyValue::Empty;
+        let mut reader = Reader::new(buf);
+        while let Some((field, v)) = reader.next_field()? {
+            value = match field {
+                1 => AnyValue::String(v.as_str("AnyValue.string_value")?.to_string()),
+                2 => AnyValue::Bool(v.as_bool("AnyValue.bool_value")?),
+                3 => AnyValue::Int(v.as_i64("AnyValue.int_value")?),
+                4 => AnyValue::Double(v.as_f64("AnyValue.double_value")?),
+                5 => AnyValue::Array(decode_repeated(
+                    v.as_bytes("AnyValue.array_value")?,
+                    "ArrayValue.values",
+                    |buf| AnyValue::decode_nested(buf, depth + 1),
+                )?),
+                6 => AnyValue::KeyValueList(decode_repeated(
+                    v.as_bytes("AnyValue.kvlist_value")?,
+                    "KeyValueList.values",
+                    |buf| KeyValue::decode_nested(buf, depth + 1),
+                )?),
+                7 => AnyValue::Bytes(v.as_bytes("AnyValue.bytes_value")?.to_vec()),
+                _ => continue,
+            };
+        }
+        Ok(value)
+    }
+
+    /// The value as JSON. Bytes are base64 encoded, as in the OTLP JSON encoding.
+    pub fn to_json(&self) -> Value {
+        match self {
+            AnyValue::Empty => Value::Null,
+            AnyValue::String(s) => Value::String(s.clone()),
+            AnyValue::Bool(b) => Value::Bool(*b),
+            AnyValue::Int(i) => Value::from(*i),
+            AnyValue::Double(d) => Value::from(*d),
+            AnyValue::Array(values) => Value::Array(values.iter().map(AnyValue::to_json).collect()),
+            AnyValue::KeyValueList(kvs) => Value::Object(attributes_to_json(kvs)),
+            AnyValue::Bytes(b) => Value::String(base64_encode(b)),
+        }
+    }
+
+    /// The value as a label value: strings as they are, anything else as JSON.
+    pub fn to_label_value(&self) -> String {
+        match self {
+            AnyValue::Empty => String::new(),
+            AnyValue::String(s) => s.clone(),
+            AnyValue::Bytes(b) => base64_encode(b),
+            other => other.to_json().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct KeyValue {
+    pub key: String,
+    pub value: AnyValue,
+}
+
+impl KeyValue {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        KeyValue::decode_nested(buf, 0)
+    }
+
+    fn decode_nested(buf: &[u8], depth: usize) -> Result<Self, String> {
+        let mut kv = KeyValue {
+            key: String::new(),
+            value: AnyValue::Empty,
+        };
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => kv.key = value.as_str("KeyValue.key")?.to_string(),
+                2 => kv.value = AnyValue::decode_nested(value.as_bytes("KeyValue.value")?, depth)?,
+                _ => {}
+            }
+        }
+        Ok(kv)
+    }
+}
+
+pub(crate) fn attributes_to_json(attributes: &[KeyValue]) -> Map<String, Value> {
+    attributes
+        .iter()
+        .map(|kv| (kv.key.clone(), kv.value.to_json()))
+        .collect()
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Resource {
+    pub attributes: Vec<KeyValue>,
+    pub dropped_attributes_count: u32,
+}
+
+impl Resource {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut resource = Resource::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => resource
+                    .attributes
+                    .push(KeyValue::decode(value.as_bytes("Resource.attributes")?)?),
+                2 => {
+                    resource.dropped_attributes_count =
+                        value.as_u64("Resource.dropped_attributes_count")? as u32
+                }
+                _ => {}
+            }
+        }
+        Ok(resource)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct InstrumentationScope {
+    pub name: String,
+    pub version: String,
+    pub attributes: Vec<KeyValue>,
+}
+
+impl InstrumentationScope {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut scope = InstrumentationScope::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => scope.name = value.as_str("InstrumentationScope.name")?.to_string(),
+                2 => scope.version = value.as_str("InstrumentationScope.version")?.to_string(),
+                3 => scope.attributes.push(KeyValue::decode(
+                    value.as_bytes("InstrumentationScope.attributes")?,
+                )?),
+                _ => {}
+            }
+        }
+        Ok(scope)
+    }
+}
+
+/// The body of an OTLP/HTTP or OTLP/gRPC metrics export.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ExportMetricsServiceRequest {
+    pub resource_metrics: Vec<ResourceMetrics>,
+}
+
+impl ExportMetricsServiceRequest {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        Ok(ExportMetricsServiceRequest {
+            resource_metrics: decode_repeated(
+                buf,
+                "ExportMetricsServiceRequest.resource_metrics",
+                ResourceMetrics::decode,
+            )?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ResourceMetrics {
+    pub resource: Resource,
+    pub scope_metrics: Vec<ScopeMetrics>,
+}
+
+impl ResourceMetrics {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut resource_metrics = ResourceMetrics::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => {
+                    resource_metrics.resource =
+                        Resource::decode(value.as_bytes("ResourceMetrics.resource")?)?
+                }
+                2 => resource_metrics.scope_metrics.push(ScopeMetrics::decode(
+                    value.as_bytes("ResourceMetrics.scope_metrics")?,
+                )?),
+                _ => {}
+            }
+        }
+        Ok(resource_metrics)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ScopeMetrics {
+    pub scope: InstrumentationScope,
+    pub metrics: Vec<Metric>,
+}
+
+impl ScopeMetrics {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut scope_metrics = ScopeMetrics::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => {
+                    scope_metrics.scope =
+                        InstrumentationScope::decode(value.as_bytes("ScopeMetrics.scope")?)?
+                }
+                2 => scope_metrics
+                    .metrics
+                    .push(Metric::decode(value.as_bytes("ScopeMetrics.metrics")?)?),
+                _ => {}
+            }
+        }
+        Ok(scope_metrics)
+    }
+}
+
+pub(crate) const TEMPORALITY_DELTA: i32 = 1;
+pub(crate) const TEMPORALITY_CUMULATIVE: i32 = 2;
+
+/// `DataPointFlags.FLAG_NO_RECORDED_VALUE`
+pub(crate) const FLAG_NO_RECORDED_VALUE: u32 = 1;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Metric {
+    pub name: String,
+    pub description: String,
+    pub unit: String,
+    /// `None` for metrics without data, e.g. of an unsupported kind.
+    pub data: Option<MetricData>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum MetricData {
+    Gauge(Vec<NumberDataPoint>),
+    Sum {
+        data_points: Vec<NumberDataPoint>,
+        temporality: i32,
+        is_monotonic: bool,
+    },
+    Histogram {
+        data_points: Vec<HistogramDataPoint>,
+        temporality: i32,
+    },
+    ExponentialHistogram {
+        data_points: Vec<ExponentialHistogramDataPoint>,
+        temporality: i32,
+    },
+    Summary(Vec<SummaryDataPoint>),
+}
+
+impl Metric {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut metric = Metric::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => metric.name = value.as_str("Metric.name")?.to_string(),
+                2 => metric.description = value.as_str("Metric.description")?.to_string(),
+                3 => metric.unit = value.as_str("Metric.unit")?.to_string(),
+                5 => metric.data = Some(decode_gauge(value.as_bytes("Metric.gauge")?)?),
+                7 => metric.data = Some(decode_sum(value.as_bytes("Metric.sum")?)?),
+                9 => metric.data = Some(decode_histogram(value.as_bytes("Metric.histogram")?)?),
+                10 => {
+                    metric.data = Some(decode_exponential_histogram(
+                        value.as_bytes("Metric.exponential_histogram")?,
+                    )?)
+                }
+                11 => metric.data = Some(decode_summary(value.as_bytes("Metric.summary")?)?),
+                _ => {}
+            }
+        }
+        Ok(metric)
+    }
+}
+
+fn decode_gauge(buf: &[u8]) -> Result<MetricData, String> {
+    Ok(MetricData::Gauge(decode_repeated(
+        buf,
+        "Gauge.data_points",
+        NumberDataPoint::decode,
+    )?))
+}
+
+fn decode_sum(buf: &[u8]) -> Result<MetricData, String> {
+    let mut data_points = vec![];
+    let mut temporality = 0;
+    let mut is_monotonic = false;
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = reader.next_field()? {
+        match field {
+            1 => data_points.push(NumberDataPoint::decode(value.as_bytes("Sum.data_points")?)?),
+            2 => temporality = value.as_i64("Sum.aggregation_temporality")? as i32,
+            3 => is_monotonic = value.as_bool("Sum.is_monotonic")?,
+            _ => {}
+        }
+    }
+    Ok(MetricData::Sum {
+        data_points,
+        temporality,
+        is_monotonic,
+    })
+}
+
+fn decode_histogram(buf: &[u8]) -> Result<MetricData, String> {
+    let mut data_points = vec![];
+    let mut temporality = 0;
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = reader.next_field()? {
+        match field {
+            1 => data_points.push(HistogramDataPoint::decode(
+                value.as_bytes("Histogram.data_points")?,
+            )?),
+            2 => temporality = value.as_i64("Histogram.aggregation_temporality")? as i32,
+            _ => {}
+        }
+    }
+    Ok(MetricData::Histogram {
+        data_points,
+        temporality,
+    })
+}
+
+fn decode_exponential_histogram(buf: &[u8]) -> Result<MetricData, String> {
+    let mut data_points = vec![];
+    let mut temporality = 0;
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = reader.next_field()? {
+        match field {
+            1 => data_points.push(ExponentialHistogramDataPoint::decode(
+                value.as_bytes("ExponentialHistogram.data_points")?,
+            )?),
+            2 => temporality = value.as_i64("ExponentialHistogram.aggregation_temporality")? as i32,
+            _ => {}
+        }
+    }
+    Ok(MetricData::ExponentialHistogram {
+        data_points,
+        temporality,
+    })
+}
+
+fn decode_summary(buf: &[u8]) -> Result<MetricData, String> {
+    Ok(MetricData::Summary(decode_repeated(
+        buf,
+        "Summary.data_points",
+        SummaryDataPoint::decode,
+    )?))
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct NumberDataPoint {
+    pub attributes: Vec<KeyValue>,
+    pub time_unix_nano: u64,
+    /// `as_double`, or `as_int` converted.
+    pub value: f64,
+    pub exemplars: Vec<Exemplar>,
+    pub flags: u32,
+}
+
+impl NumberDataPoint {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut point = NumberDataPoint::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                3 => point.time_unix_nano = value.as_fixed64("NumberDataPoint.time_unix_nano")?,
+                4 => point.value = value.as_f64("NumberDataPoint.as_double")?,
+                5 => point.exemplars.push(Exemplar::decode(
+                    value.as_bytes("NumberDataPoint.exemplars")?,
+                )?),
+                6 => point.value = value.as_fixed64("NumberDataPoint.as_int")? as i64 as f64,
+                7 => point.attributes.push(KeyValue::decode(
+                    value.as_bytes("NumberDataPoint.attributes")?,
+                )?),
+                8 => point.flags = value.as_u64("NumberDataPoint.flags")? as u32,
+                _ => {}
+            }
+        }
+        Ok(point)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct HistogramDataPoint {
+    pub attributes: Vec<KeyValue>,
+    pub time_unix_nano: u64,
+    pub count: u64,
+    pub sum: Option<f64>,
+    /// Per-bucket (not cumulative) counts, one more than `explicit_bounds`.
+    pub bucket_counts: Vec<u64>,
+    pub explicit_bounds: Vec<f64>,
+    pub exemplars: Vec<Exemplar>,
+    pub flags: u32,
+}
+
+impl HistogramDataPoint {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut point = HistogramDataPoint::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                3 => {
+                    point.time_unix_nano = value.as_fixed64("HistogramDataPoint.time_unix_nano")?
+                }
+                4 => point.count = value.as_fixed64("HistogramDataPoint.count")?,
+                5 => point.sum = Some(value.as_f64("HistogramDataPoint.sum")?),
+                6 => point
+                    .bucket_counts
+                    .extend(value.as_packed_fixed64("HistogramDataPoint.bucket_counts")?),
+                7 => point.explicit_bounds.extend(
+                    value
+                        .as_packed_fixed64("HistogramDataPoint.explicit_bounds")?
+                        .into_iter()
+                        .map(f64::from_bits),
+                ),
+                8 => point.exemplars.push(Exemplar::decode(
+                    value.as_bytes("HistogramDataPoint.exemplars")?,
+                )?),
+                9 => point.attributes.push(KeyValue::decode(
+                    value.as_bytes("HistogramDataPoint.attributes")?,
+                )?),
+                10 => point.flags = value.as_u64("HistogramDataPoint.flags")? as u32,
+                _ => {}
+            }
+        }
+        Ok(point)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ExponentialHistogramDataPoint {
+    pub attributes: Vec<KeyValue>,
+    pub time_unix_nano: u64,
+    pub count: u64,
+    pub sum: Option<f64>,
+    pub scale: i32,
+    pub zero_count: u64,
+    pub zero_threshold: f64,
+    pub positive: Buckets,
+    pub negative: Buckets,
+    pub exemplars: Vec<Exemplar>,
+    pub flags: u32,
+}
+
+impl ExponentialHistogramDataPoint {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut point = ExponentialHistogramDataPoint::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => point.attributes.push(KeyValue::decode(
+                    value.as_bytes("ExponentialHistogramDataPoint.attributes")?,
+                )?),
+                3 => {
+                    point.time_unix_nano =
+                        value.as_fixed64("ExponentialHistogramDataPoint.time_unix_nano")?
+                }
+                4 => point.count = value.as_fixed64("ExponentialHistogramDataPoint.count")?,
+                5 => point.sum = Some(value.as_f64("ExponentialHistogramDataPoint.sum")?),
+                6 => point.scale = value.as_sint64("ExponentialHistogramDataPoint.scale")? as i32,
+                7 => {
+                    point.zero_count =
+                        value.as_fixed64("ExponentialHistogramDataPoint.zero_count")?
+                }
+                8 => {
+                    point.positive =
+                        Buckets::decode(value.as_bytes("ExponentialHistogramDataPoint.positive")?)?
+                }
+                9 => {
+                    point.negative =
+                        Buckets::decode(value.as_bytes("ExponentialHistogramDataPoint.negative")?)?
+                }
+                10 => point.flags = value.as_u64("ExponentialHistogramDataPoint.flags")? as u32,
+                11 => point.exemplars.push(Exemplar::decode(
+                    value.as_bytes("ExponentialHistogramDataPoint.exemplars")?,
+                )?),
+                14 => {
+                    point.zero_threshold =
+                        value.as_f64("ExponentialHistogramDataPoint.zero_threshold")?
+                }
+                _ => {}
+            }
+        }
+        Ok(point)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Buckets {
+    pub offset: i32,
+    pub bucket_counts: Vec<u64>,
+}
+
+impl Buckets {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut buckets = Buckets::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => buckets.offset = value.as_sint64("Buckets.offset")? as i32,
+                2 => buckets
+                    .bucket_counts
+                    .extend(value.as_packed_varint("Buckets.bucket_counts")?),
+                _ => {}
+            }
+        }
+        Ok(buckets)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct SummaryDataPoint {
+    pub attributes: Vec<KeyValue>,
+    pub time_unix_nano: u64,
+    pub count: u64,
+    pub sum: f64,
+    /// `(quantile, value)` pairs.
+    pub quantile_values: Vec<(f64, f64)>,
+    pub flags: u32,
+}
+
+impl SummaryDataPoint {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut point = SummaryDataPoint::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                3 => point.time_unix_nano = value.as_fixed64("SummaryDataPoint.time_unix_nano")?,
+                4 => point.count = value.as_fixed64("SummaryDataPoint.count")?,
+                5 => point.sum = value.as_f64("SummaryDataPoint.sum")?,
+                6 => {
+                    let mut quantile = (0.0, 0.0);
+                    let mut reader =
+                        Reader::new(value.as_bytes("SummaryDataPoint.quantile_values")?);
+                    while let Some((field, value)) = reader.next_field()? {
+                        match field {
+                            1 => quantile.0 = value.as_f64("ValueAtQuantile.quantile")?,
+                            2 => quantile.1 = value.as_f64("ValueAtQuantile.value")?,
+                            _ => {}
+                        }
+                    }
+                    point.quantile_values.push(quantile);
+                }
+                7 => point.attributes.push(KeyValue::decode(
+                    value.as_bytes("SummaryDataPoint.attributes")?,
+                )?),
+                8 => point.flags = value.as_u64("SummaryDataPoint.flags")? as u32,
+                _ => {}
+            }
+        }
+        Ok(point)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Exemplar {
+    pub filtered_attributes: Vec<KeyValue>,
+    pub time_unix_nano: u64,
+    pub value: f64,
+    pub span_id: Vec<u8>,
+    pub trace_id: Vec<u8>,
+}
+
+impl Exemplar {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut exemplar = Exemplar::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                2 => exemplar.time_unix_nano = value.as_fixed64("Exemplar.time_unix_nano")?,
+                3 => exemplar.value = value.as_f64("Exemplar.as_double")?,
+                4 => exemplar.span_id = value.as_bytes("Exemplar.span_id")?.to_vec(),
+                5 => exemplar.trace_id = value.as_bytes("Exemplar.trace_id")?.to_vec(),
+                6 => exemplar.value = value.as_fixed64("Exemplar.as_int")? as i64 as f64,
+                7 => exemplar.filtered_attributes.push(KeyValue::decode(
+                    value.as_bytes("Exemplar.filtered_attributes")?,
+                )?),
+                _ => {}
+            }
+        }
+        Ok(exemplar)
+    }
+}
+
+/// The body of an OTLP/HTTP or OTLP/gRPC trace export.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ExportTraceServiceRequest {
+    pub resource_spans: Vec<ResourceSpans>,
+}
+
+impl ExportTraceServiceRequest {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        Ok(ExportTraceServiceRequest {
+            resource_spans: decode_repeated(
+                buf,
+                "ExportTraceServiceRequest.resource_spans",
+                ResourceSpans::decode,
+            )?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ResourceSpans {
+    pub resource: Resource,
+    pub scope_spans: Vec<ScopeSpans>,
+    pub schema_url: String,
+}
+
+impl ResourceSpans {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut resource_spans = ResourceSpans::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => {
+                    resource_spans.resource =
+                        Resource::decode(value.as_bytes("ResourceSpans.resource")?)?
+                }
+                2 => resource_spans.scope_spans.push(ScopeSpans::decode(
+                    value.as_bytes("ResourceSpans.scope_spans")?,
+                )?),
+                3 => {
+                    resource_spans.schema_url =
+                        value.as_str("ResourceSpans.schema_url")?.to_string()
+                }
+                _ => {}
+            }
+        }
+        Ok(resource_spans)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ScopeSpans {
+    pub scope: InstrumentationScope,
+    pub spans: Vec<Span>,
+    pub schema_url: String,
+}
+
+impl ScopeSpans {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut scope_spans = ScopeSpans::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => {
+                    scope_spans.scope =
+                        InstrumentationScope::decode(value.as_bytes("ScopeSpans.scope")?)?
+                }
+                2 => scope_spans
+                    .spans
+                    .push(Span::decode(value.as_bytes("ScopeSpans.spans")?)?),
+                3 => scope_spans.schema_url = value.as_str("ScopeSpans.schema_url")?.to_string(),
+                _ => {}
+            }
+        }
+        Ok(scope_spans)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Span {
+    pub trace_id: Vec<u8>,
+    pub span_id: Vec<u8>,
+    pub trace_state: String,
+    /// Empty for root spans.
+    pub parent_span_id: Vec<u8>,
+    pub name: String,
+    pub kind: i32,
+    pub start_time_unix_nano: u64,
+    pub end_time_unix_nano: u64,
+    pub attributes: Vec<KeyValue>,
+    pub dropped_attributes_count: u32,
+    pub events: Vec<Event>,
+    pub dropped_events_count: u32,
+    pub links: Vec<Link>,
+    pub dropped_links_count: u32,
+    pub status_message: String,
+    pub status_code: i32,
+}
+
+impl Span {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut span = Span::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => span.trace_id = value.as_bytes("Span.trace_id")?.to_vec(),
+                2 => span.span_id = value.as_bytes("Span.span_id")?.to_vec(),
+                3 => span.trace_state = value.as_str("Span.trace_state")?.to_string(),
+                4 => span.parent_span_id = value.as_bytes("Span.parent_span_id")?.to_vec(),
+                5 => span.name = value.as_str("Span.name")?.to_string(),
+                6 => span.kind = value.as_i64("Span.kind")? as i32,
+                7 => span.start_time_unix_nano = value.as_fixed64("Span.start_time_unix_nano")?,
+                8 => span.end_time_unix_nano = value.as_fixed64("Span.end_time_unix_nano")?,
+                9 => span
+                    .attributes
+                    .push(KeyValue::decode(value.as_bytes("Span.attributes")?)?),
+                10 => {
+                    span.dropped_attributes_count =
+                        value.as_u64("Span.dropped_attributes_count")? as u32
+                }
+                11 => span
+                    .events
+                    .push(Event::decode(value.as_bytes("Span.events")?)?),
+                12 => span.dropped_events_count = value.as_u64("Span.dropped_events_count")? as u32,
+                13 => span
+                    .links
+                    .push(Link::decode(value.as_bytes("Span.links")?)?),
+                14 => span.dropped_links_count = value.as_u64("Span.dropped_links_count")? as u32,
+                15 => {
+                    let mut reader = Reader::new(value.as_bytes("Span.status")?);
+                    while let Some((field, value)) = reader.next_field()? {
+                        match field {
+                            2 => span.status_message = value.as_str("Status.message")?.to_string(),
+                            3 => span.status_code = value.as_i64("Status.code")? as i32,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(span)
+    }
+}
+
+/// `Span.SpanKind` values, named like the `ps_trace.span_kind` enum.
+pub(crate) const SPAN_KINDS: &[&str] = &[
+    "unspecified",
+    "internal",
+    "server",
+    "client",
+    "producer",
+    "consumer",
+];
+
+/// `Status.StatusCode` values, named like the `ps_trace.status_code` enum.
+pub(crate) const STATUS_CODES: &[&str] = &["unset", "ok", "error"];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Event {
+    pub time_unix_nano: u64,
+    pub name: String,
+    pub attributes: Vec<KeyValue>,
+    pub dropped_attributes_count: u32,
+}
+
+impl Event {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut event = Event::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => event.time_unix_nano = value.as_fixed64("Event.time_unix_nano")?,
+                2 => event.name = value.as_str("Event.name")?.to_string(),
+                3 => event
+                    .attributes
+                    .push(KeyValue::decode(value.as_bytes("Event.attributes")?)?),
+                4 => {
+                    event.dropped_attributes_count =
+                        value.as_u64("Event.dropped_attributes_count")? as u32
+                }
+                _ => {}
+            }
+        }
+        Ok(event)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Link {
+    pub trace_id: Vec<u8>,
+    pub span_id: Vec<u8>,
+    pub trace_state: String,
+    pub attributes: Vec<KeyValue>,
+    pub dropped_attributes_count: u32,
+}
+
+impl Link {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut link = Link::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => link.trace_id = value.as_bytes("Link.trace_id")?.to_vec(),
+                2 => link.span_id = value.as_bytes("Link.span_id")?.to_vec(),
+                3 => link.trace_state = value.as_str("Link.trace_state")?.to_string(),
+                4 => link
+                    .attributes
+                    .push(KeyValue::decode(value.as_bytes("Link.attributes")?)?),
+                5 => {
+                    link.dropped_attributes_count =
+                        value.as_u64("Link.dropped_attributes_count")? as u32
+                }
+                _ => {}
+            }
+        }
+        Ok(link)
+    }
+}
+
+/// Decodes a message consisting of a single repeated message field 1, like
+/// `ArrayValue` or `Gauge`, skipping any other field.
+fn decode_repeated<T>(
+    buf: &[u8],
+    field_name: &str,
+    decode: impl Fn(&[u8]) -> Result<T, String>,
+) -> Result<Vec<T>, String> {
+    let mut items = vec![];
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = reader.next_field()? {
+        if field == 1 {
+            items.push(decode(value.as_bytes(field_name)?)?);
+        }
+    }
+    Ok(items)
+}
+
+/// Lowercase hex, the representation of trace and span ids.
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Microseconds between the Unix epoch and the Postgres epoch (2000-01-01).
+pub(crate) const PG_EPOCH_OFFSET_US: i64 = 946_684_800_000_000;
+
+/// Converts an OTLP timestamp (nanoseconds since the Unix epoch) into a
+/// Postgres `TimestampTz`, truncating to microsecond precision.
+pub(crate) fn nanos_to_pg_timestamp(unix_nano: u64) -> i64 {
+    (unix_nano / 1000) as i64 - PG_EPOCH_OFFSET_US
+}
+
+/// Converts a Zipkin or Jaeger timestamp (microseconds since the Unix epoch)
+/// into a Postgres `TimestampTz`.
+pub(crate) fn micros_to_pg_timestamp(unix_us: i64) -> i64 {
+    unix_us - PG_EPOCH_OFFSET_US
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,764 @@
+//! # OTLP metrics decoding
+//!
+//! Decodes an OpenTelemetry metrics export (a protobuf encoded
+//! `ExportMetricsServiceRequest`, as sent to `/v1/metrics`) into the same rows
+//! as `prom_api.decode_remote_write`:
+//!
+//! ```sql
+//! SELECT kind, metric_name, labels, time, value
+//! FROM prom_api.decode_otlp_metrics(pg_read_binary_file('batch.pb'));
+//! ```
+//!
+//! Metrics are translated following the OpenTelemetry
+//! [Prometheus compatibility specification][compat]:
+//!
+//! * Metric names are sanitized and suffixed with their unit, e.g. `By`
+//!   becomes `_bytes`, and monotonic cumulative sums additionally get `_total`.
+//! * Data point attributes become labels with invalid characters replaced by
+//!   `_`. Attributes that end up with the same label name have their values
+//!   joined with `;`.
+//! * The `service.name` (prefixed by `service.namespace/`) and
+//!   `service.instance.id` resource attributes become the `job` and `instance`
+//!   labels. The other resource attributes are attached to a `target_info`
+//!   series, with a single sample at the latest timestamp of the resource.
+//! * The instrumentation scope becomes the `otel_scope_name` and
+//!   `otel_scope_version` labels, scope attributes `otel_scope_<attribute>`.
+//! * Gauges and non-monotonic sums are gauges, monotonic cumulative sums are
+//!   counters, and summaries are summaries.
+//! * Cumulative histograms become classic histograms with `_bucket`, `_count`
+//!   and `_sum` series. Exponential histograms are converted to classic
+//!   histograms with one `le` bucket per exponential bucket, including the
+//!   negative and zero buckets.
+//! * Delta temporality can't be converted to cumulative without keeping state
+//!   across requests. Delta sums are therefore gauges of the per-interval
+//!   increase, and delta histograms are gauge histograms with `_bucket`,
+//!   `_gcount` and `_gsum` series. Metrics with unspecified temporality are
+//!   skipped.
+//! * Data points flagged as having no recorded value yield Prometheus'
+//!   staleness marker.
+//! * Exemplars get `trace_id` and `span_id` labels, and are attached to the
+//!   bucket their value falls into.
+//!
+//! Every metric additionally yields a `metadata` row. As the rows match those
+//! of `prom_api.decode_remote_write`, they can be inserted the same way
+//! `prom_api.insert_remote_write` does, through
+//! `_prom_catalog.get_or_create_series_id_for_kv_array` and
+//! `_prom_catalog.insert_metric_row`.
+//!
+//! [compat]: https://github.com/open-telemetry/opentelemetry-specification/blob/main/specification/compatibility/prometheus_and_openmetrics.md
+use pgx::*;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::otlp::{
+    hex_encode, ExponentialHistogramDataPoint, ExportMetricsServiceRequest, InstrumentationScope,
+    KeyValue, Metric, MetricData, Resource, FLAG_NO_RECORDED_VALUE, TEMPORALITY_CUMULATIVE,
+    TEMPORALITY_DELTA,
+};
+use crate::prompb::{Exemplar, Label, MetricMetadata, Sample, TimeSeries, WriteRequest};
+
+#[pg_schema]
+mod _prom_ext {
+    use pgx::*;
+
+    use super::to_write_request;
+    use crate::otlp::ExportMetricsServiceRequest;
+    use crate::remote_write::decode_rows;
+
+    #[pg_extern(immutable, strict, parallel_safe, create_or_replace)]
+    pub fn decode_otlp_metrics(
+        payload: &[u8],
+    ) -> TableIterator<
+        'static,
+        (
+            name!(kind, String),
+            name!(metric_name, Option<String>),
+            name!(labels, Option<JsonB>),
+            name!(time, Option<TimestampWithTimeZone>),
+            name!(value, Option<f64>),
+            name!(exemplar_labels, Option<JsonB>),
+            name!(metric_type, Option<String>),
+            name!(unit, Option<String>),
+            name!(help, Option<String>),
+        ),
+    > {
+        let request = ExportMetricsServiceRequest::decode(payload)
+            .unwrap_or_else(|e| error!("invalid OTLP metrics payload: {}", e));
+        let rows = decode_rows(to_write_request(request))
+            .unwrap_or_else(|e| error!("invalid OTLP metrics payload: {}", e));
+        TableIterator::new(rows.into_iter())
+    }
+}
+
+// `MetricMetadata.metric_type` values
+const COUNTER: i32 = 1;
+const GAUGE: i32 = 2;
+const HISTOGRAM: i32 = 3;
+const GAUGE_HISTOGRAM: i32 = 4;
+const SUMMARY: i32 = 5;
+
+/// The value Prometheus uses to mark a series as stale.
+const STALE_NAN: u64 = 0x7ff0_0000_0000_0002;
+
+type Labels = BTreeMap<String, String>;
+
+fn to_write_request(request: ExportMetricsServiceRequest) -> WriteRequest {
+    let mut translator = Translator::default();
+    for resource_metrics in &request.resource_metrics {
+        let (resource_labels, target_info_labels) = resource_labels(&resource_metrics.resource);
+        let mut latest_timestamp = None;
+        for scope_metrics in &resource_metrics.scope_metrics {
+            let mut common_labels = scope_labels(&scope_metrics.scope);
+            common_labels.extend(resource_labels.clone());
+            for metric in &scope_metrics.metrics {
+                if let Some(t) = translator.add_metric(metric, &common_labels) {
+                    latest_timestamp = latest_timestamp.max(Some(t));
+                }
+            }
+        }
+        if let Some(t) = latest_timestamp.filter(|_| !target_info_labels.is_empty()) {
+            let mut labels = target_info_labels;
+            labels.extend(resource_labels);
+            labels.insert("__name__".to_string(), "target_info".to_string());
+            translator.add_sample(labels, t, 1.0);
+            translator.add_metadata("target_info", GAUGE, "", "Target metadata");
+        }
+    }
+    translator.request
+}
+
+#[derive(Default)]
+struct Translator {
+    request: WriteRequest,
+    /// The position of every series in `request.timeseries`.
+    series: HashMap<Labels, usize>,
+    metadata: HashSet<String>,
+}
+
+impl Translator {
+    /// Adds the data points of `metric`, returning their latest timestamp.
+    fn add_metric(&mut self, metric: &Metric, common_labels: &Labels) -> Option<i64> {
+        let data = metric.data.as_ref()?;
+        let mut latest_timestamp = None;
+        let point_labels = |attributes: &[KeyValue]| {
+            let mut labels = attribute_labels(attributes, "");
+            labels.extend(common_labels.clone());
+            labels
+        };
+        match data {
+            MetricData::Gauge(points) => {
+                let name = metric_name(metric, false);
+                for point in points {
+                    let t = to_millis(point.time_unix_nano);
+                    latest_timestamp = latest_timestamp.max(Some(t));
+                    let labels = with_name(point_labels(&point.attributes), &name);
+                    let value = value_or_stale(point.value, point.flags);
+                    self.add_sample(labels.clone(), t, value);
+                    point
+                        .exemplars
+                        .iter()
+                        .for_each(|e| self.add_exemplar(&labels, e));
+                }
+                self.add_metadata(
+                    &name,
+                    GAUGE,
+                    &prometheus_unit(metric, false),
+                    &metric.description,
+                );
+            }
+            MetricData::Sum {
+                data_points,
+                temporality,
+                is_monotonic,
+            } => {
+                if *temporality != TEMPORALITY_CUMULATIVE && *temporality != TEMPORALITY_DELTA {
+                    return None;
+                }
+                let counter = *is_monotonic && *temporality == TEMPORALITY_CUMULATIVE;
+                let name = metric_name(metric, counter);
+                for point in data_points {
+                    let t = to_millis(point.time_unix_nano);
+                    latest_timestamp = latest_timestamp.max(Some(t));
+                    let labels = with_name(point_labels(&point.attributes), &name);
+                    let value = value_or_stale(point.value, point.flags);
+                    self.add_sample(labels.clone(), t, value);
+                    point
+                        .exemplars
+                        .iter()
+                        .for_each(|e| self.add_exemplar(&labels, e));
+                }
+                let metric_type = if counter { COUNTER } else { GAUGE };
+                self.add_metadata(
+                    &name,
+                    metric_type,
+                    &prometheus_unit(metric, counter),
+                    &metric.description,
+                );
+            }
+            MetricData::Histogram {
+                data_points,
+                temporality,
+            } => {
+                if *temporality != TEMPORALITY_CUMULATIVE && *temporality != TEMPORALITY_DELTA {
+                    return None;
+                }
+                let delta = *temporality == TEMPORALITY_DELTA;
+                let name = metric_name(metric, false);
+                for point in data_points {
+                    let t = to_millis(point.time_unix_nano);
+                    latest_timestamp = latest_timestamp.max(Some(t));
+                    let mut cumulative = 0;
+                    let buckets = point
+                        .explicit_bounds
+                        .iter()
+                        .zip(&point.bucket_counts)
+                        .map(|(&bound, &count)| {
+                            cumulative += count;
+                            (bound, cumulative)
+                        })
+                        .collect();
+                    self.add_histogram(HistogramPoint {
+                        name: &name,
+                        labels: point_labels(&point.attributes),
+                        timestamp: t,
+                        stale: point.flags & FLAG_NO_RECORDED_VALUE != 0,
+                        buckets,
+                        count: point.count,
+                        sum: point.sum,
+                        exemplars: &point.exemplars,
+                        delta,
+                    });
+                }
+                let metric_type = if delta { GAUGE_HISTOGRAM } else { HISTOGRAM };
+                self.add_metadata(
+                    &name,
+                    metric_type,
+                    &prometheus_unit(metric, false),
+                    &metric.description,
+                );
+            }
+            MetricData::ExponentialHistogram {
+                data_points,
+                temporality,
+            } => {
+                if *temporality != TEMPORALITY_CUMULATIVE && *temporality != TEMPORALITY_DELTA {
+                    return None;
+                }
+                let delta = *temporality == TEMPORALITY_DELTA;
+                let name = metric_name(metric, false);
+                for point in data_points {
+                    let t = to_millis(point.time_unix_nano);
+                    latest_timestamp = latest_timestamp.max(Some(t));
+                    self.add_histogram(HistogramPoint {
+                        name: &name,
+                        labels: point_labels(&point.attributes),
+                        timestamp: t,
+                        stale: point.flags & FLAG_NO_RECORDED_VALUE != 0,
+                        buckets: exponential_buckets(point),
+                        count: point.count,
+                        sum: point.sum,
+                        exemplars: &point.exemplars,
+                        delta,
+                    });
+                }
+                let metric_type = if delta { GAUGE_HISTOGRAM } else { HISTOGRAM };
+                self.add_metadata(
+                    &name,
+                    metric_type,
+                    &prometheus_unit(metric, false),
+                    &metric.description,
+                );
+            }
+            MetricData::Summary(points) => {
+                let name = metric_name(metric, false);
+                for point in points {
+                    let t = to_millis(point.time_unix_nano);
+                    latest_timestamp = latest_timestamp.max(Some(t));
+                    let labels = point_labels(&point.attributes);
+                    for &(quantile, value) in &point.quantile_values {
+                        let mut labels = with_name(labels.clone(), &name);
+                        labels.insert("quantile".to_string(), format_float(quantile));
+                        self.add_sample(labels, t, value_or_stale(value, point.flags));
+                    }
+                    self.add_sample(
+                        with_name(labels.clone(), &format!("{}_count", name)),
+                        t,
+                        value_or_stale(point.count as f64, point.flags),
+                    );
+                    self.add_sample(
+                        with_name(labels, &format!("{}_sum", name)),
+                        t,
+                        value_or_stale(point.sum, point.flags),
+                    );
+                }
+                self.add_metadata(
+                    &name,
+                    SUMMARY,
+                    &prometheus_unit(metric, false),
+                    &metric.description,
+                );
+            }
+        }
+        latest_timestamp
+    }
+
+    fn add_histogram(&mut self, point: HistogramPoint) {
+        let stale = point.stale;
+        let value = |v: f64| {
+            if stale {
+                f64::from_bits(STALE_NAN)
+            } else {
+                v
+            }
+        };
+        let bucket_name = format!("{}_bucket", point.name);
+        let mut buckets = point.buckets;
+        buckets.push((f64::INFINITY, point.count));
+        for &(bound, count) in &buckets {
+            let mut labels = with_name(point.labels.clone(), &bucket_name);
+            labels.insert("le".to_string(), format_float(bound));
+            self.add_sample(labels, point.timestamp, value(count as f64));
+        }
+        for exemplar in point.exemplars {
+            // the first bucket the value fits into, +Inf at the latest
+            let bound = buckets
+                .iter()
+                .map(|&(bound, _)| bound)
+                .find(|&bound| exemplar.value <= bound)
+                .unwrap_or(f64::INFINITY);
+            let mut labels = with_name(point.labels.clone(), &bucket_name);
+            labels.insert("le".to_string(), format_float(bound));
+            self.add_exemplar(&labels, exemplar);
+        }
+
+        let (count_suffix, sum_suffix) = if point.delta {
+            ("_gcount", "_gsum")
+        } else {
+            ("_count", "_sum")
+        };
+        self.add_sample(
+            with_name(
+                point.labels.clone(),
+                &format!("{}{}", point.name, count_suffix),
+            ),
+            point.timestamp,
+            value(point.count as f64),
+        );
+        if let Some(sum) = point.sum {
+            self.add_sample(
+                with_name(point.labels, &format!("{}{}", point.name, sum_suffix)),
+                point.timestamp,
+                value(sum),
+            );
+        }
+    }
+
+    fn series(&mut self, labels: Labels) -> &mut TimeSeries {
+        let timeseries = &mut self.request.timeseries;
+        let index = *self.series.entry(labels.clone()).or_insert_with(|| {
+            timeseries.push(TimeSeries {
+                labels: labels
+                    .into_iter()
+                    .map(|(name, value)| Label { name, value })
+                    .collect(),
+                samples: vec![],
+                exemplars: vec![],
+            });
+            timeseries.len() - 1
+        });
+        &mut self.request.timeseries[index]
+    }
+
+    fn add_sample(&mut self, labels: Labels, timestamp: i64, value: f64) {
+        self.series(labels)
+            .samples
+            .push(Sample { value, timestamp });
+    }
+
+    fn add_exemplar(&mut self, labels: &Labels, exemplar: &crate::otlp::Exemplar) {
+        let mut exemplar_labels = attribute_labels(&exemplar.filtered_attributes, "");
+        if !exemplar.trace_id.is_empty() {
+            exemplar_labels.insert("trace_id".to_string(), hex_encode(&exemplar.trace_id));
+        }
+        if !exemplar.span_id.is_empty() {
+            exemplar_labels.insert("span_id".to_string(), hex_encode(&exemplar.span_id));
+        }
+        self.series(labels.clone()).exemplars.push(Exemplar {
+            labels: exemplar_labels
+                .into_iter()
+                .map(|(name, value)| Label { name, value })
+                .collect(),
+            value: exemplar.value,
+            timestamp: to_millis(exemplar.time_unix_nano),
+        });
+    }
+
+    /// Adds the metadata of a metric family, unless it was already added.
+    fn add_metadata(&mut self, name: &str, metric_type: i32, unit: &str, help: &str) {
+        if !self.metadata.insert(name.to_string()) {
+            return;
+        }
+        self.request.metadata.push(MetricMetadata {
+            metric_type,
+            metric_family_name: name.to_string(),
+            help: help.to_string(),
+            unit: unit.to_string(),
+        });
+    }
+}
+
+/// A histogram data point, normalized to classic buckets.
+struct HistogramPoint<'a> {
+    name: &'a str,
+    labels: Labels,
+    timestamp: i64,
+    stale: bool,
+    /// Upper bounds with cumulative counts, without the `+Inf` bucket.
+    buckets: Vec<(f64, u64)>,
+    count: u64,
+    sum: Option<f64>,
+    exemplars: &'a [crate::otlp::Exemplar],
+    delta: bool,
+}
+
+/// Converts exponential buckets to classic ones. Bucket `index` covers
+/// `(base^index, base^(index+1)]` with `base = 2^(2^-scale)`, and mirrored
+/// for negative values.
+fn exponential_buckets(point: &ExponentialHistogramDataPoint) -> Vec<(f64, u64)> {
+    let exponent = 2f64.powi(-point.scale);
+    let bound = |index: i64| 2f64.powf(index as f64 * exponent);
+    let mut cumulative = 0;
+    let mut buckets = vec![];
+    let negative = &point.negative;
+    for (i, &count) in negative.bucket_counts.iter().enumerate().rev() {
+        cumulative += count;
+        buckets.push((-bound(negative.offset as i64 + i as i64), cumulative));
+    }
+    if point.zero_count > 0 || !negative.bucket_counts.is_empty() {
+        cumulative += point.zero_count;
+        buckets.push((point.zero_threshold, cumulative));
+    }
+    let positive = &point.positive;
+    for (i, &count) in positive.bucket_counts.iter().enumerate() {
+        cumulative += count;
+        buckets.push((bound(positive.offset as i64 + i as i64 + 1), cumulative));
+    }
+    buckets
+}
+
+/// Splits the resource attributes into the `job` and `instance` labels, and
+/// the labels of `target_info`.
+fn resource_labels(resource: &Resource) -> (Labels, Labels) {
+    let attribute = |key: &str| {
+        resource
+            .attributes
+            .iter()
+            .find(|kv| kv.key == key)
+            .map(|kv| kv.value.to_label_value())
+    };
+    let mut labels = Labels::new();
+    if let Some(name) = attribute("service.name") {
+        let job = match attribute("service.namespace") {
+            Some(namespace) => format!("{}/{}", namespace, name),
+            None => name,
+        };
+        labels.insert("job".to_string(), job);
+    }
+    if let Some(instance) = attribute("service.instance.id") {
+        labels.insert("instance".to_string(), instance);
+    }
+    let others: Vec<KeyValue> = resource
+        .attributes
+        .iter()
+        .filter(|kv| {
+            !matches!(
+                kv.key.as_str(),
+                "service.name" | "service.namespace" | "service.instance.id"
+            )
+        })
+        .cloned()
+        .collect();
+    (labels, attribute_labels(&others, ""))
+}
+
+fn scope_labels(scope: &InstrumentationScope) -> Labels {
+    let mut labels = attribute_labels(&scope.attributes, "otel_scope_");
+    if !scope.name.is_empty() {
+        labels.insert("otel_scope_name".to_string(), scope.name.clone());
+    }
+    if !scope.version.is_empty() {
+        labels.insert("otel_scope_version".to_string(), scope.version.clone());
+    }
+    labels
+}
+
+/// Attributes as labels. Values of attributes whose names collide after
+/// sanitization are joined with `;`, ordered by the original names.
+fn attribute_labels(attributes: &[KeyValue], prefix: &str) -> Labels {
+    let mut attributes: Vec<&KeyValue> = attributes.iter().collect();
+    attributes.sort_by(|a, b| a.key.cmp(&b.key));
+    let mut labels = Labels::new();
+    for kv in attributes {
+        let name = sanitize_label_name(&format!("{}{}", prefix, kv.key));
+        let value = kv.value.to_label_value();
+        match labels.get_mut(&name) {
+            Some(existing) => {
+                existing.push(';');
+                existing.push_str(&value);
+            }
+            None => {
+                labels.insert(name, value);
+            }
+        }
+    }
+    labels
+}
+
+fn sanitize_label_name(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("key_{}", sanitized)
+    } else if sanitized.starts_with('_') && !sanitized.starts_with("__") {
+        format!("key{}", sanitized)
+    } else {
+        sanitized
+    }
+}
+
+fn with_name(mut labels: Labels, name: &str) -> Labels {
+    labels.insert("__name__".to_string(), name.to_string());
+    labels
+}
+
+/// The Prometheus metric name: the sanitized name suffixed with the unit and,
+/// for counters, `_total`.
+fn metric_name(metric: &Metric, counter: bool) -> String {
+    let mut name: String = metric
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    let unit = prometheus_unit(metric, counter);
+    if !unit.is_empty() && !name.ends_with(&format!("_{}", unit)) {
+        name.push('_');
+        name.push_str(&unit);
+    }
+    if counter && !name.ends_with("_total") {
+        name.push_str("_total");
+    }
+    name
+}
+
+/// The unit as the Prometheus name suffix, e.g. `seconds` for `s` and
+/// `bytes_per_second` for `By/s`. Annotations in curly braces are dropped.
+fn prometheus_unit(metric: &Metric, counter: bool) -> String {
+    let mut unit = String::new();
+    let mut depth = 0;
+    for c in metric.unit.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            c if depth == 0 => unit.push(c),
+            _ => {}
+        }
+    }
+    let unit = unit.trim();
+    if unit == "1" {
+        // dimensionless, which only makes a meaningful suffix for gauges
+        return if counter {
+            String::new()
+        } else {
+            "ratio".to_string()
+        };
+    }
+    let (main, per) = match unit.split_once('/') {
+        Some((main, per)) => (main.trim(), Some(per.trim())),
+        None => (unit, None),
+    };
+    let main = match main {
+        "d" => "days",
+        "h" => "hours",
+        "min" => "minutes",
+        "s" => "seconds",
+        "ms" => "milliseconds",
+        "us" => "microseconds",
+        "ns" => "nanoseconds",
+        "By" => "bytes",
+        "KiBy" => "kibibytes",
+        "MiBy" => "mebibytes",
+        "GiBy" => "gibibytes",
+        "TiBy" => "tibibytes",
+        "KBy" => "kilobytes",
+        "MBy" => "megabytes",
+        "GBy" => "gigabytes",
+        "TBy" => "terabytes",
+        "m" => "meters",
+        "V" => "volts",
+        "A" => "amperes",
+        "J" => "joules",
+        "W" => "watts",
+        "g" => "grams",
+        "Cel" => "celsius",
+        "Hz" => "hertz",
+        "%" => "percent",
+        other => other,
+    };
+    let per = per.map(|per| match per {
+        "s" => "second",
+        "m" => "minute",
+        "h" => "hour",
+        "d" => "day",
+        "w" => "week",
+        "mo" => "month",
+        "y" => "year",
+        other => other,
+    });
+    let unit = match per {
+        Some(per) if !per.is_empty() => format!("{}_per_{}", main, per),
+        _ => main.to_string(),
+    };
+    unit.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+fn value_or_stale(value: f64, flags: u32) -> f64 {
+    if flags & FLAG_NO_RECORDED_VALUE != 0 {
+        f64::from_bits(STALE_NAN)
+    } else {
+        value
+    }
+}
+
+fn to_millis(unix_nano: u64) -> i64 {
+    (unix_nano / 1_000_000) as i64
+}
+
+/// Floats as label values, the way Prometheus formats `le` and `quantile`.
+fn format_float(v: f64) -> String {
+    if v == f64::INFINITY {
+        "+Inf".to_string()
+    } else if v == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        v.to_string()
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+
+    // An ExportMetricsServiceRequest for the resource {service.name="api",
+    // service.namespace="shop", service.instance.id="host-1", host.arch="amd64"}
+    // and the scope io.opentelemetry.http 1.2.0, with
+    // * the cumulative monotonic sum http.server.requests in {request}, described
+    //   as "Requests.", with the point {http.status_code=200, http.method="GET"}
+    //   = 42 at 2 s and an exemplar 0.5 at 1.5 s with a trace and span id,
+    // * the cumulative histogram http.server.duration in s with the bounds
+    //   [0.1, 1], bucket counts [1, 2, 3], sum 3.5 and an exemplar 0.7, at 2 s.
+    const PAYLOAD: &str = "0ab4030a6b0a150a0c736572766963652e6e616d6512050a036170690a1b0a11736572766963652e6e616d65737061636512060a0473686f700a1f0a13736572766963652e696e7374616e63652e696412080a06686f73742d310a140a09686f73742e6172636812070a05616d64363412c4020a1e0a15696f2e6f70656e74656c656d657472792e687474701205312e322e3012a5010a14687474702e7365727665722e7265717565737473120952657175657374732e1a097b726571756573747d3a770a713a170a10687474702e7374617475735f636f6465120318c8013a140a0b687474702e6d6574686f6412050a03474554190094357700000000312a000000000000002a2e11002f68590000000019000000000000e03f2208aabbccdd000000012a101111111111111111111111111111111110021801127a0a14687474702e7365727665722e6475726174696f6e1a01734a5f0a5b190094357700000000210600000000000000290000000000000c4032180100000000000000020000000000000003000000000000003a109a9999999999b93f000000000000f03f421211009435770000000019666666666666e63f1002";
+
+    fn decode(columns: &str, condition: &str) -> serde_json::Value {
+        Spi::get_one::<Json>(&format!(
+            "SELECT json_agg(json_build_array({}) ORDER BY metric_name, value)
+             FROM prom_api.decode_otlp_metrics('\\x{}'::bytea)
+             WHERE {}",
+            columns, PAYLOAD, condition
+        ))
+        .expect("SQL query failed")
+        .0
+    }
+
+    #[pg_test]
+    fn test_decode_otlp_metrics_samples() {
+        assert_eq!(
+            decode(
+                "metric_name, labels, (extract(epoch FROM time) * 1000)::bigint, value",
+                "kind = 'sample' AND metric_name IN ('http_server_requests_total', 'target_info')"
+            ),
+            serde_json::json!([
+                ["http_server_requests_total", {
+                    "__name__": "http_server_requests_total",
+                    "http_method": "GET",
+                    "http_status_code": "200",
+                    "instance": "host-1",
+                    "job": "shop/api",
+                    "otel_scope_name": "io.opentelemetry.http",
+                    "otel_scope_version": "1.2.0"
+                 }, 2000, 42],
+                ["target_info", {
+                    "__name__": "target_info",
+                    "host_arch": "amd64",
+                    "instance": "host-1",
+                    "job": "shop/api"
+                 }, 2000, 1],
+            ])
+        );
+    }
+
+    #[pg_test]
+    fn test_decode_otlp_metrics_histogram() {
+        assert_eq!(
+            decode(
+                "metric_name, labels->>'le', value",
+                "kind = 'sample' AND metric_name LIKE 'http_server_duration_seconds%'"
+            ),
+            serde_json::json!([
+                ["http_server_duration_seconds_bucket", "0.1", 1],
+                ["http_server_duration_seconds_bucket", "1", 3],
+                ["http_server_duration_seconds_bucket", "+Inf", 6],
+                ["http_server_duration_seconds_count", null, 6],
+                ["http_server_duration_seconds_sum", null, 3.5],
+            ])
+        );
+    }
+
+    #[pg_test]
+    fn test_decode_otlp_metrics_exemplars() {
+        assert_eq!(
+            decode(
+                "metric_name, labels->>'le', exemplar_labels, (extract(epoch FROM time) * 1000)::bigint, value",
+                "kind = 'exemplar'"
+            ),
+            serde_json::json!([
+                ["http_server_duration_seconds_bucket", "1", {}, 2000, 0.7],
+                ["http_server_requests_total", null, {
+                    "span_id": "aabbccdd00000001",
+                    "trace_id": "11111111111111111111111111111111"
+                 }, 1500, 0.5],
+            ])
+        );
+    }
+
+    #[pg_test]
+    fn test_decode_otlp_metrics_metadata() {
+        assert_eq!(
+            decode("metric_name, metric_type, unit, help", "kind = 'metadata'"),
+            serde_json::json!([
+                ["http_server_duration_seconds", "histogram", "seconds", ""],
+                ["http_server_requests_total", "counter", "", "Requests."],
+                ["target_info", "gauge", "", "Target metadata"],
+            ])
+        );
+    }
+
+    #[pg_test(error = "invalid OTLP metrics payload: protobuf: truncated message")]
+    fn test_decode_otlp_metrics_truncated() {
+        Spi::run("SELECT * FROM prom_api.decode_otlp_metrics('\\x0a05')");
+    }
+}
@@ -0,0 +1,661 @@
+//! # OTLP traces decoding
+//!
+//! Decodes an OpenTelemetry trace export (a protobuf encoded
+//! `ExportTraceServiceRequest`, as sent to `/v1/traces`) into rows shaped
+//! like the `_ps_trace.span`, `_ps_trace.event` and `_ps_trace.link` tables:
+//!
+//! ```sql
+//! SELECT trace_id, span_id, name, start_time, span_tags
+//! FROM ps_trace.decode_otlp_spans(pg_read_binary_file('batch.pb'));
+//! ```
+//!
+//! Trace ids become `ps_trace.trace_id` compatible UUIDs, and 8 byte span ids
+//! are read as big-endian `bigint`s, the way the connector stores them. Tags
+//! are JSON objects of the attributes, with the values keeping their JSON
+//! type. Empty trace states, status messages and schema URLs are `NULL`.
+//!
+//! `ps_trace.insert_otlp_traces` inserts a whole request in one call: it
+//! registers the tag keys and tags, operations, schema URLs and
+//! instrumentation libraries, and then inserts spans, events and links with
+//! their tag maps.
+use std::convert::TryInto;
+
+use pgx::*;
+
+use serde_json::{Map, Value};
+
+use crate::otlp::{
+    attributes_to_json, nanos_to_pg_timestamp, ExportTraceServiceRequest, PG_EPOCH_OFFSET_US,
+    SPAN_KINDS, STATUS_CODES,
+};
+
+#[pg_schema]
+mod _prom_ext {
+    use pgx::*;
+    use serde_json::Value;
+
+    use super::{decode, EventRow, LinkRow, SpanRow};
+
+    #[pg_extern(immutable, strict, parallel_safe, create_or_replace)]
+    pub fn decode_otlp_spans(
+        payload: &[u8],
+    ) -> TableIterator<
+        'static,
+        (
+            name!(trace_id, Uuid),
+            name!(span_id, i64),
+            name!(parent_span_id, Option<i64>),
+            name!(trace_state, Option<String>),
+            name!(name, String),
+            name!(span_kind, String),
+            name!(start_time, TimestampWithTimeZone),
+            name!(end_time, TimestampWithTimeZone),
+            name!(status_code, String),
+            name!(status_message, Option<String>),
+            name!(span_tags, JsonB),
+            name!(dropped_tags_count, i32),
+            name!(dropped_events_count, i32),
+            name!(dropped_link_count, i32),
+            name!(resource_tags, JsonB),
+            name!(resource_dropped_tags_count, i32),
+            name!(resource_schema_url, Option<String>),
+            name!(instrumentation_lib_name, Option<String>),
+            name!(instrumentation_lib_version, Option<String>),
+            name!(instrumentation_lib_schema_url, Option<String>),
+        ),
+    > {
+        let spans = decode(payload).spans;
+        TableIterator::new(spans.into_iter().map(|span: SpanRow| {
+            (
+                Uuid::from_bytes(span.trace_id),
+                span.span_id,
+                span.parent_span_id,
+                span.trace_state,
+                span.name,
+                span.span_kind.to_string(),
+                TimestampWithTimeZone::from(span.start_time),
+                TimestampWithTimeZone::from(span.end_time),
+                span.status_code.to_string(),
+                span.status_message,
+                JsonB(Value::Object(span.span_tags)),
+                span.dropped_tags_count,
+                span.dropped_events_count,
+                span.dropped_link_count,
+                JsonB(Value::Object(span.resource_tags)),
+                span.resource_dropped_tags_count,
+                span.resource_schema_url,
+                span.instrumentation_lib_name,
+                span.instrumentation_lib_version,
+                span.instrumentation_lib_schema_url,
+            )
+        }))
+    }
+
+    #[pg_extern(immutable, strict, parallel_safe, create_or_replace)]
+    pub fn decode_otlp_span_events(
+        payload: &[u8],
+    ) -> TableIterator<
+        'static,
+        (
+            name!(time, TimestampWithTimeZone),
+            name!(trace_id, Uuid),
+            name!(span_id, i64),
+            name!(event_nbr, i32),
+            name!(name, String),
+            name!(tags, JsonB),
+            name!(dropped_tags_count, i32),
+        ),
+    > {
+        let events = decode(payload).events;
+        TableIterator::new(events.into_iter().map(|event: EventRow| {
+            (
+                TimestampWithTimeZone::from(event.time),
+                Uuid::from_bytes(event.trace_id),
+                event.span_id,
+                event.event_nbr,
+                event.name,
+                JsonB(Value::Object(event.tags)),
+                event.dropped_tags_count,
+            )
+        }))
+    }
+
+    #[pg_extern(immutable, strict, parallel_safe, create_or_replace)]
+    pub fn decode_otlp_span_links(
+        payload: &[u8],
+    ) -> TableIterator<
+        'static,
+        (
+            name!(trace_id, Uuid),
+            name!(span_id, i64),
+            name!(span_start_time, TimestampWithTimeZone),
+            name!(linked_trace_id, Uuid),
+            name!(linked_span_id, i64),
+            name!(link_nbr, i32),
+            name!(trace_state, Option<String>),
+            name!(tags, JsonB),
+            name!(dropped_tags_count, i32),
+        ),
+    > {
+        let links = decode(payload).links;
+        TableIterator::new(links.into_iter().map(|link: LinkRow| {
+            (
+                Uuid::from_bytes(link.trace_id),
+                link.span_id,
+                TimestampWithTimeZone::from(link.span_start_time),
+                Uuid::from_bytes(link.linked_trace_id),
+                link.linked_span_id,
+                link.link_nbr,
+                link.trace_state,
+                JsonB(Value::Object(link.tags)),
+                link.dropped_tags_count,
+            )
+        }))
+    }
+}
+
+fn decode(payload: &[u8]) -> Rows {
+    ExportTraceServiceRequest::decode(payload)
+        .and_then(|request| to_rows(&request))
+        .unwrap_or_else(|e| error!("invalid OTLP traces payload: {}", e))
+}
+
+/// Timestamps are Postgres timestamps (microseconds since 2000-01-01).
+#[derive(Debug, Default)]
+struct Rows {
+    spans: Vec<SpanRow>,
+    events: Vec<EventRow>,
+    links: Vec<LinkRow>,
+}
+
+/// A row of `_ps_trace.span`, with the tags not yet turned into tag maps.
+/// Zipkin and Jaeger spans are converted into these as well.
+#[derive(Debug, Default)]
+pub(crate) struct SpanRow {
+    pub trace_id: [u8; 16],
+    pub span_id: i64,
+    pub parent_span_id: Option<i64>,
+    pub trace_state: Option<String>,
+    pub name: String,
+    pub span_kind: &'static str,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub status_code: &'static str,
+    pub status_message: Option<String>,
+    pub span_tags: Map<String, Value>,
+    pub dropped_tags_count: i32,
+    pub dropped_events_count: i32,
+    pub dropped_link_count: i32,
+    pub resource_tags: Map<String, Value>,
+    pub resource_dropped_tags_count: i32,
+    pub resource_schema_url: Option<String>,
+    pub instrumentation_lib_name: Option<String>,
+    pub instrumentation_lib_version: Option<String>,
+    pub instrumentation_lib_schema_url: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct EventRow {
+    pub time: i64,
+    pub trace_id: [u8; 16],
+    pub span_id: i64,
+    pub event_nbr: i32,
+    pub name: String,
+    pub tags: Map<String, Value>,
+    pub dropped_tags_count: i32,
+}
+
+#[derive(Debug)]
+pub(crate) struct LinkRow {
+    pub trace_id: [u8; 16],
+    pub span_id: i64,
+    pub span_start_time: i64,
+    pub linked_trace_id: [u8; 16],
+    pub linked_span_id: i64,
+    pub link_nbr: i32,
+    pub trace_state: Option<String>,
+    pub tags: Map<String, Value>,
+    pub dropped_tags_count: i32,
+}
+
+fn to_rows(request: &ExportTraceServiceRequest) -> Result<Rows, String> {
+    let mut rows = Rows::default();
+    for resource_spans in &request.resource_spans {
+        let resource = &resource_spans.resource;
+        let resource_tags = attributes_to_json(&resource.attributes);
+        for scope_spans in &resource_spans.scope_spans {
+            let scope = &scope_spans.scope;
+            for span in &scope_spans.spans {
+                let trace_id = trace_id(&span.trace_id)?;
+                let span_id = span_id(&span.span_id)?;
+                let start_time = nanos_to_pg_timestamp(span.start_time_unix_nano);
+                let parent_span_id = if span.parent_span_id.is_empty() {
+                    None
+                } else {
+                    Some(self::span_id(&span.parent_span_id)?)
+                };
+                rows.spans.push(SpanRow {
+                    trace_id,
+                    span_id,
+                    parent_span_id,
+                    trace_state: non_empty(&span.trace_state),
+                    name: span.name.clone(),
+                    span_kind: SPAN_KINDS
+                        .get(span.kind as usize)
+                        .ok_or_else(|| format!("invalid span kind {}", span.kind))?,
+                    start_time,
+                    end_time: nanos_to_pg_timestamp(span.end_time_unix_nano),
+                    status_code: STATUS_CODES
+                        .get(span.status_code as usize)
+                        .ok_or_else(|| format!("invalid status code {}", span.status_code))?,
+                    status_message: non_empty(&span.status_message),
+                    span_tags: attributes_to_json(&span.attributes),
+                    dropped_tags_count: span.dropped_attributes_count as i32,
+                    dropped_events_count: span.dropped_events_count as i32,
+                    dropped_link_count: span.dropped_links_count as i32,
+                    resource_tags: resource_tags.clone(),
+                    resource_dropped_tags_count: resource.dropped_attributes_count as i32,
+                    resource_schema_url: non_empty(&resource_spans.schema_url),
+                    instrumentation_lib_name: non_empty(&scope.name),
+                    instrumentation_lib_version: non_empty(&scope.version),
+                    instrumentation_lib_schema_url: non_empty(&scope_spans.schema_url),
+                });
+                for (i, event) in span.events.iter().enumerate() {
+                    rows.events.push(EventRow {
+                        time: nanos_to_pg_timestamp(event.time_unix_nano),
+                        trace_id,
+                        span_id,
+                        event_nbr: i as i32,
+                        name: event.name.clone(),
+                        tags: attributes_to_json(&event.attributes),
+                        dropped_tags_count: event.dropped_attributes_count as i32,
+                    });
+                }
+                for (i, link) in span.links.iter().enumerate() {
+                    rows.links.push(LinkRow {
+                        trace_id,
+                        span_id,
+                        span_start_time: start_time,
+                        linked_trace_id: self::trace_id(&link.trace_id)?,
+                        linked_span_id: self::span_id(&link.span_id)?,
+                        link_nbr: i as i32,
+                        trace_state: non_empty(&link.trace_state),
+                        tags: attributes_to_json(&link.attributes),
+                        dropped_tags_count: link.dropped_attributes_count as i32,
+                    });
+                }
+            }
+        }
+    }
+    Ok(rows)
+}
+
+fn trace_id(bytes: &[u8]) -> Result<[u8; 16], String> {
+    match bytes.try_into() {
+        Ok(id) if id != [0; 16] => Ok(id),
+        _ => Err(format!("invalid trace id {:?}", hex(bytes))),
+    }
+}
+
+/// The span id as a big-endian `bigint`.
+fn span_id(bytes: &[u8]) -> Result<i64, String> {
+    match bytes.try_into().map(i64::from_be_bytes) {
+        Ok(id) if id != 0 => Ok(id),
+        _ => Err(format!("invalid span id {:?}", hex(bytes))),
+    }
+}
+
+/// A hex encoded trace id, as used by Zipkin and Jaeger. 64-bit ids are
+/// zero-extended.
+pub(crate) fn trace_id_from_hex(s: &str) -> Result<[u8; 16], String> {
+    let id = u128::from_str_radix(s, 16)
+        .ok()
+        .filter(|_| s.len() <= 32)
+        .ok_or_else(|| format!("invalid trace id {:?}", s))?;
+    trace_id(&id.to_be_bytes())
+}
+
+/// A hex encoded span id, as used by Zipkin and Jaeger.
+pub(crate) fn span_id_from_hex(s: &str) -> Result<i64, String> {
+    let id = u64::from_str_radix(s, 16)
+        .ok()
+        .filter(|_| s.len() <= 16)
+        .ok_or_else(|| format!("invalid span id {:?}", s))?;
+    span_id(&id.to_be_bytes())
+}
+
+/// Moves the tags the OpenTelemetry collector uses to carry OTLP fields
+/// through Zipkin and Jaeger into the span: `otel.status_code`,
+/// `otel.status_description` and `error` set the status, `otel.scope.*`
+/// (formerly `otel.library.*`) the instrumentation library and
+/// `w3c.tracestate` the trace state.
+pub(crate) fn apply_otel_tags(span: &mut SpanRow) {
+    let tags = &mut span.span_tags;
+    let status_code = tags
+        .remove("otel.status_code")
+        .and_then(|code| tag_string(&code))
+        .and_then(|code| STATUS_CODES.iter().find(|c| c.eq_ignore_ascii_case(&code)));
+    let description = tags
+        .remove("otel.status_description")
+        .and_then(|d| tag_string(&d));
+    match status_code {
+        Some(&code) => {
+            // the collector sets both for errors
+            if code == "error" && matches!(tags.get("error"), Some(Value::Bool(true))) {
+                tags.remove("error");
+            }
+            span.status_code = code;
+        }
+        None => {
+            // Zipkin puts the error message into the tag, Jaeger a boolean.
+            let error = tags.get("error").map(|e| match e {
+                Value::Bool(b) => (*b, None),
+                Value::String(s) if s == "false" => (false, None),
+                Value::String(s) if s.is_empty() || s == "true" => (true, None),
+                Value::String(s) => (true, Some(s.clone())),
+                _ => (false, None),
+            });
+            if let Some((true, message)) = error {
+                tags.remove("error");
+                span.status_code = "error";
+                span.status_message = message;
+            }
+        }
+    }
+    if description.is_some() {
+        span.status_message = description;
+    }
+    for (scope, legacy, field) in [
+        (
+            "otel.scope.name",
+            "otel.library.name",
+            &mut span.instrumentation_lib_name,
+        ),
+        (
+            "otel.scope.version",
+            "otel.library.version",
+            &mut span.instrumentation_lib_version,
+        ),
+    ] {
+        let value = tags.remove(scope).or_else(|| tags.remove(legacy));
+        if let Some(value) = value.and_then(|v| tag_string(&v)) {
+            *field = non_empty(&value);
+        }
+    }
+    if let Some(state) = tags.remove("w3c.tracestate").and_then(|s| tag_string(&s)) {
+        span.trace_state = non_empty(&state);
+    }
+}
+
+fn tag_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Null => None,
+        other => Some(other.to_string()),
+    }
+}
+
+pub(crate) fn str_field<'a>(
+    object: &'a Map<String, Value>,
+    field: &str,
+) -> Result<Option<&'a str>, String> {
+    match object.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(other) => Err(format!("invalid {} {}", field, other)),
+    }
+}
+
+pub(crate) fn int_field(object: &Map<String, Value>, field: &str) -> Result<Option<i64>, String> {
+    match object.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value
+            .as_i64()
+            .map(Some)
+            .ok_or_else(|| format!("invalid {} {}", field, value)),
+    }
+}
+
+/// The events of a span as a JSON array of objects with the columns of
+/// `_ps_trace.event`, other than the span's ids.
+pub(crate) fn events_to_json(events: Vec<EventRow>) -> Value {
+    events
+        .into_iter()
+        .map(|event| {
+            serde_json::json!({
+                "time": format_pg_timestamp(event.time),
+                "event_nbr": event.event_nbr,
+                "name": event.name,
+                "tags": event.tags,
+                "dropped_tags_count": event.dropped_tags_count,
+            })
+        })
+        .collect()
+}
+
+/// The links of a span as a JSON array of objects with the columns of
+/// `_ps_trace.link`, other than the span's ids and start time.
+pub(crate) fn links_to_json(links: Vec<LinkRow>) -> Value {
+    links
+        .into_iter()
+        .map(|link| {
+            serde_json::json!({
+                "linked_trace_id": format_uuid(&link.linked_trace_id),
+                "linked_span_id": link.linked_span_id,
+                "link_nbr": link.link_nbr,
+                "trace_state": link.trace_state,
+                "tags": link.tags,
+                "dropped_tags_count": link.dropped_tags_count,
+            })
+        })
+        .collect()
+}
+
+/// RFC 3339 in UTC, which `timestamptz` input accepts.
+fn format_pg_timestamp(pg_us: i64) -> String {
+    let us = pg_us + PG_EPOCH_OFFSET_US;
+    let secs = us.div_euclid(1_000_000);
+    let (days, secs_of_day) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        us.rem_euclid(1_000_000)
+    )
+}
+
+fn format_uuid(bytes: &[u8; 16]) -> String {
+    let hex = hex(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn hex(bytes: &[u8]) -> String {
+    crate::otlp::hex_encode(bytes)
+}
+
+pub(crate) fn non_empty(s: &str) -> Option<String> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s.to_string())
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+
+    use crate::otlp::hex_encode;
+    use crate::protobuf::Writer;
+
+    // An ExportTraceServiceRequest for the resource {service.name="api",
+    // host.name="host-1"} with the schema URL .../1.9.0 and the scope
+    // io.opentelemetry.http 1.2.0, with two spans of the trace
+    // 01020304-0506-0708-090a-0b0c0d0e0f10 starting at 2023-01-01:
+    // * the server span 1 "GET /users" of 250 ms with status ok, the tags
+    //   {http.method="GET", http.status_code=200} and the event "cache miss"
+    //   {cache="users"} at 10 ms,
+    // * the client span 2 "SELECT users", child of span 1, from 20 ms to
+    //   120 ms, with the trace state "k=v", status error "timeout", the tags
+    //   {db.system="postgresql"}, 2 dropped tags and a link {reason="retry"}
+    //   to span 9 of the trace ffffffff-ffff-ffff-ffff-ffffffffffff.
+    const PAYLOAD: &str = "0ac1030a2e0a150a0c736572766963652e6e616d6512050a036170690a150a09686f73742e6e616d6512080a06686f73742d3112e6020a1e0a15696f2e6f70656e74656c656d657472792e687474701205312e322e301298010a100102030405060708090a0b0c0d0e0f10120800000000000000012a0a474554202f75736572733002390000c2d3430636174180b2a8e2430636174a140a0b687474702e6d6574686f6412050a034745544a170a10687474702e7374617475735f636f6465120318c8015a270980965ad443063617120a6361636865206d6973731a100a05636163686512070a0575736572737a02180112a8010a100102030405060708090a0b0c0d0e0f10120800000000000000021a036b3d76220800000000000000012a0c53454c454354207573657273300339002df3d44306361741000ee9da430636174a190a0964622e73797374656d120c0a0a706f737467726573716c50026a2f0a10ffffffffffffffffffffffffffffffff1208000000000000000922110a06726561736f6e12070a0572657472797a0b120774696d656f757418021a2668747470733a2f2f6f70656e74656c656d657472792e696f2f736368656d61732f312e392e30";
+
+    fn query(sql: &str) -> serde_json::Value {
+        Spi::get_one::<Json>(sql).expect("SQL query failed").0
+    }
+
+    #[pg_test]
+    fn test_decode_otlp_spans() {
+        assert_eq!(
+            query(&format!(
+                "SELECT json_agg(json_build_array(
+                    trace_id::text, span_id, parent_span_id, trace_state, name, span_kind,
+                    (extract(epoch FROM end_time - start_time) * 1000)::bigint,
+                    status_code, status_message, span_tags, dropped_tags_count, resource_tags,
+                    resource_schema_url, instrumentation_lib_name, instrumentation_lib_version
+                 ) ORDER BY span_id)
+                 FROM ps_trace.decode_otlp_spans('\\x{}'::bytea)",
+                PAYLOAD
+            )),
+            serde_json::json!([
+                [
+                    "01020304-0506-0708-090a-0b0c0d0e0f10", 1, null, null, "GET /users", "server",
+                    250, "ok", null, {"http.method": "GET", "http.status_code": 200}, 0,
+                    {"host.name": "host-1", "service.name": "api"},
+                    "https://opentelemetry.io/schemas/1.9.0", "io.opentelemetry.http", "1.2.0"
+                ],
+                [
+                    "01020304-0506-0708-090a-0b0c0d0e0f10", 2, 1, "k=v", "SELECT users", "client",
+                    100, "error", "timeout", {"db.system": "postgresql"}, 2,
+                    {"host.name": "host-1", "service.name": "api"},
+                    "https://opentelemetry.io/schemas/1.9.0", "io.opentelemetry.http", "1.2.0"
+                ]
+            ])
+        );
+    }
+
+    #[pg_test]
+    fn test_decode_otlp_span_events_and_links() {
+        Spi::run("SET TIME ZONE 'UTC'");
+        assert_eq!(
+            query(&format!(
+                "SELECT json_agg(json_build_array(
+                    time::text, span_id, event_nbr, name, tags, dropped_tags_count
+                 ))
+                 FROM ps_trace.decode_otlp_span_events('\\x{}'::bytea)",
+                PAYLOAD
+            )),
+            serde_json::json!([
+                ["2023-01-01 00:00:00.01+00", 1, 0, "cache miss", {"cache": "users"}, 0]
+            ])
+        );
+        assert_eq!(
+            query(&format!(
+                "SELECT json_agg(json_build_array(
+                    span_id, span_start_time::text, linked_trace_id::text, linked_span_id,
+                    link_nbr, trace_state, tags
+                 ))
+                 FROM ps_trace.decode_otlp_span_links('\\x{}'::bytea)",
+                PAYLOAD
+            )),
+            serde_json::json!([[
+                2, "2023-01-01 00:00:00.02+00", "ffffffff-ffff-ffff-ffff-ffffffffffff", 9,
+                0, null, {"reason": "retry"}
+            ]])
+        );
+    }
+
+    #[pg_test]
+    fn test_insert_otlp_traces() {
+        Spi::run("SET TIME ZONE 'UTC'");
+        Spi::run(&format!(
+            "CALL ps_trace.insert_otlp_traces('\\x{}'::bytea)",
+            PAYLOAD
+        ));
+        assert_eq!(
+            query(
+                "SELECT json_agg(json_build_array(
+                    span_id, service_name, span_name, span_kind, duration_ms, span_tags,
+                    resource_tags, event_time::text, instrumentation_lib_name
+                 ) ORDER BY span_id)
+                 FROM ps_trace.span"
+            ),
+            serde_json::json!([
+                [
+                    1, "api", "GET /users", "server", 250,
+                    {"http.method": "GET", "http.status_code": 200},
+                    {"host.name": "host-1", "service.name": "api"},
+                    "[\"2023-01-01 00:00:00.01+00\",\"2023-01-01 00:00:00.01+00\"]",
+                    "io.opentelemetry.http"
+                ],
+                [
+                    2, "api", "SELECT users", "client", 100,
+                    {"db.system": "postgresql"},
+                    {"host.name": "host-1", "service.name": "api"},
+                    null,
+                    "io.opentelemetry.http"
+                ]
+            ])
+        );
+        let events =
+            Spi::get_one::<i64>("SELECT count(*) FROM _ps_trace.event").expect("SQL query failed");
+        assert_eq!(events, 1);
+        let link_tags = query("SELECT json_agg(link_tags) FROM ps_trace.link");
+        assert_eq!(link_tags, serde_json::json!([{"reason": "retry"}]));
+    }
+
+    #[pg_test(error = "invalid OTLP traces payload: invalid trace id \"\"")]
+    fn test_decode_otlp_spans_without_trace_id() {
+        Spi::run("SELECT * FROM ps_trace.decode_otlp_spans('\\x0a09120712051203010203')");
+    }
+
+    #[pg_test(error = "invalid OTLP traces payload: AnyValue nested deeper than 32 levels")]
+    fn test_decode_otlp_spans_rejects_deeply_nested_tags() {
+        // a span tag of 1000 nested arrays
+        let mut value = vec![];
+        for _ in 0..1000 {
+            let mut array = Writer::new();
+            array.bytes(1, &value);
+            let mut any = Writer::new();
+            any.bytes(5, &array.into_bytes());
+            value = any.into_bytes();
+        }
+        let mut payload = Writer::new();
+        payload.bytes(1, b"k");
+        payload.bytes(2, &value);
+        // KeyValue in Span.attributes, ScopeSpans.spans, ResourceSpans.scope_spans
+        // and ExportTraceServiceRequest.resource_spans
+        for field in [9, 2, 2, 1] {
+            let mut outer = Writer::new();
+            outer.bytes(field, &payload.into_bytes());
+            payload = outer;
+        }
+        Spi::run(&format!(
+            "SELECT * FROM ps_trace.decode_otlp_spans('\\x{}')",
+            hex_encode(&payload.into_bytes())
+        ));
+    }
+}
@@ -0,0 +1,612 @@
+//! # Parquet export
+//!
+//! Writes the samples of a metric or the spans of a time range as
+//! [Apache Parquet][parquet] files on the database server, e.g. for analysis
+//! with pandas or Spark, keeping column types that `COPY ... CSV` loses:
+//!
+//! ```sql
+//! SELECT prom_api.export_parquet('node_load1', now() - interval '1 day', now(), '/var/lib/export/node_load1.parquet');
+//! SELECT ps_trace.export_parquet(now() - interval '1 hour', now(), '/var/lib/export/spans.parquet');
+//! ```
+//!
+//! Metric files have a row per sample, ordered by series, with `time`,
+//! `value` and `series_id` columns and a `labels` group of a nullable column
+//! per label key of the metric. Span files have a row per span, ordered by
+//! start time, with `span_tags` and `resource_tags` as maps from tag keys to
+//! JSON values; events and links are not exported.
+//!
+//! Rows are streamed through a cursor in batches, each TimescaleDB chunk in
+//! the time range becoming a row group, so exports aren't limited by memory
+//! or the size of a Postgres array. The catalog entries go into the file's
+//! key-value metadata: `promscale.metric` holds the `_prom_catalog.metric`
+//! row and `promscale.metric_metadata` the type, unit and help of the metric,
+//! `promscale.trace_retention_period` the retention of spans.
+//!
+//! The functions return the number of rows written. Writing files requires
+//! superuser or membership in `pg_write_server_files`; relative paths are
+//! resolved against the data directory. Files are written to `<path>.tmp`
+//! first and only replace `path` once complete.
+//!
+//! [parquet]: https://parquet.apache.org/docs/file-format/
+use std::collections::HashMap;
+use std::path::Path;
+
+use pgx::*;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::otlp::PG_EPOCH_OFFSET_US;
+use crate::util::quote_identifier;
+use writer::{Column, Field, ParquetWriter, Type, Values};
+
+mod writer;
+
+/// The number of rows fetched from the cursor at a time. Row groups are
+/// written from several batches, so this only bounds the rows held in memory.
+const BATCH_ROWS: i64 = 10_000;
+
+#[pg_schema]
+mod _prom_ext {
+    use std::path::Path;
+
+    use pgx::*;
+
+    use super::{export_metric, export_spans};
+    use crate::util::check_privileges;
+
+    #[pg_extern(strict, create_or_replace)]
+    pub fn export_metric_parquet(
+        metric_name: &str,
+        start: TimestampWithTimeZone,
+        end: TimestampWithTimeZone,
+        path: &str,
+    ) -> i64 {
+        check_privileges("pg_write_server_files", "write Parquet files");
+        export_metric(metric_name, start, end, Path::new(path))
+            .unwrap_or_else(|e| error!("cannot write Parquet file: {}", e)) as i64
+    }
+
+    #[pg_extern(strict, create_or_replace)]
+    pub fn export_spans_parquet(
+        start: TimestampWithTimeZone,
+        end: TimestampWithTimeZone,
+        path: &str,
+    ) -> i64 {
+        check_privileges("pg_write_server_files", "write Parquet files");
+        export_spans(start, end, Path::new(path))
+            .unwrap_or_else(|e| error!("cannot write Parquet file: {}", e)) as i64
+    }
+}
+
+fn export_metric(
+    metric_name: &str,
+    start: TimestampWithTimeZone,
+    end: TimestampWithTimeZone,
+    path: &Path,
+) -> Result<u64, String> {
+    let name_arg = || vec![(PgBuiltInOids::TEXTOID.oid(), metric_name.into_datum())];
+    let metric = Spi::get_one_with_args::<JsonB>(
+        "SELECT to_jsonb(m) FROM _prom_catalog.metric m
+         WHERE m.table_schema = 'prom_data' AND m.metric_name = $1",
+        name_arg(),
+    )
+    .map(|metric| metric.0)
+    .ok_or_else(|| format!("metric {} does not exist", metric_name))?;
+    let table_name = metric["table_name"]
+        .as_str()
+        .expect("metrics have a table name")
+        .to_string();
+    let label_keys = Spi::get_one_with_args::<Vec<String>>(
+        "SELECT array_agg(p.key ORDER BY p.key) FROM _prom_catalog.label_key_position p
+         WHERE p.metric_name = $1 AND p.key != '__name__'",
+        name_arg(),
+    )
+    .unwrap_or_default();
+    let metric_metadata = Spi::get_one_with_args::<JsonB>(
+        "SELECT jsonb_build_object('type', m.type, 'unit', m.unit, 'help', m.help)
+         FROM _prom_catalog.metadata m
+         WHERE m.metric_family = $1
+         ORDER BY m.last_seen DESC
+         LIMIT 1",
+        name_arg(),
+    );
+
+    let mut fields = vec![
+        Field::required("time", Type::TimestampMicros),
+        Field::required("value", Type::Double),
+        Field::required("series_id", Type::Int64),
+    ];
+    if !label_keys.is_empty() {
+        let label_fields = label_keys
+            .iter()
+            .map(|key| Field::optional(key, Type::String))
+            .collect();
+        fields.push(Field::group("labels", label_fields));
+    }
+    let mut writer = ParquetWriter::create(path, fields)?;
+
+    let query = format!(
+        "SELECT d.time, d.value, d.series_id
+        FROM prom_data.{} d
+        WHERE d.time >= $1 AND d.time < $2
+        ORDER BY d.series_id, d.time",
+        quote_identifier(&table_name)
+    );
+    let mut labels: HashMap<i64, Map<String, Value>> = HashMap::new();
+    for (start, end) in row_group_ranges("prom_data", &table_name, start, end) {
+        for_each_batch(&query, range_args(start, end), |batch| {
+            let mut times = Vec::with_capacity(batch.len());
+            let mut values = Vec::with_capacity(batch.len());
+            let mut series_ids = Vec::with_capacity(batch.len());
+            for row in batch {
+                let time = column_value::<TimestampWithTimeZone>(&row, 1);
+                times.push(i64::from(time) + PG_EPOCH_OFFSET_US);
+                values.push(column_value::<f64>(&row, 2));
+                series_ids.push(column_value::<i64>(&row, 3));
+            }
+            fetch_labels(&series_ids, &mut labels);
+
+            let label_columns: Vec<_> = label_keys
+                .iter()
+                .map(|key| {
+                    let label_values = series_ids
+                        .iter()
+                        .map(|id| {
+                            let value = labels.get(id).and_then(|labels| labels.get(key));
+                            value.and_then(Value::as_str).map(|v| v.as_bytes().to_vec())
+                        })
+                        .collect();
+                    Column::optional(label_values, Values::Bytes)
+                })
+                .collect();
+            let num_rows = times.len();
+            let mut columns = vec![
+                Column::required(Values::Int64(times)),
+                Column::required(Values::Double(values)),
+                Column::required(Values::Int64(series_ids)),
+            ];
+            columns.extend(label_columns);
+            writer.write_rows(num_rows, columns)
+        })?;
+        writer.end_row_group()?;
+    }
+
+    let mut metadata = vec![("promscale.metric".to_string(), metric.to_string())];
+    if let Some(JsonB(metric_metadata)) = metric_metadata {
+        metadata.push((
+            "promscale.metric_metadata".to_string(),
+            metric_metadata.to_string(),
+        ));
+    }
+    writer.finish(&metadata)
+}
+
+/// Adds the labels of the series that aren't in `labels` yet. Deleted series
+/// are left out, and their samples exported without labels.
+fn fetch_labels(series_ids: &[i64], labels: &mut HashMap<i64, Map<String, Value>>) {
+    let mut missing: Vec<i64> = series_ids
+        .iter()
+        .copied()
+        .filter(|id| !labels.contains_key(id))
+        .collect();
+    missing.sort_unstable();
+    missing.dedup();
+    if missing.is_empty() {
+        return;
+    }
+    let fetched = Spi::get_one_with_args::<JsonB>(
+        "SELECT jsonb_object_agg(s.id, prom_api.jsonb(s.labels))
+         FROM _prom_catalog.series s
+         WHERE s.id = ANY($1)",
+        vec![(PgBuiltInOids::INT8ARRAYOID.oid(), missing.into_datum())],
+    );
+    if let Some(JsonB(Value::Object(fetched))) = fetched {
+        for (id, series_labels) in fetched {
+            if let (Ok(id), Value::Object(series_labels)) = (id.parse(), series_labels) {
+                labels.insert(id, series_labels);
+            }
+        }
+    }
+}
+
+/// A span as read from `_ps_trace`. Times are microseconds since the Unix
+/// epoch.
+#[derive(Debug, Deserialize)]
+struct StoredSpan {
+    trace_id: String,
+    span_id: i64,
+    parent_span_id: Option<i64>,
+    trace_state: Option<String>,
+    span_name: String,
+    span_kind: String,
+    start_time: i64,
+    end_time: i64,
+    duration_ms: f64,
+    status_code: String,
+    status_message: Option<String>,
+    span_tags: Map<String, Value>,
+    dropped_tags_count: i32,
+    dropped_events_count: i32,
+    dropped_link_count: i32,
+    resource_tags: Map<String, Value>,
+    resource_dropped_tags_count: i32,
+    instrumentation_lib_name: Option<String>,
+    instrumentation_lib_version: Option<String>,
+}
+
+fn export_spans(
+    start: TimestampWithTimeZone,
+    end: TimestampWithTimeZone,
+    path: &Path,
+) -> Result<u64, String> {
+    let fields = vec![
+        Field::required("trace_id", Type::Uuid),
+        Field::required("span_id", Type::Int64),
+        Field::optional("parent_span_id", Type::Int64),
+        Field::optional("trace_state", Type::String),
+        Field::required("span_name", Type::String),
+        Field::required("span_kind", Type::String),
+        Field::required("start_time", Type::TimestampMicros),
+        Field::required("end_time", Type::TimestampMicros),
+        Field::required("duration_ms", Type::Double),
+        Field::required("status_code", Type::String),
+        Field::optional("status_message", Type::String),
+        Field::map("span_tags", Type::Json),
+        Field::required("dropped_tags_count", Type::Int32),
+        Field::required("dropped_events_count", Type::Int32),
+        Field::required("dropped_link_count", Type::Int32),
+        Field::map("resource_tags", Type::Json),
+        Field::required("resource_dropped_tags_count", Type::Int32),
+        Field::optional("instrumentation_lib_name", Type::String),
+        Field::optional("instrumentation_lib_version", Type::String),
+    ];
+    let mut writer = ParquetWriter::create(path, fields)?;
+
+    for (start, end) in row_group_ranges("_ps_trace", "span", start, end) {
+        let query = "SELECT jsonb_build_object(
+                'trace_id', s.trace_id,
+                'span_id', s.span_id,
+                'parent_span_id', s.parent_span_id,
+                'trace_state', s.trace_state,
+                'span_name', o.span_name,
+                'span_kind', o.span_kind,
+                'start_time', (extract(epoch FROM s.start_time) * 1000000)::bigint,
+                'end_time', (extract(epoch FROM s.end_time) * 1000000)::bigint,
+                'duration_ms', s.duration_ms,
+                'status_code', s.status_code,
+                'status_message', s.status_message,
+                'span_tags', coalesce(_ps_trace.tag_map_denormalize(s.span_tags)::jsonb, '{}'),
+                'dropped_tags_count', s.dropped_tags_count,
+                'dropped_events_count', s.dropped_events_count,
+                'dropped_link_count', s.dropped_link_count,
+                'resource_tags', coalesce(_ps_trace.tag_map_denormalize(s.resource_tags)::jsonb, '{}'),
+                'resource_dropped_tags_count', s.resource_dropped_tags_count,
+                'instrumentation_lib_name', il.name,
+                'instrumentation_lib_version', il.version
+            )
+            FROM _ps_trace.span s
+            INNER JOIN _ps_trace.operation o ON (o.id = s.operation_id)
+            LEFT JOIN _ps_trace.instrumentation_lib il ON (il.id = s.instrumentation_lib_id)
+            WHERE s.start_time >= $1 AND s.start_time < $2
+            ORDER BY s.start_time, s.span_id";
+        for_each_batch(query, range_args(start, end), |batch| {
+            let spans: Vec<StoredSpan> = batch
+                .map(|row| {
+                    let JsonB(span) = column_value::<JsonB>(&row, 1);
+                    serde_json::from_value(span)
+                        .unwrap_or_else(|e| error!("unexpected span row: {}", e))
+                })
+                .collect();
+            writer.write_rows(spans.len(), span_columns(&spans))
+        })?;
+        writer.end_row_group()?;
+    }
+
+    let retention = Spi::get_one::<String>("SELECT ps_trace.get_trace_retention_period()::text");
+    let metadata: Vec<_> = retention
+        .map(|retention| ("promscale.trace_retention_period".to_string(), retention))
+        .into_iter()
+        .collect();
+    writer.finish(&metadata)
+}
+
+fn span_columns(spans: &[StoredSpan]) -> Vec<Column> {
+    let int64 =
+        |f: fn(&StoredSpan) -> i64| Column::required(Values::Int64(spans.iter().map(f).collect()));
+    let int32 =
+        |f: fn(&StoredSpan) -> i32| Column::required(Values::Int32(spans.iter().map(f).collect()));
+    let string = |f: fn(&StoredSpan) -> &String| {
+        Column::required(Values::Bytes(
+            spans.iter().map(|s| f(s).as_bytes().to_vec()).collect(),
+        ))
+    };
+    let optional_string = |f: fn(&StoredSpan) -> &Option<String>| {
+        Column::optional(
+            spans
+                .iter()
+                .map(|s| f(s).as_ref().map(|v| v.as_bytes().to_vec()))
+                .collect(),
+            Values::Bytes,
+        )
+    };
+    let tags = |f: fn(&StoredSpan) -> &Map<String, Value>| {
+        Column::map(
+            spans
+                .iter()
+                .map(|s| {
+                    f(s).iter()
+                        .map(|(key, value)| (key.clone(), value.to_string().into_bytes()))
+                        .collect()
+                })
+                .collect(),
+        )
+    };
+    let trace_ids = spans
+        .iter()
+        .map(|s| {
+            parse_uuid(&s.trace_id).unwrap_or_else(|| error!("unexpected trace id {}", s.trace_id))
+        })
+        .collect();
+
+    let (span_tag_keys, span_tag_values) = tags(|s| &s.span_tags);
+    let (resource_tag_keys, resource_tag_values) = tags(|s| &s.resource_tags);
+    vec![
+        Column::required(Values::Bytes(trace_ids)),
+        int64(|s| s.span_id),
+        Column::optional(
+            spans.iter().map(|s| s.parent_span_id).collect(),
+            Values::Int64,
+        ),
+        optional_string(|s| &s.trace_state),
+        string(|s| &s.span_name),
+        string(|s| &s.span_kind),
+        int64(|s| s.start_time),
+        int64(|s| s.end_time),
+        Column::required(Values::Double(
+            spans.iter().map(|s| s.duration_ms).collect(),
+        )),
+        string(|s| &s.status_code),
+        optional_string(|s| &s.status_message),
+        span_tag_keys,
+        span_tag_values,
+        int32(|s| s.dropped_tags_count),
+        int32(|s| s.dropped_events_count),
+        int32(|s| s.dropped_link_count),
+        resource_tag_keys,
+        resource_tag_values,
+        int32(|s| s.resource_dropped_tags_count),
+        optional_string(|s| &s.instrumentation_lib_name),
+        optional_string(|s| &s.instrumentation_lib_version),
+    ]
+}
+
+/// The 16 bytes of a UUID in its text form.
+fn parse_uuid(uuid: &str) -> Option<Vec<u8>> {
+    let hex: Vec<u8> = uuid.bytes().filter(|&b| b != b'-').collect();
+    if hex.len() != 32 {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn range_args(
+    start: TimestampWithTimeZone,
+    end: TimestampWithTimeZone,
+) -> Vec<(PgOid, Option<pg_sys::Datum>)> {
+    vec![
+        (PgBuiltInOids::TIMESTAMPTZOID.oid(), start.into_datum()),
+        (PgBuiltInOids::TIMESTAMPTZOID.oid(), end.into_datum()),
+    ]
+}
+
+/// Runs `query` through a cursor, calling `f` with every batch of up to
+/// [`BATCH_ROWS`] rows until the rows run out or `f` fails. Each batch is
+/// freed before the next is fetched.
+fn for_each_batch<F>(
+    query: &str,
+    args: Vec<(PgOid, Option<pg_sys::Datum>)>,
+    mut f: F,
+) -> Result<(), String>
+where
+    F: FnMut(SpiTupleTable) -> Result<(), String>,
+{
+    let mut result = Ok(());
+    Spi::connect(|client| {
+        let mut cursor = client.open_cursor(query, Some(args));
+        loop {
+            let batch = cursor.fetch(BATCH_ROWS);
+            // `f` may run queries of its own, which replace `SPI_tuptable`.
+            let tuptable = unsafe { pg_sys::SPI_tuptable };
+            let done = batch.len() == 0;
+            if !done {
+                result = f(batch);
+            }
+            unsafe { pg_sys::SPI_freetuptable(tuptable) };
+            if done || result.is_err() {
+                break;
+            }
+        }
+        Ok(None::<i64>)
+    });
+    result
+}
+
+/// The non-null column `ordinal`, counting from 1, of a row.
+fn column_value<T: FromDatum>(row: &SpiHeapTupleData, ordinal: usize) -> T {
+    row.by_ordinal(ordinal)
+        .ok()
+        .and_then(|entry| entry.value::<T>())
+        .unwrap_or_else(|| error!("unexpected NULL in column {}", ordinal))
+}
+
+/// Splits `[start, end)` at the chunk boundaries of a hypertable, so that
+/// each range can be written as a row group.
+fn row_group_ranges(
+    schema: &str,
+    table: &str,
+    start: TimestampWithTimeZone,
+    end: TimestampWithTimeZone,
+) -> Vec<(TimestampWithTimeZone, TimestampWithTimeZone)> {
+    let mut bounds = vec![start];
+    let timescaledb = Spi::get_one::<bool>("SELECT _prom_catalog.is_timescaledb_installed()");
+    if timescaledb == Some(true) {
+        let chunk_bounds = Spi::get_one_with_args::<Vec<TimestampWithTimeZone>>(
+            "SELECT array_agg(DISTINCT b ORDER BY b)
+             FROM timescaledb_information.chunks c, unnest(ARRAY[c.range_start, c.range_end]) b
+             WHERE c.hypertable_schema = $1 AND c.hypertable_name = $2
+             AND b > $3 AND b < $4",
+            vec![
+                (PgBuiltInOids::TEXTOID.oid(), schema.into_datum()),
+                (PgBuiltInOids::TEXTOID.oid(), table.into_datum()),
+                (PgBuiltInOids::TIMESTAMPTZOID.oid(), start.into_datum()),
+                (PgBuiltInOids::TIMESTAMPTZOID.oid(), end.into_datum()),
+            ],
+        );
+        bounds.extend(chunk_bounds.unwrap_or_default());
+    }
+    bounds.push(end);
+    bounds.windows(2).map(|w| (w[0], w[1])).collect()
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use std::convert::TryInto;
+
+    use pgx::*;
+
+    // The payload of the `otlp_traces` tests: the server span 1 "GET /users"
+    // and its child, the client span 2 "SELECT users".
+    const PAYLOAD: &str = "0ac1030a2e0a150a0c736572766963652e6e616d6512050a036170690a150a09686f73742e6e616d6512080a06686f73742d3112e6020a1e0a15696f2e6f70656e74656c656d657472792e687474701205312e322e301298010a100102030405060708090a0b0c0d0e0f10120800000000000000012a0a474554202f75736572733002390000c2d3430636174180b2a8e2430636174a140a0b687474702e6d6574686f6412050a034745544a170a10687474702e7374617475735f636f6465120318c8015a270980965ad443063617120a6361636865206d6973731a100a05636163686512070a0575736572737a02180112a8010a100102030405060708090a0b0c0d0e0f10120800000000000000021a036b3d76220800000000000000012a0c53454c454354207573657273300339002df3d44306361741000ee9da430636174a190a0964622e73797374656d120c0a0a706f737467726573716c50026a2f0a10ffffffffffffffffffffffffffffffff1208000000000000000922110a06726561736f6e12070a0572657472797a0b120774696d656f757418021a2668747470733a2f2f6f70656e74656c656d657472792e696f2f736368656d61732f312e392e30";
+
+    /// Runs an export into a fresh file, returning the number of rows and the
+    /// file.
+    fn export(sql: &str, name: &str) -> (i64, Vec<u8>) {
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        let rows = Spi::get_one_with_args::<i64>(
+            sql,
+            vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                path.to_str().unwrap().into_datum(),
+            )],
+        )
+        .expect("SQL query failed");
+        (rows, std::fs::read(&path).unwrap())
+    }
+
+    /// The Thrift encoded footer of a Parquet file.
+    fn footer(file: &[u8]) -> &[u8] {
+        assert_eq!(&file[..4], b"PAR1");
+        assert_eq!(&file[file.len() - 4..], b"PAR1");
+        let len = u32::from_le_bytes(file[file.len() - 8..file.len() - 4].try_into().unwrap());
+        &file[file.len() - 8 - len as usize..file.len() - 8]
+    }
+
+    fn contains(footer: &[u8], s: &str) -> bool {
+        footer.windows(s.len()).any(|w| w == s.as_bytes())
+    }
+
+    #[pg_test]
+    fn test_export_parquet() {
+        Spi::run(
+            r#"
+            SELECT _prom_catalog.get_or_create_metric_table_name('up');
+            INSERT INTO prom_data.up(time, value, series_id)
+            SELECT
+                '2000-01-01 00:00:00+00'::timestamptz + m * interval '1 minute',
+                m % 2,
+                _prom_catalog.get_or_create_series_id(s.labels)
+            FROM (VALUES
+                ('{"__name__": "up", "job": "api", "instance": "a"}'::jsonb),
+                ('{"__name__": "up", "job": "db"}'::jsonb)
+            ) s(labels), generate_series(0, 99) m;
+            INSERT INTO _prom_catalog.metadata(last_seen, metric_family, type, unit, help)
+            VALUES (now(), 'up', 'gauge', '', 'whether the target is up');
+        "#,
+        );
+        let (rows, file) = export(
+            "SELECT prom_api.export_parquet('up', '-infinity', 'infinity', $1)",
+            "promscale_test_export_parquet.parquet",
+        );
+        assert_eq!(rows, 200);
+        let footer = footer(&file);
+        for s in &[
+            "time",
+            "value",
+            "series_id",
+            "labels",
+            "instance",
+            "job",
+            "promscale.metric",
+            r#""metric_name":"up""#,
+            "promscale.metric_metadata",
+            "whether the target is up",
+        ] {
+            assert!(contains(footer, s), "{} is missing from the footer", s);
+        }
+        assert!(!contains(footer, "__name__"));
+
+        let (rows, file) = export(
+            "SELECT prom_api.export_parquet('up', '2001-01-01', '2002-01-01', $1)",
+            "promscale_test_export_parquet_empty.parquet",
+        );
+        assert_eq!(rows, 0);
+        assert!(contains(footer(&file), "promscale.metric"));
+    }
+
+    #[pg_test]
+    fn test_export_parquet_batches() {
+        Spi::run(
+            r#"
+            SELECT _prom_catalog.get_or_create_metric_table_name('up');
+            INSERT INTO prom_data.up(time, value, series_id)
+            SELECT
+                '2000-01-01 00:00:00+00'::timestamptz + m * interval '1 second',
+                m,
+                _prom_catalog.get_or_create_series_id('{"__name__": "up", "job": "api"}')
+            FROM generate_series(1, 25000) m;
+        "#,
+        );
+        let (rows, file) = export(
+            "SELECT prom_api.export_parquet('up', '-infinity', 'infinity', $1)",
+            "promscale_test_export_parquet_batches.parquet",
+        );
+        assert_eq!(rows, 25000);
+        assert!(contains(footer(&file), "job"));
+    }
+
+    #[pg_test]
+    fn test_export_parquet_spans() {
+        Spi::run(&format!(
+            "CALL ps_trace.insert_otlp_traces('\\x{}'::bytea)",
+            PAYLOAD
+        ));
+        let (rows, file) = export(
+            "SELECT ps_trace.export_parquet('-infinity', 'infinity', $1)",
+            "promscale_test_export_parquet_spans.parquet",
+        );
+        assert_eq!(rows, 2);
+        let footer = footer(&file);
+        for s in &[
+            "trace_id",
+            "span_tags",
+            "key_value",
+            "resource_tags",
+            "instrumentation_lib_name",
+            "promscale.trace_retention_period",
+        ] {
+            assert!(contains(footer, s), "{} is missing from the footer", s);
+        }
+    }
+
+    #[pg_test(error = "cannot write Parquet file: metric missing does not exist")]
+    fn test_export_parquet_missing_metric() {
+        export(
+            "SELECT prom_api.export_parquet('missing', '-infinity', 'infinity', $1)",
+            "promscale_test_export_parquet_missing.parquet",
+        );
+    }
+}
@@ -0,0 +1,791 @@
+//! Writes files in the [Apache Parquet][format] format: v1 data pages with
+//! PLAIN encoded values and RLE encoded levels, compressed with Snappy, and a
+//! footer in the Thrift compact protocol.
+//!
+//! Only what exports need is supported: required and optional primitive
+//! columns, required groups of them, and required maps from strings.
+//!
+//! [format]: https://github.com/apache/parquet-format
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::protobuf::write_varint;
+use crate::snappy;
+
+const MAGIC: &[u8] = b"PAR1";
+/// Data pages are cut after this many values (or nulls), at the next row.
+const PAGE_VALUES: usize = 64 * 1024;
+
+const TYPE_INT32: i32 = 1;
+const TYPE_INT64: i32 = 2;
+const TYPE_DOUBLE: i32 = 5;
+const TYPE_BYTE_ARRAY: i32 = 6;
+const TYPE_FIXED_LEN_BYTE_ARRAY: i32 = 7;
+
+const REPETITION_REQUIRED: i32 = 0;
+const REPETITION_OPTIONAL: i32 = 1;
+const REPETITION_REPEATED: i32 = 2;
+
+const CONVERTED_UTF8: i32 = 0;
+const CONVERTED_MAP: i32 = 1;
+const CONVERTED_TIMESTAMP_MICROS: i32 = 10;
+const CONVERTED_JSON: i32 = 19;
+
+const ENCODING_PLAIN: i32 = 0;
+const ENCODING_RLE: i32 = 3;
+const CODEC_SNAPPY: i32 = 1;
+const PAGE_TYPE_DATA: i32 = 0;
+
+/// The type of a primitive column.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Type {
+    Int32,
+    Int64,
+    Double,
+    String,
+    Json,
+    /// Microseconds since the Unix epoch, UTC.
+    TimestampMicros,
+    /// 16 bytes.
+    Uuid,
+}
+
+impl Type {
+    fn physical(self) -> i32 {
+        match self {
+            Type::Int32 => TYPE_INT32,
+            Type::Int64 | Type::TimestampMicros => TYPE_INT64,
+            Type::Double => TYPE_DOUBLE,
+            Type::String | Type::Json => TYPE_BYTE_ARRAY,
+            Type::Uuid => TYPE_FIXED_LEN_BYTE_ARRAY,
+        }
+    }
+
+    fn converted(self) -> Option<i32> {
+        match self {
+            Type::String => Some(CONVERTED_UTF8),
+            Type::Json => Some(CONVERTED_JSON),
+            Type::TimestampMicros => Some(CONVERTED_TIMESTAMP_MICROS),
+            _ => None,
+        }
+    }
+
+    fn logical(self) -> Option<Logical> {
+        match self {
+            Type::String => Some(Logical::String),
+            Type::Json => Some(Logical::Json),
+            Type::TimestampMicros => Some(Logical::TimestampMicros),
+            Type::Uuid => Some(Logical::Uuid),
+            _ => None,
+        }
+    }
+
+    fn accepts(self, values: &Values) -> bool {
+        match values {
+            Values::Int32(_) => self == Type::Int32,
+            Values::Int64(_) => self == Type::Int64 || self == Type::TimestampMicros,
+            Values::Double(_) => self == Type::Double,
+            Values::Bytes(values) => match self {
+                Type::String | Type::Json => true,
+                Type::Uuid => values.iter().all(|v| v.len() == 16),
+                _ => false,
+            },
+        }
+    }
+}
+
+/// The members of the `LogicalType` union that are used.
+#[derive(Debug, Clone, Copy)]
+enum Logical {
+    String,
+    Map,
+    TimestampMicros,
+    Json,
+    Uuid,
+}
+
+/// A field of the schema.
+#[derive(Debug, Clone)]
+pub(crate) enum Field {
+    /// A primitive column, nullable if `optional`.
+    Column {
+        name: String,
+        ty: Type,
+        optional: bool,
+    },
+    /// A struct of fields, with a column per primitive field.
+    Group { name: String, fields: Vec<Field> },
+    /// A map from strings to `value`s, stored as `key` and `value` columns.
+    Map { name: String, value: Type },
+}
+
+impl Field {
+    pub(crate) fn required(name: &str, ty: Type) -> Self {
+        Field::Column {
+            name: name.to_string(),
+            ty,
+            optional: false,
+        }
+    }
+
+    pub(crate) fn optional(name: &str, ty: Type) -> Self {
+        Field::Column {
+            name: name.to_string(),
+            ty,
+            optional: true,
+        }
+    }
+
+    pub(crate) fn group(name: &str, fields: Vec<Field>) -> Self {
+        Field::Group {
+            name: name.to_string(),
+            fields,
+        }
+    }
+
+    pub(crate) fn map(name: &str, value: Type) -> Self {
+        Field::Map {
+            name: name.to_string(),
+            value,
+        }
+    }
+}
+
+/// The values of a column in a row group. Nulls and empty maps are only
+/// represented in the definition levels.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Values {
+    Int32(Vec<i32>),
+    Int64(Vec<i64>),
+    Double(Vec<f64>),
+    Bytes(Vec<Vec<u8>>),
+}
+
+impl Values {
+    fn len(&self) -> usize {
+        match self {
+            Values::Int32(v) => v.len(),
+            Values::Int64(v) => v.len(),
+            Values::Double(v) => v.len(),
+            Values::Bytes(v) => v.len(),
+        }
+    }
+
+    /// Appends the values in the PLAIN encoding, where byte arrays are
+    /// prefixed by their length unless they are of a `fixed_len` type.
+    fn encode_plain(&self, range: std::ops::Range<usize>, fixed_len: bool, out: &mut Vec<u8>) {
+        match self {
+            Values::Int32(v) => v[range]
+                .iter()
+                .for_each(|v| out.extend_from_slice(&v.to_le_bytes())),
+            Values::Int64(v) => v[range]
+                .iter()
+                .for_each(|v| out.extend_from_slice(&v.to_le_bytes())),
+            Values::Double(v) => v[range]
+                .iter()
+                .for_each(|v| out.extend_from_slice(&v.to_le_bytes())),
+            Values::Bytes(v) => v[range].iter().for_each(|v| {
+                if !fixed_len {
+                    out.extend_from_slice(&(v.len() as u32).to_le_bytes());
+                }
+                out.extend_from_slice(v);
+            }),
+        }
+    }
+}
+
+/// A column in a row group: its values, and unless they are implied, its
+/// definition and repetition levels.
+#[derive(Debug, Clone)]
+pub(crate) struct Column {
+    values: Values,
+    def_levels: Vec<u8>,
+    rep_levels: Vec<u8>,
+}
+
+impl Column {
+    pub(crate) fn required(values: Values) -> Self {
+        Self {
+            values,
+            def_levels: vec![],
+            rep_levels: vec![],
+        }
+    }
+
+    /// A column of values that may be null, for optional fields at the top
+    /// level or in a group.
+    pub(crate) fn optional<T>(values: Vec<Option<T>>, wrap: fn(Vec<T>) -> Values) -> Self {
+        let def_levels = values.iter().map(|v| v.is_some() as u8).collect();
+        Self {
+            values: wrap(values.into_iter().flatten().collect()),
+            def_levels,
+            rep_levels: vec![],
+        }
+    }
+
+    /// The `key` and `value` columns of a map, from the entries of each row.
+    pub(crate) fn map(rows: Vec<Vec<(String, Vec<u8>)>>) -> (Self, Self) {
+        let (mut keys, mut values) = (vec![], vec![]);
+        let (mut def_levels, mut rep_levels) = (vec![], vec![]);
+        for row in rows {
+            if row.is_empty() {
+                def_levels.push(0);
+                rep_levels.push(0);
+            }
+            for (i, (key, value)) in row.into_iter().enumerate() {
+                def_levels.push(1);
+                rep_levels.push((i > 0) as u8);
+                keys.push(key.into_bytes());
+                values.push(value);
+            }
+        }
+        let keys = Self {
+            values: Values::Bytes(keys),
+            def_levels: def_levels.clone(),
+            rep_levels: rep_levels.clone(),
+        };
+        let values = Self {
+            values: Values::Bytes(values),
+            def_levels,
+            rep_levels,
+        };
+        (keys, values)
+    }
+}
+
+/// A primitive column of the schema.
+#[derive(Debug)]
+struct Leaf {
+    path: Vec<String>,
+    ty: Type,
+    max_def: u8,
+    max_rep: u8,
+}
+
+#[derive(Debug)]
+struct SchemaElement {
+    name: String,
+    ty: Option<Type>,
+    repetition: Option<i32>,
+    num_children: Option<i32>,
+    converted: Option<i32>,
+    logical: Option<Logical>,
+}
+
+#[derive(Debug)]
+struct ColumnChunkMeta {
+    path: Vec<String>,
+    ty: Type,
+    num_values: i64,
+    uncompressed_size: i64,
+    compressed_size: i64,
+    data_page_offset: i64,
+}
+
+#[derive(Debug)]
+struct RowGroupMeta {
+    columns: Vec<ColumnChunkMeta>,
+    num_rows: i64,
+}
+
+/// The compressed pages of a column chunk, buffered until its row group is
+/// complete, as the chunks of a row group are stored one after the other.
+#[derive(Debug, Default)]
+struct ColumnChunk {
+    pages: Vec<u8>,
+    num_values: i64,
+    uncompressed_size: i64,
+    compressed_size: i64,
+}
+
+/// Writes a Parquet file into `<path>.tmp`, which is renamed to `path` once
+/// the footer is written, and removed if writing fails.
+#[derive(Debug)]
+pub(crate) struct ParquetWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    file: Option<BufWriter<File>>,
+    offset: u64,
+    schema: Vec<SchemaElement>,
+    leaves: Vec<Leaf>,
+    row_groups: Vec<RowGroupMeta>,
+    num_rows: u64,
+    /// The column chunks and number of rows of the row group being written.
+    row_group: Vec<ColumnChunk>,
+    row_group_rows: usize,
+}
+
+impl ParquetWriter {
+    pub(crate) fn create(path: &Path, fields: Vec<Field>) -> Result<Self, String> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let file = File::create(&tmp_path).map_err(|e| format!("{}: {}", tmp_path.display(), e))?;
+
+        let mut schema = vec![SchemaElement {
+            name: "schema".to_string(),
+            ty: None,
+            repetition: None,
+            num_children: Some(fields.len() as i32),
+            converted: None,
+            logical: None,
+        }];
+        let mut leaves = vec![];
+        flatten_schema(&fields, &[], 0, &mut schema, &mut leaves);
+
+        let mut writer = Self {
+            path: path.to_path_buf(),
+            tmp_path,
+            file: Some(BufWriter::new(file)),
+            offset: 0,
+            schema,
+            leaves,
+            row_groups: vec![],
+            num_rows: 0,
+            row_group: vec![],
+            row_group_rows: 0,
+        };
+        writer.write(MAGIC)?;
+        Ok(writer)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let file = self.file.as_mut().expect("writer is not finished");
+        file.write_all(bytes)
+            .map_err(|e| format!("{}: {}", self.tmp_path.display(), e))?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Appends rows to the current row group, with a column per primitive
+    /// column of the schema, in schema order. Only their encoded pages are
+    /// kept, so a large row group can be written in batches of rows.
+    pub(crate) fn write_rows(
+        &mut self,
+        num_rows: usize,
+        columns: Vec<Column>,
+    ) -> Result<(), String> {
+        if columns.len() != self.leaves.len() {
+            return Err(format!(
+                "expected {} columns, got {}",
+                self.leaves.len(),
+                columns.len()
+            ));
+        }
+        if self.row_group.is_empty() {
+            self.row_group = self.leaves.iter().map(|_| ColumnChunk::default()).collect();
+        }
+        for (i, column) in columns.into_iter().enumerate() {
+            let mut chunk = std::mem::take(&mut self.row_group[i]);
+            let encoded = self.encode_column(i, column, &mut chunk);
+            self.row_group[i] = chunk;
+            encoded?;
+        }
+        self.row_group_rows += num_rows;
+        Ok(())
+    }
+
+    /// Writes the rows written since the last row group as a row group.
+    /// Empty row groups are skipped.
+    pub(crate) fn end_row_group(&mut self) -> Result<(), String> {
+        let row_group = std::mem::take(&mut self.row_group);
+        let num_rows = std::mem::take(&mut self.row_group_rows);
+        if num_rows == 0 {
+            return Ok(());
+        }
+        let mut metas = Vec::with_capacity(row_group.len());
+        for (leaf, chunk) in row_group.into_iter().enumerate() {
+            let Leaf { path, ty, .. } = &self.leaves[leaf];
+            metas.push(ColumnChunkMeta {
+                path: path.clone(),
+                ty: *ty,
+                num_values: chunk.num_values,
+                uncompressed_size: chunk.uncompressed_size,
+                compressed_size: chunk.compressed_size,
+                data_page_offset: self.offset as i64,
+            });
+            self.write(&chunk.pages)?;
+        }
+        self.row_groups.push(RowGroupMeta {
+            columns: metas,
+            num_rows: num_rows as i64,
+        });
+        self.num_rows += num_rows as u64;
+        Ok(())
+    }
+
+    /// Appends the pages of `column` to `chunk`.
+    fn encode_column(
+        &self,
+        leaf: usize,
+        column: Column,
+        chunk: &mut ColumnChunk,
+    ) -> Result<(), String> {
+        let Leaf {
+            path,
+            ty,
+            max_def,
+            max_rep,
+        } = &self.leaves[leaf];
+        let (ty, max_def, max_rep) = (*ty, *max_def, *max_rep);
+        if !ty.accepts(&column.values) {
+            return Err(format!("invalid values for column {}", path.join(".")));
+        }
+        let num_values = if max_def == 0 {
+            column.values.len()
+        } else {
+            column.def_levels.len()
+        };
+        let present = column.def_levels.iter().filter(|&&l| l == max_def).count();
+        let consistent = (max_def == 0 || present == column.values.len())
+            && (max_rep == 0 || column.rep_levels.len() == num_values);
+        if !consistent {
+            return Err(format!("inconsistent levels in column {}", path.join(".")));
+        }
+
+        let (mut start, mut value) = (0, 0);
+        while start < num_values {
+            let mut end = (start + PAGE_VALUES).min(num_values);
+            // pages start at a new row
+            while max_rep > 0 && end < num_values && column.rep_levels[end] != 0 {
+                end += 1;
+            }
+            let mut page = vec![];
+            if max_rep > 0 {
+                encode_levels(&column.rep_levels[start..end], &mut page);
+            }
+            let values = if max_def > 0 {
+                encode_levels(&column.def_levels[start..end], &mut page);
+                column.def_levels[start..end]
+                    .iter()
+                    .filter(|&&l| l == max_def)
+                    .count()
+            } else {
+                end - start
+            };
+            let fixed_len = ty == Type::Uuid;
+            column
+                .values
+                .encode_plain(value..value + values, fixed_len, &mut page);
+            let compressed = snappy::compress(&page);
+
+            let mut header = Thrift::default();
+            header.begin();
+            header.i32(1, PAGE_TYPE_DATA);
+            header.i32(2, page.len() as i32);
+            header.i32(3, compressed.len() as i32);
+            header.struct_(5, |h| {
+                h.i32(1, (end - start) as i32);
+                h.i32(2, ENCODING_PLAIN);
+                h.i32(3, ENCODING_RLE);
+                h.i32(4, ENCODING_RLE);
+            });
+            header.end();
+            chunk.pages.extend_from_slice(&header.bytes);
+            chunk.pages.extend_from_slice(&compressed);
+            chunk.uncompressed_size += (header.bytes.len() + page.len()) as i64;
+            chunk.compressed_size += (header.bytes.len() + compressed.len()) as i64;
+            start = end;
+            value += values;
+        }
+        chunk.num_values += num_values as i64;
+        Ok(())
+    }
+
+    /// Writes the footer with the key-value metadata and moves the file into
+    /// place, returning the number of rows written.
+    pub(crate) fn finish(mut self, metadata: &[(String, String)]) -> Result<u64, String> {
+        self.end_row_group()?;
+        let mut footer = Thrift::default();
+        footer.begin();
+        footer.i32(1, 1);
+        footer.list_struct(2, &self.schema, encode_schema_element);
+        footer.i64(3, self.num_rows as i64);
+        footer.list_struct(4, &self.row_groups, |t, row_group| {
+            t.list_struct(1, &row_group.columns, encode_column_chunk);
+            let size = row_group.columns.iter().map(|c| c.uncompressed_size).sum();
+            t.i64(2, size);
+            t.i64(3, row_group.num_rows);
+        });
+        if !metadata.is_empty() {
+            footer.list_struct(5, metadata, |t, (key, value)| {
+                t.binary(1, key.as_bytes());
+                t.binary(2, value.as_bytes());
+            });
+        }
+        footer.binary(
+            6,
+            concat!("promscale_extension version ", env!("CARGO_PKG_VERSION")).as_bytes(),
+        );
+        footer.end();
+
+        self.write(&footer.bytes)?;
+        self.write(&(footer.bytes.len() as u32).to_le_bytes())?;
+        self.write(MAGIC)?;
+        let mut file = self.file.take().expect("writer is not finished");
+        let error = |e: std::io::Error| format!("{}: {}", self.tmp_path.display(), e);
+        file.flush().map_err(error)?;
+        file.get_ref().sync_all().map_err(error)?;
+        std::fs::rename(&self.tmp_path, &self.path)
+            .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        Ok(self.num_rows)
+    }
+}
+
+impl Drop for ParquetWriter {
+    fn drop(&mut self) {
+        // only left behind by errors, it was renamed otherwise
+        let _ = std::fs::remove_file(&self.tmp_path);
+    }
+}
+
+/// Appends the schema elements of `fields` in depth-first order, and their
+/// primitive columns to `leaves`.
+fn flatten_schema(
+    fields: &[Field],
+    parent: &[String],
+    max_def: u8,
+    schema: &mut Vec<SchemaElement>,
+    leaves: &mut Vec<Leaf>,
+) {
+    let path = |name: &str| {
+        let mut path = parent.to_vec();
+        path.push(name.to_string());
+        path
+    };
+    for field in fields {
+        match field {
+            Field::Column { name, ty, optional } => {
+                schema.push(primitive_element(name, *ty, *optional));
+                leaves.push(Leaf {
+                    path: path(name),
+                    ty: *ty,
+                    max_def: max_def + *optional as u8,
+                    max_rep: 0,
+                });
+            }
+            Field::Group { name, fields } => {
+                schema.push(SchemaElement {
+                    name: name.clone(),
+                    ty: None,
+                    repetition: Some(REPETITION_REQUIRED),
+                    num_children: Some(fields.len() as i32),
+                    converted: None,
+                    logical: None,
+                });
+                flatten_schema(fields, &path(name), max_def, schema, leaves);
+            }
+            Field::Map { name, value } => {
+                schema.push(SchemaElement {
+                    name: name.clone(),
+                    ty: None,
+                    repetition: Some(REPETITION_REQUIRED),
+                    num_children: Some(1),
+                    converted: Some(CONVERTED_MAP),
+                    logical: Some(Logical::Map),
+                });
+                schema.push(SchemaElement {
+                    name: "key_value".to_string(),
+                    ty: None,
+                    repetition: Some(REPETITION_REPEATED),
+                    num_children: Some(2),
+                    converted: None,
+                    logical: None,
+                });
+                schema.push(primitive_element("key", Type::String, false));
+                schema.push(primitive_element("value", *value, false));
+                let key_value = path(name).into_iter().chain(Some("key_value".to_string()));
+                for (column, ty) in &[("key", Type::String), ("value", *value)] {
+                    leaves.push(Leaf {
+                        path: key_value.clone().chain(Some(column.to_string())).collect(),
+                        ty: *ty,
+                        max_def: max_def + 1,
+                        max_rep: 1,
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn primitive_element(name: &str, ty: Type, optional: bool) -> SchemaElement {
+    SchemaElement {
+        name: name.to_string(),
+        ty: Some(ty),
+        repetition: Some(if optional {
+            REPETITION_OPTIONAL
+        } else {
+            REPETITION_REQUIRED
+        }),
+        num_children: None,
+        converted: ty.converted(),
+        logical: ty.logical(),
+    }
+}
+
+fn encode_schema_element(t: &mut Thrift, element: &SchemaElement) {
+    if let Some(ty) = element.ty {
+        t.i32(1, ty.physical());
+        if ty == Type::Uuid {
+            t.i32(2, 16);
+        }
+    }
+    if let Some(repetition) = element.repetition {
+        t.i32(3, repetition);
+    }
+    t.binary(4, element.name.as_bytes());
+    if let Some(num_children) = element.num_children {
+        t.i32(5, num_children);
+    }
+    if let Some(converted) = element.converted {
+        t.i32(6, converted);
+    }
+    if let Some(logical) = element.logical {
+        t.struct_(10, |t| match logical {
+            Logical::String => t.struct_(1, |_| {}),
+            Logical::Map => t.struct_(2, |_| {}),
+            Logical::TimestampMicros => t.struct_(8, |t| {
+                // isAdjustedToUTC
+                t.bool(1, true);
+                t.struct_(2, |t| t.struct_(2, |_| {}));
+            }),
+            Logical::Json => t.struct_(12, |_| {}),
+            Logical::Uuid => t.struct_(14, |_| {}),
+        });
+    }
+}
+
+fn encode_column_chunk(t: &mut Thrift, column: &ColumnChunkMeta) {
+    t.i64(2, column.data_page_offset);
+    t.struct_(3, |t| {
+        t.i32(1, column.ty.physical());
+        t.list_i32(2, &[ENCODING_PLAIN, ENCODING_RLE]);
+        t.list_binary(3, &column.path);
+        t.i32(4, CODEC_SNAPPY);
+        t.i64(5, column.num_values);
+        t.i64(6, column.uncompressed_size);
+        t.i64(7, column.compressed_size);
+        t.i64(9, column.data_page_offset);
+    });
+}
+
+/// Appends levels in the RLE/bit-packed hybrid encoding, using only RLE
+/// runs, prefixed by their length. Levels are at most 255, so run values
+/// take a byte whatever the bit width.
+fn encode_levels(levels: &[u8], out: &mut Vec<u8>) {
+    let mut runs = vec![];
+    let mut i = 0;
+    while i < levels.len() {
+        let run = levels[i..].iter().take_while(|&&l| l == levels[i]).count();
+        write_varint(&mut runs, (run as u64) << 1);
+        runs.push(levels[i]);
+        i += run;
+    }
+    out.extend_from_slice(&(runs.len() as u32).to_le_bytes());
+    out.extend_from_slice(&runs);
+}
+
+const THRIFT_TRUE: u8 = 1;
+const THRIFT_FALSE: u8 = 2;
+const THRIFT_I32: u8 = 5;
+const THRIFT_I64: u8 = 6;
+const THRIFT_BINARY: u8 = 8;
+const THRIFT_LIST: u8 = 9;
+const THRIFT_STRUCT: u8 = 12;
+
+/// An encoder for the Thrift compact protocol, where field ids are written
+/// as deltas to the previous field of the struct.
+#[derive(Debug, Default)]
+struct Thrift {
+    bytes: Vec<u8>,
+    last_field: i16,
+    outer_fields: Vec<i16>,
+}
+
+impl Thrift {
+    fn begin(&mut self) {
+        self.outer_fields.push(self.last_field);
+        self.last_field = 0;
+    }
+
+    fn end(&mut self) {
+        self.bytes.push(0);
+        self.last_field = self.outer_fields.pop().expect("a struct was begun");
+    }
+
+    fn field(&mut self, id: i16, ty: u8) {
+        let delta = id - self.last_field;
+        if 0 < delta && delta <= 15 {
+            self.bytes.push((delta as u8) << 4 | ty);
+        } else {
+            self.bytes.push(ty);
+            self.zigzag(id as i64);
+        }
+        self.last_field = id;
+    }
+
+    fn zigzag(&mut self, v: i64) {
+        write_varint(&mut self.bytes, ((v << 1) ^ (v >> 63)) as u64);
+    }
+
+    fn bool(&mut self, id: i16, v: bool) {
+        self.field(id, if v { THRIFT_TRUE } else { THRIFT_FALSE });
+    }
+
+    fn i32(&mut self, id: i16, v: i32) {
+        self.field(id, THRIFT_I32);
+        self.zigzag(v as i64);
+    }
+
+    fn i64(&mut self, id: i16, v: i64) {
+        self.field(id, THRIFT_I64);
+        self.zigzag(v);
+    }
+
+    fn binary(&mut self, id: i16, v: &[u8]) {
+        self.field(id, THRIFT_BINARY);
+        self.raw_binary(v);
+    }
+
+    fn raw_binary(&mut self, v: &[u8]) {
+        write_varint(&mut self.bytes, v.len() as u64);
+        self.bytes.extend_from_slice(v);
+    }
+
+    fn struct_(&mut self, id: i16, f: impl FnOnce(&mut Self)) {
+        self.field(id, THRIFT_STRUCT);
+        self.begin();
+        f(self);
+        self.end();
+    }
+
+    fn list(&mut self, id: i16, ty: u8, len: usize) {
+        self.field(id, THRIFT_LIST);
+        if len < 15 {
+            self.bytes.push((len as u8) << 4 | ty);
+        } else {
+            self.bytes.push(0xf0 | ty);
+            write_varint(&mut self.bytes, len as u64);
+        }
+    }
+
+    fn list_i32(&mut self, id: i16, values: &[i32]) {
+        self.list(id, THRIFT_I32, values.len());
+        values.iter().for_each(|&v| self.zigzag(v as i64));
+    }
+
+    fn list_binary(&mut self, id: i16, values: &[String]) {
+        self.list(id, THRIFT_BINARY, values.len());
+        values.iter().for_each(|v| self.raw_binary(v.as_bytes()));
+    }
+
+    fn list_struct<T>(&mut self, id: i16, items: &[T], f: impl Fn(&mut Self, &T)) {
+        self.list(id, THRIFT_STRUCT, items.len());
+        for item in items {
+            self.begin();
+            f(self, item);
+            self.end();
+        }
+    }
+}
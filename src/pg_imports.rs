@@ -16,9 +16,20 @@ pub struct FuncDetail {
     pub retset: bool,
     pub nvargs: ::std::os::raw::c_int,
     pub vatype: pg_sys::Oid,
+    /// Declared argument types of the resolved function
+    pub arg_type_oids: Vec<pg_sys::Oid>,
     pub code: pg_sys::FuncDetailCode,
 }
 
+/// Copies the `true_typeoids` array, returned by `func_get_detail`, into a [`Vec`].
+#[inline]
+unsafe fn copy_true_typeoids(true_typeoids: *mut pg_sys::Oid, arg_cnt: i32) -> Vec<pg_sys::Oid> {
+    if true_typeoids.is_null() {
+        return Vec::new();
+    }
+    std::slice::from_raw_parts(true_typeoids, arg_cnt as usize).to_vec()
+}
+
 #[cfg(not(any(feature = "pg12", feature = "pg13")))]
 #[inline]
 pub fn func_get_detail<'a, I>(func_path: I, types: &mut [pg_sys::Oid]) -> FuncDetail
@@ -48,6 +59,7 @@ where
             std::ptr::null_mut(),
         )
     };
+    fd_struct.arg_type_oids = unsafe { copy_true_typeoids(true_typeoids, arg_cnt) };
     fd_struct
 }
 
@@ -80,6 +92,7 @@ where
             std::ptr::null_mut(),
         )
     };
+    fd_struct.arg_type_oids = unsafe { copy_true_typeoids(true_typeoids, arg_cnt) };
     fd_struct
 }
 
@@ -113,4 +126,7 @@ extern "C" {
         noError: bool,
         location: ::std::os::raw::c_int,
     ) -> pg_sys::Oid;
+
+    // from "parser/parse_coerce.h"
+    pub fn IsBinaryCoercible(srctype: pg_sys::Oid, targettype: pg_sys::Oid) -> bool;
 }
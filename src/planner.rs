@@ -0,0 +1,641 @@
+//! A planner hook for the rewrites that can't be done by support functions.
+//!
+//! Support functions are only ever invoked for function calls and operators,
+//! thus constructs like `expr IN (...)` or `expr = ANY(...)` (both of which
+//! end up as a [`pg_sys::ScalarArrayOpExpr`]) never reach them. This hook
+//! walks the query tree before it is handed over to the planner and replaces
+//! such constructs with plain function calls, which are then subject to the
+//! usual support function machinery. Queries without such constructs cost
+//! a single walk of the tree, with a syscache lookup per `= ANY(...)`.
+//!
+//! Likewise, aggregates have no say in how their input is scanned. Yet, our
+//! time series aggregates (`vector_selector`, `prom_rate`, `prom_delta` and
+//! `prom_increase`) only accept samples within the time range defined by their
+//! own arguments. The hook turns these arguments into `WHERE` clause predicates
+//! on the sample time column, so that the planner (and TimescaleDB's chunk
+//! exclusion) skip the data the aggregates would reject anyway.
+//!
+//! Note: the hook is installed when the extension's library is loaded into
+//! a backend, i.e. upon the first call to any of its functions, unless it is
+//! listed in `session_preload_libraries` or `shared_preload_libraries`. Either of
+//! the settings makes the rewrites apply from the very first query of a session.
+
+use std::ffi::CString;
+use std::os::raw::c_void;
+
+use pgx::*;
+
+use crate::build_pg_list_of_cstrings;
+use crate::palloc::PallocdString;
+use crate::pg_imports::*;
+
+static mut PREV_PLANNER_HOOK: pg_sys::planner_hook_type = None;
+
+/// Installs the hook, preserving any previously installed one.
+pub unsafe fn init() {
+    PREV_PLANNER_HOOK = pg_sys::planner_hook;
+    pg_sys::planner_hook = Some(planner_hook);
+}
+
+// When compiling against PG12 the hook doesn't receive the query string
+#[cfg(any(feature = "pg12"))]
+#[pg_guard]
+unsafe extern "C" fn planner_hook(
+    parse: *mut pg_sys::Query,
+    cursor_options: ::std::os::raw::c_int,
+    bound_params: pg_sys::ParamListInfo,
+) -> *mut pg_sys::PlannedStmt {
+    let parse = rewrite_query(parse);
+    match PREV_PLANNER_HOOK {
+        Some(prev_hook) => prev_hook(parse, cursor_options, bound_params),
+        None => pg_sys::standard_planner(parse, cursor_options, bound_params),
+    }
+}
+
+#[cfg(not(any(feature = "pg12")))]
+#[pg_guard]
+unsafe extern "C" fn planner_hook(
+    parse: *mut pg_sys::Query,
+    query_string: *const ::std::os::raw::c_char,
+    cursor_options: ::std::os::raw::c_int,
+    bound_params: pg_sys::ParamListInfo,
+) -> *mut pg_sys::PlannedStmt {
+    let parse = rewrite_query(parse);
+    match PREV_PLANNER_HOOK {
+        Some(prev_hook) => prev_hook(parse, query_string, cursor_options, bound_params),
+        None => pg_sys::standard_planner(parse, query_string, cursor_options, bound_params),
+    }
+}
+
+/// Applies all the rewrites to the query.
+unsafe fn rewrite_query(parse: *mut pg_sys::Query) -> *mut pg_sys::Query {
+    if parse.is_null() {
+        return parse;
+    }
+    let parse = rewrite_tag_map_any(parse);
+    time_bounds_walker(parse.cast(), std::ptr::null_mut());
+    parse
+}
+
+/// Walks the tree first so that queries without any candidates are left untouched.
+unsafe fn rewrite_tag_map_any(parse: *mut pg_sys::Query) -> *mut pg_sys::Query {
+    if !pg_sys::query_tree_walker(
+        parse,
+        Some(std::mem::transmute(
+            has_tag_map_any_walker as unsafe extern "C" fn(*mut pg_sys::Node, *mut c_void) -> bool,
+        )),
+        std::ptr::null_mut(),
+        0,
+    ) {
+        return parse;
+    }
+    pg_sys::query_tree_mutator(
+        parse,
+        Some(std::mem::transmute(
+            tag_map_any_mutator
+                as unsafe extern "C" fn(*mut pg_sys::Node, *mut c_void) -> *mut pg_sys::Node,
+        )),
+        std::ptr::null_mut(),
+        0,
+    )
+}
+
+#[pg_guard]
+unsafe extern "C" fn has_tag_map_any_walker(node: *mut pg_sys::Node, context: *mut c_void) -> bool {
+    if node.is_null() {
+        return false;
+    }
+    if pgx::is_a(node, pg_sys::NodeTag_T_ScalarArrayOpExpr)
+        && tag_map_op_func(node.cast()).is_some()
+    {
+        return true;
+    }
+    let walker = Some(std::mem::transmute(
+        has_tag_map_any_walker as unsafe extern "C" fn(*mut pg_sys::Node, *mut c_void) -> bool,
+    ));
+    if pgx::is_a(node, pg_sys::NodeTag_T_Query) {
+        return pg_sys::query_tree_walker(node.cast(), walker, context, 0);
+    }
+    pg_sys::expression_tree_walker(node, walker, context)
+}
+
+const ANY_FUNC_SCHEMA: &str = "ps_trace";
+const TAG_MAP_SUPPORT_FUNC_NAME: &str = "tag_map_rewrite";
+
+/// Rewrites `lhs OP ANY(rhs)` into `ps_trace.OP_FUNC_any(lhs, rhs)`
+/// if `OP_FUNC` has [`crate::support`]'s `tag_map_rewrite` attached and
+/// the corresponding `_any` function exists, e.g.:
+/// ```sql
+/// tag_map_denormalize(map_attribute) -> key IN ('"a"', '"b"')
+/// ```
+/// becomes
+/// ```sql
+/// ps_trace.tag_v_eq_any(tag_map_denormalize(map_attribute) -> key, ARRAY['"a"', '"b"']::jsonb[])
+/// ```
+#[pg_guard]
+unsafe extern "C" fn tag_map_any_mutator(
+    node: *mut pg_sys::Node,
+    context: *mut c_void,
+) -> *mut pg_sys::Node {
+    if node.is_null() {
+        return node;
+    }
+    let mutator = Some(std::mem::transmute(
+        tag_map_any_mutator
+            as unsafe extern "C" fn(*mut pg_sys::Node, *mut c_void) -> *mut pg_sys::Node,
+    ));
+    if pgx::is_a(node, pg_sys::NodeTag_T_Query) {
+        return pg_sys::query_tree_mutator(node.cast(), mutator, context, 0).cast();
+    }
+    let node = pg_sys::expression_tree_mutator(node, mutator, context);
+    if !pgx::is_a(node, pg_sys::NodeTag_T_ScalarArrayOpExpr) {
+        return node;
+    }
+    scalar_array_op_to_any_func(node.cast()).unwrap_or(node)
+}
+
+/// Returns the function underlying the operator of `lhs OP ANY(rhs)`
+/// if it has `tag_map_rewrite` attached as a support function.
+unsafe fn tag_map_op_func(saop: *mut pg_sys::ScalarArrayOpExpr) -> Option<pg_sys::Oid> {
+    // ALL(...) has no index-friendly equivalent
+    if !(*saop).useOr {
+        return None;
+    }
+    // The parser fills in the function, no need to look it up
+    let op_func_oid = if (*saop).opfuncid != pg_sys::InvalidOid {
+        (*saop).opfuncid
+    } else {
+        pg_sys::get_opcode((*saop).opno)
+    };
+    let support_func_oid = pg_sys::get_func_support(op_func_oid);
+    if support_func_oid == pg_sys::InvalidOid {
+        return None;
+    }
+    let support_name_const = CString::new(TAG_MAP_SUPPORT_FUNC_NAME).unwrap();
+    PallocdString::from_ptr(pg_sys::get_func_name(support_func_oid))
+        .filter(|fname| fname.as_c_str() == support_name_const.as_c_str())?;
+    Some(op_func_oid)
+}
+
+unsafe fn scalar_array_op_to_any_func(
+    saop: *mut pg_sys::ScalarArrayOpExpr,
+) -> Option<*mut pg_sys::Node> {
+    let op_func_oid = tag_map_op_func(saop)?;
+
+    let args = PgList::<pg_sys::Node>::from_pg((*saop).args);
+    let lhs = args.head()?;
+    let rhs = args.tail()?;
+
+    let op_func_name_box = PallocdString::from_ptr(pg_sys::get_func_name(op_func_oid))?;
+    let op_func_name = op_func_name_box
+        .as_c_str()
+        .to_str()
+        .expect("Non-UTF8 function name");
+    let any_func_name = format!("{}_any", op_func_name);
+    let mut arg_types = [pg_sys::exprType(lhs), pg_sys::exprType(rhs)];
+    let any_func_detail =
+        func_get_detail([ANY_FUNC_SCHEMA, any_func_name.as_str()], &mut arg_types);
+    if any_func_detail.code != pg_sys::FuncDetailCode_FUNCDETAIL_NORMAL
+        || any_func_detail.ret_type_oid != pg_sys::BOOLOID
+    {
+        return None;
+    }
+    let args_are_compatible = arg_types
+        .iter()
+        .zip(any_func_detail.arg_type_oids.iter())
+        .all(|(&actual, &declared)| IsBinaryCoercible(actual, declared));
+    if !args_are_compatible {
+        return None;
+    }
+
+    let mut any_func_args = PgList::new();
+    any_func_args.push(lhs);
+    any_func_args.push(rhs);
+    let any_func_expr = pg_sys::makeFuncExpr(
+        any_func_detail.func_oid,
+        pg_sys::BOOLOID,
+        any_func_args.into_pg(),
+        pg_sys::InvalidOid,
+        (*saop).inputcollid,
+        pg_sys::CoercionForm_COERCE_EXPLICIT_CALL,
+    );
+    (*any_func_expr).location = (*saop).location;
+    Some(any_func_expr.cast())
+}
+
+const TIME_BOUND_AGG_SCHEMA: &str = "_prom_ext";
+const RANGE_START_FUNC_PATH: [&str; 2] = ["_prom_catalog", "range_start"];
+const LESS_OR_EQUAL_OP_PATH: [&str; 2] = ["pg_catalog", "<="];
+const GREATER_OR_EQUAL_OP_PATH: [&str; 2] = ["pg_catalog", ">="];
+
+/// Positions of the arguments of an aggregate, which define
+/// the time range of the samples it accepts.
+struct TimeBoundArgs {
+    agg_name: &'static str,
+    start_time: usize,
+    end_time: usize,
+    /// The `lookback` of `vector_selector`, the window `range` of the others
+    range: usize,
+    sample_time: usize,
+}
+
+/// All of the aggregates take `(start, end, step, lookback/range, sample_time, ...)`.
+/// They never accept samples prior to `start - range`, so the bound never
+/// discards anything the aggregate would've taken into account.
+const TIME_BOUND_AGGS: [TimeBoundArgs; 4] = [
+    TimeBoundArgs {
+        agg_name: "vector_selector",
+        start_time: 0,
+        end_time: 1,
+        range: 3,
+        sample_time: 4,
+    },
+    TimeBoundArgs {
+        agg_name: "prom_rate",
+        start_time: 0,
+        end_time: 1,
+        range: 3,
+        sample_time: 4,
+    },
+    TimeBoundArgs {
+        agg_name: "prom_delta",
+        start_time: 0,
+        end_time: 1,
+        range: 3,
+        sample_time: 4,
+    },
+    TimeBoundArgs {
+        agg_name: "prom_increase",
+        start_time: 0,
+        end_time: 1,
+        range: 3,
+        sample_time: 4,
+    },
+];
+
+/// Visits every (sub)query of the tree and adds time bound predicates where possible.
+#[pg_guard]
+unsafe extern "C" fn time_bounds_walker(node: *mut pg_sys::Node, context: *mut c_void) -> bool {
+    if node.is_null() {
+        return false;
+    }
+    let walker = Some(std::mem::transmute(
+        time_bounds_walker as unsafe extern "C" fn(*mut pg_sys::Node, *mut c_void) -> bool,
+    ));
+    if pgx::is_a(node, pg_sys::NodeTag_T_Query) {
+        let query = node.cast::<pg_sys::Query>();
+        add_time_bound_quals(query);
+        return pg_sys::query_tree_walker(query, walker, context, 0);
+    }
+    pg_sys::expression_tree_walker(node, walker, context)
+}
+
+/// Injects `sample_time >= start_time - range AND sample_time <= end_time`
+/// into the `WHERE` clause of the query for each of the supported aggregates, e.g.:
+/// ```sql
+/// SELECT vector_selector(start, end, step, lookback, t, v) FROM metric
+/// ```
+/// becomes
+/// ```sql
+/// SELECT vector_selector(start, end, step, lookback, t, v) FROM metric
+/// WHERE t >= _prom_catalog.range_start(start, lookback) AND t <= end
+/// ```
+///
+/// This only discards rows the aggregates would've raised an error on, hence
+/// the rewrite is only applied if all the aggregates of the query are supported
+/// and all their time bounds are constant for the duration of the query.
+unsafe fn add_time_bound_quals(query: *mut pg_sys::Query) {
+    if !(*query).hasAggs
+        || (*query).commandType != pg_sys::CmdType_CMD_SELECT
+        || (*query).jointree.is_null()
+        || has_outer_joins((*query).jointree.cast())
+    {
+        return;
+    }
+
+    let mut aggrefs = AggrefCollector::default();
+    aggref_collector_walker(
+        (*query).targetList.cast(),
+        (&mut aggrefs as *mut AggrefCollector).cast(),
+    );
+    aggref_collector_walker(
+        (*query).havingQual,
+        (&mut aggrefs as *mut AggrefCollector).cast(),
+    );
+    if aggrefs.found.is_empty() {
+        return;
+    }
+
+    let mut quals = PgList::<pg_sys::Node>::new();
+    for aggref in aggrefs.found {
+        match aggref_time_bound_quals(aggref) {
+            Some((lower, upper)) => {
+                quals.push(lower);
+                quals.push(upper);
+            }
+            // Filtering would affect the results of this aggregate
+            None => return,
+        }
+    }
+
+    let jointree = (*query).jointree;
+    if !(*jointree).quals.is_null() {
+        quals.push((*jointree).quals);
+    }
+    (*jointree).quals =
+        pg_sys::makeBoolExpr(pg_sys::BoolExprType_AND_EXPR, quals.into_pg(), -1).cast();
+}
+
+/// Outer joins produce rows with `NULL` sample times,
+/// which the aggregates skip, but a `WHERE` clause wouldn't.
+unsafe fn has_outer_joins(node: *mut pg_sys::Node) -> bool {
+    if node.is_null() {
+        return false;
+    }
+    if pgx::is_a(node, pg_sys::NodeTag_T_FromExpr) {
+        let from_list =
+            PgList::<pg_sys::Node>::from_pg((*node.cast::<pg_sys::FromExpr>()).fromlist);
+        return from_list.iter_ptr().any(|item| has_outer_joins(item));
+    }
+    if pgx::is_a(node, pg_sys::NodeTag_T_JoinExpr) {
+        let join = node.cast::<pg_sys::JoinExpr>();
+        return (*join).jointype != pg_sys::JoinType_JOIN_INNER
+            || has_outer_joins((*join).larg)
+            || has_outer_joins((*join).rarg);
+    }
+    false
+}
+
+#[derive(Default)]
+struct AggrefCollector {
+    /// Nesting level of the (sub)query being visited
+    depth: u32,
+    found: Vec<*mut pg_sys::Aggref>,
+}
+
+/// Collects the aggregates that belong to the query the walk started at,
+/// including the ones referenced from within its subqueries.
+#[pg_guard]
+unsafe extern "C" fn aggref_collector_walker(
+    node: *mut pg_sys::Node,
+    context: *mut c_void,
+) -> bool {
+    if node.is_null() {
+        return false;
+    }
+    let collector = context.cast::<AggrefCollector>();
+    let walker = Some(std::mem::transmute(
+        aggref_collector_walker as unsafe extern "C" fn(*mut pg_sys::Node, *mut c_void) -> bool,
+    ));
+    if pgx::is_a(node, pg_sys::NodeTag_T_Query) {
+        (*collector).depth += 1;
+        let result = pg_sys::query_tree_walker(node.cast(), walker, context, 0);
+        (*collector).depth -= 1;
+        return result;
+    }
+    if pgx::is_a(node, pg_sys::NodeTag_T_Aggref)
+        && (*node.cast::<pg_sys::Aggref>()).agglevelsup == (*collector).depth
+    {
+        (*collector).found.push(node.cast());
+        // aggregates can't be nested
+        return false;
+    }
+    pg_sys::expression_tree_walker(node, walker, context)
+}
+
+/// Returns `(sample_time >= lower_bound, sample_time <= upper_bound)` for a supported aggregate.
+unsafe fn aggref_time_bound_quals(
+    aggref: *mut pg_sys::Aggref,
+) -> Option<(*mut pg_sys::Node, *mut pg_sys::Node)> {
+    let agg_oid = (*aggref).aggfnoid;
+    let schema_name = PallocdString::from_ptr(pg_sys::get_namespace_name(
+        pg_sys::get_func_namespace(agg_oid),
+    ))?;
+    let schema_name_const = CString::new(TIME_BOUND_AGG_SCHEMA).unwrap();
+    if schema_name.as_c_str() != schema_name_const.as_c_str() {
+        return None;
+    }
+    let agg_name = PallocdString::from_ptr(pg_sys::get_func_name(agg_oid))?;
+    let agg_name = agg_name
+        .as_c_str()
+        .to_str()
+        .expect("Non-UTF8 function name");
+    let bound_args = TIME_BOUND_AGGS
+        .iter()
+        .find(|agg| agg.agg_name == agg_name)?;
+
+    // Aggregate arguments are wrapped into TargetEntry nodes
+    let args = PgList::<pg_sys::TargetEntry>::from_pg((*aggref).args);
+    let arg = |idx: usize| {
+        args.get_ptr(idx)
+            .map(|te| (*te).expr.cast::<pg_sys::Node>())
+    };
+
+    let sample_time = arg(bound_args.sample_time)?;
+    if !pgx::is_a(sample_time, pg_sys::NodeTag_T_Var)
+        || (*sample_time.cast::<pg_sys::Var>()).varlevelsup != 0
+        || pg_sys::exprType(sample_time) != pg_sys::TIMESTAMPTZOID
+    {
+        return None;
+    }
+
+    let start_time = arg(bound_args.start_time)?;
+    let end_time = arg(bound_args.end_time)?;
+    let range = arg(bound_args.range)?;
+    if !is_query_constant(start_time) || !is_query_constant(end_time) || !is_query_constant(range) {
+        return None;
+    }
+    let lower_bound = range_start(start_time, range)?;
+
+    Some((
+        make_time_comparison(GREATER_OR_EQUAL_OP_PATH, sample_time, lower_bound),
+        make_time_comparison(LESS_OR_EQUAL_OP_PATH, sample_time, end_time),
+    ))
+}
+
+/// Whether the expression is guaranteed to yield the same value for every row.
+unsafe fn is_query_constant(expr: *mut pg_sys::Node) -> bool {
+    !pg_sys::contain_var_clause(expr)
+        && !pg_sys::contain_volatile_functions(expr)
+        && !pg_sys::contain_subplans(expr)
+}
+
+/// Builds `_prom_catalog.range_start(start_time, range)` call.
+unsafe fn range_start(
+    start_time: *mut pg_sys::Node,
+    range: *mut pg_sys::Node,
+) -> Option<*mut pg_sys::Node> {
+    let mut arg_types = [pg_sys::exprType(start_time), pg_sys::exprType(range)];
+    let func_detail = func_get_detail(RANGE_START_FUNC_PATH, &mut arg_types);
+    if func_detail.code != pg_sys::FuncDetailCode_FUNCDETAIL_NORMAL
+        || func_detail.ret_type_oid != pg_sys::TIMESTAMPTZOID
+        || func_detail.arg_type_oids.as_slice() != arg_types
+    {
+        return None;
+    }
+    let mut func_args = PgList::<pg_sys::Node>::new();
+    func_args.push(pg_sys::copyObjectImpl(start_time.cast()).cast());
+    func_args.push(pg_sys::copyObjectImpl(range.cast()).cast());
+    let func_expr = pg_sys::makeFuncExpr(
+        func_detail.func_oid,
+        pg_sys::TIMESTAMPTZOID,
+        func_args.into_pg(),
+        pg_sys::InvalidOid,
+        pg_sys::InvalidOid,
+        pg_sys::CoercionForm_COERCE_EXPLICIT_CALL,
+    );
+    Some(func_expr.cast())
+}
+
+/// Builds `sample_time OP bound`, where both sides are `timestamptz`.
+unsafe fn make_time_comparison(
+    op_path: [&str; 2],
+    sample_time: *mut pg_sys::Node,
+    bound: *mut pg_sys::Node,
+) -> *mut pg_sys::Node {
+    let op_name = build_pg_list_of_cstrings(op_path);
+    let op_oid = LookupOperName(
+        std::ptr::null_mut(),
+        op_name.as_ptr(),
+        pg_sys::TIMESTAMPTZOID,
+        pg_sys::TIMESTAMPTZOID,
+        false, // Raises an error if the operator is not found
+        -1,
+    );
+    pg_sys::make_opclause(
+        op_oid,
+        pg_sys::BOOLOID,
+        false, // not a set returning operator
+        pg_sys::copyObjectImpl(sample_time.cast()).cast(),
+        pg_sys::copyObjectImpl(bound.cast()).cast(),
+        pg_sys::InvalidOid,
+        pg_sys::InvalidOid,
+    )
+    .cast()
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+
+    use pgx::*;
+
+    fn setup() {
+        Spi::run(
+            r#"
+            CREATE TABLE time_bounds_test_table(t TIMESTAMPTZ, v DOUBLE PRECISION);
+            INSERT INTO time_bounds_test_table (t, v) VALUES
+                ('2000-01-02 14:00:00 UTC', -1),
+                ('2000-01-02 14:58:00 UTC', 1),
+                ('2000-01-02 15:00:00 UTC', 2),
+                ('2000-01-02 15:07:00 UTC', 3),
+                ('2000-01-02 15:10:00 UTC', 4),
+                ('2000-01-02 16:00:00 UTC', -1);
+            "#,
+        );
+    }
+
+    #[pg_test]
+    fn test_vector_selector_time_bounds_injected() {
+        setup();
+        // Would've raised "input time less than expected" without the time bounds
+        let result = Spi::get_one::<Vec<Option<f64>>>(
+            r#"
+            SELECT
+                vector_selector(
+                  '2000-01-02 15:00:00 UTC'::TIMESTAMPTZ
+                , '2000-01-02 15:10:00 UTC'::TIMESTAMPTZ
+                , 10 * 60 * 1000
+                , 5 * 60 * 1000
+                , t
+                , v)
+            FROM time_bounds_test_table;
+            "#,
+        )
+        .expect("SQL query failed");
+        assert_eq!(result, vec![Some(2_f64), Some(4_f64)]);
+    }
+
+    #[pg_test]
+    fn test_prom_rate_time_bounds_output_as_expected() {
+        setup();
+        let result = Spi::get_one::<Json>(
+            r#"
+            EXPLAIN (COSTS OFF, FORMAT JSON)
+            SELECT
+                prom_rate(
+                  '2000-01-02 15:00:00 UTC'::TIMESTAMPTZ
+                , '2000-01-02 15:10:00 UTC'::TIMESTAMPTZ
+                , 10 * 60 * 1000
+                , 5 * 60 * 1000
+                , t
+                , v ORDER BY t)
+            FROM time_bounds_test_table;
+            "#,
+        )
+        .expect("SQL query failed");
+        let filter = result.0[0]["Plan"]["Plans"][0]["Filter"]
+            .as_str()
+            .expect("expected a filter")
+            .to_string();
+        // The lower bound is extended by the range
+        assert!(
+            filter.contains("(t >= ('2000-01-02 15:00:00+00'::timestamp with time zone - '00:05:00'::interval))"),
+            "unexpected filter: {}",
+            filter
+        );
+        assert!(filter.contains("(t <= "), "unexpected filter: {}", filter);
+    }
+
+    #[pg_test]
+    fn test_prom_rate_time_bounds_keep_output() {
+        setup();
+        let query = |where_clause: &str| {
+            Spi::get_one::<Vec<Option<f64>>>(&format!(
+                r#"
+                SELECT
+                    prom_rate(
+                      '2000-01-02 15:00:00 UTC'::TIMESTAMPTZ
+                    , '2000-01-02 15:10:00 UTC'::TIMESTAMPTZ
+                    , 5 * 60 * 1000
+                    , 5 * 60 * 1000
+                    , t
+                    , v ORDER BY t)
+                FROM time_bounds_test_table
+                WHERE {};
+                "#,
+                where_clause
+            ))
+            .expect("SQL query failed")
+        };
+        // Only the lower bound is given, the upper one is injected
+        let injected = query("t >= '2000-01-02 15:00:00 UTC'");
+        let explicit = query("t BETWEEN '2000-01-02 15:00:00 UTC' AND '2000-01-02 15:10:00 UTC'");
+        assert_eq!(injected, explicit);
+    }
+
+    #[pg_test]
+    fn test_time_bounds_skipped_with_other_aggregates() {
+        setup();
+        let result = Spi::get_one::<Json>(
+            r#"
+            EXPLAIN (COSTS OFF, FORMAT JSON)
+            SELECT
+                count(*),
+                vector_selector(
+                  '2000-01-02 15:00:00 UTC'::TIMESTAMPTZ
+                , '2000-01-02 15:10:00 UTC'::TIMESTAMPTZ
+                , 10 * 60 * 1000
+                , 5 * 60 * 1000
+                , t
+                , v)
+            FROM time_bounds_test_table;
+            "#,
+        )
+        .expect("SQL query failed");
+        assert_eq!(
+            result.0[0]["Plan"]["Plans"][0]["Filter"],
+            serde_json::Value::Null
+        );
+    }
+}
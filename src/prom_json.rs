@@ -0,0 +1,393 @@
+//! # Prometheus HTTP API responses
+//!
+//! Aggregates rendering query results as the JSON responses of the Prometheus
+//! [HTTP API][api], so that a proxy can pass them through unchanged:
+//!
+//! ```sql
+//! SELECT prom_api.to_prom_matrix_json(
+//!     prom_api.jsonb(s.labels),
+//!     ARRAY(SELECT generate_series(now() - interval '1 hour', now(), interval '1 minute')),
+//!     r.values)
+//! FROM (
+//!     SELECT series_id, _prom_ext.vector_selector(now() - interval '1 hour', now(), 60 * 1000, 5 * 60 * 1000, time, value) AS values
+//!     FROM prom_data.up
+//!     GROUP BY series_id
+//! ) r
+//! INNER JOIN prom_data_series.up s ON s.id = r.series_id;
+//! ```
+//!
+//! `to_prom_matrix_json` takes a series' labels with the values at the
+//! evaluation times, as returned by `vector_selector` or `prom_rate`, and
+//! renders a `/api/v1/query_range` response with `resultType` `matrix`. `NULL`
+//! values, i.e. evaluation times without a sample, are omitted, as are series
+//! without any values, and the series are sorted by their labels.
+//! `to_prom_vector_json` takes a value per series and renders a
+//! `/api/v1/query` response with `resultType` `vector`, keeping the order of
+//! the rows and skipping those with a `NULL` value.
+//!
+//! The output matches Prometheus' byte for byte: timestamps are seconds with
+//! millisecond precision, values are strings formatted like Go's
+//! `strconv.FormatFloat` (switching to exponent notation below 1e-6 and from
+//! 1e21 on, `NaN`, `+Inf` and `-Inf`), and strings are escaped like Go's
+//! `encoding/json` does. Without any rows, the result is a response with an
+//! empty `result`.
+//!
+//! [api]: https://prometheus.io/docs/prometheus/latest/querying/api/#expression-query-result-formats
+use pgx::utils::sql_entity_graph::metadata::{
+    ArgumentError, Returns, ReturnsError, SqlMapping, SqlTranslatable,
+};
+use pgx::*;
+
+use std::fmt::Write;
+
+use serde_json::Value;
+
+// Trick DDL generator into recognizing our implementation of a built-in type.
+extension_sql!(
+    "",
+    name = "json_text_pseudotype",
+    creates = [Type(JsonText)],
+);
+
+/// JSON text returned as is, as `json`. Unlike [`pgx::Json`] it isn't
+/// re-serialized, which would lose the exact formatting.
+pub struct JsonText(pub String);
+
+unsafe impl SqlTranslatable for JsonText {
+    fn argument_sql() -> Result<SqlMapping, ArgumentError> {
+        Ok(SqlMapping::literal("json"))
+    }
+    fn return_sql() -> Result<Returns, ReturnsError> {
+        Ok(Returns::One(SqlMapping::literal("json")))
+    }
+}
+
+impl IntoDatum for JsonText {
+    // `json` is stored as text
+    fn into_datum(self) -> Option<pg_sys::Datum> {
+        self.0.into_datum()
+    }
+    fn type_oid() -> pg_sys::Oid {
+        pg_sys::JSONOID
+    }
+}
+
+#[pg_schema]
+mod _prom_ext {
+    use pgx::*;
+
+    use super::{labels_of, render_matrix, render_vector, JsonText, Series};
+    use crate::aggregate_utils::in_aggregate_context;
+    use crate::palloc::{Inner, InternalAsValue, ToInternal};
+    use crate::prompb::from_pg_timestamp;
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn to_prom_matrix_json_transition(
+        state: Internal,
+        labels: Option<JsonB>,
+        eval_times: Option<Vec<Option<TimestampWithTimeZone>>>,
+        values: Option<Vec<Option<f64>>>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        to_prom_json_transition_inner(
+            unsafe { state.to_inner() },
+            labels,
+            eval_times.unwrap_or_default(),
+            values.unwrap_or_default(),
+            fcinfo,
+        )
+        .internal()
+    }
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn to_prom_vector_json_transition(
+        state: Internal,
+        labels: Option<JsonB>,
+        eval_time: Option<TimestampWithTimeZone>,
+        value: Option<f64>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        to_prom_json_transition_inner(
+            unsafe { state.to_inner() },
+            labels,
+            vec![eval_time],
+            vec![value],
+            fcinfo,
+        )
+        .internal()
+    }
+
+    fn to_prom_json_transition_inner(
+        state: Option<Inner<Vec<Series>>>,
+        labels: Option<JsonB>,
+        eval_times: Vec<Option<TimestampWithTimeZone>>,
+        values: Vec<Option<f64>>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<Vec<Series>>> {
+        if eval_times.len() != values.len() {
+            error!(
+                "got {} evaluation times but {} values",
+                eval_times.len(),
+                values.len()
+            );
+        }
+        unsafe {
+            in_aggregate_context(fcinfo, || {
+                let mut state = state.unwrap_or_else(|| Vec::new().into());
+                let points: Vec<(i64, f64)> = eval_times
+                    .into_iter()
+                    .zip(values)
+                    .filter_map(|(time, value)| {
+                        let time = time.unwrap_or_else(|| error!("evaluation time is NULL"));
+                        value.map(|value| (from_pg_timestamp(time.into()), value))
+                    })
+                    .collect();
+                if !points.is_empty() {
+                    state.push(Series {
+                        labels: labels_of(labels.map(|l| l.0)),
+                        points,
+                    });
+                }
+                Some(state)
+            })
+        }
+    }
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn to_prom_matrix_json_final(
+        state: Internal, /* Option<Inner<Vec<Series>>> */
+    ) -> JsonText {
+        let state: Option<Inner<Vec<Series>>> = unsafe { state.to_inner() };
+        let mut series: Vec<&Series> = state
+            .as_deref()
+            .map(|s| s.iter().collect())
+            .unwrap_or_default();
+        JsonText(render_matrix(&mut series))
+    }
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn to_prom_vector_json_final(
+        state: Internal, /* Option<Inner<Vec<Series>>> */
+    ) -> JsonText {
+        let state: Option<Inner<Vec<Series>>> = unsafe { state.to_inner() };
+        let series: Vec<&Series> = state
+            .as_deref()
+            .map(|s| s.iter().collect())
+            .unwrap_or_default();
+        JsonText(render_vector(&series))
+    }
+}
+
+/// A series with its points. Timestamps are milliseconds since the Unix epoch.
+#[derive(Debug)]
+struct Series {
+    /// Sorted by name.
+    labels: Vec<(String, String)>,
+    points: Vec<(i64, f64)>,
+}
+
+fn labels_of(labels: Option<Value>) -> Vec<(String, String)> {
+    match labels {
+        None | Some(Value::Null) => vec![],
+        Some(Value::Object(map)) => map
+            .into_iter()
+            .map(|(name, value)| match value {
+                Value::String(value) => (name, value),
+                _ => error!("value of label \"{}\" must be a string", name),
+            })
+            .collect(),
+        Some(_) => error!("labels must be a JSON object"),
+    }
+}
+
+/// Renders a `matrix` response with the series sorted by labels, the way the
+/// Prometheus engine sorts range query results.
+fn render_matrix(series: &mut [&Series]) -> String {
+    series.sort_by(|a, b| a.labels.cmp(&b.labels));
+    let mut out = String::from(r#"{"status":"success","data":{"resultType":"matrix","result":["#);
+    for (i, s) in series.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(r#"{"metric":"#);
+        render_labels(&mut out, &s.labels);
+        out.push_str(r#","values":["#);
+        for (j, &(ts, value)) in s.points.iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            render_point(&mut out, ts, value);
+        }
+        out.push_str("]}");
+    }
+    out.push_str("]}}");
+    out
+}
+
+/// Renders a `vector` response with a sample per series, in input order.
+fn render_vector(series: &[&Series]) -> String {
+    let mut out = String::from(r#"{"status":"success","data":{"resultType":"vector","result":["#);
+    for (i, s) in series.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(r#"{"metric":"#);
+        render_labels(&mut out, &s.labels);
+        out.push_str(r#","value":"#);
+        let (ts, value) = s.points[0];
+        render_point(&mut out, ts, value);
+        out.push('}');
+    }
+    out.push_str("]}}");
+    out
+}
+
+fn render_labels(out: &mut String, labels: &[(String, String)]) {
+    out.push('{');
+    for (i, (name, value)) in labels.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        render_string(out, name);
+        out.push(':');
+        render_string(out, value);
+    }
+    out.push('}');
+}
+
+/// `[<seconds>,"<value>"]`, the timestamp with up to three decimals, as
+/// Prometheus' `jsonutil.MarshalTimestamp` writes it.
+fn render_point(out: &mut String, ms: i64, value: f64) {
+    out.push('[');
+    if ms < 0 {
+        out.push('-');
+    }
+    let abs = ms.unsigned_abs();
+    write!(out, "{}", abs / 1000).unwrap();
+    if abs % 1000 != 0 {
+        write!(out, ".{:03}", abs % 1000).unwrap();
+    }
+    out.push_str(",\"");
+    out.push_str(&format_value(value));
+    out.push_str("\"]");
+}
+
+/// Like Go's `strconv.FormatFloat(v, f, -1, 64)`, with `f` being `'e'` for
+/// absolute values below 1e-6 or from 1e21 on and `'f'` otherwise.
+fn format_value(v: f64) -> String {
+    if v.is_nan() {
+        return "NaN".to_string();
+    }
+    if v.is_infinite() {
+        return if v > 0.0 { "+Inf" } else { "-Inf" }.to_string();
+    }
+    let abs = v.abs();
+    if abs != 0.0 && !(1e-6..1e21).contains(&abs) {
+        // Rust writes `1.5e-7`, Go `1.5e-07`
+        let formatted = format!("{:e}", v);
+        let (mantissa, exponent) = formatted.split_at(formatted.find('e').unwrap());
+        let exponent: i32 = exponent[1..].parse().unwrap();
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+    } else {
+        v.to_string()
+    }
+}
+
+/// Writes a JSON string the way Go's `encoding/json` does, which also escapes
+/// `<`, `>`, `&` and the line and paragraph separators.
+fn render_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '<' | '>' | '&' | '\u{2028}' | '\u{2029}' => {
+                write!(out, "\\u{:04x}", c as u32).unwrap()
+            }
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+
+    #[pg_test]
+    fn test_to_prom_matrix_json() {
+        let result = Spi::get_one::<String>(
+            r#"SELECT prom_api.to_prom_matrix_json(labels, eval_times, "values")::text
+               FROM (VALUES
+                   ('{"job": "b<&>", "__name__": "up"}'::jsonb,
+                    ARRAY['1970-01-01 00:00:01.5+00', '1970-01-01 00:00:02+00']::timestamptz[],
+                    ARRAY[1, NULL]::float8[]),
+                   ('{"__name__": "up", "job": "a\"\n"}',
+                    ARRAY['1970-01-01 00:00:01.5+00', '1970-01-01 00:00:02+00']::timestamptz[],
+                    ARRAY['NaN', '-Infinity']::float8[]),
+                   ('{"job": "c"}', ARRAY['1970-01-01 00:00:01+00']::timestamptz[], ARRAY[NULL]::float8[]),
+                   ('{}', ARRAY['1969-12-31 23:59:59.99+00']::timestamptz[], ARRAY[0.0000001]::float8[]),
+                   (NULL, ARRAY['2000-01-01 00:00:00+00']::timestamptz[], ARRAY[1e21 + 1e6]::float8[])
+               ) AS v(labels, eval_times, "values")"#,
+        )
+        .expect("SQL query failed");
+        assert_eq!(
+            result,
+            concat!(
+                r#"{"status":"success","data":{"resultType":"matrix","result":["#,
+                r#"{"metric":{},"values":[[-0.010,"1e-07"]]},"#,
+                r#"{"metric":{},"values":[[946684800,"1.000000000000001e+21"]]},"#,
+                r#"{"metric":{"__name__":"up","job":"a\"\n"},"values":[[1.500,"NaN"],[2,"-Inf"]]},"#,
+                r#"{"metric":{"__name__":"up","job":"b\u003c\u0026\u003e"},"values":[[1.500,"1"]]}"#,
+                r#"]}}"#
+            )
+        );
+    }
+
+    #[pg_test]
+    fn test_to_prom_vector_json() {
+        let result = Spi::get_one::<String>(
+            r#"SELECT prom_api.to_prom_vector_json(labels, eval_time, value)::text
+               FROM (VALUES
+                   ('{"job": "b"}'::jsonb, '2023-01-01 00:00:00+00'::timestamptz, 0.25::float8),
+                   ('{"job": "c"}', '2023-01-01 00:00:00+00', NULL),
+                   ('{"job": "a"}', '2023-01-01 00:00:00+00', 123456789)
+               ) AS v(labels, eval_time, value)"#,
+        )
+        .expect("SQL query failed");
+        assert_eq!(
+            result,
+            concat!(
+                r#"{"status":"success","data":{"resultType":"vector","result":["#,
+                r#"{"metric":{"job":"b"},"value":[1672531200,"0.25"]},"#,
+                r#"{"metric":{"job":"a"},"value":[1672531200,"123456789"]}"#,
+                r#"]}}"#
+            )
+        );
+    }
+
+    #[pg_test]
+    fn test_to_prom_matrix_json_no_rows() {
+        let result = Spi::get_one::<String>(
+            "SELECT prom_api.to_prom_matrix_json('{}', ARRAY[now()], ARRAY[1]::float8[])::text WHERE false",
+        )
+        .expect("SQL query failed");
+        assert_eq!(
+            result,
+            r#"{"status":"success","data":{"resultType":"matrix","result":[]}}"#
+        );
+    }
+
+    #[pg_test(error = "got 2 evaluation times but 1 values")]
+    fn test_to_prom_matrix_json_length_mismatch() {
+        Spi::run(
+            "SELECT prom_api.to_prom_matrix_json('{}', ARRAY[now(), now()], ARRAY[1]::float8[])",
+        );
+    }
+}
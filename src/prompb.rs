@@ -0,0 +1,324 @@
+//! Hand-rolled bindings for the subset of the [`prometheus` protobuf package][prompb]
+//! used by the remote write and remote read protocols.
+//!
+//! [prompb]: https://github.com/prometheus/prometheus/tree/main/prompb
+
+use crate::protobuf::{Reader, Writer};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Label {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Sample {
+    pub value: f64,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Exemplar {
+    pub labels: Vec<Label>,
+    pub value: f64,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct TimeSeries {
+    pub labels: Vec<Label>,
+    pub samples: Vec<Sample>,
+    pub exemplars: Vec<Exemplar>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct MetricMetadata {
+    pub metric_type: i32,
+    pub metric_family_name: String,
+    pub help: String,
+    pub unit: String,
+}
+
+impl MetricMetadata {
+    /// The `MetricType` enum value, named the way Prometheus' HTTP API names it.
+    pub fn type_name(&self) -> &'static str {
+        match self.metric_type {
+            1 => "counter",
+            2 => "gauge",
+            3 => "histogram",
+            4 => "gaugehistogram",
+            5 => "summary",
+            6 => "info",
+            7 => "stateset",
+            _ => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct WriteRequest {
+    pub timeseries: Vec<TimeSeries>,
+    pub metadata: Vec<MetricMetadata>,
+}
+
+impl WriteRequest {
+    /// Decodes a snappy-compressed `prometheus.WriteRequest`, as sent in the
+    /// body of a remote write HTTP request.
+    pub fn decode_compressed(payload: &[u8]) -> Result<Self, String> {
+        let raw = crate::snappy::decompress(payload)?;
+        Self::decode(&raw)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut request = WriteRequest::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => request.timeseries.push(TimeSeries::decode(
+                    value.as_bytes("WriteRequest.timeseries")?,
+                )?),
+                3 => request.metadata.push(MetricMetadata::decode(
+                    value.as_bytes("WriteRequest.metadata")?,
+                )?),
+                _ => {}
+            }
+        }
+        Ok(request)
+    }
+}
+
+impl TimeSeries {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut series = TimeSeries::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => series
+                    .labels
+                    .push(Label::decode(value.as_bytes("TimeSeries.labels")?)?),
+                2 => series
+                    .samples
+                    .push(Sample::decode(value.as_bytes("TimeSeries.samples")?)?),
+                3 => series
+                    .exemplars
+                    .push(Exemplar::decode(value.as_bytes("TimeSeries.exemplars")?)?),
+                // native histograms (field 4) are not supported yet
+                _ => {}
+            }
+        }
+        Ok(series)
+    }
+
+    /// The value of the `__name__` label, if any.
+    pub fn metric_name(&self) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|l| l.name == "__name__")
+            .map(|l| l.value.as_str())
+    }
+}
+
+impl Label {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut label = Label {
+            name: String::new(),
+            value: String::new(),
+        };
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => label.name = value.as_str("Label.name")?.to_string(),
+                2 => label.value = value.as_str("Label.value")?.to_string(),
+                _ => {}
+            }
+        }
+        Ok(label)
+    }
+}
+
+impl Sample {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut sample = Sample {
+            value: 0.0,
+            timestamp: 0,
+        };
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => sample.value = value.as_f64("Sample.value")?,
+                2 => sample.timestamp = value.as_i64("Sample.timestamp")?,
+                _ => {}
+            }
+        }
+        Ok(sample)
+    }
+}
+
+impl Exemplar {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut exemplar = Exemplar {
+            labels: vec![],
+            value: 0.0,
+            timestamp: 0,
+        };
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => exemplar
+                    .labels
+                    .push(Label::decode(value.as_bytes("Exemplar.labels")?)?),
+                2 => exemplar.value = value.as_f64("Exemplar.value")?,
+                3 => exemplar.timestamp = value.as_i64("Exemplar.timestamp")?,
+                _ => {}
+            }
+        }
+        Ok(exemplar)
+    }
+}
+
+impl MetricMetadata {
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        let mut metadata = MetricMetadata::default();
+        let mut reader = Reader::new(buf);
+        while let Some((field, value)) = reader.next_field()? {
+            match field {
+                1 => metadata.metric_type = value.as_u64("MetricMetadata.type")? as i32,
+                2 => {
+                    metadata.metric_family_name = value
+                        .as_str("MetricMetadata.metric_family_name")?
+                        .to_string()
+                }
+                4 => metadata.help = value.as_str("MetricMetadata.help")?.to_string(),
+                5 => metadata.unit = value.as_str("MetricMetadata.unit")?.to_string(),
+                _ => {}
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+/// Milliseconds between the Unix epoch and the Postgres epoch (2000-01-01).
+const PG_EPOCH_OFFSET_MS: i64 = 946_684_800_000;
+
+/// Converts a Prometheus timestamp (milliseconds since the Unix epoch) into a
+/// Postgres `TimestampTz` (microseconds since 2000-01-01).
+pub(crate) fn to_pg_timestamp(unix_ms: i64) -> Result<i64, String> {
+    unix_ms
+        .checked_sub(PG_EPOCH_OFFSET_MS)
+        .and_then(|ms| ms.checked_mul(crate::aggregates::USECS_PER_MS))
+        .ok_or_else(|| format!("timestamp out of range: {}", unix_ms))
+}
+
+/// The inverse of [`to_pg_timestamp`], truncating to millisecond precision.
+pub(crate) fn from_pg_timestamp(pg_us: i64) -> i64 {
+    pg_us.div_euclid(crate::aggregates::USECS_PER_MS) + PG_EPOCH_OFFSET_MS
+}
+
+impl Label {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.bytes(1, self.name.as_bytes());
+        w.bytes(2, self.value.as_bytes());
+        w.into_bytes()
+    }
+}
+
+impl Sample {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.double(1, self.value);
+        w.varint(2, self.timestamp as u64);
+        w.into_bytes()
+    }
+}
+
+impl TimeSeries {
+    /// Encodes labels and samples. Exemplars are not part of remote read
+    /// responses and are omitted.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        for label in &self.labels {
+            w.bytes(1, &label.encode());
+        }
+        for sample in &self.samples {
+            w.bytes(2, &sample.encode());
+        }
+        w.into_bytes()
+    }
+}
+
+/// `prometheus.ReadResponse`, with one `QueryResult` (a list of series) per query
+/// of the corresponding `ReadRequest`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ReadResponse {
+    pub results: Vec<Vec<TimeSeries>>,
+}
+
+impl ReadResponse {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        for result in &self.results {
+            let mut query_result = Writer::new();
+            for series in result {
+                query_result.bytes(1, &series.encode());
+            }
+            w.bytes(1, &query_result.into_bytes());
+        }
+        w.into_bytes()
+    }
+
+    /// Encodes the response the way it is sent for the `SAMPLES` response type.
+    pub fn encode_compressed(&self) -> Vec<u8> {
+        crate::snappy::compress(&self.encode())
+    }
+}
+
+/// The `Chunk.Encoding` value for Gorilla-style XOR chunks.
+pub(crate) const CHUNK_ENCODING_XOR: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Chunk {
+    pub min_time_ms: i64,
+    pub max_time_ms: i64,
+    pub encoding: u64,
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.varint(1, self.min_time_ms as u64);
+        w.varint(2, self.max_time_ms as u64);
+        w.varint(3, self.encoding);
+        w.bytes(4, &self.data);
+        w.into_bytes()
+    }
+}
+
+/// `prometheus.ChunkedReadResponse`, a single message of the
+/// `STREAMED_XOR_CHUNKS` response type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ChunkedReadResponse {
+    pub series: Vec<(Vec<Label>, Vec<Chunk>)>,
+    pub query_index: i64,
+}
+
+impl ChunkedReadResponse {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        for (labels, chunks) in &self.series {
+            let mut series = Writer::new();
+            for label in labels {
+                series.bytes(1, &label.encode());
+            }
+            for chunk in chunks {
+                series.bytes(2, &chunk.encode());
+            }
+            w.bytes(1, &series.into_bytes());
+        }
+        w.varint(2, self.query_index as u64);
+        w.into_bytes()
+    }
+}
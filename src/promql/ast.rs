@@ -0,0 +1,522 @@
+//! The PromQL syntax tree, and its JSON representation.
+//!
+//! The JSON follows the one of Prometheus' `/api/v1/parse_query` endpoint,
+//! with the addition of a `posRange` on every node.
+use serde_json::{json, Value};
+
+/// Byte offsets into the query, the end being exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PosRange {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ValueType {
+    Scalar,
+    Vector,
+    Matrix,
+    String,
+}
+
+impl ValueType {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ValueType::Scalar => "scalar",
+            ValueType::Vector => "vector",
+            ValueType::Matrix => "matrix",
+            ValueType::String => "string",
+        }
+    }
+
+    /// The name used in error messages.
+    pub(crate) fn documented_name(self) -> &'static str {
+        match self {
+            ValueType::Scalar => "scalar",
+            ValueType::Vector => "instant vector",
+            ValueType::Matrix => "range vector",
+            ValueType::String => "string",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MatchOp {
+    Equal,
+    NotEqual,
+    RegexMatch,
+    NotRegexMatch,
+}
+
+impl MatchOp {
+    pub(crate) fn symbol(self) -> &'static str {
+        match self {
+            MatchOp::Equal => "=",
+            MatchOp::NotEqual => "!=",
+            MatchOp::RegexMatch => "=~",
+            MatchOp::NotRegexMatch => "!~",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Matcher {
+    pub(crate) name: String,
+    pub(crate) op: MatchOp,
+    pub(crate) value: String,
+}
+
+impl Matcher {
+    pub(crate) fn to_json(&self) -> Value {
+        json!({"name": self.name, "type": self.op.symbol(), "value": self.value})
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StartOrEnd {
+    Start,
+    End,
+}
+
+/// The modifiers shared by selectors and subqueries.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Modifiers {
+    /// Milliseconds, as written.
+    pub(crate) offset: i64,
+    /// Milliseconds since the Unix epoch, of `@ <timestamp>`.
+    pub(crate) timestamp: Option<i64>,
+    pub(crate) start_or_end: Option<StartOrEnd>,
+}
+
+impl Modifiers {
+    fn add_json(&self, node: &mut Value) {
+        node["offset"] = json!(self.offset);
+        node["timestamp"] = json!(self.timestamp);
+        node["startOrEnd"] = json!(self.start_or_end.map(|s| match s {
+            StartOrEnd::Start => "start",
+            StartOrEnd::End => "end",
+        }));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct VectorSelector {
+    /// Empty if the metric name is only given as a matcher.
+    pub(crate) name: String,
+    /// Including the one on `__name__` for `name`, which comes last.
+    pub(crate) matchers: Vec<Matcher>,
+    pub(crate) modifiers: Modifiers,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Cardinality {
+    OneToOne,
+    ManyToOne,
+    OneToMany,
+    ManyToMany,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct VectorMatching {
+    pub(crate) card: Cardinality,
+    pub(crate) matching_labels: Vec<String>,
+    /// `on` rather than `ignoring` the matching labels.
+    pub(crate) on: bool,
+    /// The labels of `group_left` and `group_right`.
+    pub(crate) include: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Atan2,
+    Eql,
+    Neq,
+    Gtr,
+    Lss,
+    Gte,
+    Lte,
+    And,
+    Or,
+    Unless,
+}
+
+impl BinaryOp {
+    pub(crate) fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::Pow => "^",
+            BinaryOp::Atan2 => "atan2",
+            BinaryOp::Eql => "==",
+            BinaryOp::Neq => "!=",
+            BinaryOp::Gtr => ">",
+            BinaryOp::Lss => "<",
+            BinaryOp::Gte => ">=",
+            BinaryOp::Lte => "<=",
+            BinaryOp::And => "and",
+            BinaryOp::Or => "or",
+            BinaryOp::Unless => "unless",
+        }
+    }
+
+    pub(crate) fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinaryOp::Eql
+                | BinaryOp::Neq
+                | BinaryOp::Gtr
+                | BinaryOp::Lss
+                | BinaryOp::Gte
+                | BinaryOp::Lte
+        )
+    }
+
+    pub(crate) fn is_set_operator(self) -> bool {
+        matches!(self, BinaryOp::And | BinaryOp::Or | BinaryOp::Unless)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Function {
+    pub(crate) name: &'static str,
+    pub(crate) arg_types: &'static [ValueType],
+    /// 0 for a fixed number of arguments, otherwise the last argument is
+    /// optional, and it may be repeated `variadic` times in total, or any
+    /// number of times for -1.
+    pub(crate) variadic: i32,
+    pub(crate) return_type: ValueType,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ExprKind {
+    Aggregate {
+        op: &'static str,
+        expr: Box<Expr>,
+        param: Option<Box<Expr>>,
+        grouping: Vec<String>,
+        without: bool,
+    },
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+        matching: Option<VectorMatching>,
+        return_bool: bool,
+    },
+    Call {
+        func: &'static Function,
+        args: Vec<Expr>,
+    },
+    MatrixSelector {
+        selector: VectorSelector,
+        /// Milliseconds.
+        range: i64,
+    },
+    Subquery {
+        expr: Box<Expr>,
+        /// Milliseconds.
+        range: i64,
+        /// Milliseconds, 0 if omitted.
+        step: i64,
+        modifiers: Modifiers,
+    },
+    NumberLiteral(f64),
+    StringLiteral(String),
+    Paren(Box<Expr>),
+    Unary {
+        op: BinaryOp,
+        expr: Box<Expr>,
+    },
+    VectorSelector(VectorSelector),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Expr {
+    pub(crate) kind: ExprKind,
+    pub(crate) pos: PosRange,
+}
+
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Expr {
+    pub(crate) fn value_type(&self) -> ValueType {
+        match &self.kind {
+            ExprKind::Aggregate { .. } => ValueType::Vector,
+            ExprKind::Binary { lhs, rhs, .. } => {
+                if lhs.value_type() == ValueType::Scalar && rhs.value_type() == ValueType::Scalar {
+                    ValueType::Scalar
+                } else {
+                    ValueType::Vector
+                }
+            }
+            ExprKind::Call { func, .. } => func.return_type,
+            ExprKind::MatrixSelector { .. } | ExprKind::Subquery { .. } => ValueType::Matrix,
+            ExprKind::NumberLiteral(_) => ValueType::Scalar,
+            ExprKind::StringLiteral(_) => ValueType::String,
+            ExprKind::Paren(expr) | ExprKind::Unary { expr, .. } => expr.value_type(),
+            ExprKind::VectorSelector(_) => ValueType::Vector,
+        }
+    }
+
+    /// Calls `f` on every node, parents before their children.
+    pub(crate) fn walk<'a>(&'a self, f: &mut impl FnMut(&'a Expr)) {
+        f(self);
+        match &self.kind {
+            ExprKind::Aggregate { expr, param, .. } => {
+                if let Some(param) = param {
+                    param.walk(f);
+                }
+                expr.walk(f);
+            }
+            ExprKind::Binary { lhs, rhs, .. } => {
+                lhs.walk(f);
+                rhs.walk(f);
+            }
+            ExprKind::Call { args, .. } => args.iter().for_each(|arg| arg.walk(f)),
+            ExprKind::Subquery { expr, .. }
+            | ExprKind::Paren(expr)
+            | ExprKind::Unary { expr, .. } => expr.walk(f),
+            ExprKind::MatrixSelector { .. }
+            | ExprKind::NumberLiteral(_)
+            | ExprKind::StringLiteral(_)
+            | ExprKind::VectorSelector(_) => {}
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let mut node = match &self.kind {
+            ExprKind::Aggregate {
+                op,
+                expr,
+                param,
+                grouping,
+                without,
+            } => json!({
+                "type": "aggregation",
+                "op": op,
+                "expr": expr.to_json(),
+                "param": param.as_ref().map(|p| p.to_json()),
+                "grouping": grouping,
+                "without": without,
+            }),
+            ExprKind::Binary {
+                op,
+                lhs,
+                rhs,
+                matching,
+                return_bool,
+            } => json!({
+                "type": "binaryExpr",
+                "op": op.symbol(),
+                "lhs": lhs.to_json(),
+                "rhs": rhs.to_json(),
+                "matching": matching.as_ref().map(|m| json!({
+                    "card": match m.card {
+                        Cardinality::OneToOne => "one-to-one",
+                        Cardinality::ManyToOne => "many-to-one",
+                        Cardinality::OneToMany => "one-to-many",
+                        Cardinality::ManyToMany => "many-to-many",
+                    },
+                    "labels": m.matching_labels,
+                    "on": m.on,
+                    "include": m.include,
+                })),
+                "bool": return_bool,
+            }),
+            ExprKind::Call { func, args } => json!({
+                "type": "call",
+                "func": {
+                    "name": func.name,
+                    "argTypes": func.arg_types.iter().map(|t| t.name()).collect::<Vec<_>>(),
+                    "variadic": func.variadic,
+                    "returnType": func.return_type.name(),
+                },
+                "args": args.iter().map(Expr::to_json).collect::<Vec<_>>(),
+            }),
+            ExprKind::MatrixSelector { selector, range } => {
+                let mut node = json!({
+                    "type": "matrixSelector",
+                    "name": selector.name,
+                    "range": range,
+                    "matchers": selector.matchers.iter().map(Matcher::to_json).collect::<Vec<_>>(),
+                });
+                selector.modifiers.add_json(&mut node);
+                node
+            }
+            ExprKind::Subquery {
+                expr,
+                range,
+                step,
+                modifiers,
+            } => {
+                let mut node = json!({
+                    "type": "subquery",
+                    "expr": expr.to_json(),
+                    "range": range,
+                    "step": step,
+                });
+                modifiers.add_json(&mut node);
+                node
+            }
+            ExprKind::NumberLiteral(val) => json!({
+                "type": "numberLiteral",
+                "val": format_number(*val),
+            }),
+            ExprKind::StringLiteral(val) => json!({"type": "stringLiteral", "val": val}),
+            ExprKind::Paren(expr) => json!({"type": "parenExpr", "expr": expr.to_json()}),
+            ExprKind::Unary { op, expr } => json!({
+                "type": "unaryExpr",
+                "op": op.symbol(),
+                "expr": expr.to_json(),
+            }),
+            ExprKind::VectorSelector(selector) => {
+                let mut node = json!({
+                    "type": "vectorSelector",
+                    "name": selector.name,
+                    "matchers": selector.matchers.iter().map(Matcher::to_json).collect::<Vec<_>>(),
+                });
+                selector.modifiers.add_json(&mut node);
+                node
+            }
+        };
+        node["posRange"] = json!({"start": self.pos.start, "end": self.pos.end});
+        node
+    }
+}
+
+/// Like Go's `strconv.FormatFloat(v, 'f', -1, 64)`.
+fn format_number(v: f64) -> String {
+    if v.is_nan() {
+        "NaN".to_string()
+    } else if v == f64::INFINITY {
+        "+Inf".to_string()
+    } else if v == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        v.to_string()
+    }
+}
+
+pub(crate) const AGGREGATORS: &[&str] = &[
+    "avg",
+    "bottomk",
+    "count",
+    "count_values",
+    "group",
+    "max",
+    "min",
+    "quantile",
+    "stddev",
+    "stdvar",
+    "sum",
+    "topk",
+];
+
+/// The type of the parameter of aggregators that take one.
+pub(crate) fn aggregator_param_type(op: &str) -> Option<ValueType> {
+    match op {
+        "bottomk" | "quantile" | "topk" => Some(ValueType::Scalar),
+        "count_values" => Some(ValueType::String),
+        _ => None,
+    }
+}
+
+pub(crate) fn function(name: &str) -> Option<&'static Function> {
+    FUNCTIONS.iter().find(|f| f.name == name)
+}
+
+use ValueType::{Matrix as M, Scalar as S, String as Str, Vector as V};
+
+macro_rules! functions {
+    ($(($name:expr, [$($arg:expr),*], $variadic:expr, $ret:expr)),* $(,)?) => {
+        &[$(Function {
+            name: $name,
+            arg_types: &[$($arg),*],
+            variadic: $variadic,
+            return_type: $ret,
+        }),*]
+    };
+}
+
+const FUNCTIONS: &[Function] = functions![
+    ("abs", [V], 0, V),
+    ("absent", [V], 0, V),
+    ("absent_over_time", [M], 0, V),
+    ("acos", [V], 0, V),
+    ("acosh", [V], 0, V),
+    ("asin", [V], 0, V),
+    ("asinh", [V], 0, V),
+    ("atan", [V], 0, V),
+    ("atanh", [V], 0, V),
+    ("avg_over_time", [M], 0, V),
+    ("ceil", [V], 0, V),
+    ("changes", [M], 0, V),
+    ("clamp", [V, S, S], 0, V),
+    ("clamp_max", [V, S], 0, V),
+    ("clamp_min", [V, S], 0, V),
+    ("cos", [V], 0, V),
+    ("cosh", [V], 0, V),
+    ("count_over_time", [M], 0, V),
+    ("day_of_month", [V], 1, V),
+    ("day_of_week", [V], 1, V),
+    ("day_of_year", [V], 1, V),
+    ("days_in_month", [V], 1, V),
+    ("deg", [V], 0, V),
+    ("delta", [M], 0, V),
+    ("deriv", [M], 0, V),
+    ("exp", [V], 0, V),
+    ("floor", [V], 0, V),
+    ("histogram_quantile", [S, V], 0, V),
+    ("holt_winters", [M, S, S], 0, V),
+    ("hour", [V], 1, V),
+    ("idelta", [M], 0, V),
+    ("increase", [M], 0, V),
+    ("irate", [M], 0, V),
+    ("label_join", [V, Str, Str, Str], -1, V),
+    ("label_replace", [V, Str, Str, Str, Str], 0, V),
+    ("last_over_time", [M], 0, V),
+    ("ln", [V], 0, V),
+    ("log10", [V], 0, V),
+    ("log2", [V], 0, V),
+    ("max_over_time", [M], 0, V),
+    ("min_over_time", [M], 0, V),
+    ("minute", [V], 1, V),
+    ("month", [V], 1, V),
+    ("pi", [], 0, S),
+    ("predict_linear", [M, S], 0, V),
+    ("present_over_time", [M], 0, V),
+    ("quantile_over_time", [S, M], 0, V),
+    ("rad", [V], 0, V),
+    ("rate", [M], 0, V),
+    ("resets", [M], 0, V),
+    ("round", [V, S], 1, V),
+    ("scalar", [V], 0, S),
+    ("sgn", [V], 0, V),
+    ("sin", [V], 0, V),
+    ("sinh", [V], 0, V),
+    ("sort", [V], 0, V),
+    ("sort_desc", [V], 0, V),
+    ("sqrt", [V], 0, V),
+    ("stddev_over_time", [M], 0, V),
+    ("stdvar_over_time", [M], 0, V),
+    ("sum_over_time", [M], 0, V),
+    ("tan", [V], 0, V),
+    ("tanh", [V], 0, V),
+    ("time", [], 0, S),
+    ("timestamp", [V], 0, V),
+    ("vector", [S], 0, V),
+    ("year", [V], 1, V),
+];
@@ -0,0 +1,710 @@
+//! Evaluates PromQL over a range of evaluation times.
+//!
+//! Selectors are fetched by the caller, see [`Selection`]: instant vector
+//! selectors through `vector_selector`, and `rate`, `increase` and `delta`
+//! over range vector selectors through the aggregates of the same name. Every
+//! other operation is evaluated here, one evaluation time (step) at a time,
+//! following Prometheus' engine.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use super::ast::{
+    BinaryOp, Cardinality, Expr, ExprKind, MatchOp, StartOrEnd, VectorMatching, VectorSelector,
+};
+
+pub(crate) type Labels = BTreeMap<String, String>;
+
+/// Prometheus' default lookback delta, in milliseconds.
+pub(crate) const LOOKBACK_MS: i64 = 5 * 60 * 1000;
+
+/// A series with a value, or none, at every evaluation time.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Series {
+    pub(crate) labels: Labels,
+    pub(crate) values: Vec<Option<f64>>,
+}
+
+/// The evaluation times: from `start` to `end`, every `step` milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct EvalRange {
+    /// Unix timestamp in milliseconds.
+    pub(crate) start: i64,
+    /// Unix timestamp in milliseconds.
+    pub(crate) end: i64,
+    pub(crate) step: i64,
+}
+
+impl EvalRange {
+    /// Checks the range like Prometheus' query API does.
+    pub(crate) fn new(start: i64, end: i64, step: i64) -> Result<Self, EvalError> {
+        if end < start {
+            return Err(EvalError(
+                "end timestamp must not be before start time".to_string(),
+            ));
+        }
+        if step <= 0 {
+            return Err(EvalError(
+                "zero or negative query resolution step widths are not accepted. Try a positive integer".to_string(),
+            ));
+        }
+        if (end - start) / step > 11_000 {
+            return Err(EvalError(
+                "exceeded maximum resolution of 11,000 points per timeseries. Try decreasing the query resolution".to_string(),
+            ));
+        }
+        Ok(Self { start, end, step })
+    }
+
+    pub(crate) fn steps(&self) -> usize {
+        ((self.end - self.start) / self.step) as usize + 1
+    }
+
+    fn time(&self, step: usize) -> i64 {
+        self.start + step as i64 * self.step
+    }
+}
+
+/// Samples to fetch from storage for a selector.
+#[derive(Debug)]
+pub(crate) struct Selection<'a> {
+    pub(crate) selector: &'a VectorSelector,
+    /// The aggregate to compute the values with: `vector_selector`, or
+    /// `prom_rate`, `prom_increase` or `prom_delta` for range vectors.
+    pub(crate) aggregate: &'static str,
+    /// The evaluation times, shifted by the offset of the selector.
+    pub(crate) range: EvalRange,
+    /// The range of range vector selectors, or the lookback delta, in
+    /// milliseconds.
+    pub(crate) window: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct EvalError(String);
+
+impl EvalError {
+    fn unsupported(what: impl fmt::Display) -> Self {
+        EvalError(format!("unsupported PromQL feature: {}", what))
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+enum Value {
+    /// A value at every evaluation time.
+    Scalar(Vec<f64>),
+    Vector(Vec<Series>),
+    String,
+}
+
+/// Evaluates `expr` over `range`, with `fetch` returning the series of a
+/// selection. Scalar results are a single series without labels.
+pub(crate) fn evaluate(
+    expr: &Expr,
+    range: EvalRange,
+    fetch: &mut dyn FnMut(&Selection) -> Vec<Series>,
+) -> Result<Vec<Series>, EvalError> {
+    let mut evaluator = Evaluator { range, fetch };
+    match evaluator.eval(expr)? {
+        Value::Scalar(values) => Ok(vec![Series {
+            labels: Labels::new(),
+            values: values.into_iter().map(Some).collect(),
+        }]),
+        Value::Vector(series) => Ok(series),
+        Value::String => Err(EvalError(
+            "invalid expression type \"string\" for range query, must be Scalar or instant Vector"
+                .to_string(),
+        )),
+    }
+}
+
+struct Evaluator<'f> {
+    range: EvalRange,
+    fetch: &'f mut dyn FnMut(&Selection) -> Vec<Series>,
+}
+
+impl<'f> Evaluator<'f> {
+    fn eval(&mut self, expr: &Expr) -> Result<Value, EvalError> {
+        let steps = self.range.steps();
+        match &expr.kind {
+            ExprKind::NumberLiteral(n) => Ok(Value::Scalar(vec![*n; steps])),
+            ExprKind::StringLiteral(_) => Ok(Value::String),
+            ExprKind::Paren(expr) => self.eval(expr),
+            ExprKind::Unary { op, expr } => match self.eval(expr)? {
+                Value::Scalar(values) if *op == BinaryOp::Sub => {
+                    Ok(Value::Scalar(values.into_iter().map(|v| -v).collect()))
+                }
+                Value::Vector(series) if *op == BinaryOp::Sub => {
+                    Ok(Value::Vector(map_values(series, |v| -v)))
+                }
+                value => Ok(value),
+            },
+            ExprKind::VectorSelector(selector) => self.select(selector, "vector_selector", None),
+            ExprKind::MatrixSelector { .. } => Err(EvalError(
+                "invalid expression type \"range vector\" for range query, must be Scalar or instant Vector"
+                    .to_string(),
+            )),
+            ExprKind::Subquery { .. } => Err(EvalError::unsupported("subqueries")),
+            ExprKind::Call { func, args } => self.call(func.name, args),
+            ExprKind::Aggregate {
+                op,
+                expr,
+                param,
+                grouping,
+                without,
+            } => {
+                if param.is_some() {
+                    return Err(EvalError::unsupported(format_args!("aggregation {}()", op)));
+                }
+                let series = match self.eval(expr)? {
+                    Value::Vector(series) => series,
+                    _ => unreachable!("aggregations of non-vectors are rejected by the parser"),
+                };
+                aggregate(op, series, grouping, *without, steps)
+            }
+            ExprKind::Binary {
+                op,
+                lhs,
+                rhs,
+                matching,
+                return_bool,
+            } => {
+                let lhs = self.eval(lhs)?;
+                let rhs = self.eval(rhs)?;
+                binary(*op, lhs, rhs, matching.as_ref(), *return_bool, steps)
+            }
+        }
+    }
+
+    fn select(
+        &mut self,
+        selector: &VectorSelector,
+        aggregate: &'static str,
+        range: Option<i64>,
+    ) -> Result<Value, EvalError> {
+        let modifiers = &selector.modifiers;
+        if modifiers.timestamp.is_some() || modifiers.start_or_end.is_some() {
+            let modifier = match modifiers.start_or_end {
+                Some(StartOrEnd::Start) => "@ start()",
+                Some(StartOrEnd::End) => "@ end()",
+                None => "@ <timestamp>",
+            };
+            return Err(EvalError::unsupported(format_args!(
+                "the {} modifier",
+                modifier
+            )));
+        }
+        let names: Vec<_> = selector
+            .matchers
+            .iter()
+            .filter(|m| m.name == "__name__")
+            .collect();
+        if names.len() != 1 || names[0].op != MatchOp::Equal {
+            return Err(EvalError::unsupported(
+                "selectors without exactly one metric name",
+            ));
+        }
+        let selection = Selection {
+            selector,
+            aggregate,
+            range: EvalRange {
+                start: self.range.start - modifiers.offset,
+                end: self.range.end - modifiers.offset,
+                step: self.range.step,
+            },
+            window: range.unwrap_or(LOOKBACK_MS),
+        };
+        let steps = self.range.steps();
+        let mut series = (self.fetch)(&selection);
+        for s in &mut series {
+            s.values.resize(steps, None);
+        }
+        series.retain(|s| s.values.iter().any(Option::is_some));
+        Ok(Value::Vector(series))
+    }
+
+    fn call(&mut self, name: &'static str, args: &[Expr]) -> Result<Value, EvalError> {
+        let steps = self.range.steps();
+        let math: fn(f64) -> f64 = match name {
+            "rate" | "increase" | "delta" => {
+                let (selector, range) = match &args[0].kind {
+                    ExprKind::MatrixSelector { selector, range } => (selector, *range),
+                    _ => {
+                        return Err(EvalError::unsupported(format_args!(
+                            "{}() of subqueries",
+                            name
+                        )))
+                    }
+                };
+                let aggregate = match name {
+                    "rate" => "prom_rate",
+                    "increase" => "prom_increase",
+                    _ => "prom_delta",
+                };
+                return match self.select(selector, aggregate, Some(range))? {
+                    Value::Vector(series) => Ok(Value::Vector(drop_metric_names(series))),
+                    _ => unreachable!(),
+                };
+            }
+            "time" => {
+                return Ok(Value::Scalar(
+                    (0..steps)
+                        .map(|i| self.range.time(i) as f64 / 1000.0)
+                        .collect(),
+                ))
+            }
+            "vector" => {
+                return match self.eval(&args[0])? {
+                    Value::Scalar(values) => Ok(Value::Vector(vec![Series {
+                        labels: Labels::new(),
+                        values: values.into_iter().map(Some).collect(),
+                    }])),
+                    _ => unreachable!(),
+                }
+            }
+            "scalar" => {
+                let series = match self.eval(&args[0])? {
+                    Value::Vector(series) => series,
+                    _ => unreachable!(),
+                };
+                return Ok(Value::Scalar(
+                    (0..steps)
+                        .map(|i| {
+                            let mut samples = series.iter().filter_map(|s| s.values[i]);
+                            match (samples.next(), samples.next()) {
+                                (Some(v), None) => v,
+                                _ => f64::NAN,
+                            }
+                        })
+                        .collect(),
+                ));
+            }
+            "abs" => f64::abs,
+            "ceil" => f64::ceil,
+            "exp" => f64::exp,
+            "floor" => f64::floor,
+            "ln" => f64::ln,
+            "log10" => f64::log10,
+            "log2" => f64::log2,
+            "sqrt" => f64::sqrt,
+            _ => return Err(EvalError::unsupported(format_args!("function {}()", name))),
+        };
+        match self.eval(&args[0])? {
+            Value::Vector(series) => Ok(Value::Vector(map_values(series, math))),
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn drop_metric_names(mut series: Vec<Series>) -> Vec<Series> {
+    for s in &mut series {
+        s.labels.remove("__name__");
+    }
+    series
+}
+
+/// Applies `f` to every value, dropping the metric names like Prometheus does
+/// for all functions and operators that change values.
+fn map_values(series: Vec<Series>, f: impl Fn(f64) -> f64) -> Vec<Series> {
+    let mut series = drop_metric_names(series);
+    for s in &mut series {
+        for v in s.values.iter_mut().flatten() {
+            *v = f(*v);
+        }
+    }
+    series
+}
+
+/// Collects the samples of every step into series, ordered by their labels.
+struct SeriesBuilder {
+    steps: usize,
+    series: BTreeMap<Labels, Vec<Option<f64>>>,
+}
+
+impl SeriesBuilder {
+    fn new(steps: usize) -> Self {
+        Self {
+            steps,
+            series: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, labels: Labels, step: usize, value: f64) {
+        let steps = self.steps;
+        self.series
+            .entry(labels)
+            .or_insert_with(|| vec![None; steps])[step] = Some(value);
+    }
+
+    fn finish(self) -> Vec<Series> {
+        self.series
+            .into_iter()
+            .map(|(labels, values)| Series { labels, values })
+            .collect()
+    }
+}
+
+fn aggregate(
+    op: &str,
+    series: Vec<Series>,
+    grouping: &[String],
+    without: bool,
+    steps: usize,
+) -> Result<Value, EvalError> {
+    if !matches!(
+        op,
+        "avg" | "count" | "group" | "max" | "min" | "stddev" | "stdvar" | "sum"
+    ) {
+        return Err(EvalError::unsupported(format_args!("aggregation {}()", op)));
+    }
+    // the series of each group
+    let mut groups: BTreeMap<Labels, Vec<&Series>> = BTreeMap::new();
+    for s in &series {
+        let labels = s
+            .labels
+            .iter()
+            .filter(|(name, _)| {
+                if without {
+                    *name != "__name__" && !grouping.contains(name)
+                } else {
+                    grouping.contains(name)
+                }
+            })
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        groups.entry(labels).or_default().push(s);
+    }
+
+    let mut builder = SeriesBuilder::new(steps);
+    for (labels, members) in groups {
+        for step in 0..steps {
+            let values: Vec<f64> = members.iter().filter_map(|s| s.values[step]).collect();
+            if values.is_empty() {
+                continue;
+            }
+            let count = values.len() as f64;
+            let mean = values.iter().sum::<f64>() / count;
+            let variance = || values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / count;
+            let value =
+                match op {
+                    "avg" => mean,
+                    "count" => count,
+                    "group" => 1.0,
+                    // NaN only wins if there is nothing else
+                    "max" => values.iter().copied().fold(f64::NAN, |a, b| {
+                        if a.is_nan() || b > a {
+                            b
+                        } else {
+                            a
+                        }
+                    }),
+                    "min" => values.iter().copied().fold(f64::NAN, |a, b| {
+                        if a.is_nan() || b < a {
+                            b
+                        } else {
+                            a
+                        }
+                    }),
+                    "stddev" => variance().sqrt(),
+                    "stdvar" => variance(),
+                    _ => values.iter().sum(),
+                };
+            builder.add(labels.clone(), step, value);
+        }
+    }
+    Ok(Value::Vector(builder.finish()))
+}
+
+/// Applies `op` to two samples. For comparisons, returns the left hand side
+/// and whether the comparison holds.
+fn apply(op: BinaryOp, lhs: f64, rhs: f64) -> (f64, bool) {
+    match op {
+        BinaryOp::Add => (lhs + rhs, true),
+        BinaryOp::Sub => (lhs - rhs, true),
+        BinaryOp::Mul => (lhs * rhs, true),
+        BinaryOp::Div => (lhs / rhs, true),
+        BinaryOp::Mod => (lhs % rhs, true),
+        BinaryOp::Pow => (lhs.powf(rhs), true),
+        BinaryOp::Atan2 => (lhs.atan2(rhs), true),
+        BinaryOp::Eql => (lhs, lhs == rhs),
+        BinaryOp::Neq => (lhs, lhs != rhs),
+        BinaryOp::Gtr => (lhs, lhs > rhs),
+        BinaryOp::Lss => (lhs, lhs < rhs),
+        BinaryOp::Gte => (lhs, lhs >= rhs),
+        BinaryOp::Lte => (lhs, lhs <= rhs),
+        BinaryOp::And | BinaryOp::Or | BinaryOp::Unless => {
+            unreachable!("set operators only apply to vectors")
+        }
+    }
+}
+
+/// Applies `op` to samples, turning comparisons with `bool` into 0 or 1.
+/// Returns `None` for comparisons that don't hold.
+fn apply_filtered(op: BinaryOp, lhs: f64, rhs: f64, return_bool: bool) -> Option<f64> {
+    let (value, keep) = apply(op, lhs, rhs);
+    if return_bool {
+        Some(if keep { 1.0 } else { 0.0 })
+    } else if keep {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn binary(
+    op: BinaryOp,
+    lhs: Value,
+    rhs: Value,
+    matching: Option<&VectorMatching>,
+    return_bool: bool,
+    steps: usize,
+) -> Result<Value, EvalError> {
+    let drop_name = return_bool || !op.is_comparison();
+    match (lhs, rhs) {
+        (Value::Scalar(lhs), Value::Scalar(rhs)) => Ok(Value::Scalar(
+            lhs.into_iter()
+                .zip(rhs)
+                .map(|(l, r)| apply_filtered(op, l, r, return_bool).unwrap())
+                .collect(),
+        )),
+        (Value::Vector(series), Value::Scalar(scalar)) => Ok(Value::Vector(vector_scalar(
+            op,
+            series,
+            &scalar,
+            false,
+            return_bool,
+            drop_name,
+        ))),
+        (Value::Scalar(scalar), Value::Vector(series)) => Ok(Value::Vector(vector_scalar(
+            op,
+            series,
+            &scalar,
+            true,
+            return_bool,
+            drop_name,
+        ))),
+        (Value::Vector(lhs), Value::Vector(rhs)) => {
+            let matching = matching.expect("binary expressions between vectors have a matching");
+            let result = match op {
+                BinaryOp::And | BinaryOp::Or | BinaryOp::Unless => {
+                    set_operation(op, &lhs, &rhs, matching, steps)
+                }
+                _ => vector_vector(op, &lhs, &rhs, matching, return_bool, drop_name, steps)?,
+            };
+            Ok(Value::Vector(result))
+        }
+        _ => unreachable!("binary expressions of strings are rejected by the parser"),
+    }
+}
+
+/// `swapped` is set if the scalar is the left hand side.
+fn vector_scalar(
+    op: BinaryOp,
+    series: Vec<Series>,
+    scalar: &[f64],
+    swapped: bool,
+    return_bool: bool,
+    drop_name: bool,
+) -> Vec<Series> {
+    let mut series = if drop_name {
+        drop_metric_names(series)
+    } else {
+        series
+    };
+    for s in &mut series {
+        for (value, scalar) in s.values.iter_mut().zip(scalar) {
+            if let Some(v) = *value {
+                let (l, r) = if swapped { (*scalar, v) } else { (v, *scalar) };
+                *value = apply_filtered(op, l, r, return_bool);
+                // comparisons keep the value of the vector, even on the right
+                if swapped && !return_bool && op.is_comparison() && value.is_some() {
+                    *value = Some(v);
+                }
+            }
+        }
+    }
+    series.retain(|s| s.values.iter().any(Option::is_some));
+    series
+}
+
+/// The labels the sides of a vector matching are matched on.
+fn signature<'a>(labels: &'a Labels, matching: &VectorMatching) -> Vec<(&'a str, &'a str)> {
+    labels
+        .iter()
+        .filter(|(name, _)| {
+            if matching.on {
+                matching.matching_labels.contains(name)
+            } else {
+                *name != "__name__" && !matching.matching_labels.contains(name)
+            }
+        })
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect()
+}
+
+/// The signatures of the series with a sample at `step`.
+fn present_signatures<'a>(
+    series: &[Series],
+    sigs: &'a [Vec<(&'a str, &'a str)>],
+    step: usize,
+) -> HashSet<&'a Vec<(&'a str, &'a str)>> {
+    series
+        .iter()
+        .zip(sigs)
+        .filter(|(s, _)| s.values[step].is_some())
+        .map(|(_, sig)| sig)
+        .collect()
+}
+
+fn set_operation(
+    op: BinaryOp,
+    lhs: &[Series],
+    rhs: &[Series],
+    matching: &VectorMatching,
+    steps: usize,
+) -> Vec<Series> {
+    let lhs_sigs: Vec<_> = lhs.iter().map(|s| signature(&s.labels, matching)).collect();
+    let rhs_sigs: Vec<_> = rhs.iter().map(|s| signature(&s.labels, matching)).collect();
+    let mut builder = SeriesBuilder::new(steps);
+    for step in 0..steps {
+        let rhs_present = present_signatures(rhs, &rhs_sigs, step);
+        for (s, sig) in lhs.iter().zip(&lhs_sigs) {
+            if let Some(v) = s.values[step] {
+                let keep = match op {
+                    BinaryOp::And => rhs_present.contains(sig),
+                    BinaryOp::Unless => !rhs_present.contains(sig),
+                    _ => true,
+                };
+                if keep {
+                    builder.add(s.labels.clone(), step, v);
+                }
+            }
+        }
+        if op == BinaryOp::Or {
+            let lhs_present = present_signatures(lhs, &lhs_sigs, step);
+            for (s, sig) in rhs.iter().zip(&rhs_sigs) {
+                if let Some(v) = s.values[step] {
+                    if !lhs_present.contains(sig) {
+                        builder.add(s.labels.clone(), step, v);
+                    }
+                }
+            }
+        }
+    }
+    builder.finish()
+}
+
+fn format_signature(sig: &[(&str, &str)]) -> String {
+    let labels: Vec<_> = sig
+        .iter()
+        .map(|(name, value)| format!("{}={:?}", name, value))
+        .collect();
+    format!("{{{}}}", labels.join(", "))
+}
+
+/// Arithmetic and comparisons between vectors, one-to-one or with
+/// `group_left` or `group_right`.
+fn vector_vector(
+    op: BinaryOp,
+    lhs: &[Series],
+    rhs: &[Series],
+    matching: &VectorMatching,
+    return_bool: bool,
+    drop_name: bool,
+    steps: usize,
+) -> Result<Vec<Series>, EvalError> {
+    // the "many" side comes first
+    let swapped = matching.card == Cardinality::OneToMany;
+    let (many, one) = if swapped { (rhs, lhs) } else { (lhs, rhs) };
+    let many_sigs: Vec<_> = many
+        .iter()
+        .map(|s| signature(&s.labels, matching))
+        .collect();
+    let one_sigs: Vec<_> = one.iter().map(|s| signature(&s.labels, matching)).collect();
+
+    let mut builder = SeriesBuilder::new(steps);
+    for step in 0..steps {
+        let mut one_by_sig: HashMap<&[(&str, &str)], &Series> = HashMap::new();
+        for (s, sig) in one.iter().zip(&one_sigs) {
+            if s.values[step].is_none() {
+                continue;
+            }
+            if one_by_sig.insert(sig.as_slice(), s).is_some() {
+                return Err(EvalError(format!(
+                    "found duplicate series for the match group {} on the {} hand-side of the operation; many-to-many matching not allowed: matching labels must be unique on one side",
+                    format_signature(sig),
+                    if swapped { "left" } else { "right" }
+                )));
+            }
+        }
+        let mut matched_sigs = HashSet::new();
+        let mut result_labels = HashSet::new();
+        for (s, sig) in many.iter().zip(&many_sigs) {
+            let many_value = match s.values[step] {
+                Some(v) => v,
+                None => continue,
+            };
+            let other = match one_by_sig.get(sig.as_slice()) {
+                Some(other) => other,
+                None => continue,
+            };
+            let one_value = other.values[step].unwrap();
+            let (l, r) = if swapped {
+                (one_value, many_value)
+            } else {
+                (many_value, one_value)
+            };
+            let value = match apply_filtered(op, l, r, return_bool) {
+                Some(value) => value,
+                None => continue,
+            };
+            let labels = result_labels_of(&s.labels, &other.labels, matching, drop_name);
+            if matching.card == Cardinality::OneToOne {
+                if !matched_sigs.insert(sig) {
+                    return Err(EvalError(
+                        "multiple matches for labels: many-to-one matching must be explicit (group_left/group_right)"
+                            .to_string(),
+                    ));
+                }
+            } else if !result_labels.insert(labels.clone()) {
+                return Err(EvalError(
+                    "multiple matches for labels: grouping labels must ensure unique matches"
+                        .to_string(),
+                ));
+            }
+            builder.add(labels, step, value);
+        }
+    }
+    Ok(builder.finish())
+}
+
+/// The labels of the result of a vector matching, `many` being the side of
+/// a `group_left` or `group_right` with the higher cardinality.
+fn result_labels_of(
+    many: &Labels,
+    one: &Labels,
+    matching: &VectorMatching,
+    drop_name: bool,
+) -> Labels {
+    let mut labels = many.clone();
+    if drop_name {
+        labels.remove("__name__");
+    }
+    if matching.card == Cardinality::OneToOne {
+        labels.retain(|name, _| matching.on == matching.matching_labels.contains(name));
+    }
+    for name in &matching.include {
+        match one.get(name) {
+            Some(value) if !value.is_empty() => {
+                labels.insert(name.clone(), value.clone());
+            }
+            _ => {
+                labels.remove(name);
+            }
+        }
+    }
+    labels
+}
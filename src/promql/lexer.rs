@@ -0,0 +1,289 @@
+//! Splits a PromQL query into tokens.
+use super::SyntaxError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TokenKind {
+    /// Metric names, label names, function names and keywords.
+    Identifier(String),
+    /// Including `Inf` and `NaN`.
+    Number(f64),
+    /// Milliseconds.
+    Duration(i64),
+    String(String),
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    Comma,
+    Colon,
+    At,
+    /// Binary and matcher operators: `+ - * / % ^ == != > < >= <= = =~ !~`.
+    Operator(&'static str),
+    Eof,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Token {
+    pub(crate) kind: TokenKind,
+    /// Byte offsets, the end being exclusive.
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
+
+impl Token {
+    /// How the token is named in error messages.
+    pub(crate) fn describe(&self, input: &str) -> String {
+        match self.kind {
+            TokenKind::Eof => "end of input".to_string(),
+            _ => format!("{:?}", &input[self.start..self.end]),
+        }
+    }
+}
+
+const OPERATORS: &[&str] = &[
+    "==", "!=", ">=", "<=", "=~", "!~", "+", "-", "*", "/", "%", "^", ">", "<", "=",
+];
+
+pub(crate) fn tokenize(input: &str) -> Result<Vec<Token>, SyntaxError> {
+    let mut tokens = vec![];
+    let mut pos = 0;
+    while let Some(c) = input[pos..].chars().next() {
+        let start = pos;
+        let rest = &input[pos..];
+        let kind = match c {
+            _ if c.is_whitespace() => {
+                pos += c.len_utf8();
+                continue;
+            }
+            '#' => {
+                pos += rest.find('\n').unwrap_or(rest.len());
+                continue;
+            }
+            '(' => TokenKind::LeftParen,
+            ')' => TokenKind::RightParen,
+            '{' => TokenKind::LeftBrace,
+            '}' => TokenKind::RightBrace,
+            '[' => TokenKind::LeftBracket,
+            ']' => TokenKind::RightBracket,
+            ',' => TokenKind::Comma,
+            ':' if !rest[1..].starts_with(is_identifier_start) => TokenKind::Colon,
+            '@' => TokenKind::At,
+            '"' | '\'' | '`' => {
+                let (value, len) = string(rest, start)?;
+                pos += len;
+                tokens.push(Token {
+                    kind: TokenKind::String(value),
+                    start,
+                    end: pos,
+                });
+                continue;
+            }
+            _ if c.is_ascii_digit()
+                || (c == '.' && rest[1..].starts_with(|c: char| c.is_ascii_digit())) =>
+            {
+                let (kind, len) = number_or_duration(rest, start)?;
+                pos += len;
+                tokens.push(Token {
+                    kind,
+                    start,
+                    end: pos,
+                });
+                continue;
+            }
+            _ if is_identifier_start(c) => {
+                let len = rest
+                    .find(|c: char| !is_identifier_start(c) && !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                let identifier = &rest[..len];
+                pos += len;
+                let kind = if identifier.eq_ignore_ascii_case("inf") {
+                    TokenKind::Number(f64::INFINITY)
+                } else if identifier.eq_ignore_ascii_case("nan") {
+                    TokenKind::Number(f64::NAN)
+                } else {
+                    TokenKind::Identifier(identifier.to_string())
+                };
+                tokens.push(Token {
+                    kind,
+                    start,
+                    end: pos,
+                });
+                continue;
+            }
+            _ => match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    pos += op.len();
+                    tokens.push(Token {
+                        kind: TokenKind::Operator(op),
+                        start,
+                        end: pos,
+                    });
+                    continue;
+                }
+                None => {
+                    return Err(SyntaxError::new(
+                        start,
+                        format!("unexpected character {:?}", c),
+                    ))
+                }
+            },
+        };
+        pos += c.len_utf8();
+        tokens.push(Token {
+            kind,
+            start,
+            end: pos,
+        });
+    }
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        start: input.len(),
+        end: input.len(),
+    });
+    Ok(tokens)
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == ':'
+}
+
+/// A number (decimal, hexadecimal or with exponent) or a duration like
+/// `1h30m`. Returns the token and its length.
+fn number_or_duration(input: &str, start: usize) -> Result<(TokenKind, usize), SyntaxError> {
+    let word_len = input
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '_')
+        .unwrap_or(input.len());
+    // an exponent's sign isn't part of the word
+    let word_len = match input[..word_len].chars().last() {
+        Some('e') | Some('E')
+            if !input[..word_len].starts_with("0x")
+                && input[word_len..].starts_with(['+', '-']) =>
+        {
+            word_len
+                + 1
+                + input[word_len + 1..]
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(input.len() - word_len - 1)
+        }
+        _ => word_len,
+    };
+    let word = &input[..word_len];
+    let number = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok().map(|n| n as f64),
+        None if word
+            .chars()
+            .all(|c| c.is_ascii_digit() || "+-.eE".contains(c)) =>
+        {
+            word.parse::<f64>().ok()
+        }
+        None => None,
+    };
+    if let Some(number) = number {
+        return Ok((TokenKind::Number(number), word_len));
+    }
+    match parse_duration(word) {
+        Some(ms) => Ok((TokenKind::Duration(ms), word_len)),
+        None => Err(SyntaxError::new(
+            start,
+            format!("bad number or duration syntax: {:?}", word),
+        )),
+    }
+}
+
+/// Parses durations like `1h30m`, with the units `y`, `w`, `d`, `h`, `m`,
+/// `s` and `ms` in this order, each at most once. Returns milliseconds.
+pub(crate) fn parse_duration(input: &str) -> Option<i64> {
+    const UNITS: &[(&str, i64)] = &[
+        ("y", 365 * 24 * 60 * 60 * 1000),
+        ("w", 7 * 24 * 60 * 60 * 1000),
+        ("d", 24 * 60 * 60 * 1000),
+        ("h", 60 * 60 * 1000),
+        ("ms", 1),
+        ("m", 60 * 1000),
+        ("s", 1000),
+    ];
+    // the position of each unit in the required order
+    const ORDER: &[&str] = &["y", "w", "d", "h", "m", "s", "ms"];
+    let mut rest = input;
+    let mut total: i64 = 0;
+    let mut last_unit = None;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return None;
+        }
+        let value: i64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let (unit, factor) = UNITS.iter().find(|(unit, _)| rest.starts_with(unit))?;
+        let order = ORDER.iter().position(|u| u == unit);
+        if order <= last_unit {
+            return None;
+        }
+        last_unit = order;
+        rest = &rest[unit.len()..];
+        total = total.checked_add(value.checked_mul(*factor)?)?;
+    }
+    last_unit.map(|_| total)
+}
+
+/// A quoted string with Go's escape sequences, or a raw string in backticks.
+/// Returns the value and the length of the token.
+fn string(input: &str, start: usize) -> Result<(String, usize), SyntaxError> {
+    let mut chars = input.char_indices();
+    let (_, quote) = chars.next().unwrap();
+    let mut value = String::new();
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            _ if c == quote => return Ok((value, offset + 1)),
+            '\\' if quote != '`' => {
+                let escaped = match chars.next() {
+                    Some((_, 'a')) => '\u{7}',
+                    Some((_, 'b')) => '\u{8}',
+                    Some((_, 'f')) => '\u{c}',
+                    Some((_, 'n')) => '\n',
+                    Some((_, 'r')) => '\r',
+                    Some((_, 't')) => '\t',
+                    Some((_, 'v')) => '\u{b}',
+                    Some((_, '\\')) => '\\',
+                    Some((_, c)) if c == quote => c,
+                    Some((_, c @ ('x' | 'u' | 'U'))) => {
+                        let len = match c {
+                            'x' => 2,
+                            'u' => 4,
+                            _ => 8,
+                        };
+                        let digits: String = chars.by_ref().take(len).map(|(_, c)| c).collect();
+                        u32::from_str_radix(&digits, 16)
+                            .ok()
+                            .filter(|_| digits.len() == len)
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| {
+                                SyntaxError::new(start + offset, "invalid escape sequence")
+                            })?
+                    }
+                    Some((_, c @ '0'..='7')) => {
+                        let digits: String = std::iter::once(c)
+                            .chain(chars.by_ref().take(2).map(|(_, c)| c))
+                            .collect();
+                        u32::from_str_radix(&digits, 8)
+                            .ok()
+                            .filter(|n| digits.len() == 3 && *n <= 255)
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| {
+                                SyntaxError::new(start + offset, "invalid escape sequence")
+                            })?
+                    }
+                    _ => return Err(SyntaxError::new(start + offset, "invalid escape sequence")),
+                };
+                value.push(escaped);
+            }
+            '\n' if quote != '`' => break,
+            c => value.push(c),
+        }
+    }
+    Err(SyntaxError::new(start, "unterminated quoted string"))
+}
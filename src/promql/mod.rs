@@ -0,0 +1,492 @@
+//! # PromQL
+//!
+//! Parses [PromQL] queries into a typed syntax tree, with the same type checks
+//! and error messages as Prometheus' own parser:
+//!
+//! ```sql
+//! SELECT prom_api.promql_parse('sum by (job) (rate(http_requests_total{code=~"5.."}[5m]))');
+//! ```
+//!
+//! The tree is returned as JSON in the format of Prometheus'
+//! `/api/v1/parse_query` endpoint, with the byte offsets of every node in
+//! `posRange`. `prom_api.promql_selectors` lists the vector and matrix
+//! selectors of a query, which are what has to be fetched from storage to
+//! evaluate it:
+//!
+//! ```sql
+//! SELECT kind, metric_name, matchers, range_ms
+//! FROM prom_api.promql_selectors('rate(foo[5m]) / on(job) bar');
+//! ```
+//!
+//! Syntax errors are reported with the line and column of the offending
+//! token.
+//!
+//! Queries are evaluated in the database by `prom_api.promql_query_range` and
+//! `prom_api.promql_query`, without the connector:
+//!
+//! ```sql
+//! SELECT * FROM prom_api.promql_query_range(
+//!     'sum by (job) (rate(http_requests_total[5m]))',
+//!     now() - interval '1 hour', now(), interval '1 minute');
+//! ```
+//!
+//! Selectors are read from the `prom_metric` views, with the matchers applied
+//! through the `prom_api.?` label matcher operators. Instant vector selectors
+//! are evaluated by `vector_selector`, and `rate`, `increase` and `delta` of
+//! range vector selectors by `prom_rate`, `prom_increase` and `prom_delta`.
+//! Aggregations, binary operators and the remaining functions are evaluated
+//! in Rust (see [`eval`]). Everything else, e.g. subqueries, the `@`
+//! modifier or `topk`, is rejected with an "unsupported PromQL feature"
+//! error.
+//!
+//! Series selectors on their own, e.g. `{__name__=~"http_.*", job=""}`, are
+//! parsed by `prom_api.parse_selector`, and `prom_api.select_series` returns
+//! the series they match, optionally only those with samples in a time range.
+//! As in Prometheus, matchers that match the empty string also match series
+//! without the label.
+//!
+//! [PromQL]: https://prometheus.io/docs/prometheus/latest/querying/basics/
+use pgx::*;
+
+use serde_json::Value;
+
+use crate::exposition::ParseError;
+
+pub(crate) mod ast;
+mod eval;
+mod lexer;
+mod parser;
+mod storage;
+
+#[pg_schema]
+mod _prom_ext {
+    use pgx::*;
+
+    use serde_json::Value;
+
+    use super::ast::ExprKind;
+    use super::eval::{evaluate, EvalRange};
+    use super::{matchers_json, parse, parse_selector, storage};
+    use crate::prompb::from_pg_timestamp;
+
+    #[pg_extern(immutable, strict, parallel_safe, create_or_replace)]
+    pub fn promql_parse(query: &str) -> JsonB {
+        let expr = parse(query).unwrap_or_else(|e| error!("invalid PromQL at {}", e));
+        JsonB(expr.to_json())
+    }
+
+    #[pg_extern(immutable, strict, parallel_safe, create_or_replace)]
+    pub fn promql_selectors(
+        query: &str,
+    ) -> TableIterator<
+        'static,
+        (
+            name!(kind, String),
+            name!(metric_name, Option<String>),
+            name!(matchers, JsonB),
+            name!(range_ms, Option<i64>),
+            name!(offset_ms, i64),
+            name!(start_pos, i32),
+            name!(end_pos, i32),
+        ),
+    > {
+        let expr = parse(query).unwrap_or_else(|e| error!("invalid PromQL at {}", e));
+        let mut rows = vec![];
+        expr.walk(&mut |e| {
+            let (kind, selector, range) = match &e.kind {
+                ExprKind::VectorSelector(selector) => ("vector", selector, None),
+                ExprKind::MatrixSelector { selector, range } => ("matrix", selector, Some(*range)),
+                _ => return,
+            };
+            rows.push((
+                kind.to_string(),
+                Some(selector.name.clone()).filter(|n| !n.is_empty()),
+                JsonB(matchers_json(&selector.matchers)),
+                range,
+                selector.modifiers.offset,
+                e.pos.start as i32,
+                e.pos.end as i32,
+            ));
+        });
+        TableIterator::new(rows.into_iter())
+    }
+
+    #[pg_extern(stable, strict, parallel_safe, create_or_replace)]
+    pub fn promql_query_range(
+        query: &str,
+        start: TimestampWithTimeZone,
+        end: TimestampWithTimeZone,
+        step_ms: i64,
+    ) -> TableIterator<'static, (name!(labels, JsonB), name!(values, Vec<Option<f64>>))> {
+        let expr = parse(query).unwrap_or_else(|e| error!("invalid PromQL at {}", e));
+        let start: i64 = start.into();
+        let end: i64 = end.into();
+        let range = EvalRange::new(from_pg_timestamp(start), from_pg_timestamp(end), step_ms)
+            .unwrap_or_else(|e| error!("{}", e));
+        let series =
+            evaluate(&expr, range, &mut storage::fetch).unwrap_or_else(|e| error!("{}", e));
+        TableIterator::new(series.into_iter().map(|s| {
+            let labels = s
+                .labels
+                .into_iter()
+                .map(|(name, value)| (name, Value::String(value)))
+                .collect();
+            (JsonB(Value::Object(labels)), s.values)
+        }))
+    }
+
+    #[pg_extern(immutable, strict, parallel_safe, create_or_replace)]
+    pub fn parse_selector(selector: &str) -> JsonB {
+        let selector =
+            parse_selector(selector).unwrap_or_else(|e| error!("invalid series selector at {}", e));
+        JsonB(serde_json::json!({
+            "name": Some(selector.name).filter(|n| !n.is_empty()),
+            "matchers": matchers_json(&selector.matchers),
+        }))
+    }
+
+    #[pg_extern(stable, parallel_safe, create_or_replace)]
+    pub fn select_series(
+        selector: Option<&str>,
+        start: Option<TimestampWithTimeZone>,
+        end: Option<TimestampWithTimeZone>,
+    ) -> TableIterator<
+        'static,
+        (
+            name!(series_id, i64),
+            name!(metric_name, String),
+            name!(labels, JsonB),
+        ),
+    > {
+        let selector = match selector {
+            Some(selector) => parse_selector(selector)
+                .unwrap_or_else(|e| error!("invalid series selector at {}", e)),
+            None => error!("series selector cannot be NULL"),
+        };
+        let series = storage::select_series(&selector.matchers, start, end);
+        TableIterator::new(
+            series
+                .into_iter()
+                .map(|(id, metric_name, labels)| (id, metric_name, JsonB(labels))),
+        )
+    }
+}
+
+/// A syntax error at a byte offset of the query.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SyntaxError {
+    pub(crate) pos: usize,
+    pub(crate) message: String,
+}
+
+impl SyntaxError {
+    pub(crate) fn new(pos: usize, message: impl Into<String>) -> Self {
+        Self {
+            pos,
+            message: message.into(),
+        }
+    }
+
+    /// Locates the error in `query`, with 1-based lines and columns counted
+    /// in characters.
+    fn locate(self, query: &str) -> ParseError {
+        let before = &query[..self.pos.min(query.len())];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        ParseError {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            message: self.message,
+        }
+    }
+}
+
+/// Parses and type checks a PromQL query.
+pub(crate) fn parse(query: &str) -> Result<ast::Expr, ParseError> {
+    parser::parse(query).map_err(|e| e.locate(query))
+}
+
+/// Parses a series selector, i.e. a vector selector without modifiers.
+fn parse_selector(selector: &str) -> Result<ast::VectorSelector, ParseError> {
+    let expr = parse(selector)?;
+    match expr.kind {
+        ast::ExprKind::VectorSelector(s) if s.modifiers == ast::Modifiers::default() => Ok(s),
+        _ => Err(SyntaxError::new(expr.pos.start, "expected a series selector").locate(selector)),
+    }
+}
+
+/// The JSON of the matchers of a selector.
+fn matchers_json(matchers: &[ast::Matcher]) -> Value {
+    Value::Array(matchers.iter().map(|m| m.to_json()).collect())
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+    use serde_json::json;
+
+    fn query(sql: &str, promql: &str) -> serde_json::Value {
+        Spi::get_one_with_args::<Json>(
+            sql,
+            vec![(PgBuiltInOids::TEXTOID.oid(), promql.into_datum())],
+        )
+        .expect("SQL query failed")
+        .0
+    }
+
+    #[pg_test]
+    fn test_promql_parse() {
+        assert_eq!(
+            query(
+                "SELECT prom_api.promql_parse($1)::json",
+                r#"foo{job="api"} offset 1m > bool 2"#
+            ),
+            json!({
+                "type": "binaryExpr",
+                "op": ">",
+                "bool": true,
+                "matching": null,
+                "lhs": {
+                    "type": "vectorSelector",
+                    "name": "foo",
+                    "matchers": [
+                        {"name": "job", "type": "=", "value": "api"},
+                        {"name": "__name__", "type": "=", "value": "foo"},
+                    ],
+                    "offset": 60000,
+                    "timestamp": null,
+                    "startOrEnd": null,
+                    "posRange": {"start": 0, "end": 24},
+                },
+                "rhs": {
+                    "type": "numberLiteral",
+                    "val": "2",
+                    "posRange": {"start": 32, "end": 33},
+                },
+                "posRange": {"start": 0, "end": 33},
+            })
+        );
+    }
+
+    #[pg_test]
+    fn test_promql_selectors() {
+        assert_eq!(
+            query(
+                "SELECT json_agg(json_build_array(kind, metric_name, matchers, range_ms, offset_ms, start_pos, end_pos))
+                 FROM prom_api.promql_selectors($1)",
+                r#"rate(foo[5m] offset 1h) / ignoring(x) bar{a!~"b|c"}"#
+            ),
+            json!([
+                [
+                    "matrix",
+                    "foo",
+                    [{"name": "__name__", "type": "=", "value": "foo"}],
+                    300000,
+                    3600000,
+                    5,
+                    22
+                ],
+                [
+                    "vector",
+                    "bar",
+                    [
+                        {"name": "a", "type": "!~", "value": "b|c"},
+                        {"name": "__name__", "type": "=", "value": "bar"},
+                    ],
+                    null,
+                    0,
+                    38,
+                    51
+                ],
+            ])
+        );
+    }
+
+    #[pg_test(
+        error = "invalid PromQL at line 2, column 13: label \"job\" must not occur in ON and GROUP clause at once"
+    )]
+    fn test_promql_parse_invalid_grouping() {
+        query(
+            "SELECT prom_api.promql_parse($1)::json",
+            "sum(rate(foo[5m]))\n  / on(job) group_left(job) bar",
+        );
+    }
+
+    #[pg_test(
+        error = "invalid PromQL at line 1, column 5: expected type instant vector in aggregation expression, got range vector"
+    )]
+    fn test_promql_parse_type_error() {
+        query(
+            "SELECT prom_api.promql_parse($1)::json",
+            r#"sum(foo{a="b"}[5m])"#,
+        );
+    }
+
+    #[pg_test(
+        error = "invalid PromQL at line 1, column 257: expression nested deeper than 256 levels"
+    )]
+    fn test_promql_parse_too_deeply_nested() {
+        Spi::run(
+            "SELECT prom_api.promql_parse(repeat('(', 1000000) || '1' || repeat(')', 1000000))",
+        );
+    }
+
+    #[pg_test(
+        error = "invalid PromQL at line 1, column 257: expression nested deeper than 256 levels"
+    )]
+    fn test_promql_parse_too_many_unary_operators() {
+        Spi::run("SELECT prom_api.promql_parse(repeat('-', 1000000) || '1')");
+    }
+
+    #[pg_test]
+    fn test_promql_parse_long_binary_chain() {
+        let result = Spi::get_one::<bool>(
+            "SELECT prom_api.promql_parse(string_agg('up' || i, ' or ')) IS NOT NULL FROM generate_series(1, 300) i",
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test(error = "invalid PromQL at line 1, column 4098: more than 2048 binary operators")]
+    fn test_promql_parse_too_many_binary_operators() {
+        Spi::run("SELECT prom_api.promql_parse('1' || repeat('+1', 1000000))");
+    }
+
+    /// Three `http_requests_total` counters with a sample every minute from
+    /// 2000-01-01 00:00 to 00:30, increasing by 1, 3 and 1 per second.
+    fn setup() {
+        Spi::run(
+            r#"
+            SELECT _prom_catalog.get_or_create_metric_table_name('http_requests_total');
+            INSERT INTO prom_data.http_requests_total(time, value, series_id)
+            SELECT
+                '2000-01-01 00:00:00+00'::timestamptz + m * interval '1 minute',
+                60 * m * s.per_second,
+                _prom_catalog.get_or_create_series_id(s.labels)
+            FROM (VALUES
+                ('{"__name__": "http_requests_total", "job": "api", "instance": "a"}'::jsonb, 1),
+                ('{"__name__": "http_requests_total", "job": "api", "instance": "b"}'::jsonb, 3),
+                ('{"__name__": "http_requests_total", "job": "db", "instance": "c"}'::jsonb, 1)
+            ) s(labels, per_second), generate_series(0, 30) m;
+        "#,
+        );
+    }
+
+    #[pg_test]
+    fn test_promql_query_range() {
+        setup();
+        assert_eq!(
+            query(
+                r#"SELECT json_agg(json_build_array(labels, "values"))
+                   FROM prom_api.promql_query_range($1,
+                       '2000-01-01 00:10:00+00', '2000-01-01 00:20:00+00', '5 minutes')"#,
+                "sum by (job) (rate(http_requests_total[5m]))"
+            ),
+            json!([
+                [{"job": "api"}, [4, 4, 4]],
+                [{"job": "db"}, [1, 1, 1]],
+            ])
+        );
+    }
+
+    #[pg_test]
+    fn test_promql_query() {
+        setup();
+        let instant = |promql: &str| {
+            query(
+                "SELECT json_agg(json_build_array(labels, value))
+                 FROM prom_api.promql_query($1, '2000-01-01 00:10:00+00')",
+                promql,
+            )
+        };
+        assert_eq!(
+            instant(
+                "http_requests_total / ignoring(instance) group_left
+                 sum without (instance) (http_requests_total)"
+            ),
+            json!([
+                [{"instance": "a", "job": "api"}, 0.25],
+                [{"instance": "b", "job": "api"}, 0.75],
+                [{"instance": "c", "job": "db"}, 1],
+            ])
+        );
+        assert_eq!(
+            instant(r#"http_requests_total{job=~"a.*", instance!="b"} offset 1m"#),
+            json!([[
+                {"__name__": "http_requests_total", "instance": "a", "job": "api"},
+                540
+            ]])
+        );
+        // `team=""` matches series without the label
+        assert_eq!(
+            instant(
+                r#"count(http_requests_total{job=~"a.*"})
+                   + count(http_requests_total{instance!="b", team=""})"#
+            ),
+            json!([[{}, 4]])
+        );
+    }
+
+    #[pg_test(error = "unsupported PromQL feature: aggregation topk()")]
+    fn test_promql_query_unsupported() {
+        query(
+            "SELECT json_agg(labels) FROM prom_api.promql_query($1)",
+            "topk(1, http_requests_total)",
+        );
+    }
+
+    #[pg_test]
+    fn test_parse_selector() {
+        assert_eq!(
+            query(
+                "SELECT prom_api.parse_selector($1)::json",
+                r#"http_requests_total{job=~"a.*", team=""}"#
+            ),
+            json!({
+                "name": "http_requests_total",
+                "matchers": [
+                    {"name": "job", "type": "=~", "value": "a.*"},
+                    {"name": "team", "type": "=", "value": ""},
+                    {"name": "__name__", "type": "=", "value": "http_requests_total"},
+                ],
+            })
+        );
+    }
+
+    #[pg_test]
+    fn test_select_series() {
+        setup();
+        let select = |selector: &str| {
+            query(
+                "SELECT json_agg(json_build_array(metric_name, labels->>'instance'))
+                 FROM prom_api.select_series($1)",
+                selector,
+            )
+        };
+        assert_eq!(
+            select(r#"{job="api"}"#),
+            json!([["http_requests_total", "a"], ["http_requests_total", "b"]])
+        );
+        // `team=""` matches series without the label
+        assert_eq!(
+            select(r#"{__name__=~"http_.*", team="", instance!~"a|b"}"#),
+            json!([["http_requests_total", "c"]])
+        );
+        assert_eq!(select(r#"{job="api", team!=""}"#), serde_json::Value::Null);
+        assert_eq!(
+            query(
+                "SELECT to_json(count(*)) FROM prom_api.select_series($1,
+                     '2000-01-01 01:00:00+00', '2000-01-01 02:00:00+00')",
+                "http_requests_total",
+            ),
+            json!(0)
+        );
+    }
+
+    #[pg_test(error = "invalid series selector at line 1, column 1: expected a series selector")]
+    fn test_select_series_invalid() {
+        query(
+            "SELECT json_agg(labels) FROM prom_api.select_series($1)",
+            "rate(http_requests_total[5m])",
+        );
+    }
+}
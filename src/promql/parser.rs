@@ -0,0 +1,908 @@
+//! A recursive descent parser for PromQL, with the type checks of Prometheus'
+//! parser.
+use super::ast::{
+    aggregator_param_type, function, BinaryOp, Cardinality, Expr, ExprKind, MatchOp, Matcher,
+    Modifiers, PosRange, StartOrEnd, ValueType, VectorMatching, VectorSelector, AGGREGATORS,
+};
+use super::lexer::{tokenize, Token, TokenKind};
+use super::SyntaxError;
+use crate::regex::cached_regex;
+
+pub(crate) fn parse(input: &str) -> Result<Expr, SyntaxError> {
+    let mut parser = Parser {
+        input,
+        tokens: tokenize(input)?,
+        pos: 0,
+        depth: 0,
+        binary_operators: 0,
+    };
+    let expr = parser.expr(0)?;
+    if parser.peek().kind != TokenKind::Eof {
+        return Err(parser.unexpected());
+    }
+    Ok(expr)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Nesting depth of the expression being parsed.
+    depth: usize,
+    /// Binary operators parsed so far.
+    binary_operators: usize,
+}
+
+/// How deep expressions may nest, counting parentheses, unary operators,
+/// arguments and right-hand operands. Parsing recurses once per level, so
+/// without a bound a short query could overflow the stack.
+const MAX_DEPTH: usize = 256;
+
+/// How many binary operators a query may have. A chain like `a or b or c`
+/// doesn't recurse while parsing, but becomes a left-deep tree that evaluating
+/// and dropping it recurse into, so its length needs a bound of its own. With
+/// [`MAX_DEPTH`], this bounds the height of any expression tree.
+const MAX_BINARY_OPERATORS: usize = 2048;
+
+/// Binary operators with their precedence, from loosest to tightest binding.
+fn binary_op(token: &TokenKind) -> Option<(BinaryOp, u8)> {
+    let op = match token {
+        TokenKind::Identifier(keyword) => match keyword.as_str() {
+            "or" => BinaryOp::Or,
+            "and" => BinaryOp::And,
+            "unless" => BinaryOp::Unless,
+            "atan2" => BinaryOp::Atan2,
+            _ => return None,
+        },
+        TokenKind::Operator(op) => match *op {
+            "==" => BinaryOp::Eql,
+            "!=" => BinaryOp::Neq,
+            ">" => BinaryOp::Gtr,
+            "<" => BinaryOp::Lss,
+            ">=" => BinaryOp::Gte,
+            "<=" => BinaryOp::Lte,
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Sub,
+            "*" => BinaryOp::Mul,
+            "/" => BinaryOp::Div,
+            "%" => BinaryOp::Mod,
+            "^" => BinaryOp::Pow,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let precedence = match op {
+        BinaryOp::Or => 1,
+        BinaryOp::And | BinaryOp::Unless => 2,
+        _ if op.is_comparison() => 3,
+        BinaryOp::Add | BinaryOp::Sub => 4,
+        BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod | BinaryOp::Atan2 => 5,
+        _ => 6,
+    };
+    Some((op, precedence))
+}
+
+/// Unary operators bind like multiplication.
+const UNARY_PRECEDENCE: u8 = 5;
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+
+    fn peek_nth(&self, n: usize) -> &Token {
+        &self.tokens[(self.pos + n).min(self.tokens.len() - 1)]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if self.pos < self.tokens.len() - 1 {
+            self.pos += 1;
+        }
+        token
+    }
+
+    /// The end of the last consumed token.
+    fn last_end(&self) -> usize {
+        self.tokens[self.pos.saturating_sub(1)].end
+    }
+
+    fn unexpected(&self) -> SyntaxError {
+        let token = self.peek();
+        SyntaxError::new(
+            token.start,
+            format!("unexpected {}", token.describe(self.input)),
+        )
+    }
+
+    fn expect(&mut self, kind: TokenKind, what: &str) -> Result<Token, SyntaxError> {
+        if self.peek().kind == kind {
+            Ok(self.next())
+        } else {
+            let token = self.peek();
+            Err(SyntaxError::new(
+                token.start,
+                format!(
+                    "unexpected {}, expected {}",
+                    token.describe(self.input),
+                    what
+                ),
+            ))
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(&self.peek().kind, TokenKind::Identifier(k) if k == keyword)
+    }
+
+    /// Parses binary expressions whose operators bind at least as tightly as
+    /// `min_precedence`.
+    fn expr(&mut self, min_precedence: u8) -> Result<Expr, SyntaxError> {
+        let depth = self.depth;
+        self.descend(self.peek().start)?;
+        let mut lhs = self.unary()?;
+        while let Some((op, precedence)) = binary_op(&self.peek().kind) {
+            if precedence < min_precedence {
+                break;
+            }
+            if self.binary_operators >= MAX_BINARY_OPERATORS {
+                return Err(SyntaxError::new(
+                    self.peek().start,
+                    format!("more than {} binary operators", MAX_BINARY_OPERATORS),
+                ));
+            }
+            self.binary_operators += 1;
+            let op_token = self.next();
+            let return_bool = op.is_comparison() && self.is_keyword("bool");
+            if return_bool {
+                self.next();
+            } else if self.is_keyword("bool") {
+                return Err(SyntaxError::new(
+                    self.peek().start,
+                    "bool modifier can only be used on comparison operators",
+                ));
+            }
+            let matching = self.vector_matching(op)?;
+            // `^` is right-associative
+            let rhs = self.expr(if op == BinaryOp::Pow {
+                precedence
+            } else {
+                precedence + 1
+            })?;
+            lhs = binary_expr(op, op_token.start, lhs, rhs, matching, return_bool)?;
+        }
+        self.depth = depth;
+        Ok(lhs)
+    }
+
+    fn descend(&mut self, pos: usize) -> Result<(), SyntaxError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(SyntaxError::new(
+                pos,
+                format!("expression nested deeper than {} levels", MAX_DEPTH),
+            ));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Parses `on`/`ignoring` and `group_left`/`group_right`.
+    fn vector_matching(&mut self, op: BinaryOp) -> Result<Option<VectorMatching>, SyntaxError> {
+        let on = if self.is_keyword("on") {
+            true
+        } else if self.is_keyword("ignoring") {
+            false
+        } else {
+            return Ok(None);
+        };
+        self.next();
+        let matching_labels = self.label_list()?;
+        let card = if self.is_keyword("group_left") {
+            Cardinality::ManyToOne
+        } else if self.is_keyword("group_right") {
+            Cardinality::OneToMany
+        } else {
+            return Ok(Some(VectorMatching {
+                card: Cardinality::OneToOne,
+                matching_labels,
+                on,
+                include: vec![],
+            }));
+        };
+        let group = self.next();
+        if op.is_set_operator() {
+            return Err(SyntaxError::new(
+                group.start,
+                format!("no grouping allowed for {:?} operation", op.symbol()),
+            ));
+        }
+        let include = if self.peek().kind == TokenKind::LeftParen {
+            self.label_list()?
+        } else {
+            vec![]
+        };
+        if on {
+            if let Some(label) = include.iter().find(|l| matching_labels.contains(l)) {
+                return Err(SyntaxError::new(
+                    group.start,
+                    format!(
+                        "label {:?} must not occur in ON and GROUP clause at once",
+                        label
+                    ),
+                ));
+            }
+        }
+        Ok(Some(VectorMatching {
+            card,
+            matching_labels,
+            on,
+            include,
+        }))
+    }
+
+    fn unary(&mut self) -> Result<Expr, SyntaxError> {
+        let op = match &self.peek().kind {
+            TokenKind::Operator("-") => BinaryOp::Sub,
+            TokenKind::Operator("+") => BinaryOp::Add,
+            _ => return self.postfix(),
+        };
+        let start = self.next().start;
+        let expr = self.expr(UNARY_PRECEDENCE + 1)?;
+        let pos = PosRange {
+            start,
+            end: expr.pos.end,
+        };
+        match expr.kind {
+            ExprKind::NumberLiteral(n) => Ok(Expr {
+                kind: ExprKind::NumberLiteral(if op == BinaryOp::Sub { -n } else { n }),
+                pos,
+            }),
+            _ => {
+                let value_type = expr.value_type();
+                if value_type != ValueType::Scalar && value_type != ValueType::Vector {
+                    return Err(SyntaxError::new(
+                        start,
+                        format!(
+                            "unary expression only allowed on expressions of type scalar or instant vector, got {:?}",
+                            value_type.documented_name()
+                        ),
+                    ));
+                }
+                Ok(Expr {
+                    kind: ExprKind::Unary {
+                        op,
+                        expr: Box::new(expr),
+                    },
+                    pos,
+                })
+            }
+        }
+    }
+
+    /// Parses a primary expression followed by ranges, subqueries, `offset`
+    /// and `@` modifiers.
+    fn postfix(&mut self) -> Result<Expr, SyntaxError> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.peek().kind == TokenKind::LeftBracket {
+                expr = self.range_or_subquery(expr)?;
+            } else if self.is_keyword("offset") {
+                let keyword = self.next();
+                let negative = self.peek().kind == TokenKind::Operator("-");
+                if negative || self.peek().kind == TokenKind::Operator("+") {
+                    self.next();
+                }
+                let offset = match self.next().kind {
+                    TokenKind::Duration(ms) => ms,
+                    _ => {
+                        self.pos -= 1;
+                        return Err(self.unexpected());
+                    }
+                };
+                let modifiers = self.modifiers_of(&mut expr, keyword.start, "offset")?;
+                if modifiers.offset != 0 {
+                    return Err(SyntaxError::new(
+                        keyword.start,
+                        "offset may not be set multiple times",
+                    ));
+                }
+                modifiers.offset = if negative { -offset } else { offset };
+                expr.pos.end = self.last_end();
+            } else if self.peek().kind == TokenKind::At {
+                let at = self.next();
+                let (timestamp, start_or_end) = self.at_modifier()?;
+                let modifiers = self.modifiers_of(&mut expr, at.start, "@")?;
+                if modifiers.timestamp.is_some() || modifiers.start_or_end.is_some() {
+                    return Err(SyntaxError::new(
+                        at.start,
+                        "@ <timestamp> may not be set multiple times",
+                    ));
+                }
+                modifiers.timestamp = timestamp;
+                modifiers.start_or_end = start_or_end;
+                expr.pos.end = self.last_end();
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    /// The modifiers `offset` or `@` apply to.
+    fn modifiers_of<'e>(
+        &self,
+        expr: &'e mut Expr,
+        pos: usize,
+        what: &str,
+    ) -> Result<&'e mut Modifiers, SyntaxError> {
+        match &mut expr.kind {
+            ExprKind::VectorSelector(selector) | ExprKind::MatrixSelector { selector, .. } => {
+                Ok(&mut selector.modifiers)
+            }
+            ExprKind::Subquery { modifiers, .. } => Ok(modifiers),
+            _ => Err(SyntaxError::new(
+                pos,
+                format!(
+                    "{} modifier must be preceded by an instant vector selector or range vector selector or a subquery",
+                    what
+                ),
+            )),
+        }
+    }
+
+    /// Parses the argument of `@`: a Unix timestamp in seconds, `start()` or
+    /// `end()`.
+    fn at_modifier(&mut self) -> Result<(Option<i64>, Option<StartOrEnd>), SyntaxError> {
+        let token = self.next();
+        match &token.kind {
+            TokenKind::Identifier(f) if f == "start" || f == "end" => {
+                self.expect(TokenKind::LeftParen, "\"(\"")?;
+                self.expect(TokenKind::RightParen, "\")\"")?;
+                let start_or_end = if f == "start" {
+                    StartOrEnd::Start
+                } else {
+                    StartOrEnd::End
+                };
+                Ok((None, Some(start_or_end)))
+            }
+            TokenKind::Operator("-") | TokenKind::Operator("+") | TokenKind::Number(_) => {
+                let mut seconds = match token.kind {
+                    TokenKind::Number(n) => n,
+                    _ => match self.next().kind {
+                        TokenKind::Number(n) => n,
+                        _ => {
+                            self.pos -= 1;
+                            return Err(self.unexpected());
+                        }
+                    },
+                };
+                if token.kind == TokenKind::Operator("-") {
+                    seconds = -seconds;
+                }
+                let ms = (seconds * 1000.0).round();
+                if !ms.is_finite() || ms.abs() >= i64::MAX as f64 {
+                    return Err(SyntaxError::new(
+                        token.start,
+                        "timestamp out of bounds for @ modifier",
+                    ));
+                }
+                Ok((Some(ms as i64), None))
+            }
+            _ => {
+                self.pos -= 1;
+                Err(self.unexpected())
+            }
+        }
+    }
+
+    /// Parses `[<range>]` after a vector selector, or `[<range>:<step>]`
+    /// after any instant vector expression.
+    fn range_or_subquery(&mut self, expr: Expr) -> Result<Expr, SyntaxError> {
+        let bracket = self.next();
+        let range = self.duration()?;
+        if self.peek().kind == TokenKind::Colon {
+            self.next();
+            let step = if self.peek().kind == TokenKind::RightBracket {
+                0
+            } else {
+                self.duration()?
+            };
+            self.expect(TokenKind::RightBracket, "\"]\"")?;
+            if expr.value_type() != ValueType::Vector {
+                return Err(SyntaxError::new(
+                    expr.pos.start,
+                    format!(
+                        "subquery is only allowed on instant vector, got {} instead",
+                        expr.value_type().documented_name()
+                    ),
+                ));
+            }
+            let start = expr.pos.start;
+            return Ok(Expr {
+                kind: ExprKind::Subquery {
+                    expr: Box::new(expr),
+                    range,
+                    step,
+                    modifiers: Modifiers::default(),
+                },
+                pos: PosRange {
+                    start,
+                    end: self.last_end(),
+                },
+            });
+        }
+        self.expect(TokenKind::RightBracket, "\"]\" or \":\"")?;
+        match expr.kind {
+            ExprKind::VectorSelector(selector) => {
+                if selector.modifiers != Modifiers::default() {
+                    return Err(SyntaxError::new(
+                        bracket.start,
+                        "no offset or @ modifiers allowed before range",
+                    ));
+                }
+                Ok(Expr {
+                    kind: ExprKind::MatrixSelector { selector, range },
+                    pos: PosRange {
+                        start: expr.pos.start,
+                        end: self.last_end(),
+                    },
+                })
+            }
+            _ => Err(SyntaxError::new(
+                bracket.start,
+                "ranges only allowed for vector selectors",
+            )),
+        }
+    }
+
+    fn duration(&mut self) -> Result<i64, SyntaxError> {
+        match self.peek().kind {
+            TokenKind::Duration(ms) => {
+                self.next();
+                Ok(ms)
+            }
+            _ => {
+                let token = self.peek();
+                Err(SyntaxError::new(
+                    token.start,
+                    format!(
+                        "unexpected {}, expected a duration",
+                        token.describe(self.input)
+                    ),
+                ))
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, SyntaxError> {
+        let token = self.peek().clone();
+        match &token.kind {
+            TokenKind::Number(n) => {
+                self.next();
+                Ok(Expr {
+                    kind: ExprKind::NumberLiteral(*n),
+                    pos: PosRange {
+                        start: token.start,
+                        end: token.end,
+                    },
+                })
+            }
+            TokenKind::String(s) => {
+                self.next();
+                Ok(Expr {
+                    kind: ExprKind::StringLiteral(s.clone()),
+                    pos: PosRange {
+                        start: token.start,
+                        end: token.end,
+                    },
+                })
+            }
+            TokenKind::LeftParen => {
+                self.next();
+                let expr = self.expr(0)?;
+                self.expect(TokenKind::RightParen, "\")\"")?;
+                Ok(Expr {
+                    kind: ExprKind::Paren(Box::new(expr)),
+                    pos: PosRange {
+                        start: token.start,
+                        end: self.last_end(),
+                    },
+                })
+            }
+            TokenKind::LeftBrace => self.vector_selector(String::new(), token.start),
+            TokenKind::Identifier(name) => {
+                let next = &self.peek_nth(1).kind;
+                let is_aggregation = AGGREGATORS.contains(&name.as_str())
+                    && (*next == TokenKind::LeftParen
+                        || matches!(next, TokenKind::Identifier(k) if k == "by" || k == "without"));
+                if is_aggregation {
+                    self.aggregation()
+                } else if *next == TokenKind::LeftParen {
+                    self.call()
+                } else {
+                    self.next();
+                    self.vector_selector(name.clone(), token.start)
+                }
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn call(&mut self) -> Result<Expr, SyntaxError> {
+        let name_token = self.next();
+        let name = match &name_token.kind {
+            TokenKind::Identifier(name) => name.clone(),
+            _ => unreachable!(),
+        };
+        let func = function(&name).ok_or_else(|| {
+            SyntaxError::new(
+                name_token.start,
+                format!("unknown function with name {:?}", name),
+            )
+        })?;
+        self.next();
+        let mut args = vec![];
+        while self.peek().kind != TokenKind::RightParen {
+            args.push(self.expr(0)?);
+            if self.peek().kind != TokenKind::Comma {
+                break;
+            }
+            self.next();
+        }
+        self.expect(TokenKind::RightParen, "\",\" or \")\"")?;
+        let pos = PosRange {
+            start: name_token.start,
+            end: self.last_end(),
+        };
+
+        let nargs = args.len();
+        let ntypes = func.arg_types.len();
+        if func.variadic == 0 {
+            if nargs != ntypes {
+                return Err(SyntaxError::new(
+                    pos.start,
+                    format!(
+                        "expected {} argument(s) in call to {:?}, got {}",
+                        ntypes, name, nargs
+                    ),
+                ));
+            }
+        } else if nargs + 1 < ntypes {
+            return Err(SyntaxError::new(
+                pos.start,
+                format!(
+                    "expected at least {} argument(s) in call to {:?}, got {}",
+                    ntypes - 1,
+                    name,
+                    nargs
+                ),
+            ));
+        } else if func.variadic > 0 && nargs > ntypes + func.variadic as usize - 1 {
+            return Err(SyntaxError::new(
+                pos.start,
+                format!(
+                    "expected at most {} argument(s) in call to {:?}, got {}",
+                    ntypes + func.variadic as usize - 1,
+                    name,
+                    nargs
+                ),
+            ));
+        }
+        for (i, arg) in args.iter().enumerate() {
+            let expected = func.arg_types[i.min(ntypes - 1)];
+            expect_type(arg, expected, &format!("call to function {:?}", name))?;
+        }
+        Ok(Expr {
+            kind: ExprKind::Call { func, args },
+            pos,
+        })
+    }
+
+    fn aggregation(&mut self) -> Result<Expr, SyntaxError> {
+        let op_token = self.next();
+        let op = match &op_token.kind {
+            TokenKind::Identifier(op) => *AGGREGATORS.iter().find(|a| *a == op).unwrap(),
+            _ => unreachable!(),
+        };
+        let mut grouping = self.grouping()?;
+
+        self.expect(TokenKind::LeftParen, "\"(\"")?;
+        let mut args = vec![self.expr(0)?];
+        while self.peek().kind == TokenKind::Comma {
+            self.next();
+            if self.peek().kind == TokenKind::RightParen {
+                break;
+            }
+            args.push(self.expr(0)?);
+        }
+        self.expect(TokenKind::RightParen, "\",\" or \")\"")?;
+
+        if let Some(trailing) = self.grouping()? {
+            if grouping.is_some() {
+                return Err(SyntaxError::new(
+                    op_token.start,
+                    "aggregation modifiers may only be given once",
+                ));
+            }
+            grouping = Some(trailing);
+        }
+        let pos = PosRange {
+            start: op_token.start,
+            end: self.last_end(),
+        };
+
+        let param_type = aggregator_param_type(op);
+        let expected_args = if param_type.is_some() { 2 } else { 1 };
+        if args.len() != expected_args {
+            return Err(SyntaxError::new(
+                pos.start,
+                format!(
+                    "wrong number of arguments for aggregate expression provided, expected {}, got {}",
+                    expected_args,
+                    args.len()
+                ),
+            ));
+        }
+        let expr = args.pop().unwrap();
+        expect_type(&expr, ValueType::Vector, "aggregation expression")?;
+        let param = args.pop();
+        if let (Some(param), Some(param_type)) = (&param, param_type) {
+            expect_type(param, param_type, "aggregation parameter")?;
+        }
+        let (grouping, without) = grouping.unwrap_or_default();
+        Ok(Expr {
+            kind: ExprKind::Aggregate {
+                op,
+                expr: Box::new(expr),
+                param: param.map(Box::new),
+                grouping,
+                without,
+            },
+            pos,
+        })
+    }
+
+    /// Parses an optional `by (...)` or `without (...)`.
+    fn grouping(&mut self) -> Result<Option<(Vec<String>, bool)>, SyntaxError> {
+        let without = if self.is_keyword("by") {
+            false
+        } else if self.is_keyword("without") {
+            true
+        } else {
+            return Ok(None);
+        };
+        self.next();
+        Ok(Some((self.label_list()?, without)))
+    }
+
+    /// Parses `(label, ...)`.
+    fn label_list(&mut self) -> Result<Vec<String>, SyntaxError> {
+        self.expect(TokenKind::LeftParen, "\"(\"")?;
+        let mut labels = vec![];
+        while self.peek().kind != TokenKind::RightParen {
+            labels.push(self.label_name()?);
+            if self.peek().kind != TokenKind::Comma {
+                break;
+            }
+            self.next();
+        }
+        self.expect(TokenKind::RightParen, "\",\" or \")\"")?;
+        Ok(labels)
+    }
+
+    fn label_name(&mut self) -> Result<String, SyntaxError> {
+        let token = self.peek();
+        match &token.kind {
+            TokenKind::Identifier(name) if !name.contains(':') => {
+                let name = name.clone();
+                self.next();
+                Ok(name)
+            }
+            _ => Err(SyntaxError::new(
+                token.start,
+                format!(
+                    "unexpected {}, expected a label name",
+                    token.describe(self.input)
+                ),
+            )),
+        }
+    }
+
+    /// Parses the optional `{...}` of a selector whose name, if any, has been
+    /// consumed already.
+    fn vector_selector(&mut self, name: String, start: usize) -> Result<Expr, SyntaxError> {
+        let mut matchers = vec![];
+        if self.peek().kind == TokenKind::LeftBrace {
+            self.next();
+            while self.peek().kind != TokenKind::RightBrace {
+                matchers.push(self.matcher()?);
+                if self.peek().kind != TokenKind::Comma {
+                    break;
+                }
+                self.next();
+            }
+            self.expect(TokenKind::RightBrace, "\",\" or \"}\"")?;
+        }
+        let pos = PosRange {
+            start,
+            end: self.last_end(),
+        };
+
+        if !name.is_empty() {
+            if let Some(m) = matchers.iter().find(|m| m.name == "__name__") {
+                return Err(SyntaxError::new(
+                    start,
+                    format!(
+                        "metric name must not be set twice: {:?} or {:?}",
+                        name, m.value
+                    ),
+                ));
+            }
+            matchers.push(Matcher {
+                name: "__name__".to_string(),
+                op: MatchOp::Equal,
+                value: name.clone(),
+            });
+        }
+        if !matchers.iter().any(|m| !matches_empty(m)) {
+            return Err(SyntaxError::new(
+                start,
+                "vector selector must contain at least one non-empty matcher",
+            ));
+        }
+        Ok(Expr {
+            kind: ExprKind::VectorSelector(VectorSelector {
+                name,
+                matchers,
+                modifiers: Modifiers::default(),
+            }),
+            pos,
+        })
+    }
+
+    fn matcher(&mut self) -> Result<Matcher, SyntaxError> {
+        let name = self.label_name()?;
+        let op_token = self.next();
+        let op = match op_token.kind {
+            TokenKind::Operator("=") => MatchOp::Equal,
+            TokenKind::Operator("!=") => MatchOp::NotEqual,
+            TokenKind::Operator("=~") => MatchOp::RegexMatch,
+            TokenKind::Operator("!~") => MatchOp::NotRegexMatch,
+            _ => {
+                return Err(SyntaxError::new(
+                    op_token.start,
+                    format!(
+                        "unexpected {}, expected a label matching operator",
+                        op_token.describe(self.input)
+                    ),
+                ))
+            }
+        };
+        let value_token = self.next();
+        let value = match value_token.kind {
+            TokenKind::String(value) => value,
+            _ => {
+                return Err(SyntaxError::new(
+                    value_token.start,
+                    format!(
+                        "unexpected {}, expected a label value",
+                        value_token.describe(self.input)
+                    ),
+                ))
+            }
+        };
+        if op == MatchOp::RegexMatch || op == MatchOp::NotRegexMatch {
+            if let Err(e) = cached_regex(&anchored(&value)) {
+                return Err(SyntaxError::new(
+                    value_token.start,
+                    format!("invalid regular expression {:?}: {}", value, e),
+                ));
+            }
+        }
+        Ok(Matcher { name, op, value })
+    }
+}
+
+/// Regular expressions of matchers have to match the whole label value.
+pub(crate) fn anchored(pattern: &str) -> String {
+    format!("^(?:{})$", pattern)
+}
+
+fn matches_empty(matcher: &Matcher) -> bool {
+    let regex_matches_empty = || {
+        cached_regex(&anchored(&matcher.value))
+            .map(|r| r.is_match(""))
+            .unwrap_or(false)
+    };
+    match matcher.op {
+        MatchOp::Equal => matcher.value.is_empty(),
+        MatchOp::NotEqual => !matcher.value.is_empty(),
+        MatchOp::RegexMatch => regex_matches_empty(),
+        MatchOp::NotRegexMatch => !regex_matches_empty(),
+    }
+}
+
+fn expect_type(expr: &Expr, expected: ValueType, context: &str) -> Result<(), SyntaxError> {
+    let actual = expr.value_type();
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(SyntaxError::new(
+            expr.pos.start,
+            format!(
+                "expected type {} in {}, got {}",
+                expected.documented_name(),
+                context,
+                actual.documented_name()
+            ),
+        ))
+    }
+}
+
+fn binary_expr(
+    op: BinaryOp,
+    op_pos: usize,
+    lhs: Expr,
+    rhs: Expr,
+    matching: Option<VectorMatching>,
+    return_bool: bool,
+) -> Result<Expr, SyntaxError> {
+    let (lt, rt) = (lhs.value_type(), rhs.value_type());
+    for (side, t) in [(&lhs, lt), (&rhs, rt)] {
+        if t != ValueType::Scalar && t != ValueType::Vector {
+            return Err(SyntaxError::new(
+                side.pos.start,
+                "binary expression must contain only scalar and instant vector types",
+            ));
+        }
+    }
+    let both_vectors = lt == ValueType::Vector && rt == ValueType::Vector;
+    if !both_vectors && matching.is_some() {
+        return Err(SyntaxError::new(
+            op_pos,
+            "vector matching only allowed between instant vectors",
+        ));
+    }
+    if op.is_set_operator() && !both_vectors {
+        return Err(SyntaxError::new(
+            op_pos,
+            format!(
+                "set operator {:?} not allowed in binary scalar expression",
+                op.symbol()
+            ),
+        ));
+    }
+    if op.is_comparison() && !return_bool && lt == ValueType::Scalar && rt == ValueType::Scalar {
+        return Err(SyntaxError::new(
+            op_pos,
+            "comparisons between scalars must use BOOL modifier",
+        ));
+    }
+    let matching = if both_vectors {
+        let mut matching = matching.unwrap_or(VectorMatching {
+            card: Cardinality::OneToOne,
+            matching_labels: vec![],
+            on: false,
+            include: vec![],
+        });
+        if op.is_set_operator() {
+            matching.card = Cardinality::ManyToMany;
+        }
+        Some(matching)
+    } else {
+        None
+    };
+    let pos = PosRange {
+        start: lhs.pos.start,
+        end: rhs.pos.end,
+    };
+    Ok(Expr {
+        kind: ExprKind::Binary {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+            matching,
+            return_bool,
+        },
+        pos,
+    })
+}
@@ -0,0 +1,240 @@
+//! Fetches the series of PromQL selectors from the metric views.
+use pgx::*;
+
+use serde_json::Value;
+
+use super::ast::{MatchOp, Matcher};
+use super::eval::{Labels, Selection, Series};
+use super::parser::anchored;
+use crate::prompb::to_pg_timestamp;
+use crate::regex::cached_regex;
+use crate::util::quote_identifier;
+
+/// Matches every value of a label, i.e. its presence. Label values are never
+/// empty.
+const ANY_VALUE: &str = "^.+$";
+
+/// A parameter of an SPI query.
+type Arg = (PgOid, Option<pg_sys::Datum>);
+
+/// Computes the values of a selection with its aggregate, over the
+/// `prom_metric` view of the metric.
+pub(super) fn fetch(selection: &Selection) -> Vec<Series> {
+    let metric_name = selection
+        .selector
+        .matchers
+        .iter()
+        .find(|m| m.name == "__name__")
+        .map(|m| m.value.as_str())
+        .expect("selections have a metric name");
+    let table_name = Spi::get_one_with_args::<String>(
+        "SELECT (SELECT table_name::text
+                 FROM _prom_catalog.get_metric_table_name_if_exists('prom_data', $1))",
+        vec![(PgBuiltInOids::TEXTOID.oid(), metric_name.into_datum())],
+    );
+    let table_name = match table_name {
+        Some(table_name) => table_name,
+        None => return vec![],
+    };
+
+    let range = &selection.range;
+    let timestamp = |unix_ms: i64| {
+        let pg_us = to_pg_timestamp(unix_ms).unwrap_or_else(|e| error!("{}", e));
+        (
+            PgBuiltInOids::TIMESTAMPTZOID.oid(),
+            TimestampWithTimeZone::from(pg_us).into_datum(),
+        )
+    };
+    let mut args = vec![
+        timestamp(range.start - selection.window),
+        timestamp(range.start),
+        timestamp(range.end),
+        (PgBuiltInOids::INT8OID.oid(), range.step.into_datum()),
+        (PgBuiltInOids::INT8OID.oid(), selection.window.into_datum()),
+    ];
+    let conditions = label_conditions(&selection.selector.matchers, "m.labels", &mut args);
+    // `prom_rate` and friends evaluate at `$1 + window + k * step`,
+    // `vector_selector` at `$2 + k * step`
+    let aggregate = match selection.aggregate {
+        "vector_selector" => "_prom_ext.vector_selector($2, $3, $4, $5, m.time, m.value)",
+        "prom_rate" => "_prom_ext.prom_rate($1, $3, $4, $5, m.time, m.value ORDER BY m.time)",
+        "prom_increase" => {
+            "_prom_ext.prom_increase($1, $3, $4, $5, m.time, m.value ORDER BY m.time)"
+        }
+        "prom_delta" => "_prom_ext.prom_delta($1, $3, $4, $5, m.time, m.value ORDER BY m.time)",
+        other => unreachable!("unknown aggregate {}", other),
+    };
+    let query = format!(
+        "SELECT coalesce(jsonb_agg(jsonb_build_array(prom_api.jsonb(r.labels), r.vals::text[])), '[]')
+        FROM (
+            SELECT m.labels, {} AS vals
+            FROM prom_metric.{} m
+            WHERE m.time >= $1 AND m.time <= $3{}
+            GROUP BY m.series_id, m.labels
+        ) r",
+        aggregate,
+        quote_identifier(&table_name),
+        conditions
+    );
+    let rows = Spi::get_one_with_args::<JsonB>(&query, args)
+        .map(|rows| rows.0)
+        .unwrap_or_else(|| Value::Array(vec![]));
+    let rows: Vec<(Labels, Option<Vec<Option<String>>>)> = serde_json::from_value(rows)
+        .unwrap_or_else(|e| error!("unexpected series of {}: {}", metric_name, e));
+    rows.into_iter()
+        .map(|(labels, values)| Series {
+            labels,
+            values: values
+                .unwrap_or_default()
+                .into_iter()
+                .map(|v| v.map(|v| v.parse().expect("float8 text is a valid float")))
+                .collect(),
+        })
+        .collect()
+}
+
+/// The id, metric name and labels of the series matching `matchers`,
+/// ordered by metric name and id. With `start` or `end`, only series with
+/// samples in between are returned.
+pub(super) fn select_series(
+    matchers: &[Matcher],
+    start: Option<TimestampWithTimeZone>,
+    end: Option<TimestampWithTimeZone>,
+) -> Vec<(i64, String, Value)> {
+    let mut args = vec![];
+    let conditions = name_conditions(matchers, "m.metric_name", &mut args);
+    let metrics = Spi::get_one_with_args::<JsonB>(
+        &format!(
+            "SELECT coalesce(jsonb_agg(jsonb_build_array(m.id, m.table_name)), '[]')
+            FROM _prom_catalog.metric m
+            WHERE m.table_schema = 'prom_data'{}",
+            conditions
+        ),
+        args,
+    )
+    .map(|metrics| metrics.0)
+    .unwrap_or_else(|| Value::Array(vec![]));
+    let metrics: Vec<(i32, String)> = serde_json::from_value(metrics)
+        .unwrap_or_else(|e| error!("unexpected metric catalog: {}", e));
+    if metrics.is_empty() {
+        return vec![];
+    }
+
+    let metric_ids: Vec<i32> = metrics.iter().map(|(id, _)| *id).collect();
+    let mut args = vec![
+        (PgBuiltInOids::INT4ARRAYOID.oid(), metric_ids.into_datum()),
+        (PgBuiltInOids::TIMESTAMPTZOID.oid(), start.into_datum()),
+        (PgBuiltInOids::TIMESTAMPTZOID.oid(), end.into_datum()),
+    ];
+    let mut conditions = label_conditions(matchers, "s.labels", &mut args);
+    if start.is_some() || end.is_some() {
+        // the samples are in the table of the series' metric
+        let exists: String = metrics
+            .iter()
+            .map(|(id, table_name)| {
+                format!(
+                    " WHEN {} THEN EXISTS (
+                        SELECT 1 FROM prom_data.{} d
+                        WHERE d.series_id = s.id
+                        AND d.time >= coalesce($2, '-infinity')
+                        AND d.time <= coalesce($3, 'infinity'))",
+                    id,
+                    quote_identifier(table_name)
+                )
+            })
+            .collect();
+        conditions.push_str(&format!(" AND CASE s.metric_id{} END", exists));
+    }
+    let query = format!(
+        "SELECT coalesce(jsonb_agg(jsonb_build_array(s.id, m.metric_name, prom_api.jsonb(s.labels))
+                                   ORDER BY m.metric_name, s.id), '[]')
+        FROM _prom_catalog.series s
+        INNER JOIN _prom_catalog.metric m ON (m.id = s.metric_id)
+        WHERE s.metric_id = ANY($1) AND s.delete_epoch IS NULL{}",
+        conditions
+    );
+    let rows = Spi::get_one_with_args::<JsonB>(&query, args)
+        .map(|rows| rows.0)
+        .unwrap_or_else(|| Value::Array(vec![]));
+    serde_json::from_value(rows).unwrap_or_else(|e| error!("unexpected series: {}", e))
+}
+
+/// The conditions on the metric name `column` of the `__name__` matchers,
+/// each preceded by `AND`. Their parameters are appended to `args`.
+fn name_conditions(matchers: &[Matcher], column: &str, args: &mut Vec<Arg>) -> String {
+    let mut conditions = String::new();
+    for matcher in matchers.iter().filter(|m| m.name == "__name__") {
+        let (op, value) = match matcher.op {
+            MatchOp::Equal => ("=", matcher.value.clone()),
+            MatchOp::NotEqual => ("<>", matcher.value.clone()),
+            MatchOp::RegexMatch => ("~", anchored(&matcher.value)),
+            MatchOp::NotRegexMatch => ("!~", anchored(&matcher.value)),
+        };
+        args.push((PgBuiltInOids::TEXTOID.oid(), value.into_datum()));
+        conditions.push_str(&format!(" AND {} {} ${}", column, op, args.len()));
+    }
+    conditions
+}
+
+/// The conditions on the label array `column` of the matchers other than
+/// `__name__`, each preceded by `AND`. Their parameters are appended to `args`.
+fn label_conditions(matchers: &[Matcher], column: &str, args: &mut Vec<Arg>) -> String {
+    let mut conditions = String::new();
+    for matcher in matchers {
+        if matcher.name == "__name__" {
+            continue;
+        }
+        let (condition, value) = matcher_condition(matcher, column, args.len() + 1);
+        conditions.push_str(" AND ");
+        conditions.push_str(&condition);
+        args.push((
+            PgBuiltInOids::TEXTOID.oid(),
+            matcher.name.as_str().into_datum(),
+        ));
+        args.push((PgBuiltInOids::TEXTOID.oid(), value.into_datum()));
+        args.push((PgBuiltInOids::TEXTOID.oid(), ANY_VALUE.into_datum()));
+    }
+    conditions
+}
+
+/// The condition on the label array `column` of a matcher, with the label
+/// name as parameter `$<param>`, the value or anchored pattern as
+/// `$<param + 1>` and [`ANY_VALUE`] as `$<param + 2>`; and the value of the
+/// second parameter.
+///
+/// Labels are stored only with non-empty values, so matchers that match the
+/// empty string also have to match series without the label, and the other
+/// way around.
+fn matcher_condition(matcher: &Matcher, column: &str, param: usize) -> (String, String) {
+    let (name, value, any) = (param, param + 1, param + 2);
+    let op = |op: &str, value: usize| {
+        format!(
+            "{} OPERATOR(prom_api.?) (${} OPERATOR(ps_tag.{}) ${})",
+            column, name, op, value
+        )
+    };
+    match matcher.op {
+        MatchOp::Equal | MatchOp::NotEqual if matcher.value.is_empty() => {
+            let present = matcher.op == MatchOp::NotEqual;
+            let condition = op(if present { "==~" } else { "!=~" }, any);
+            (condition, String::new())
+        }
+        MatchOp::Equal => (op("==", value), matcher.value.clone()),
+        MatchOp::NotEqual => (op("!==", value), matcher.value.clone()),
+        MatchOp::RegexMatch | MatchOp::NotRegexMatch => {
+            let pattern = anchored(&matcher.value);
+            let matches_empty = cached_regex(&pattern)
+                .map(|r| r.is_match(""))
+                .unwrap_or(false);
+            let condition = match (matcher.op, matches_empty) {
+                (MatchOp::RegexMatch, false) => op("==~", value),
+                (MatchOp::RegexMatch, true) => {
+                    format!("({} OR {})", op("!=~", any), op("==~", value))
+                }
+                (_, false) => op("!=~", value),
+                (_, true) => format!("({} AND {})", op("==~", any), op("!=~", value)),
+            };
+            (condition, pattern)
+        }
+    }
+}
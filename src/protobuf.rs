@@ -0,0 +1,213 @@
+//! Just enough of the [protobuf wire format][encoding] to read and write
+//! the handful of Prometheus and OpenTelemetry messages we deal with, without pulling in a
+//! code generator.
+//!
+//! [encoding]: https://protobuf.dev/programming-guides/encoding/
+
+use std::convert::TryInto;
+
+/// A single decoded field value, tagged by its wire type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum WireValue<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> WireValue<'a> {
+    pub(crate) fn as_bytes(&self, field: &str) -> Result<&'a [u8], String> {
+        match self {
+            WireValue::Bytes(b) => Ok(b),
+            _ => Err(format!("{}: expected a length-delimited field", field)),
+        }
+    }
+
+    pub(crate) fn as_str(&self, field: &str) -> Result<&'a str, String> {
+        std::str::from_utf8(self.as_bytes(field)?)
+            .map_err(|_| format!("{}: invalid UTF-8 string", field))
+    }
+
+    pub(crate) fn as_f64(&self, field: &str) -> Result<f64, String> {
+        match self {
+            WireValue::Fixed64(v) => Ok(f64::from_bits(*v)),
+            _ => Err(format!("{}: expected a 64-bit field", field)),
+        }
+    }
+
+    pub(crate) fn as_u64(&self, field: &str) -> Result<u64, String> {
+        match self {
+            WireValue::Varint(v) => Ok(*v),
+            _ => Err(format!("{}: expected a varint field", field)),
+        }
+    }
+
+    /// Plain (not zig-zag encoded) `int64`.
+    pub(crate) fn as_i64(&self, field: &str) -> Result<i64, String> {
+        self.as_u64(field).map(|v| v as i64)
+    }
+
+    /// Zig-zag encoded `sint32` or `sint64`.
+    pub(crate) fn as_sint64(&self, field: &str) -> Result<i64, String> {
+        self.as_u64(field)
+            .map(|v| ((v >> 1) as i64) ^ -((v & 1) as i64))
+    }
+
+    pub(crate) fn as_bool(&self, field: &str) -> Result<bool, String> {
+        self.as_u64(field).map(|v| v != 0)
+    }
+
+    /// `fixed64` or `sfixed64`, the latter to be cast by the caller.
+    pub(crate) fn as_fixed64(&self, field: &str) -> Result<u64, String> {
+        match self {
+            WireValue::Fixed64(v) => Ok(*v),
+            _ => Err(format!("{}: expected a 64-bit field", field)),
+        }
+    }
+
+    /// A repeated `fixed64` or `double` field, either packed or a single element.
+    pub(crate) fn as_packed_fixed64(&self, field: &str) -> Result<Vec<u64>, String> {
+        match self {
+            WireValue::Fixed64(v) => Ok(vec![*v]),
+            WireValue::Bytes(b) if b.len() % 8 == 0 => Ok(b
+                .chunks_exact(8)
+                .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+                .collect()),
+            _ => Err(format!("{}: expected packed 64-bit values", field)),
+        }
+    }
+
+    /// A repeated varint field, either packed or a single element.
+    pub(crate) fn as_packed_varint(&self, field: &str) -> Result<Vec<u64>, String> {
+        match self {
+            WireValue::Varint(v) => Ok(vec![*v]),
+            WireValue::Bytes(b) => {
+                let mut reader = Reader::new(b);
+                let mut values = vec![];
+                while reader.pos < b.len() {
+                    values.push(reader.read_varint()?);
+                }
+                Ok(values)
+            }
+            _ => Err(format!("{}: expected packed varints", field)),
+        }
+    }
+}
+
+/// Iterates over the fields of a serialized message in wire order.
+/// Unknown fields are returned like any other and can simply be skipped.
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub(crate) fn next_field(&mut self) -> Result<Option<(u32, WireValue<'a>)>, String> {
+        if self.pos >= self.buf.len() {
+            return Ok(None);
+        }
+        let key = self.read_varint()?;
+        let field = (key >> 3) as u32;
+        let value = match key & 0b111 {
+            0 => WireValue::Varint(self.read_varint()?),
+            1 => WireValue::Fixed64(u64::from_le_bytes(self.read_slice(8)?.try_into().unwrap())),
+            2 => {
+                let len = self.read_varint()? as usize;
+                WireValue::Bytes(self.read_slice(len)?)
+            }
+            5 => WireValue::Fixed32(u32::from_le_bytes(self.read_slice(4)?.try_into().unwrap())),
+            wire_type => {
+                return Err(format!(
+                    "protobuf: unsupported wire type {} at offset {}",
+                    wire_type, self.pos
+                ))
+            }
+        };
+        Ok(Some((field, value)))
+    }
+
+    fn read_varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = *self
+                .buf
+                .get(self.pos)
+                .ok_or_else(|| "protobuf: truncated varint".to_string())?;
+            self.pos += 1;
+            value |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("protobuf: varint too long".to_string())
+    }
+
+    fn read_slice(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.buf.len())
+            .ok_or_else(|| "protobuf: truncated message".to_string())?;
+        let slice = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+}
+
+/// Serializes fields of a single message. Nested messages are serialized
+/// into a writer of their own first, as their length has to be known upfront.
+#[derive(Default)]
+pub(crate) struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Writes a varint field, omitting it if it has the default value.
+    pub(crate) fn varint(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, 0);
+            write_varint(&mut self.buf, value);
+        }
+    }
+
+    /// Writes a `double` field, omitting it if it has the default value.
+    pub(crate) fn double(&mut self, field: u32, value: f64) {
+        if value.to_bits() != 0 {
+            self.key(field, 1);
+            self.buf.extend_from_slice(&value.to_bits().to_le_bytes());
+        }
+    }
+
+    /// Writes a `bytes`, `string` or embedded message field. Unlike the
+    /// scalar fields, it is always written, so that repeated messages are
+    /// not lost even when empty.
+    pub(crate) fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, 2);
+        write_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        write_varint(&mut self.buf, ((field as u64) << 3) | wire_type as u64);
+    }
+}
+
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
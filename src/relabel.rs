@@ -0,0 +1,495 @@
+//! # Relabeling
+//!
+//! An implementation of Prometheus' [`relabel_config`][relabel], applicable to
+//! label sets stored in the database, e.g. for ingest-time normalisation or
+//! to fix up historical series.
+//!
+//! Rules are passed as a JSON array of objects, using the same field names and
+//! defaults as the Prometheus configuration file:
+//!
+//! ```sql
+//! SELECT prom_api.relabel(
+//!     '{"__name__": "up", "job": "node", "instance": "localhost:9100"}',
+//!     '[{"source_labels": ["instance"], "regex": "(.*):.*", "target_label": "host"},
+//!       {"action": "labeldrop", "regex": "instance"}]'
+//! );
+//! -- {"job": "node", "host": "localhost", "__name__": "up"}
+//! ```
+//!
+//! `NULL` is returned if the label set is dropped by a `keep`, `drop`,
+//! `keepequal` or `dropequal` rule. As in Prometheus, labels with an empty
+//! value are considered absent and are removed from the output.
+//!
+//! [relabel]: https://prometheus.io/docs/prometheus/latest/configuration/configuration/#relabel_config
+use pgx::*;
+
+#[pg_schema]
+mod _prom_ext {
+    use md5::{Digest, Md5};
+    use pgx::*;
+    use regex::Regex;
+    use serde::Deserialize;
+    use serde_json::{Map, Value};
+    use std::collections::BTreeMap;
+
+    use crate::regex::cached_regex;
+
+    type Labels = BTreeMap<String, String>;
+
+    /// Applies the `rules` to the `labels` in order. Returns `NULL` if the labels were dropped.
+    #[pg_extern(immutable, strict, parallel_safe, create_or_replace)]
+    pub fn relabel(labels: JsonB, rules: JsonB) -> Option<JsonB> {
+        let labels = parse_labels(labels.0).unwrap_or_else(|e| error!("invalid labels: {}", e));
+        let rules = parse_rules(rules.0).unwrap_or_else(|e| error!("invalid relabel rules: {}", e));
+        apply_rules(labels, &rules).map(|labels| {
+            JsonB(Value::Object(
+                labels
+                    .into_iter()
+                    .map(|(name, value)| (name, Value::String(value)))
+                    .collect::<Map<_, _>>(),
+            ))
+        })
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Action {
+        Replace,
+        Keep,
+        Drop,
+        KeepEqual,
+        DropEqual,
+        HashMod,
+        LabelMap,
+        LabelDrop,
+        LabelKeep,
+        Lowercase,
+        Uppercase,
+    }
+
+    impl Action {
+        /// Like Prometheus, action names are case-insensitive.
+        fn parse(name: &str) -> Result<Self, String> {
+            use Action::*;
+            match name.to_lowercase().as_str() {
+                "replace" => Ok(Replace),
+                "keep" => Ok(Keep),
+                "drop" => Ok(Drop),
+                "keepequal" => Ok(KeepEqual),
+                "dropequal" => Ok(DropEqual),
+                "hashmod" => Ok(HashMod),
+                "labelmap" => Ok(LabelMap),
+                "labeldrop" => Ok(LabelDrop),
+                "labelkeep" => Ok(LabelKeep),
+                "lowercase" => Ok(Lowercase),
+                "uppercase" => Ok(Uppercase),
+                "" => Err("relabel action cannot be empty".to_string()),
+                _ => Err(format!("unknown relabel action {:?}", name)),
+            }
+        }
+    }
+
+    const DEFAULT_SEPARATOR: &str = ";";
+    const DEFAULT_REGEX: &str = "(.*)";
+    const DEFAULT_REPLACEMENT: &str = "$1";
+    const DEFAULT_ACTION: &str = "replace";
+
+    /// A single rule, as it appears in the Prometheus configuration.
+    #[derive(Deserialize, Debug)]
+    #[serde(deny_unknown_fields)]
+    struct RelabelConfig {
+        #[serde(default)]
+        source_labels: Vec<String>,
+        #[serde(default = "default_separator")]
+        separator: String,
+        #[serde(default = "default_regex")]
+        regex: String,
+        #[serde(default)]
+        modulus: u64,
+        #[serde(default)]
+        target_label: String,
+        #[serde(default = "default_replacement")]
+        replacement: String,
+        #[serde(default = "default_action")]
+        action: String,
+    }
+
+    fn default_separator() -> String {
+        DEFAULT_SEPARATOR.to_string()
+    }
+
+    fn default_regex() -> String {
+        DEFAULT_REGEX.to_string()
+    }
+
+    fn default_replacement() -> String {
+        DEFAULT_REPLACEMENT.to_string()
+    }
+
+    fn default_action() -> String {
+        DEFAULT_ACTION.to_string()
+    }
+
+    /// A validated rule, ready to be applied.
+    struct Rule {
+        source_labels: Vec<String>,
+        separator: String,
+        regex: Regex,
+        modulus: u64,
+        target_label: String,
+        replacement: String,
+        action: Action,
+    }
+
+    fn parse_labels(labels: Value) -> Result<Labels, String> {
+        match labels {
+            Value::Object(map) => map
+                .into_iter()
+                .filter_map(|(name, value)| match value {
+                    Value::String(value) if value.is_empty() => None,
+                    Value::String(value) => Some(Ok((name, value))),
+                    other => Some(Err(format!(
+                        "value of label {:?} must be a string, got {}",
+                        name, other
+                    ))),
+                })
+                .collect(),
+            other => Err(format!("expected a JSON object, got {}", other)),
+        }
+    }
+
+    fn parse_rules(rules: Value) -> Result<Vec<Rule>, String> {
+        let configs: Vec<RelabelConfig> =
+            serde_json::from_value(rules).map_err(|e| e.to_string())?;
+        configs.into_iter().map(validate_rule).collect()
+    }
+
+    fn is_valid_label_name(name: &str) -> bool {
+        let mut chars = name.chars();
+        matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    /// A label name, which may contain references to capture groups, e.g. `${1}_total`.
+    fn is_valid_relabel_target(name: &str) -> bool {
+        const RELABEL_TARGET: &str = r"^(?:(?:[a-zA-Z_]|\$(?:\{\w+\}|\w+))+\w*)+$";
+        cached_regex(RELABEL_TARGET)
+            .expect("relabel target pattern must compile")
+            .is_match(name)
+    }
+
+    /// Mirrors the checks Prometheus performs when loading its configuration.
+    fn validate_rule(config: RelabelConfig) -> Result<Rule, String> {
+        use Action::*;
+        let action = Action::parse(&config.action)?;
+        let action_name = config.action.to_lowercase();
+
+        if action == HashMod && config.modulus == 0 {
+            return Err("relabel configuration for hashmod requires non-zero modulus".to_string());
+        }
+        if matches!(
+            action,
+            Replace | HashMod | Lowercase | Uppercase | KeepEqual | DropEqual
+        ) && config.target_label.is_empty()
+        {
+            return Err(format!(
+                "relabel configuration for {} action requires 'target_label' value",
+                action_name
+            ));
+        }
+        let target_is_valid = match action {
+            Replace => is_valid_relabel_target(&config.target_label),
+            HashMod | Lowercase | Uppercase | KeepEqual | DropEqual => {
+                is_valid_label_name(&config.target_label)
+            }
+            _ => true,
+        };
+        if !target_is_valid {
+            return Err(format!(
+                "{:?} is invalid 'target_label' for {} action",
+                config.target_label, action_name
+            ));
+        }
+        if matches!(action, Lowercase | Uppercase | KeepEqual | DropEqual)
+            && config.replacement != DEFAULT_REPLACEMENT
+        {
+            return Err(format!(
+                "'replacement' can not be set for {} action",
+                action_name
+            ));
+        }
+        if action == LabelMap && !is_valid_relabel_target(&config.replacement) {
+            return Err(format!(
+                "{:?} is invalid 'replacement' for {} action",
+                config.replacement, action_name
+            ));
+        }
+        if matches!(action, KeepEqual | DropEqual)
+            && (config.regex != DEFAULT_REGEX
+                || config.modulus != 0
+                || config.separator != DEFAULT_SEPARATOR)
+        {
+            return Err(format!(
+                "{} action requires only 'source_labels' and 'target_label', and no other fields",
+                action_name
+            ));
+        }
+        if matches!(action, LabelDrop | LabelKeep)
+            && (!config.source_labels.is_empty()
+                || !config.target_label.is_empty()
+                || config.modulus != 0
+                || config.separator != DEFAULT_SEPARATOR
+                || config.replacement != DEFAULT_REPLACEMENT)
+        {
+            return Err(format!(
+                "{} action requires only 'regex', and no other fields",
+                action_name
+            ));
+        }
+
+        // Prometheus regular expressions are always fully anchored
+        let regex = cached_regex(&format!("^(?:{})$", config.regex))
+            .map_err(|e| format!("unable to compile regular expression: {}", e))?;
+
+        Ok(Rule {
+            source_labels: config.source_labels,
+            separator: config.separator,
+            regex,
+            modulus: config.modulus,
+            target_label: config.target_label,
+            replacement: config.replacement,
+            action,
+        })
+    }
+
+    /// Applies all the rules in order, stops as soon as the labels are dropped.
+    fn apply_rules(mut labels: Labels, rules: &[Rule]) -> Option<Labels> {
+        for rule in rules {
+            if !apply_rule(&mut labels, rule) {
+                return None;
+            }
+        }
+        Some(labels)
+    }
+
+    /// Setting a label to an empty value removes it.
+    fn set_label(labels: &mut Labels, name: String, value: String) {
+        if value.is_empty() {
+            labels.remove(&name);
+        } else {
+            labels.insert(name, value);
+        }
+    }
+
+    /// Same as the `sum64` of Prometheus: in Go, shifting a `uint64` by 64 bits or
+    /// more yields zero, thus only the last 8 bytes of the MD5 hash contribute.
+    fn hash_mod(value: &str, modulus: u64) -> u64 {
+        let hash = Md5::digest(value.as_bytes());
+        let mut last_bytes = [0u8; 8];
+        last_bytes.copy_from_slice(&hash[8..16]);
+        u64::from_be_bytes(last_bytes) % modulus
+    }
+
+    /// Returns `false` if the labels are to be dropped.
+    fn apply_rule(labels: &mut Labels, rule: &Rule) -> bool {
+        use Action::*;
+        let value = rule
+            .source_labels
+            .iter()
+            .map(|name| labels.get(name).map(String::as_str).unwrap_or(""))
+            .collect::<Vec<_>>()
+            .join(&rule.separator);
+
+        match rule.action {
+            Drop => return !rule.regex.is_match(&value),
+            Keep => return rule.regex.is_match(&value),
+            DropEqual => {
+                return labels
+                    .get(&rule.target_label)
+                    .map(String::as_str)
+                    .unwrap_or("")
+                    != value
+            }
+            KeepEqual => {
+                return labels
+                    .get(&rule.target_label)
+                    .map(String::as_str)
+                    .unwrap_or("")
+                    == value
+            }
+            Replace => {
+                if let Some(captures) = rule.regex.captures(&value) {
+                    let mut target = String::new();
+                    captures.expand(&rule.target_label, &mut target);
+                    if !is_valid_label_name(&target) {
+                        labels.remove(&rule.target_label);
+                        return true;
+                    }
+                    let mut replacement = String::new();
+                    captures.expand(&rule.replacement, &mut replacement);
+                    if replacement.is_empty() {
+                        labels.remove(&rule.target_label);
+                    } else {
+                        labels.insert(target, replacement);
+                    }
+                }
+            }
+            Lowercase => set_label(labels, rule.target_label.clone(), value.to_lowercase()),
+            Uppercase => set_label(labels, rule.target_label.clone(), value.to_uppercase()),
+            HashMod => set_label(
+                labels,
+                rule.target_label.clone(),
+                hash_mod(&value, rule.modulus).to_string(),
+            ),
+            LabelMap => {
+                // the rule is applied to the labels as they were before it
+                let original = labels.clone();
+                for (name, value) in original {
+                    if rule.regex.is_match(&name) {
+                        let new_name = rule
+                            .regex
+                            .replace_all(&name, rule.replacement.as_str())
+                            .into_owned();
+                        set_label(labels, new_name, value);
+                    }
+                }
+            }
+            LabelDrop => labels.retain(|name, _| !rule.regex.is_match(name)),
+            LabelKeep => labels.retain(|name, _| rule.regex.is_match(name)),
+        }
+        true
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+    use serde_json::json;
+
+    fn relabel(labels: serde_json::Value, rules: serde_json::Value) -> Option<serde_json::Value> {
+        Spi::get_one_with_args::<JsonB>(
+            "SELECT prom_api.relabel($1, $2)",
+            vec![
+                (PgBuiltInOids::JSONBOID.oid(), JsonB(labels).into_datum()),
+                (PgBuiltInOids::JSONBOID.oid(), JsonB(rules).into_datum()),
+            ],
+        )
+        .map(|result| result.0)
+    }
+
+    #[pg_test]
+    fn test_relabel_replace() {
+        let result = relabel(
+            json!({"__name__": "up", "instance": "localhost:9100"}),
+            json!([{
+                "source_labels": ["__name__", "instance"],
+                "regex": "(.*);(.*):.*",
+                "target_label": "${1}_host",
+                "replacement": "$2"
+            }]),
+        );
+        assert_eq!(
+            result,
+            Some(json!({"__name__": "up", "instance": "localhost:9100", "up_host": "localhost"}))
+        );
+
+        // no match, no change
+        let result = relabel(
+            json!({"a": "foo"}),
+            json!([{"source_labels": ["a"], "regex": "bar", "target_label": "b"}]),
+        );
+        assert_eq!(result, Some(json!({"a": "foo"})));
+
+        // an empty replacement removes the label
+        let result = relabel(
+            json!({"a": "foo", "b": "bar"}),
+            json!([{"source_labels": ["a"], "target_label": "b", "replacement": ""}]),
+        );
+        assert_eq!(result, Some(json!({"a": "foo"})));
+    }
+
+    #[pg_test]
+    fn test_relabel_keep_and_drop() {
+        let labels = json!({"a": "foo", "b": "bar"});
+        assert_eq!(
+            relabel(
+                labels.clone(),
+                json!([{"action": "keep", "source_labels": ["a"], "regex": "f.*"}])
+            ),
+            Some(labels.clone())
+        );
+        assert_eq!(
+            relabel(
+                labels.clone(),
+                json!([{"action": "keep", "source_labels": ["a"], "regex": "f"}])
+            ),
+            None
+        );
+        assert_eq!(
+            relabel(
+                labels.clone(),
+                json!([{"action": "Drop", "source_labels": ["a", "b"], "regex": "foo;bar"}])
+            ),
+            None
+        );
+        assert_eq!(
+            relabel(
+                labels.clone(),
+                json!([{"action": "dropequal", "source_labels": ["a"], "target_label": "b"}])
+            ),
+            Some(labels.clone())
+        );
+        assert_eq!(
+            relabel(
+                json!({"a": "foo", "b": "foo"}),
+                json!([{"action": "keepequal", "source_labels": ["a"], "target_label": "b"}])
+            ),
+            Some(json!({"a": "foo", "b": "foo"}))
+        );
+    }
+
+    #[pg_test]
+    fn test_relabel_label_actions() {
+        let result = relabel(
+            json!({"__meta_kubernetes_pod_label_app": "web", "__meta_kubernetes_pod_label_tier": "front", "job": "k8s"}),
+            json!([
+                {"action": "labelmap", "regex": "__meta_kubernetes_pod_label_(.+)"},
+                {"action": "labeldrop", "regex": "__meta_.*"},
+                {"action": "uppercase", "source_labels": ["tier"], "target_label": "tier"},
+            ]),
+        );
+        assert_eq!(
+            result,
+            Some(json!({"app": "web", "tier": "FRONT", "job": "k8s"}))
+        );
+
+        let result = relabel(
+            json!({"a": "foo", "b": "bar", "c": "baz"}),
+            json!([{"action": "labelkeep", "regex": "a|b"}]),
+        );
+        assert_eq!(result, Some(json!({"a": "foo", "b": "bar"})));
+    }
+
+    #[pg_test]
+    fn test_relabel_hashmod_matches_prometheus() {
+        // The expected value is taken from Prometheus' own test suite
+        let result = relabel(
+            json!({"a": "foo", "b": "bar", "c": "baz"}),
+            json!([{"action": "hashmod", "source_labels": ["c"], "target_label": "d", "modulus": 1000}]),
+        );
+        assert_eq!(
+            result,
+            Some(json!({"a": "foo", "b": "bar", "c": "baz", "d": "976"}))
+        );
+    }
+
+    #[pg_test(
+        error = "invalid relabel rules: relabel configuration for hashmod requires non-zero modulus"
+    )]
+    fn test_relabel_hashmod_without_modulus_fails() {
+        relabel(
+            json!({"a": "foo"}),
+            json!([{"action": "hashmod", "source_labels": ["a"], "target_label": "b"}]),
+        );
+    }
+}
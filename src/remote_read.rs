@@ -0,0 +1,350 @@
+//! # Remote read encoding
+//!
+//! Produces Prometheus [remote read][rr] responses from stored series, so that
+//! a thin HTTP proxy can serve `/api/v1/read` straight out of the database.
+//!
+//! Both response types are supported:
+//!
+//! * `SAMPLES`: the `prom_api.encode_remote_read` aggregate returns a whole
+//!   snappy-compressed `ReadResponse`. The optional leading `query_index`
+//!   argument places a series into the result of the corresponding query.
+//! * `STREAMED_XOR_CHUNKS`: `prom_api.encode_remote_read_chunked` returns the
+//!   length- and checksum-prefixed `ChunkedReadResponse` frame for a single
+//!   series. Frames are independent and can be concatenated with `string_agg`.
+//!
+//! ```sql
+//! SELECT prom_api.encode_remote_read(prom_api.jsonb(s.labels), d.times, d.vals)
+//! FROM prom_data_series.up s
+//! INNER JOIN (
+//!     SELECT series_id, array_agg(time ORDER BY time) times, array_agg(value ORDER BY time) vals
+//!     FROM prom_data.up
+//!     GROUP BY series_id
+//! ) d ON d.series_id = s.id;
+//! ```
+//!
+//! Samples need not be sorted by time; timestamps are truncated to milliseconds.
+//!
+//! [rr]: https://github.com/prometheus/prometheus/blob/main/prompb/remote.proto
+use pgx::*;
+
+#[pg_schema]
+mod _prom_ext {
+    use pgx::*;
+    use serde_json::Value;
+
+    use super::crc32c;
+    use crate::aggregate_utils::in_aggregate_context;
+    use crate::chunkenc::{XorChunkBuilder, SAMPLES_PER_CHUNK};
+    use crate::palloc::{Inner, InternalAsValue, ToInternal};
+    use crate::prompb::{
+        from_pg_timestamp, Chunk, ChunkedReadResponse, Label, ReadResponse, Sample, TimeSeries,
+        CHUNK_ENCODING_XOR,
+    };
+
+    /// A remote read request holds a handful of queries, this only guards
+    /// against allocating a result for every index up to a bogus one.
+    const MAX_QUERY_INDEX: i32 = u16::MAX as i32;
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn encode_remote_read_transition(
+        state: Internal,
+        labels: Option<JsonB>,
+        sample_times: Option<Vec<Option<TimestampWithTimeZone>>>,
+        sample_values: Option<Vec<Option<f64>>>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        encode_remote_read_transition_inner(
+            unsafe { state.to_inner() },
+            None,
+            labels,
+            sample_times,
+            sample_values,
+            fcinfo,
+        )
+        .internal()
+    }
+
+    #[pg_extern(
+        immutable,
+        parallel_safe,
+        create_or_replace,
+        name = "encode_remote_read_transition"
+    )]
+    pub fn encode_remote_read_query_transition(
+        state: Internal,
+        query_index: Option<i32>,
+        labels: Option<JsonB>,
+        sample_times: Option<Vec<Option<TimestampWithTimeZone>>>,
+        sample_values: Option<Vec<Option<f64>>>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        encode_remote_read_transition_inner(
+            unsafe { state.to_inner() },
+            query_index,
+            labels,
+            sample_times,
+            sample_values,
+            fcinfo,
+        )
+        .internal()
+    }
+
+    fn encode_remote_read_transition_inner(
+        state: Option<Inner<ReadResponse>>,
+        query_index: Option<i32>,
+        labels: Option<JsonB>,
+        sample_times: Option<Vec<Option<TimestampWithTimeZone>>>,
+        sample_values: Option<Vec<Option<f64>>>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<ReadResponse>> {
+        unsafe {
+            in_aggregate_context(fcinfo, || {
+                let mut state = state.unwrap_or_else(|| ReadResponse::default().into());
+                let query_index = query_index.unwrap_or(0);
+                if !(0..=MAX_QUERY_INDEX).contains(&query_index) {
+                    error!(
+                        "query_index must be between 0 and {}, got {}",
+                        MAX_QUERY_INDEX, query_index
+                    );
+                }
+                let query_index = query_index as usize;
+                if state.results.len() <= query_index {
+                    state.results.resize(query_index + 1, vec![]);
+                }
+                // a series without labels can't be identified, skip it like NULL input
+                if let Some(labels) = labels {
+                    let samples = to_samples(
+                        sample_times.unwrap_or_default(),
+                        sample_values.unwrap_or_default(),
+                    );
+                    state.results[query_index].push(TimeSeries {
+                        labels: to_labels(labels),
+                        samples,
+                        exemplars: vec![],
+                    });
+                }
+                Some(state)
+            })
+        }
+    }
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn encode_remote_read_final(state: Internal, /* Option<Inner<ReadResponse>> */) -> Vec<u8> {
+        let state: Option<Inner<ReadResponse>> = unsafe { state.to_inner() };
+        match state {
+            Some(state) => state.encode_compressed(),
+            None => ReadResponse::default().encode_compressed(),
+        }
+    }
+
+    /// Encodes a single series as a frame of the streamed remote read protocol:
+    /// a varint length, a big-endian CRC32C checksum and a `ChunkedReadResponse`.
+    #[pg_extern(immutable, strict, parallel_safe, create_or_replace)]
+    pub fn encode_remote_read_chunked(
+        labels: JsonB,
+        sample_times: Vec<Option<TimestampWithTimeZone>>,
+        sample_values: Vec<Option<f64>>,
+        query_index: i64,
+    ) -> Vec<u8> {
+        let samples = to_samples(sample_times, sample_values);
+        let chunks = samples
+            .chunks(SAMPLES_PER_CHUNK)
+            .map(|samples| {
+                let mut builder = XorChunkBuilder::new();
+                samples
+                    .iter()
+                    .for_each(|s| builder.append(s.timestamp, s.value));
+                Chunk {
+                    min_time_ms: samples[0].timestamp,
+                    max_time_ms: samples[samples.len() - 1].timestamp,
+                    encoding: CHUNK_ENCODING_XOR,
+                    data: builder.finish(),
+                }
+            })
+            .collect();
+        let message = ChunkedReadResponse {
+            series: vec![(to_labels(labels), chunks)],
+            query_index,
+        }
+        .encode();
+
+        let mut frame = Vec::with_capacity(message.len() + 14);
+        crate::protobuf::write_varint(&mut frame, message.len() as u64);
+        frame.extend_from_slice(&crc32c(&message).to_be_bytes());
+        frame.extend_from_slice(&message);
+        frame
+    }
+
+    /// Labels sorted by name, as Prometheus expects them.
+    fn to_labels(labels: JsonB) -> Vec<Label> {
+        let labels = match labels.0 {
+            Value::Object(map) => map,
+            _ => error!("labels must be a JSON object"),
+        };
+        let mut labels: Vec<Label> = labels
+            .into_iter()
+            .map(|(name, value)| match value {
+                Value::String(value) => Label { name, value },
+                _ => error!("value of label \"{}\" must be a string", name),
+            })
+            .collect();
+        labels.sort_by(|a, b| a.name.cmp(&b.name));
+        labels
+    }
+
+    fn to_samples(
+        sample_times: Vec<Option<TimestampWithTimeZone>>,
+        sample_values: Vec<Option<f64>>,
+    ) -> Vec<Sample> {
+        if sample_times.len() != sample_values.len() {
+            error!(
+                "sample_times and sample_values must have the same length, got {} and {}",
+                sample_times.len(),
+                sample_values.len()
+            );
+        }
+        let mut samples: Vec<Sample> = sample_times
+            .into_iter()
+            .zip(sample_values)
+            .map(|(time, value)| match (time, value) {
+                (Some(time), Some(value)) => Sample {
+                    value,
+                    timestamp: from_pg_timestamp(time.into()),
+                },
+                _ => error!("sample_times and sample_values must not contain NULLs"),
+            })
+            .collect();
+        samples.sort_by_key(|s| s.timestamp);
+        samples
+    }
+}
+
+/// CRC-32 with the Castagnoli polynomial, as used by the streamed protocol
+/// and Prometheus TSDB blocks.
+pub(crate) fn crc32c(data: &[u8]) -> u32 {
+    const POLY: u32 = 0x82f6_3b78;
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+
+    #[pg_test]
+    fn test_encode_remote_read() {
+        let result = Spi::get_one::<Vec<u8>>(
+            r#"SELECT prom_api.encode_remote_read(labels, times, vals)
+               FROM (VALUES
+                   ('{"__name__": "up", "job": "node"}'::jsonb,
+                    ARRAY['1970-01-01 00:00:02+00', '1970-01-01 00:00:01+00']::timestamptz[],
+                    ARRAY[0, 1]::float8[])
+               ) AS v(labels, times, vals)"#,
+        )
+        .expect("SQL query failed");
+        let decoded = crate::snappy::decompress(&result).unwrap();
+        let expected = crate::prompb::ReadResponse {
+            results: vec![vec![crate::prompb::TimeSeries {
+                labels: vec![
+                    crate::prompb::Label {
+                        name: "__name__".into(),
+                        value: "up".into(),
+                    },
+                    crate::prompb::Label {
+                        name: "job".into(),
+                        value: "node".into(),
+                    },
+                ],
+                samples: vec![
+                    crate::prompb::Sample {
+                        value: 1.0,
+                        timestamp: 1000,
+                    },
+                    crate::prompb::Sample {
+                        value: 0.0,
+                        timestamp: 2000,
+                    },
+                ],
+                exemplars: vec![],
+            }]],
+        };
+        assert_eq!(decoded, expected.encode());
+    }
+
+    #[pg_test]
+    fn test_encode_remote_read_query_index() {
+        let result = Spi::get_one::<Vec<u8>>(
+            r#"SELECT prom_api.encode_remote_read(1, '{"__name__": "up"}', ARRAY[]::timestamptz[], ARRAY[]::float8[])"#,
+        )
+        .expect("SQL query failed");
+        let decoded = crate::snappy::decompress(&result).unwrap();
+        // an empty QueryResult for query 0, then one with a single series
+        assert_eq!(
+            decoded,
+            [
+                &[0x0a, 0x00, 0x0a, 0x12, 0x0a, 0x10, 0x0a, 0x0e][..],
+                &[0x0a, 0x08][..],
+                b"__name__",
+                &[0x12, 0x02][..],
+                b"up",
+            ]
+            .concat()
+        );
+    }
+
+    #[pg_test(error = "query_index must be between 0 and 65535, got 2147483647")]
+    fn test_encode_remote_read_query_index_too_large() {
+        Spi::run(
+            r#"SELECT prom_api.encode_remote_read(2147483647, '{"__name__": "up"}', ARRAY[]::timestamptz[], ARRAY[]::float8[])"#,
+        );
+    }
+
+    #[pg_test(error = "query_index must be between 0 and 65535, got -1")]
+    fn test_encode_remote_read_query_index_negative() {
+        Spi::run(
+            r#"SELECT prom_api.encode_remote_read(-1, '{"__name__": "up"}', ARRAY[]::timestamptz[], ARRAY[]::float8[])"#,
+        );
+    }
+
+    #[pg_test]
+    fn test_encode_remote_read_chunked() {
+        let result = Spi::get_one::<Vec<u8>>(
+            r#"SELECT prom_api.encode_remote_read_chunked(
+                   '{"__name__": "up"}',
+                   ARRAY['1970-01-01 00:00:01+00', '1970-01-01 00:00:02+00', '1970-01-01 00:00:03+00']::timestamptz[],
+                   ARRAY[1, 1, 2]::float8[])"#,
+        )
+        .expect("SQL query failed");
+        assert_eq!(result, hex_decode("3036249f120a2e0a0e0a085f5f6e616d655f5f12027570121c08e80710b817180122120003d00f3ff0000000000000e8073097ffc0"));
+    }
+
+    #[pg_test]
+    fn test_crc32c() {
+        assert_eq!(super::crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[pg_test(error = "sample_times and sample_values must have the same length, got 1 and 0")]
+    fn test_encode_remote_read_mismatched_arrays() {
+        Spi::run(
+            r#"SELECT prom_api.encode_remote_read_chunked('{}', ARRAY[now()], ARRAY[]::float8[])"#,
+        );
+    }
+
+    fn hex_decode(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}
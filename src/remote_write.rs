@@ -0,0 +1,206 @@
+//! # Remote write decoding
+//!
+//! Decodes the body of a Prometheus [remote write][rw] request (a
+//! snappy-compressed `prometheus.WriteRequest`) into rows, so that captured
+//! batches can be inspected or replayed with plain SQL:
+//!
+//! ```sql
+//! SELECT kind, metric_name, labels, time, value
+//! FROM prom_api.decode_remote_write(pg_read_binary_file('batch.bin'));
+//! ```
+//!
+//! Every sample yields a `sample` row, every exemplar an `exemplar` row and
+//! every metadata entry a `metadata` row. Columns that don't apply to a kind
+//! are `NULL`. Native histograms are not supported and are silently skipped.
+//!
+//! `prom_api.insert_remote_write` inserts the decoded rows through the same
+//! catalog functions the connector uses.
+//!
+//! [rw]: https://prometheus.io/docs/concepts/remote_write_spec/
+use pgx::*;
+use serde_json::{Map, Value};
+
+use crate::prompb::{to_pg_timestamp, Label, WriteRequest};
+
+#[pg_schema]
+mod _prom_ext {
+    use pgx::*;
+
+    use super::decode_rows;
+    use crate::prompb::WriteRequest;
+
+    #[pg_extern(immutable, strict, parallel_safe, create_or_replace)]
+    pub fn decode_remote_write(
+        payload: &[u8],
+    ) -> TableIterator<
+        'static,
+        (
+            name!(kind, String),
+            name!(metric_name, Option<String>),
+            name!(labels, Option<JsonB>),
+            name!(time, Option<TimestampWithTimeZone>),
+            name!(value, Option<f64>),
+            name!(exemplar_labels, Option<JsonB>),
+            name!(metric_type, Option<String>),
+            name!(unit, Option<String>),
+            name!(help, Option<String>),
+        ),
+    > {
+        let request = WriteRequest::decode_compressed(payload)
+            .unwrap_or_else(|e| error!("invalid remote write payload: {}", e));
+        let rows =
+            decode_rows(request).unwrap_or_else(|e| error!("invalid remote write payload: {}", e));
+        TableIterator::new(rows.into_iter())
+    }
+}
+
+pub(crate) type DecodedRow = (
+    String,
+    Option<String>,
+    Option<JsonB>,
+    Option<TimestampWithTimeZone>,
+    Option<f64>,
+    Option<JsonB>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+/// The rows of `decode_remote_write`, shared by decoders of other protocols
+/// that translate to remote write.
+pub(crate) fn decode_rows(request: WriteRequest) -> Result<Vec<DecodedRow>, String> {
+    let mut rows = vec![];
+    for series in request.timeseries {
+        let metric_name = series
+            .metric_name()
+            .ok_or_else(|| "time series without a __name__ label".to_string())?
+            .to_string();
+        let labels = labels_to_json(&series.labels);
+        for sample in series.samples {
+            rows.push((
+                "sample".to_string(),
+                Some(metric_name.clone()),
+                Some(JsonB(labels.clone())),
+                Some(to_pg_timestamp(sample.timestamp)?.into()),
+                Some(sample.value),
+                None,
+                None,
+                None,
+                None,
+            ));
+        }
+        for exemplar in series.exemplars {
+            rows.push((
+                "exemplar".to_string(),
+                Some(metric_name.clone()),
+                Some(JsonB(labels.clone())),
+                Some(to_pg_timestamp(exemplar.timestamp)?.into()),
+                Some(exemplar.value),
+                Some(JsonB(labels_to_json(&exemplar.labels))),
+                None,
+                None,
+                None,
+            ));
+        }
+    }
+    for metadata in request.metadata {
+        rows.push((
+            "metadata".to_string(),
+            Some(metadata.metric_family_name.clone()),
+            None,
+            None,
+            None,
+            None,
+            Some(metadata.type_name().to_string()),
+            Some(metadata.unit),
+            Some(metadata.help),
+        ));
+    }
+    Ok(rows)
+}
+
+fn labels_to_json(labels: &[Label]) -> Value {
+    Value::Object(
+        labels
+            .iter()
+            .map(|l| (l.name.clone(), Value::String(l.value.clone())))
+            .collect::<Map<_, _>>(),
+    )
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+
+    // A WriteRequest with one `up{job="node"}` series holding the samples
+    // (1000 ms, 1) and (2000 ms, 0), an exemplar {trace_id="abc"} at
+    // (1500 ms, 0.5), and gauge metadata for `up` with help "Up." and an
+    // empty unit.
+    const PAYLOAD: &str = "67b00a580a0e0a085f5f6e616d655f5f120275700a0b0a036a6f6212046e6f6465120c09000000000000f03f10e807150e60000010d00f1a1d0a0f0a0874726163655f6964120361626311052c4800e03f18dc0b1a0b080212027570220355702e";
+
+    #[pg_test]
+    fn test_decode_remote_write_samples() {
+        let result = Spi::get_one::<Json>(&format!(
+            "SELECT json_agg(json_build_array(metric_name, labels, (extract(epoch FROM time) * 1000)::bigint, value) ORDER BY time)
+             FROM prom_api.decode_remote_write('\\x{}'::bytea)
+             WHERE kind = 'sample'",
+            PAYLOAD
+        ))
+        .expect("SQL query failed");
+        assert_eq!(
+            result.0,
+            serde_json::json!([
+                ["up", {"__name__": "up", "job": "node"}, 1000, 1],
+                ["up", {"__name__": "up", "job": "node"}, 2000, 0],
+            ])
+        );
+    }
+
+    #[pg_test]
+    fn test_decode_remote_write_exemplars_and_metadata() {
+        let result = Spi::get_one::<Json>(&format!(
+            "SELECT json_agg(json_build_array(kind, metric_name, exemplar_labels, value, metric_type, unit, help) ORDER BY kind)
+             FROM prom_api.decode_remote_write('\\x{}'::bytea)
+             WHERE kind <> 'sample'",
+            PAYLOAD
+        ))
+        .expect("SQL query failed");
+        assert_eq!(
+            result.0,
+            serde_json::json!([
+                ["exemplar", "up", {"trace_id": "abc"}, 0.5, null, null, null],
+                ["metadata", "up", null, null, "gauge", "", "Up."],
+            ])
+        );
+    }
+
+    #[pg_test]
+    fn test_insert_remote_write() {
+        Spi::run(&format!(
+            "CALL prom_api.insert_remote_write('\\x{}'::bytea)",
+            PAYLOAD
+        ));
+        let samples =
+            Spi::get_one::<i64>("SELECT count(*) FROM prom_data.up").expect("SQL query failed");
+        assert_eq!(samples, 2);
+        let exemplars = Spi::get_one::<i64>("SELECT count(*) FROM prom_data_exemplar.up")
+            .expect("SQL query failed");
+        assert_eq!(exemplars, 1);
+        let exemplar_label_values = Spi::get_one::<Vec<String>>(
+            "SELECT exemplar_label_values::TEXT[] FROM prom_data_exemplar.up",
+        )
+        .expect("SQL query failed");
+        assert_eq!(exemplar_label_values, vec!["abc".to_string()]);
+        let help = Spi::get_one::<String>(
+            "SELECT help FROM _prom_catalog.metadata WHERE metric_family = 'up'",
+        )
+        .expect("SQL query failed");
+        assert_eq!(help, "Up.");
+    }
+
+    #[pg_test(error = "invalid remote write payload: snappy: invalid copy offset 0")]
+    fn test_decode_remote_write_rejects_corrupt_payload() {
+        Spi::run("SELECT * FROM prom_api.decode_remote_write('\\x020100'::bytea)");
+    }
+}
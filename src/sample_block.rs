@@ -0,0 +1,369 @@
+//! # Sample blocks
+//!
+//! `prom_api.sample_block` stores a series of (time, value) samples in the
+//! Gorilla encoding of Prometheus chunks: timestamps as delta-of-deltas and
+//! values XORed with their predecessor. Regularly scraped series take a couple
+//! of bytes per sample instead of the 16 of a `timestamptz[]` and `float8[]`
+//! pair, so materialized rollups and exports shrink by an order of magnitude.
+//!
+//! Blocks are built with the `prom_api.sample_block_agg` aggregate and read back
+//! with `prom_api.unnest`:
+//!
+//! ```sql
+//! CREATE TABLE up_blocks AS
+//! SELECT series_id, time_bucket('1 day', time) AS day, prom_api.sample_block_agg(time, value) AS samples
+//! FROM prom_data.up
+//! GROUP BY 1, 2;
+//!
+//! SELECT s.* FROM up_blocks b, prom_api.unnest(b.samples) s;
+//! ```
+//!
+//! `vector_selector` and `prom_rate` accept blocks in place of the sample
+//! time and value, skipping the samples of a block outside of the evaluated
+//! range. As with single samples, `prom_rate` expects its input in time order,
+//! i.e. blocks that don't overlap, ordered by their first sample.
+//!
+//! On disk a block is a format version byte followed by XOR chunks, each with
+//! a big-endian `u32` length prefix. Timestamps are Postgres microseconds rather
+//! than Prometheus milliseconds, so that no precision is lost. The type is
+//! stored like `bytea` and shares its text and binary output, but there are no
+//! casts between the two: its input functions only accept valid blocks, so the
+//! functions here can take any `prom_api.sample_block` as bytes. As the type is
+//! defined by the migrations, so are the functions taking or returning it.
+use std::convert::TryInto;
+
+use pgx::*;
+
+use crate::chunkenc::{decode_xor_chunk, XorChunkBuilder};
+
+const FORMAT_VERSION: u8 = 1;
+
+/// The number of samples fitting into a chunk's 16-bit sample count.
+const SAMPLES_PER_BLOCK_CHUNK: usize = u16::MAX as usize;
+
+/// Encodes samples into a block. Timestamps are in Postgres microseconds and
+/// must not decrease.
+pub(crate) fn encode_sample_block(samples: &[(i64, f64)]) -> Vec<u8> {
+    let mut bytes = vec![FORMAT_VERSION];
+    for chunk_samples in samples.chunks(SAMPLES_PER_BLOCK_CHUNK) {
+        let mut chunk = XorChunkBuilder::new();
+        for &(t, v) in chunk_samples {
+            chunk.append(t, v);
+        }
+        let chunk = chunk.finish();
+        bytes.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&chunk);
+    }
+    bytes
+}
+
+/// Decodes the samples of a block, as returned by [`encode_sample_block`].
+pub(crate) fn decode_sample_block(bytes: &[u8]) -> Result<Vec<(i64, f64)>, String> {
+    match bytes.first() {
+        Some(&FORMAT_VERSION) => (),
+        Some(version) => return Err(format!("unsupported sample block version {}", version)),
+        None => return Err("empty sample block".to_string()),
+    }
+    let mut samples = vec![];
+    let mut rest = &bytes[1..];
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err("sample block: truncated chunk length".to_string());
+        }
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        let chunk = rest[4..]
+            .get(..len)
+            .ok_or_else(|| "sample block: truncated chunk".to_string())?;
+        samples.extend(decode_xor_chunk(chunk)?);
+        rest = &rest[4 + len..];
+    }
+    Ok(samples)
+}
+
+#[pg_schema]
+mod _prom_ext {
+    use pgx::*;
+
+    use super::{decode_sample_block, encode_sample_block};
+    use crate::aggregate_utils::in_aggregate_context;
+    use crate::palloc::{Inner, InternalAsValue, ToInternal};
+
+    fn validate_sample_block(block: Vec<u8>) -> Vec<u8> {
+        if let Err(e) = decode_sample_block(&block) {
+            error!("invalid sample block: {}", e)
+        }
+        block
+    }
+
+    /// Input function of `prom_api.sample_block`, parses the text form of
+    /// `bytea` and rejects anything that isn't a valid block.
+    #[pg_extern(immutable, parallel_safe, strict, sql = false)]
+    pub fn sample_block_in(input: &cstr_core::CStr) -> Vec<u8> {
+        let block =
+            unsafe { direct_function_call::<Vec<u8>>(pg_sys::byteain, vec![input.into_datum()]) }
+                .expect("byteain returned NULL");
+        validate_sample_block(block)
+    }
+
+    /// Receive function of `prom_api.sample_block`, the binary counterpart of
+    /// [`sample_block_in`].
+    #[pg_extern(immutable, parallel_safe, strict, sql = false)]
+    pub fn sample_block_recv(buf: Internal) -> Vec<u8> {
+        let block =
+            unsafe { direct_function_call::<Vec<u8>>(pg_sys::bytearecv, vec![buf.into_datum()]) }
+                .expect("bytearecv returned NULL");
+        validate_sample_block(block)
+    }
+
+    #[pg_extern(immutable, parallel_safe, create_or_replace)]
+    pub fn sample_block_transition(
+        state: Internal,
+        time: Option<TimestampWithTimeZone>,
+        value: Option<f64>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        sample_block_transition_inner(unsafe { state.to_inner() }, time, value, fcinfo).internal()
+    }
+
+    fn sample_block_transition_inner(
+        state: Option<Inner<Vec<(i64, f64)>>>,
+        time: Option<TimestampWithTimeZone>,
+        value: Option<f64>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<Vec<(i64, f64)>>> {
+        unsafe {
+            in_aggregate_context(fcinfo, || {
+                let mut state = state.unwrap_or_else(|| Vec::new().into());
+                // like array_agg, but a sample without time or value has no representation
+                if let (Some(time), Some(value)) = (time, value) {
+                    state.push((time.into(), value));
+                }
+                Some(state)
+            })
+        }
+    }
+
+    /// Encodes the aggregated samples in time order. An aggregate over no
+    /// samples returns an empty block rather than `NULL`, as long as there
+    /// were input rows.
+    #[pg_extern(immutable, parallel_safe, sql = false)]
+    pub fn sample_block_final(
+        state: Internal, /* Option<Inner<Vec<(i64, f64)>>> */
+    ) -> Option<Vec<u8>> {
+        let mut state: Inner<Vec<(i64, f64)>> = unsafe { state.to_inner() }?;
+        state.sort_by_key(|&(time, _)| time);
+        Some(encode_sample_block(&state))
+    }
+
+    #[pg_extern(immutable, strict, parallel_safe, sql = false)]
+    pub fn sample_block_unnest(
+        block: &[u8],
+    ) -> TableIterator<'static, (name!(time, TimestampWithTimeZone), name!(value, f64))> {
+        let samples =
+            decode_sample_block(block).unwrap_or_else(|e| error!("invalid sample block: {}", e));
+        TableIterator::new(
+            samples
+                .into_iter()
+                .map(|(time, value)| (TimestampWithTimeZone::from(time), value)),
+        )
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+
+    use super::{decode_sample_block, encode_sample_block};
+
+    fn setup() {
+        Spi::run(
+            r#"
+            CREATE TABLE sample_block_test(series INT, t TIMESTAMPTZ, v DOUBLE PRECISION);
+            INSERT INTO sample_block_test
+            SELECT s, t, s * 1000 + extract(epoch FROM t)::int % 600
+            FROM generate_series(1, 2) s,
+                 generate_series('2000-01-02T14:50:00+00:00'::TIMESTAMPTZ, '2000-01-02T16:00:00+00:00', '15 seconds') t;
+        "#,
+        );
+    }
+
+    #[pg_test]
+    fn test_sample_block_encoding_round_trip() {
+        // more samples than fit into a single chunk, with irregular times and odd values
+        let samples: Vec<(i64, f64)> = (0..70_000i64)
+            .map(|i| {
+                let time = -1_000_000 + i * 15_000_000 + (i % 7) * 1_001;
+                let value = match i % 5 {
+                    0 => f64::NAN,
+                    1 => f64::from_bits(crate::aggregates::STALE_NAN),
+                    2 => -0.0,
+                    _ => i as f64 * 0.1,
+                };
+                (time, value)
+            })
+            .collect();
+        let bytes = encode_sample_block(&samples);
+        let decoded = decode_sample_block(&bytes).unwrap();
+        assert_eq!(decoded.len(), samples.len());
+        for (decoded, sample) in decoded.iter().zip(samples.iter()) {
+            assert_eq!(decoded.0, sample.0);
+            assert_eq!(decoded.1.to_bits(), sample.1.to_bits());
+        }
+
+        assert_eq!(
+            decode_sample_block(&encode_sample_block(&[])).unwrap(),
+            vec![]
+        );
+        assert!(decode_sample_block(&[]).is_err());
+        assert!(decode_sample_block(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[pg_test]
+    fn test_sample_block_agg_unnest() {
+        setup();
+        let result = Spi::get_one::<bool>(
+            r#"
+            WITH blocks AS (
+                SELECT series, prom_api.sample_block_agg(t, v ORDER BY t DESC) AS samples
+                FROM sample_block_test
+                GROUP BY series
+            )
+            SELECT array_agg((u.time, u.value) ORDER BY b.series, u.time)
+                = (SELECT array_agg((t, v) ORDER BY series, t) FROM sample_block_test)
+            FROM blocks b, prom_api.unnest(b.samples) u;
+        "#,
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test]
+    fn test_sample_block_size() {
+        setup();
+        // 281 samples at regular intervals, the arrays take about 16 bytes per sample
+        let result = Spi::get_one::<bool>(
+            r#"
+            SELECT pg_column_size(prom_api.sample_block_agg(t, v)) * 5
+                < pg_column_size(array_agg(t)) + pg_column_size(array_agg(v))
+            FROM sample_block_test
+            WHERE series = 1;
+        "#,
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test]
+    fn test_sample_block_without_samples() {
+        let result = Spi::get_one::<i64>(
+            r#"
+            SELECT count(*)
+            FROM prom_api.unnest((SELECT prom_api.sample_block_agg(NULL, 1.0)));
+        "#,
+        );
+        assert_eq!(result, Some(0));
+    }
+
+    #[pg_test(error = "invalid sample block: unsupported sample block version 2")]
+    fn test_sample_block_invalid_version() {
+        Spi::run(r#"SELECT * FROM prom_api.unnest('\x02'::prom_api.sample_block);"#);
+    }
+
+    #[pg_test(error = "invalid sample block: sample block: truncated chunk")]
+    fn test_sample_block_invalid_input() {
+        Spi::run(r#"SELECT '\x0100000010ff'::prom_api.sample_block;"#);
+    }
+
+    #[pg_test]
+    fn test_sample_block_text_round_trip() {
+        setup();
+        let result = Spi::get_one::<bool>(
+            r#"
+            WITH block AS (
+                SELECT prom_api.sample_block_agg(t, v) AS samples
+                FROM sample_block_test
+                WHERE series = 1
+            )
+            SELECT array_agg((u.time, u.value) ORDER BY u.time)
+                = (SELECT array_agg((t, v) ORDER BY t) FROM sample_block_test WHERE series = 1)
+            FROM block b, prom_api.unnest(b.samples::text::prom_api.sample_block) u;
+        "#,
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test]
+    fn test_vector_selector_sample_block() {
+        setup();
+        let result = Spi::get_one::<bool>(
+            r#"
+            WITH blocks AS (
+                SELECT series, date_trunc('hour', t) AS hour, prom_api.sample_block_agg(t, v) AS samples
+                FROM sample_block_test
+                GROUP BY 1, 2
+            )
+            SELECT array_agg(b.vs ORDER BY b.series) = array_agg(r.vs ORDER BY r.series)
+            FROM (
+                SELECT series, vector_selector(
+                      '2000-01-02T15:00:00+00:00'::TIMESTAMPTZ
+                    , '2000-01-02T15:30:00+00:00'::TIMESTAMPTZ
+                    , 60 * 1000
+                    , 5 * 60 * 1000
+                    , samples) AS vs
+                FROM blocks
+                GROUP BY series
+            ) b
+            JOIN (
+                SELECT series, vector_selector(
+                      '2000-01-02T15:00:00+00:00'::TIMESTAMPTZ
+                    , '2000-01-02T15:30:00+00:00'::TIMESTAMPTZ
+                    , 60 * 1000
+                    , 5 * 60 * 1000
+                    , t
+                    , v) AS vs
+                FROM sample_block_test
+                WHERE t BETWEEN '2000-01-02T14:55:00+00:00' AND '2000-01-02T15:30:00+00:00'
+                GROUP BY series
+            ) r USING (series);
+        "#,
+        );
+        assert_eq!(result, Some(true));
+    }
+
+    #[pg_test]
+    fn test_prom_rate_sample_block() {
+        setup();
+        let result = Spi::get_one::<bool>(
+            r#"
+            WITH blocks AS (
+                SELECT series, date_trunc('hour', t) AS hour, prom_api.sample_block_agg(t, v) AS samples
+                FROM sample_block_test
+                GROUP BY 1, 2
+            )
+            SELECT array_agg(b.rate ORDER BY b.series) = array_agg(r.rate ORDER BY r.series)
+            FROM (
+                SELECT series, prom_rate(
+                      '2000-01-02T15:00:00+00:00'::TIMESTAMPTZ
+                    , '2000-01-02T15:30:00+00:00'::TIMESTAMPTZ
+                    , 60 * 1000
+                    , 5 * 60 * 1000
+                    , samples ORDER BY hour) AS rate
+                FROM blocks
+                GROUP BY series
+            ) b
+            JOIN (
+                SELECT series, prom_rate(
+                      '2000-01-02T15:00:00+00:00'::TIMESTAMPTZ
+                    , '2000-01-02T15:30:00+00:00'::TIMESTAMPTZ
+                    , 60 * 1000
+                    , 5 * 60 * 1000
+                    , t
+                    , v ORDER BY t) AS rate
+                FROM sample_block_test
+                WHERE t BETWEEN '2000-01-02T15:00:00+00:00' AND '2000-01-02T15:30:00+00:00'
+                GROUP BY series
+            ) r USING (series);
+        "#,
+        );
+        assert_eq!(result, Some(true));
+    }
+}
@@ -0,0 +1,278 @@
+use pgx::*;
+
+#[pg_schema]
+mod _prom_ext {
+    use crate::palloc::ToInternal;
+    use crate::pg_imports::*;
+    use crate::*;
+    use std::ptr;
+
+    /// Mirrors `DEFAULT_CONTAIN_SEL` PostgreSQL uses for array operators.
+    const DEFAULT_MATCHER_SELECTIVITY: f64 = 0.005;
+    const ARRAY_OVERLAP_OP_PATH: [&str; 2] = ["pg_catalog", "&&"];
+
+    /// Restriction selectivity estimator for the `label_array ? matcher_positive`
+    /// operator.
+    ///
+    /// The estimate is the one of the built-in array overlap operator, which
+    /// relies on the most common elements statistics of `labels`. It is only
+    /// available if the matchers are a constant: the `label_find_key_*`
+    /// functions computing most matchers query `_prom_catalog.label`, so they
+    /// aren't evaluated and such matchers get the default estimate. Thus, no
+    /// table is ever scanned at planning time.
+    ///
+    /// Note that the function behind the operator is an inlinable SQL function.
+    /// Once inlined, the planner estimates the built-in operator it uses instead.
+    #[pg_extern(stable, strict, create_or_replace)]
+    pub unsafe fn label_matcher_sel(
+        root: Internal,
+        _operator: pg_sys::Oid,
+        args: Internal,
+        var_relid: i32,
+    ) -> f64 {
+        let root: *mut pg_sys::PlannerInfo = root.unwrap().unwrap().cast_mut_ptr();
+        let args: *mut pg_sys::List = args.unwrap().unwrap().cast_mut_ptr();
+        matcher_selectivity(root, args, var_relid).unwrap_or(DEFAULT_MATCHER_SELECTIVITY)
+    }
+
+    /// Restriction selectivity estimator for the `label_array ? matcher_negative`
+    /// operator: the complement of [`label_matcher_sel`].
+    #[pg_extern(stable, strict, create_or_replace)]
+    pub unsafe fn label_matcher_negative_sel(
+        root: Internal,
+        _operator: pg_sys::Oid,
+        args: Internal,
+        var_relid: i32,
+    ) -> f64 {
+        let root: *mut pg_sys::PlannerInfo = root.unwrap().unwrap().cast_mut_ptr();
+        let args: *mut pg_sys::List = args.unwrap().unwrap().cast_mut_ptr();
+        1.0 - matcher_selectivity(root, args, var_relid).unwrap_or(DEFAULT_MATCHER_SELECTIVITY)
+    }
+
+    /// This [support function] provides row estimates for the set returning
+    /// functions, used to iterate over labels, e.g. `_prom_catalog.label_unnest`.
+    /// If the argument can be reduced to a constant, its actual number of elements
+    /// is used instead of the `ROWS` estimate of the function.
+    ///
+    /// [support function]: https://www.postgresql.org/docs/current/xfunc-optimization.html
+    #[pg_extern(immutable, strict, create_or_replace)]
+    pub unsafe fn label_rows_support(input: Internal) -> Internal {
+        unsafe fn inner(node: *mut pg_sys::Node) -> Option<Internal> {
+            if !pgx::is_a(node, pg_sys::NodeTag_T_SupportRequestRows) {
+                return None;
+            }
+            let req = node.cast::<pg_sys::SupportRequestRows>();
+            if !pgx::is_a((*req).node, pg_sys::NodeTag_T_FuncExpr) {
+                return None;
+            }
+            let func_expr = (*req).node.cast::<pg_sys::FuncExpr>();
+            let args = PgList::<pg_sys::Node>::from_pg((*func_expr).args);
+            let arg = args.head()?;
+            let value = if (*req).root.is_null() {
+                arg
+            } else {
+                pg_sys::estimate_expression_value((*req).root, arg)
+            };
+            let value = const_value(value)?;
+
+            let value_type = pg_sys::getBaseType((*value).consttype);
+            let rows = if value_type == pg_sys::JSONBOID {
+                let jsonb = pg_sys::pg_detoast_datum((*value).constvalue.cast_mut_ptr())
+                    .cast::<pg_sys::Jsonb>();
+                // for objects the header holds the number of key-value pairs
+                ((*jsonb).root.header & pg_sys::JB_CMASK) as f64
+            } else if pg_sys::type_is_array(value_type) {
+                let array = pg_sys::pg_detoast_datum((*value).constvalue.cast_mut_ptr())
+                    .cast::<pg_sys::ArrayType>();
+                let dims = array
+                    .cast::<u8>()
+                    .add(std::mem::size_of::<pg_sys::ArrayType>())
+                    .cast::<i32>();
+                pg_sys::ArrayGetNItems((*array).ndim, dims) as f64
+            } else {
+                return None;
+            };
+            // the planner clamps estimates to at least one row anyway
+            (*req).rows = rows.max(1.0);
+            Some(node.internal())
+        }
+
+        let node: *mut pg_sys::Node = input.unwrap().unwrap().cast_mut_ptr();
+        inner(node).unwrap_or_else(|| ptr::null_mut::<pg_sys::Node>().internal())
+    }
+
+    /// Computes the selectivity of `label_array && matchers` given the arguments
+    /// of the operator. Returns `None` unless the matchers are a constant.
+    unsafe fn matcher_selectivity(
+        root: *mut pg_sys::PlannerInfo,
+        args: *mut pg_sys::List,
+        var_relid: i32,
+    ) -> Option<f64> {
+        let args = PgList::<pg_sys::Node>::from_pg(args);
+        if args.len() != 2 {
+            return None;
+        }
+        let matchers = matcher_const(args.tail()?)?;
+
+        let mut builtin_args = PgList::<pg_sys::Node>::new();
+        builtin_args.push(args.head()?);
+        builtin_args.push(matchers.cast());
+        let selectivity = pg_sys::restriction_selectivity(
+            root,
+            builtin_overlap_op(),
+            builtin_args.into_pg(),
+            pg_sys::InvalidOid,
+            var_relid,
+        );
+        Some(selectivity)
+            .filter(|selectivity| selectivity.is_finite())
+            .map(|selectivity| selectivity.clamp(0.0, 1.0))
+    }
+
+    /// Converts a constant of one of the matcher domains into an `int[]` constant,
+    /// as the built-in estimator expects the array itself.
+    unsafe fn matcher_const(expr: *mut pg_sys::Node) -> Option<*mut pg_sys::Const> {
+        let value = const_value(expr)?;
+        if pg_sys::getBaseType((*value).consttype) != pg_sys::INT4ARRAYOID {
+            return None;
+        }
+        Some(pg_sys::makeConst(
+            pg_sys::INT4ARRAYOID,
+            -1,
+            pg_sys::InvalidOid,
+            -1,
+            (*value).constvalue,
+            false,
+            false,
+        ))
+    }
+
+    /// Returns the built-in `anyarray && anyarray` operator.
+    unsafe fn builtin_overlap_op() -> pg_sys::Oid {
+        let op_name = build_pg_list_of_cstrings(ARRAY_OVERLAP_OP_PATH);
+        LookupOperName(
+            std::ptr::null_mut(),
+            op_name.as_ptr(),
+            pg_sys::ANYARRAYOID,
+            pg_sys::ANYARRAYOID,
+            false, // Raises an error if the operator is not found
+            -1,
+        )
+    }
+
+    /// Returns the [`pg_sys::Const`] node if the expression is a non-null constant.
+    unsafe fn const_value(expr: *mut pg_sys::Node) -> Option<*mut pg_sys::Const> {
+        let expr = strip_relabel(expr);
+        if !pgx::is_a(expr, pg_sys::NodeTag_T_Const) {
+            return None;
+        }
+        let value = expr.cast::<pg_sys::Const>();
+        if (*value).constisnull {
+            return None;
+        }
+        Some(value)
+    }
+
+    /// Matchers are domains over `int[]` thus they are often wrapped into
+    /// relabeling and domain coercion nodes.
+    unsafe fn strip_relabel(mut expr: *mut pg_sys::Node) -> *mut pg_sys::Node {
+        loop {
+            if pgx::is_a(expr, pg_sys::NodeTag_T_RelabelType) {
+                expr = (*expr.cast::<pg_sys::RelabelType>()).arg.cast();
+            } else if pgx::is_a(expr, pg_sys::NodeTag_T_CoerceToDomain) {
+                expr = (*expr.cast::<pg_sys::CoerceToDomain>()).arg.cast();
+            } else {
+                return expr;
+            }
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+
+    use pgx::*;
+
+    fn setup() {
+        Spi::run(
+            r#"
+            SELECT _prom_catalog.get_or_create_metric_table_name('selectivity_test');
+            SELECT _prom_catalog.get_or_create_series_id(
+                jsonb_build_object(
+                    '__name__', 'selectivity_test',
+                    'job', CASE WHEN g <= 90 THEN 'frequent' ELSE 'rare' END,
+                    'instance', g::text
+                )
+            )
+            FROM generate_series(1, 100) g;
+            ANALYZE;
+        "#,
+        );
+    }
+
+    #[pg_test]
+    fn test_label_matcher_operator_estimators() {
+        let estimators = Spi::get_one::<Vec<String>>(
+            r#"
+                SELECT array_agg(o.oprrest::text ORDER BY o.oprright::regtype::text)
+                FROM pg_operator o
+                WHERE o.oprname = '?'
+                AND o.oprleft = 'prom_api.label_array'::regtype
+                AND o.oprright IN ('prom_api.matcher_positive'::regtype, 'prom_api.matcher_negative'::regtype);
+            "#,
+        )
+        .expect("SQL query failed");
+
+        assert_eq!(
+            estimators,
+            vec!["label_matcher_negative_sel", "label_matcher_sel"]
+        );
+    }
+
+    #[pg_test]
+    fn test_label_matcher_uses_index() {
+        setup();
+        Spi::run("SET LOCAL enable_seqscan = off");
+
+        let result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (COSTS OFF, FORMAT JSON)
+                    SELECT * FROM _prom_catalog.series
+                    WHERE labels OPERATOR(prom_api.?) ('job' OPERATOR(ps_tag.==) 'rare');
+            "#,
+        )
+        .expect("SQL query failed");
+
+        // The matchers use the built-in operator, which GIN indexes support as is
+        let plan = result.0[0]["Plan"].to_string();
+        assert!(
+            plan.contains(r#""Index Cond":"((labels)::integer[] && ($0)::integer[])""#),
+            "expected an index condition: {}",
+            plan
+        );
+    }
+
+    #[pg_test]
+    fn test_label_unnest_row_estimate() {
+        let result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (FORMAT JSON)
+                    SELECT * FROM _prom_catalog.label_unnest(array[1, 2, 3]);
+            "#,
+        )
+        .expect("SQL query failed");
+
+        assert_eq!(result.0[0]["Plan"]["Plan Rows"], 3);
+
+        let result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (FORMAT JSON)
+                    SELECT * FROM _prom_catalog.label_jsonb_each_text('{"a": "1", "b": "2"}');
+            "#,
+        )
+        .expect("SQL query failed");
+
+        assert_eq!(result.0[0]["Plan"]["Plan Rows"], 2);
+    }
+}
@@ -0,0 +1,183 @@
+//! A minimal implementation of the [Snappy block format][format], as used by
+//! the Prometheus remote read and write protocols.
+//!
+//! The framing (streaming) format is not supported.
+//!
+//! [format]: https://github.com/google/snappy/blob/main/format_description.txt
+
+use std::convert::TryFrom;
+
+/// Upper bound on the declared uncompressed length we are willing to allocate
+/// upfront. Larger payloads are still decoded, the buffer just grows as needed.
+const MAX_PREALLOCATION: usize = 16 * 1024 * 1024;
+
+/// Like the reference implementation, the input is compressed in independent
+/// blocks, which keeps copy offsets within two bytes.
+const BLOCK_SIZE: usize = 1 << 16;
+const HASH_BITS: u32 = 14;
+
+/// Decompresses a Snappy block.
+pub(crate) fn decompress(input: &[u8]) -> Result<Vec<u8>, String> {
+    let (expected_len, mut pos) = read_uvarint(input)?;
+    let expected_len = usize::try_from(expected_len)
+        .map_err(|_| "snappy: uncompressed length overflows usize".to_string())?;
+    let mut out = Vec::with_capacity(expected_len.min(MAX_PREALLOCATION));
+
+    while pos < input.len() {
+        let tag = input[pos];
+        pos += 1;
+        match tag & 0b11 {
+            0 => {
+                let mut len = (tag >> 2) as usize;
+                if len >= 60 {
+                    let width = len - 59;
+                    let bytes = input
+                        .get(pos..pos + width)
+                        .ok_or_else(|| "snappy: truncated literal length".to_string())?;
+                    len = bytes
+                        .iter()
+                        .rev()
+                        .fold(0usize, |acc, &b| (acc << 8) | b as usize);
+                    pos += width;
+                }
+                let len = len + 1;
+                let literal = input
+                    .get(pos..pos + len)
+                    .ok_or_else(|| "snappy: truncated literal".to_string())?;
+                out.extend_from_slice(literal);
+                pos += len;
+            }
+            kind => {
+                let (len, offset) = match kind {
+                    1 => {
+                        let b = *input
+                            .get(pos)
+                            .ok_or_else(|| "snappy: truncated copy".to_string())?;
+                        pos += 1;
+                        (
+                            4 + ((tag >> 2) & 0b111) as usize,
+                            (((tag >> 5) as usize) << 8) | b as usize,
+                        )
+                    }
+                    2 => {
+                        let b = input
+                            .get(pos..pos + 2)
+                            .ok_or_else(|| "snappy: truncated copy".to_string())?;
+                        pos += 2;
+                        (
+                            1 + (tag >> 2) as usize,
+                            u16::from_le_bytes([b[0], b[1]]) as usize,
+                        )
+                    }
+                    _ => {
+                        let b = input
+                            .get(pos..pos + 4)
+                            .ok_or_else(|| "snappy: truncated copy".to_string())?;
+                        pos += 4;
+                        (
+                            1 + (tag >> 2) as usize,
+                            u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize,
+                        )
+                    }
+                };
+                if offset == 0 || offset > out.len() {
+                    return Err(format!("snappy: invalid copy offset {}", offset));
+                }
+                // Copies may overlap with their own output, hence byte by byte.
+                let start = out.len() - offset;
+                for i in 0..len {
+                    let b = out[start + i];
+                    out.push(b);
+                }
+            }
+        }
+        if out.len() > expected_len {
+            return Err("snappy: output exceeds declared length".to_string());
+        }
+    }
+
+    if out.len() != expected_len {
+        return Err(format!(
+            "snappy: expected {} bytes of output, got {}",
+            expected_len,
+            out.len()
+        ));
+    }
+    Ok(out)
+}
+
+/// Compresses `input` into a Snappy block. This is a simple greedy compressor:
+/// the output is valid, but not as compact as the reference implementation's.
+pub(crate) fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    crate::protobuf::write_varint(&mut out, input.len() as u64);
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    for block in input.chunks(BLOCK_SIZE) {
+        table.iter_mut().for_each(|e| *e = 0);
+        compress_block(block, &mut table, &mut out);
+    }
+    out
+}
+
+fn compress_block(block: &[u8], table: &mut [usize], out: &mut Vec<u8>) {
+    let load = |i: usize| u32::from_le_bytes([block[i], block[i + 1], block[i + 2], block[i + 3]]);
+    let mut literal_start = 0;
+    let mut i = 0;
+    while i + 4 <= block.len() {
+        let current = load(i);
+        let hash = (current.wrapping_mul(0x1e35_a7bd) >> (32 - HASH_BITS)) as usize;
+        // table entries are offset by one, so that zero means "empty"
+        let candidate = table[hash];
+        table[hash] = i + 1;
+        if candidate != 0 && load(candidate - 1) == current {
+            let candidate = candidate - 1;
+            let mut len = 4;
+            while i + len < block.len() && block[candidate + len] == block[i + len] {
+                len += 1;
+            }
+            emit_literal(&block[literal_start..i], out);
+            emit_copy(i - candidate, len, out);
+            i += len;
+            literal_start = i;
+        } else {
+            i += 1;
+        }
+    }
+    emit_literal(&block[literal_start..], out);
+}
+
+fn emit_literal(literal: &[u8], out: &mut Vec<u8>) {
+    if literal.is_empty() {
+        return;
+    }
+    let n = literal.len() - 1;
+    if n < 60 {
+        out.push((n as u8) << 2);
+    } else {
+        let width = (usize::BITS - n.leading_zeros() + 7) as usize / 8;
+        out.push(((59 + width) as u8) << 2);
+        out.extend_from_slice(&n.to_le_bytes()[..width]);
+    }
+    out.extend_from_slice(literal);
+}
+
+fn emit_copy(offset: usize, mut len: usize, out: &mut Vec<u8>) {
+    debug_assert!(offset > 0 && offset <= u16::MAX as usize);
+    while len > 0 {
+        let chunk = len.min(64);
+        out.push((((chunk - 1) as u8) << 2) | 0b10);
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        len -= chunk;
+    }
+}
+
+fn read_uvarint(input: &[u8]) -> Result<(u64, usize), String> {
+    let mut value = 0u64;
+    for (i, &b) in input.iter().enumerate().take(10) {
+        value |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err("snappy: invalid length header".to_string())
+}
@@ -30,6 +30,33 @@ mod _prom_ext {
     /// This should be used on any stable function that is often called with constant-like
     /// arguments.
     ///
+    /// If only some of the arguments are constant, the call itself has to be evaluated
+    /// for every row. Yet, constant arguments that aren't plain constants or parameters
+    /// (e.g. calls of stable functions, which the planner can't pre-compute, or constants
+    /// cast to a domain, whose checks would be repeated) are moved into InitPlans of their
+    /// own, so that they are evaluated once per query:
+    ///
+    /// ```sql
+    /// SELECT * FROM some_table WHERE supported_fn(some_column, stable_fn('parameter'));
+    /// ```
+    ///
+    /// becomes
+    ///
+    /// ```sql
+    /// SELECT * FROM some_table WHERE supported_fn(some_column, (SELECT stable_fn('parameter')));
+    /// ```
+    ///
+    /// To sum up, the following call shapes are optimized:
+    ///
+    /// | arguments                                     | result                                  |
+    /// |-----------------------------------------------|-----------------------------------------|
+    /// | constants, params or constant expressions     | the whole call is an InitPlan           |
+    /// | some vary, some are constant expressions      | each constant expression is an InitPlan |
+    /// | some vary, the rest are constants or params   | left as is                              |
+    ///
+    /// Expressions referencing columns (of this query or an outer one), aggregates,
+    /// window functions, subqueries and volatile functions are never moved.
+    ///
     /// [support function]: https://www.postgresql.org/docs/current/xfunc-optimization.html
     #[pg_extern(immutable, strict, create_or_replace)]
     pub unsafe fn rewrite_fn_call_to_subquery(input: Internal) -> Internal {
@@ -46,18 +73,54 @@ mod _prom_ext {
         // i.e. they are constants or expressions of constants
         let args_are_constants = original_args
             .iter_ptr()
-            .all(|arg| arg_can_be_put_into_subquery(arg));
+            .all(|arg| arg_can_be_put_into_subquery(arg) || arg_is_constant_expression(arg));
         if !args_are_constants {
-            return ptr::null_mut::<pg_sys::Node>().internal();
+            return hoist_constant_args(root, expr)
+                .map(|f| f.cast::<pg_sys::Node>())
+                .unwrap_or_else(ptr::null_mut)
+                .internal();
+        }
+
+        (*(*root).parse).hasSubLinks = true;
+
+        make_expr_sublink(expr.cast()).internal()
+    }
+
+    /// Replaces each argument that is a constant expression, but not a plain constant,
+    /// with a subquery. Returns `None` if there is nothing to replace.
+    unsafe fn hoist_constant_args(
+        root: *mut pg_sys::PlannerInfo,
+        expr: *mut pg_sys::FuncExpr,
+    ) -> Option<*mut pg_sys::FuncExpr> {
+        let original_args = PgList::<pg_sys::Node>::from_pg((*expr).args);
+        let mut new_args = PgList::<pg_sys::Node>::new();
+        let mut hoisted_any = false;
+        for arg in original_args.iter_ptr() {
+            if !arg_is_trivial(arg) && arg_is_constant_expression(arg) {
+                new_args.push(make_expr_sublink(arg));
+                hoisted_any = true;
+            } else {
+                new_args.push(arg);
+            }
+        }
+        if !hoisted_any {
+            return None;
         }
 
         (*(*root).parse).hasSubLinks = true;
 
         let f2: *mut pg_sys::FuncExpr =
             pg_sys::copyObjectImpl(expr as *const ::std::os::raw::c_void) as *mut pg_sys::FuncExpr;
+        (*f2).args = new_args.into_pg();
+        Some(f2)
+    }
+
+    /// Wraps a copy of the expression into `(SELECT expr)`.
+    unsafe fn make_expr_sublink(expr: *mut pg_sys::Node) -> *mut pg_sys::Node {
+        let expr_copy = pg_sys::copyObjectImpl(expr as *const ::std::os::raw::c_void);
 
         let mut te = PgBox::<pg_sys::TargetEntry>::alloc_node(pg_sys::NodeTag_T_TargetEntry);
-        te.expr = f2 as *mut pg_sys::Expr;
+        te.expr = expr_copy as *mut pg_sys::Expr;
         te.resno = 1;
 
         let mut query = PgBox::<pg_sys::Query>::alloc_node(pg_sys::NodeTag_T_Query);
@@ -76,7 +139,64 @@ mod _prom_ext {
         sublink.subLinkId = 0;
         sublink.subselect = query.into_pg() as *mut pg_sys::Node;
 
-        (sublink.into_pg() as *mut pg_sys::Node).internal()
+        sublink.into_pg() as *mut pg_sys::Node
+    }
+
+    /// Plain constants and parameters are cheap to evaluate, moving them into
+    /// a subquery would gain nothing and keep the call from being inlined.
+    /// Domain coercions aren't stripped, as their checks are worth hoisting.
+    unsafe fn arg_is_trivial(arg: *mut pg_sys::Node) -> bool {
+        let arg = if pgx::is_a(arg, pg_sys::NodeTag_T_RelabelType) {
+            (*arg.cast::<pg_sys::RelabelType>())
+                .arg
+                .cast::<pg_sys::Node>()
+        } else {
+            arg
+        };
+        pgx::is_a(arg, pg_sys::NodeTag_T_Const) || pgx::is_a(arg, pg_sys::NodeTag_T_Param)
+    }
+
+    /// Checks that the expression yields the same value for the whole query
+    /// and can be evaluated outside of it, i.e. in an InitPlan.
+    unsafe fn arg_is_constant_expression(arg: *mut pg_sys::Node) -> bool {
+        !pg_sys::contain_volatile_functions(arg)
+            && !pg_sys::expression_returns_set(arg)
+            && !has_row_dependent_nodes(arg, ptr::null_mut())
+    }
+
+    #[pg_guard]
+    unsafe extern "C" fn has_row_dependent_nodes(
+        node: *mut pg_sys::Node,
+        context: *mut ::std::os::raw::c_void,
+    ) -> bool {
+        if node.is_null() {
+            return false;
+        }
+        if pgx::is_a(node, pg_sys::NodeTag_T_Var)
+            || pgx::is_a(node, pg_sys::NodeTag_T_PlaceHolderVar)
+            || pgx::is_a(node, pg_sys::NodeTag_T_Aggref)
+            || pgx::is_a(node, pg_sys::NodeTag_T_GroupingFunc)
+            || pgx::is_a(node, pg_sys::NodeTag_T_WindowFunc)
+            || pgx::is_a(node, pg_sys::NodeTag_T_SubLink)
+            || pgx::is_a(node, pg_sys::NodeTag_T_SubPlan)
+            || pgx::is_a(node, pg_sys::NodeTag_T_CurrentOfExpr)
+        {
+            return true;
+        }
+        // Executor parameters are set by the enclosing plan nodes
+        if pgx::is_a(node, pg_sys::NodeTag_T_Param)
+            && (*node.cast::<pg_sys::Param>()).paramkind != pg_sys::ParamKind_PARAM_EXTERN
+        {
+            return true;
+        }
+        pg_sys::expression_tree_walker(
+            node,
+            Some(std::mem::transmute(
+                has_row_dependent_nodes
+                    as unsafe extern "C" fn(*mut pg_sys::Node, *mut ::std::os::raw::c_void) -> bool,
+            )),
+            context,
+        )
     }
 
     /// Backwards compatibility
@@ -107,19 +227,26 @@ mod _prom_ext {
     }
 
     const DENORMALIZE_FUNC_NAME: &str = "tag_map_denormalize";
-    const ARROW_OP_NAME: &str = "->";
+    const ARROW_OP_NAMES: [&str; 2] = ["->", "->>"];
     const HELPER_FUNC_SCHEMA: &str = "_ps_trace";
     const CONTAINS_OP_PATH: [&str; 2] = ["pg_catalog", "@>"];
-    /// This support function expects an expression in the following form:
+    /// This support function expects an expression in one of the following forms:
     /// ```sql
     /// SELECT * FROM some_table
     /// WHERE tag_map_denormalize(map_attribute) -> key OP value;
+    ///
+    /// SELECT * FROM some_table
+    /// WHERE tag_map_denormalize(map_attribute) ->> key OP value;
+    ///
+    /// SELECT * FROM some_table
+    /// WHERE tag_map_denormalize(map_attribute) OP key;
     /// ```
     /// where `OP` could be any binary operator this function is attached to.
     /// For a given operator `OP` the name of its underlying function is used
     /// to locate a corresponding helper function: `_ps_trace.OP_FUNC_matching_tags`.
     /// E.g. for operator `=` backed by function `tag_v_eq` the helper function
-    /// will be `_ps_trace.tag_v_eq_matching_tags`.
+    /// will be `_ps_trace.tag_v_eq_matching_tags`. The helper takes `(key, value)`
+    /// for the first two forms and just `(key)` for the last one (e.g. `?`).
     ///
     /// If input expression matches the expected form, it will be rewritten as:
     /// ```sql
@@ -138,6 +265,10 @@ mod _prom_ext {
     /// SELECT * FROM some_table
     /// WHERE map_attribute @> (SELECT _ps_trace.OP_matching_tags(key, value))
     /// ```
+    ///
+    /// `IN (...)` and `= ANY(...)` predicates are never passed to support functions,
+    /// [`crate::planner`] turns them into calls to `ps_trace.OP_FUNC_any` instead,
+    /// which this function then handles as any other binary operator.
     #[pg_extern(immutable, strict, create_or_replace)]
     pub unsafe fn tag_map_rewrite(input: Internal) -> Internal {
         // Wrapping core logic into a function, that returns a Result,
@@ -148,42 +279,27 @@ mod _prom_ext {
             // Deconstructing the top level operator:
             // tag_map_denormalize(any_tag_map_attribute) -> key OP const_value
             // ^- op_arg_left ---------------------------------^    ^- op_arg_right
+            // or
+            // tag_map_denormalize(any_tag_map_attribute) OP key
+            // ^- op_arg_left ---------------------------^    ^- op_arg_right
             let op_func_expr = (*req).fcall;
             let original_args = PgList::<pg_sys::Node>::from_pg((*op_func_expr).args);
+            if original_args.len() != 2 {
+                return None;
+            }
             // when -> is a regular operator (as opposed to our own special one),
             // there might be a domain coercion node.
             let op_arg_left = strip_type_coercion(original_args.head()?);
             let op_arg_right = original_args.tail()?;
 
-            // Deconstructing the -> operator
-            // tag_map_denormalize(any_tag_map_attribute) -> key
-            // ^- arrow_op_arg_left                          ^- arrow_op_arg_right
-            if !pgx::is_a(op_arg_left, pg_sys::NodeTag_T_OpExpr) {
-                return None;
-            }
-            // the operator is indeed ->
-            let arrow_op = op_arg_left.cast::<pg_sys::OpExpr>();
-            let arrow_op_name_const = CString::new(ARROW_OP_NAME).unwrap();
-            PallocdString::from_ptr(pg_sys::get_opname((*arrow_op).opno))
-                .filter(|op_name| op_name.as_c_str() == arrow_op_name_const.as_c_str())?;
-            // extract operator's args
-            let arrow_args = PgList::<pg_sys::Node>::from_pg((*arrow_op).args);
-            let arrow_op_arg_left = strip_type_coercion(arrow_args.head()?);
-            let arrow_op_arg_right = arrow_args.tail()?;
-
-            // Deconstructing the func call to tag_map_denormalize
-            // and extracting its argument.
-            if !pgx::is_a(arrow_op_arg_left, pg_sys::NodeTag_T_FuncExpr) {
-                return None;
-            }
-            // Validate the function is indeed tag_map_denormalize
-            let denormalize_func = arrow_op_arg_left.cast::<pg_sys::FuncExpr>();
-            let denormalize_name_const = CString::new(DENORMALIZE_FUNC_NAME).unwrap();
-            PallocdString::from_ptr(pg_sys::get_func_name((*denormalize_func).funcid))
-                .filter(|fname| fname.as_c_str() == denormalize_name_const.as_c_str())?;
-            // extract its argument
-            let denormalize_args = PgList::<pg_sys::Node>::from_pg((*denormalize_func).args);
-            let denormalize_arg = denormalize_args.head()?;
+            let (denormalize_arg, helper_args) = match denormalized_tag_map_arg(op_arg_left) {
+                // The key-only form, e.g. the ? operator
+                Some(denormalize_arg) => (denormalize_arg, vec![op_arg_right]),
+                None => {
+                    let (denormalize_arg, key) = tag_map_field_access_args(op_arg_left)?;
+                    (denormalize_arg, vec![key, op_arg_right])
+                }
+            };
 
             // Locate the helper function
             let top_level_func_name_box =
@@ -193,9 +309,13 @@ mod _prom_ext {
                 .to_str()
                 .expect("Non-UTF8 function name");
             let helper_func_name = format!("{}_matching_tags", top_level_func_name);
+            let mut helper_arg_types: Vec<pg_sys::Oid> = helper_args
+                .iter()
+                .map(|&arg| pg_sys::getBaseType(pg_sys::exprType(arg)))
+                .collect();
             let helper_func_detail = func_get_detail(
                 [HELPER_FUNC_SCHEMA, helper_func_name.as_str()],
-                &mut [pg_sys::TEXTOID, pg_sys::JSONBOID],
+                &mut helper_arg_types,
             );
             if helper_func_detail.code == pg_sys::FuncDetailCode_FUNCDETAIL_NOTFOUND {
                 pgx::warning!(
@@ -212,6 +332,15 @@ mod _prom_ext {
                     helper_func_name,
                 );
             }
+            // We pass the arguments as is, without adding any coercion nodes,
+            // so the helper has to accept them verbatim.
+            let args_are_compatible = helper_arg_types
+                .iter()
+                .zip(helper_func_detail.arg_type_oids.iter())
+                .all(|(&actual, &declared)| IsBinaryCoercible(actual, declared));
+            if !args_are_compatible {
+                return None;
+            }
             let helper_func_returns_array = pg_sys::type_is_array(helper_func_detail.ret_type_oid);
 
             // Locate @> jsonb operator
@@ -227,8 +356,9 @@ mod _prom_ext {
 
             // Make a planner node for the helper function call
             let mut helper_func_args = PgList::new();
-            helper_func_args.push(arrow_op_arg_right);
-            helper_func_args.push(op_arg_right);
+            for arg in helper_args {
+                helper_func_args.push(arg);
+            }
             let helper_func_expr = pg_sys::makeFuncExpr(
                 helper_func_detail.func_oid,
                 helper_func_detail.ret_type_oid,
@@ -290,6 +420,51 @@ mod _prom_ext {
         inner(input).unwrap_or_else(|| ptr::null_mut::<pg_sys::Node>().internal())
     }
 
+    /// Deconstructs either of the arrow operators:
+    /// ```text
+    /// tag_map_denormalize(any_tag_map_attribute) -> key
+    /// ^- arrow_op_arg_left                          ^- arrow_op_arg_right
+    /// ```
+    /// and returns the argument of `tag_map_denormalize` along with the key.
+    unsafe fn tag_map_field_access_args(
+        expr: *mut pg_sys::Node,
+    ) -> Option<(*mut pg_sys::Node, *mut pg_sys::Node)> {
+        if !pgx::is_a(expr, pg_sys::NodeTag_T_OpExpr) {
+            return None;
+        }
+        // the operator is indeed -> or ->>
+        let arrow_op = expr.cast::<pg_sys::OpExpr>();
+        PallocdString::from_ptr(pg_sys::get_opname((*arrow_op).opno)).filter(|op_name| {
+            ARROW_OP_NAMES
+                .iter()
+                .any(|&arrow| op_name.as_c_str().to_bytes() == arrow.as_bytes())
+        })?;
+        // extract operator's args
+        let arrow_args = PgList::<pg_sys::Node>::from_pg((*arrow_op).args);
+        let arrow_op_arg_left = strip_type_coercion(arrow_args.head()?);
+        let arrow_op_arg_right = arrow_args.tail()?;
+
+        Some((
+            denormalized_tag_map_arg(arrow_op_arg_left)?,
+            arrow_op_arg_right,
+        ))
+    }
+
+    /// Deconstructs the func call to tag_map_denormalize and extracts its argument.
+    unsafe fn denormalized_tag_map_arg(expr: *mut pg_sys::Node) -> Option<*mut pg_sys::Node> {
+        if !pgx::is_a(expr, pg_sys::NodeTag_T_FuncExpr) {
+            return None;
+        }
+        // Validate the function is indeed tag_map_denormalize
+        let denormalize_func = expr.cast::<pg_sys::FuncExpr>();
+        let denormalize_name_const = CString::new(DENORMALIZE_FUNC_NAME).unwrap();
+        PallocdString::from_ptr(pg_sys::get_func_name((*denormalize_func).funcid))
+            .filter(|fname| fname.as_c_str() == denormalize_name_const.as_c_str())?;
+        // extract its argument
+        let denormalize_args = PgList::<pg_sys::Node>::from_pg((*denormalize_func).args);
+        denormalize_args.head()
+    }
+
     /// Returns a sub-node if passed argument is a type coercing or a relabel node,
     /// otherwise returns its argument as is.
     fn strip_type_coercion(expr: *mut pg_sys::Node) -> *mut pg_sys::Node {
@@ -434,6 +609,106 @@ mod tests {
         );
     }
 
+    #[pg_test]
+    fn test_supported_function_partially_constant_args_output_as_expected() {
+        setup();
+        Spi::run(
+            r#"
+                CREATE OR REPLACE FUNCTION arbitrary_function(key text, value text)
+                RETURNS text
+                AS $func$
+                    SELECT key || value
+                $func$
+                LANGUAGE SQL STABLE PARALLEL SAFE
+                SUPPORT rewrite_fn_call_to_subquery;
+            "#,
+        );
+
+        // Only the constant expression is evaluated once per query
+        let hoisted_arg_result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (COSTS OFF, FORMAT JSON)
+                    SELECT * FROM gfs_test_table
+                    WHERE arbitrary_function(v::text, current_setting('search_path')) = 'constvalue';
+            "#,
+        )
+        .expect("SQL query failed");
+
+        let top_level_plan = hoisted_arg_result.0[0]["Plan"].clone();
+        assert_eq!(top_level_plan["Node Type"], "Seq Scan");
+        assert_eq!(
+            top_level_plan["Filter"],
+            "(arbitrary_function((v)::text, $0) = 'constvalue'::text)"
+        );
+        assert!(
+            top_level_plan["Plans"]
+                .as_array()
+                .expect("expected a plan with sub-plans")
+                .iter()
+                .any(|plan| {
+                    plan["Node Type"] == "Result"
+                        && plan["Parent Relationship"] == "InitPlan"
+                        && plan["Subplan Name"] == "InitPlan 1 (returns $0)"
+                }),
+            "didn't find an InitPlan subplan among subplans."
+        );
+
+        // Plain constants are left alone, as is the call (which is then inlined)
+        let untouched_result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (COSTS OFF, FORMAT JSON)
+                    SELECT * FROM gfs_test_table
+                    WHERE arbitrary_function(v::text, 'value') = 'constvalue';
+            "#,
+        )
+        .expect("SQL query failed");
+
+        let top_level_plan = untouched_result.0[0]["Plan"].clone();
+        assert_eq!(top_level_plan["Node Type"], "Seq Scan");
+        assert_eq!(
+            top_level_plan["Filter"],
+            "(((v)::text || 'value'::text) = 'constvalue'::text)"
+        );
+        assert!(
+            top_level_plan.get("Plans").is_none(),
+            "did not expect to find a plan with multiple sub-plans"
+        );
+
+        // Constants cast to a domain are hoisted, so that the domain check runs once
+        Spi::run("CREATE DOMAIN nonempty_text AS text CHECK (VALUE <> '')");
+        let hoisted_domain_result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (COSTS OFF, FORMAT JSON)
+                    SELECT * FROM gfs_test_table
+                    WHERE arbitrary_function(v::text, 'value'::nonempty_text) = 'constvalue';
+            "#,
+        )
+        .expect("SQL query failed");
+
+        let top_level_plan = hoisted_domain_result.0[0]["Plan"].clone();
+        assert_eq!(
+            top_level_plan["Filter"],
+            "(arbitrary_function((v)::text, $0) = 'constvalue'::text)"
+        );
+
+        // A call of constant expressions only is evaluated once per query as a whole
+        let whole_call_result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (COSTS OFF, FORMAT JSON)
+                    SELECT * FROM gfs_test_table
+                    WHERE arbitrary_function(current_setting('search_path'), 'value') = 'constvalue';
+            "#,
+        )
+        .expect("SQL query failed");
+
+        let top_level_plan = whole_call_result.0[0]["Plan"].clone();
+        assert_eq!(top_level_plan["Node Type"], "Result");
+        assert_eq!(
+            top_level_plan["One-Time Filter"],
+            "($0 = 'constvalue'::text)"
+        );
+    }
+
     #[pg_test]
     fn test_supported_tag_map_function_output_as_expected() {
         setup();
@@ -509,4 +784,137 @@ mod tests {
             "(tm @> ANY (tag_v_ne_matching_tags('a'::text, ((v)::text)::jsonb)))"
         );
     }
+
+    #[pg_test]
+    fn test_tag_map_key_exists_output_as_expected() {
+        setup();
+
+        let result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (ANALYZE, COSTS OFF, FORMAT JSON)
+                    SELECT * FROM gfs_test_table
+                    WHERE tag_map_denormalize(tm) ? 'a';
+            "#,
+        )
+        .expect("SQL query failed");
+
+        let top_level_plan = result.0[0]["Plan"].clone();
+        assert_eq!(top_level_plan["Node Type"], "Seq Scan");
+        assert_eq!(top_level_plan["Filter"], "(tm @> ANY ($0))");
+        assert!(
+            top_level_plan["Plans"]
+                .as_array()
+                .expect("expected a plan with multiple sub-plans")
+                .iter()
+                .any(|plan| plan["Parent Relationship"] == "InitPlan"),
+            "didn't find an InitPlan subplan among subplans."
+        );
+    }
+
+    #[pg_test]
+    fn test_tag_map_text_eq_output_as_expected() {
+        setup();
+
+        let init_plan_result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (ANALYZE, COSTS OFF, FORMAT JSON)
+                    SELECT * FROM gfs_test_table
+                    WHERE tag_map_denormalize(tm) ->> 'a' = '0';
+            "#,
+        )
+        .expect("SQL query failed");
+
+        assert_eq!(init_plan_result.0[0]["Plan"]["Filter"], "(tm @> ANY ($0))");
+
+        let no_init_plan_result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (ANALYZE, COSTS OFF, FORMAT JSON)
+                    SELECT * FROM gfs_test_table
+                    WHERE tag_map_denormalize(tm) ->> 'a' = v::text;
+            "#,
+        )
+        .expect("SQL query failed");
+
+        assert_eq!(
+            no_init_plan_result.0[0]["Plan"]["Filter"],
+            "(tm @> ANY (tag_v_text_eq_matching_tags('a'::text, (v)::text)))"
+        );
+    }
+
+    #[pg_test]
+    fn test_tag_map_eq_any_output_as_expected() {
+        setup();
+
+        let eq_any_result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (ANALYZE, COSTS OFF, FORMAT JSON)
+                    SELECT * FROM gfs_test_table
+                    WHERE tag_v_eq_any(tag_map_denormalize(tm) -> 'a', array['0', '12']::jsonb[]);
+            "#,
+        )
+        .expect("SQL query failed");
+
+        assert_eq!(eq_any_result.0[0]["Plan"]["Filter"], "(tm @> ANY ($0))");
+
+        let no_init_plan_result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (ANALYZE, COSTS OFF, FORMAT JSON)
+                    SELECT * FROM gfs_test_table
+                    WHERE tag_v_eq_any(tag_map_denormalize(tm) -> 'a', array[v::text::jsonb]);
+            "#,
+        )
+        .expect("SQL query failed");
+
+        assert_eq!(
+            no_init_plan_result.0[0]["Plan"]["Filter"],
+            "(tm @> ANY (tag_v_eq_any_matching_tags('a'::text, ARRAY[((v)::text)::jsonb])))"
+        );
+    }
+
+    #[pg_test]
+    fn test_tag_map_in_list_output_as_expected() {
+        setup();
+
+        let in_list_result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (ANALYZE, COSTS OFF, FORMAT JSON)
+                    SELECT * FROM gfs_test_table
+                    WHERE tag_map_denormalize(tm) -> 'a' IN ('0', '12');
+            "#,
+        )
+        .expect("SQL query failed");
+
+        assert_eq!(in_list_result.0[0]["Plan"]["Filter"], "(tm @> ANY ($0))");
+
+        let eq_any_result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (ANALYZE, COSTS OFF, FORMAT JSON)
+                    SELECT * FROM gfs_test_table
+                    WHERE tag_map_denormalize(tm) -> 'a' = ANY(array['0', '12']::jsonb[]);
+            "#,
+        )
+        .expect("SQL query failed");
+
+        assert_eq!(eq_any_result.0[0]["Plan"]["Filter"], "(tm @> ANY ($0))");
+
+        // ALL(...) is left as is
+        let eq_all_result = Spi::get_one::<Json>(
+            r#"
+                EXPLAIN (ANALYZE, COSTS OFF, FORMAT JSON)
+                    SELECT * FROM gfs_test_table
+                    WHERE tag_map_denormalize(tm) -> 'a' = ALL(array['0', '12']::jsonb[]);
+            "#,
+        )
+        .expect("SQL query failed");
+
+        let eq_all_filter = eq_all_result.0[0]["Plan"]["Filter"]
+            .as_str()
+            .expect("expected a filter")
+            .to_string();
+        assert!(
+            !eq_all_filter.contains("@>"),
+            "unexpected rewrite: {}",
+            eq_all_filter
+        );
+    }
 }
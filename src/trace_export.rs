@@ -0,0 +1,581 @@
+//! # Trace export
+//!
+//! Serializes a stored trace as [OTLP JSON][otlp] (an `ExportTraceServiceRequest`
+//! as accepted by `/v1/traces`) or in the JSON model of the Jaeger query API
+//! (`{"data": [trace]}`, as returned by `/api/traces/{id}` and loaded by the
+//! Jaeger UI):
+//!
+//! ```sql
+//! SELECT ps_trace.trace_to_otlp_json('05a8be0f-bb79-c052-223e-48608580efce');
+//! ```
+//!
+//! Spans are read from `_ps_trace.span` with their operation, instrumentation
+//! library and schema URLs, their events and links, with all tag maps decoded
+//! through `_ps_trace.tag_map_denormalize`.
+//!
+//! For OTLP, spans are grouped by resource (the resource tags, their dropped
+//! count and schema URL) and then by scope (instrumentation library), in the
+//! order of their start time. Jaeger has no place for some OTLP fields, and
+//! they are carried in tags the way the OpenTelemetry collector does it:
+//! `span.kind`, `otel.status_code`, `otel.status_description`, `error`,
+//! `otel.scope.name`, `otel.scope.version` and `w3c.tracestate`. Links become
+//! `FOLLOWS_FROM` references (or `CHILD_OF`, if they were one originally),
+//! losing their tags, and every distinct resource becomes a process.
+//!
+//! [otlp]: https://opentelemetry.io/docs/specs/otlp/#json-protobuf-encoding
+use pgx::*;
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::otlp::{hex_encode, SPAN_KINDS, STATUS_CODES};
+
+#[pg_schema]
+mod _prom_ext {
+    use pgx::*;
+
+    use super::{fetch_spans, to_jaeger_json, to_otlp_json};
+
+    #[pg_extern(stable, strict, parallel_safe, create_or_replace)]
+    pub fn trace_to_otlp_json(trace_id: Uuid) -> Option<JsonB> {
+        let spans = fetch_spans(&trace_id)?;
+        Some(JsonB(to_otlp_json(&trace_id[..], spans)))
+    }
+
+    #[pg_extern(stable, strict, parallel_safe, create_or_replace)]
+    pub fn trace_to_jaeger_json(trace_id: Uuid) -> Option<JsonB> {
+        let spans = fetch_spans(&trace_id)?;
+        Some(JsonB(to_jaeger_json(&trace_id[..], spans)))
+    }
+}
+
+/// A span as read from `_ps_trace`. Times are microseconds since the Unix epoch.
+#[derive(Debug, Deserialize)]
+struct StoredSpan {
+    span_id: i64,
+    parent_span_id: Option<i64>,
+    trace_state: Option<String>,
+    name: String,
+    span_kind: String,
+    start_time: i64,
+    end_time: i64,
+    status_code: String,
+    status_message: Option<String>,
+    span_tags: Map<String, Value>,
+    dropped_tags_count: u32,
+    dropped_events_count: u32,
+    dropped_link_count: u32,
+    resource_tags: Map<String, Value>,
+    resource_dropped_tags_count: u32,
+    resource_schema_url: Option<String>,
+    instrumentation_lib_name: Option<String>,
+    instrumentation_lib_version: Option<String>,
+    instrumentation_lib_schema_url: Option<String>,
+    events: Vec<StoredEvent>,
+    links: Vec<StoredLink>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredEvent {
+    time: i64,
+    name: String,
+    tags: Map<String, Value>,
+    dropped_tags_count: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredLink {
+    linked_trace_id: String,
+    linked_span_id: i64,
+    trace_state: Option<String>,
+    tags: Map<String, Value>,
+    dropped_tags_count: u32,
+}
+
+/// The spans of the trace, `None` if there are none.
+fn fetch_spans(trace_id: &Uuid) -> Option<Vec<StoredSpan>> {
+    let spans = Spi::get_one_with_args::<JsonB>(
+        "SELECT jsonb_agg(jsonb_build_object(
+            'span_id', s.span_id,
+            'parent_span_id', s.parent_span_id,
+            'trace_state', s.trace_state,
+            'name', o.span_name,
+            'span_kind', o.span_kind,
+            'start_time', (extract(epoch FROM s.start_time) * 1000000)::bigint,
+            'end_time', (extract(epoch FROM s.end_time) * 1000000)::bigint,
+            'status_code', s.status_code,
+            'status_message', s.status_message,
+            'span_tags', coalesce(_ps_trace.tag_map_denormalize(s.span_tags)::jsonb, '{}'),
+            'dropped_tags_count', s.dropped_tags_count,
+            'dropped_events_count', s.dropped_events_count,
+            'dropped_link_count', s.dropped_link_count,
+            'resource_tags', coalesce(_ps_trace.tag_map_denormalize(s.resource_tags)::jsonb, '{}'),
+            'resource_dropped_tags_count', s.resource_dropped_tags_count,
+            'resource_schema_url', ru.url,
+            'instrumentation_lib_name', il.name,
+            'instrumentation_lib_version', il.version,
+            'instrumentation_lib_schema_url', iu.url,
+            'events', coalesce((
+                SELECT jsonb_agg(jsonb_build_object(
+                    'time', (extract(epoch FROM e.time) * 1000000)::bigint,
+                    'name', e.name,
+                    'tags', coalesce(_ps_trace.tag_map_denormalize(e.tags)::jsonb, '{}'),
+                    'dropped_tags_count', e.dropped_tags_count
+                ) ORDER BY e.event_nbr, e.time)
+                FROM _ps_trace.event e
+                WHERE e.trace_id OPERATOR(ps_trace.=) s.trace_id
+                AND e.span_id = s.span_id
+            ), '[]'),
+            'links', coalesce((
+                SELECT jsonb_agg(jsonb_build_object(
+                    'linked_trace_id', l.linked_trace_id::uuid,
+                    'linked_span_id', l.linked_span_id,
+                    'trace_state', l.trace_state,
+                    'tags', coalesce(_ps_trace.tag_map_denormalize(l.tags)::jsonb, '{}'),
+                    'dropped_tags_count', l.dropped_tags_count
+                ) ORDER BY l.link_nbr)
+                FROM _ps_trace.link l
+                WHERE l.trace_id OPERATOR(ps_trace.=) s.trace_id
+                AND l.span_id = s.span_id
+                AND l.span_start_time = s.start_time
+            ), '[]')
+        ) ORDER BY s.start_time, s.span_id)
+        FROM _ps_trace.span s
+        INNER JOIN _ps_trace.operation o ON (o.id = s.operation_id)
+        LEFT JOIN _ps_trace.instrumentation_lib il ON (il.id = s.instrumentation_lib_id)
+        LEFT JOIN _ps_trace.schema_url iu ON (iu.id = il.schema_url_id)
+        LEFT JOIN _ps_trace.schema_url ru ON (ru.id = s.resource_schema_url_id)
+        WHERE s.trace_id OPERATOR(ps_trace.=) $1::ps_trace.trace_id",
+        vec![(PgBuiltInOids::UUIDOID.oid(), trace_id.into_datum())],
+    )?;
+    let spans: Vec<StoredSpan> =
+        serde_json::from_value(spans.0).unwrap_or_else(|e| error!("unexpected span row: {}", e));
+    Some(spans)
+}
+
+fn to_otlp_json(trace_id: &[u8], spans: Vec<StoredSpan>) -> Value {
+    // resource and scope groups, in the order they are first seen
+    let mut resources: Vec<(Value, Vec<(Value, Vec<Value>)>)> = vec![];
+    for span in spans {
+        let mut resource = json!({ "attributes": otlp_attributes(&span.resource_tags) });
+        if span.resource_dropped_tags_count > 0 {
+            resource["droppedAttributesCount"] = json!(span.resource_dropped_tags_count);
+        }
+        let mut resource_spans = json!({ "resource": resource });
+        if let Some(url) = &span.resource_schema_url {
+            resource_spans["schemaUrl"] = json!(url);
+        }
+        let mut scope_spans = json!({});
+        if let Some(name) = &span.instrumentation_lib_name {
+            scope_spans["scope"] = json!({ "name": name });
+            if let Some(version) = span
+                .instrumentation_lib_version
+                .as_ref()
+                .filter(|v| !v.is_empty())
+            {
+                scope_spans["scope"]["version"] = json!(version);
+            }
+        }
+        if let Some(url) = &span.instrumentation_lib_schema_url {
+            scope_spans["schemaUrl"] = json!(url);
+        }
+
+        let scopes = match resources.iter().position(|(r, _)| *r == resource_spans) {
+            Some(i) => &mut resources[i].1,
+            None => {
+                resources.push((resource_spans, vec![]));
+                &mut resources.last_mut().unwrap().1
+            }
+        };
+        let otlp_span = otlp_span(trace_id, span);
+        match scopes.iter_mut().find(|(s, _)| *s == scope_spans) {
+            Some((_, spans)) => spans.push(otlp_span),
+            None => scopes.push((scope_spans, vec![otlp_span])),
+        }
+    }
+
+    let resource_spans: Vec<Value> = resources
+        .into_iter()
+        .map(|(mut resource_spans, scopes)| {
+            resource_spans["scopeSpans"] = scopes
+                .into_iter()
+                .map(|(mut scope_spans, spans)| {
+                    scope_spans["spans"] = Value::Array(spans);
+                    scope_spans
+                })
+                .collect();
+            resource_spans
+        })
+        .collect();
+    json!({ "resourceSpans": resource_spans })
+}
+
+/// Follows the protobuf JSON mapping, as OTLP does: 64-bit integers are
+/// strings, enums are numbers and fields with default values are omitted.
+fn otlp_span(trace_id: &[u8], span: StoredSpan) -> Value {
+    let mut s = Map::new();
+    s.insert("traceId".to_string(), json!(hex_encode(trace_id)));
+    s.insert("spanId".to_string(), json!(span_id_hex(span.span_id)));
+    if let Some(state) = span.trace_state {
+        s.insert("traceState".to_string(), json!(state));
+    }
+    if let Some(parent) = span.parent_span_id {
+        s.insert("parentSpanId".to_string(), json!(span_id_hex(parent)));
+    }
+    s.insert("name".to_string(), json!(span.name));
+    s.insert(
+        "kind".to_string(),
+        json!(enum_value(SPAN_KINDS, &span.span_kind)),
+    );
+    s.insert(
+        "startTimeUnixNano".to_string(),
+        json!(unix_nanos(span.start_time)),
+    );
+    s.insert(
+        "endTimeUnixNano".to_string(),
+        json!(unix_nanos(span.end_time)),
+    );
+    s.insert("attributes".to_string(), otlp_attributes(&span.span_tags));
+    insert_count(&mut s, "droppedAttributesCount", span.dropped_tags_count);
+    let events: Vec<Value> = span
+        .events
+        .into_iter()
+        .map(|event| {
+            let mut e = Map::new();
+            e.insert("timeUnixNano".to_string(), json!(unix_nanos(event.time)));
+            e.insert("name".to_string(), json!(event.name));
+            e.insert("attributes".to_string(), otlp_attributes(&event.tags));
+            insert_count(&mut e, "droppedAttributesCount", event.dropped_tags_count);
+            Value::Object(e)
+        })
+        .collect();
+    s.insert("events".to_string(), Value::Array(events));
+    insert_count(&mut s, "droppedEventsCount", span.dropped_events_count);
+    let links: Vec<Value> = span
+        .links
+        .into_iter()
+        .map(|link| {
+            let mut l = Map::new();
+            l.insert(
+                "traceId".to_string(),
+                json!(link.linked_trace_id.replace('-', "")),
+            );
+            l.insert(
+                "spanId".to_string(),
+                json!(span_id_hex(link.linked_span_id)),
+            );
+            if let Some(state) = link.trace_state {
+                l.insert("traceState".to_string(), json!(state));
+            }
+            l.insert("attributes".to_string(), otlp_attributes(&link.tags));
+            insert_count(&mut l, "droppedAttributesCount", link.dropped_tags_count);
+            Value::Object(l)
+        })
+        .collect();
+    s.insert("links".to_string(), Value::Array(links));
+    insert_count(&mut s, "droppedLinksCount", span.dropped_link_count);
+    let mut status = Map::new();
+    if let Some(message) = span.status_message {
+        status.insert("message".to_string(), json!(message));
+    }
+    let code = enum_value(STATUS_CODES, &span.status_code);
+    if code != 0 {
+        status.insert("code".to_string(), json!(code));
+    }
+    s.insert("status".to_string(), Value::Object(status));
+    Value::Object(s)
+}
+
+fn otlp_attributes(tags: &Map<String, Value>) -> Value {
+    tags.iter()
+        .map(|(key, value)| json!({ "key": key, "value": otlp_any_value(value) }))
+        .collect()
+}
+
+fn otlp_any_value(value: &Value) -> Value {
+    match value {
+        Value::Null => json!({}),
+        Value::String(s) => json!({ "stringValue": s }),
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => json!({ "intValue": i.to_string() }),
+            None => json!({ "doubleValue": n }),
+        },
+        Value::Array(values) => {
+            let values: Vec<Value> = values.iter().map(otlp_any_value).collect();
+            json!({ "arrayValue": { "values": values } })
+        }
+        Value::Object(kvs) => json!({ "kvlistValue": { "values": otlp_attributes(kvs) } }),
+    }
+}
+
+fn insert_count(object: &mut Map<String, Value>, field: &str, count: u32) {
+    if count > 0 {
+        object.insert(field.to_string(), json!(count));
+    }
+}
+
+fn to_jaeger_json(trace_id: &[u8], spans: Vec<StoredSpan>) -> Value {
+    let trace_id = hex_encode(trace_id);
+    let mut processes: Vec<Value> = vec![];
+    let spans: Vec<Value> = spans
+        .into_iter()
+        .map(|mut span| {
+            let mut resource_tags = std::mem::take(&mut span.resource_tags);
+            let service_name = match resource_tags.remove("service.name") {
+                Some(Value::String(name)) => name,
+                Some(other) => other.to_string(),
+                None => String::new(),
+            };
+            let process = json!({
+                "serviceName": service_name,
+                "tags": jaeger_tags(&resource_tags),
+            });
+            let process_id = match processes.iter().position(|p| *p == process) {
+                Some(i) => i,
+                None => {
+                    processes.push(process);
+                    processes.len() - 1
+                }
+            };
+            jaeger_span(&trace_id, span, format!("p{}", process_id + 1))
+        })
+        .collect();
+    let processes: Map<String, Value> = processes
+        .into_iter()
+        .enumerate()
+        .map(|(i, process)| (format!("p{}", i + 1), process))
+        .collect();
+    json!({
+        "data": [{
+            "traceID": trace_id,
+            "spans": spans,
+            "processes": processes,
+            "warnings": null,
+        }]
+    })
+}
+
+fn jaeger_span(trace_id: &str, span: StoredSpan, process_id: String) -> Value {
+    let mut tags = span.span_tags;
+    if span.span_kind != "unspecified" {
+        tags.insert("span.kind".to_string(), json!(span.span_kind));
+    }
+    match span.status_code.as_str() {
+        "ok" => {
+            tags.insert("otel.status_code".to_string(), json!("OK"));
+        }
+        "error" => {
+            tags.insert("otel.status_code".to_string(), json!("ERROR"));
+            tags.insert("error".to_string(), json!(true));
+        }
+        _ => {}
+    }
+    if let Some(message) = span.status_message {
+        tags.insert("otel.status_description".to_string(), json!(message));
+    }
+    if let Some(name) = span.instrumentation_lib_name {
+        tags.insert("otel.scope.name".to_string(), json!(name));
+    }
+    if let Some(version) = span.instrumentation_lib_version.filter(|v| !v.is_empty()) {
+        tags.insert("otel.scope.version".to_string(), json!(version));
+    }
+    if let Some(state) = span.trace_state {
+        tags.insert("w3c.tracestate".to_string(), json!(state));
+    }
+
+    let mut references = vec![];
+    if let Some(parent) = span.parent_span_id {
+        references.push(json!({
+            "refType": "CHILD_OF",
+            "traceID": trace_id,
+            "spanID": span_id_hex(parent),
+        }));
+    }
+    for link in span.links {
+        let ref_type = match link.tags.get("opentracing.ref_type") {
+            Some(Value::String(t)) if t == "child_of" => "CHILD_OF",
+            _ => "FOLLOWS_FROM",
+        };
+        references.push(json!({
+            "refType": ref_type,
+            "traceID": link.linked_trace_id.replace('-', ""),
+            "spanID": span_id_hex(link.linked_span_id),
+        }));
+    }
+    let logs: Vec<Value> = span
+        .events
+        .into_iter()
+        .map(|event| {
+            let mut fields = event.tags;
+            if !event.name.is_empty() {
+                fields.insert("event".to_string(), json!(event.name));
+            }
+            json!({ "timestamp": event.time, "fields": jaeger_tags(&fields) })
+        })
+        .collect();
+
+    json!({
+        "traceID": trace_id,
+        "spanID": span_id_hex(span.span_id),
+        "operationName": span.name,
+        "references": references,
+        "startTime": span.start_time,
+        "duration": span.end_time - span.start_time,
+        "tags": jaeger_tags(&tags),
+        "logs": logs,
+        "processID": process_id,
+        "warnings": null,
+    })
+}
+
+fn jaeger_tags(tags: &Map<String, Value>) -> Value {
+    tags.iter()
+        .map(|(key, value)| {
+            let (value_type, value) = match value {
+                Value::String(_) => ("string", value.clone()),
+                Value::Bool(_) => ("bool", value.clone()),
+                Value::Number(n) if n.is_i64() || n.is_u64() => ("int64", value.clone()),
+                Value::Number(_) => ("float64", value.clone()),
+                other => ("string", Value::String(other.to_string())),
+            };
+            json!({ "key": key, "type": value_type, "value": value })
+        })
+        .collect()
+}
+
+fn span_id_hex(span_id: i64) -> String {
+    hex_encode(&span_id.to_be_bytes())
+}
+
+fn unix_nanos(unix_us: i64) -> String {
+    (unix_us * 1000).to_string()
+}
+
+fn enum_value(names: &[&str], name: &str) -> usize {
+    names.iter().position(|&n| n == name).unwrap_or(0)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+
+    // The payload of the `otlp_traces` tests: the server span 1 "GET /users"
+    // with an event, and its child, the client span 2 "SELECT users", with a
+    // link, in trace 01020304-0506-0708-090a-0b0c0d0e0f10.
+    const PAYLOAD: &str = "0ac1030a2e0a150a0c736572766963652e6e616d6512050a036170690a150a09686f73742e6e616d6512080a06686f73742d3112e6020a1e0a15696f2e6f70656e74656c656d657472792e687474701205312e322e301298010a100102030405060708090a0b0c0d0e0f10120800000000000000012a0a474554202f75736572733002390000c2d3430636174180b2a8e2430636174a140a0b687474702e6d6574686f6412050a034745544a170a10687474702e7374617475735f636f6465120318c8015a270980965ad443063617120a6361636865206d6973731a100a05636163686512070a0575736572737a02180112a8010a100102030405060708090a0b0c0d0e0f10120800000000000000021a036b3d76220800000000000000012a0c53454c454354207573657273300339002df3d44306361741000ee9da430636174a190a0964622e73797374656d120c0a0a706f737467726573716c50026a2f0a10ffffffffffffffffffffffffffffffff1208000000000000000922110a06726561736f6e12070a0572657472797a0b120774696d656f757418021a2668747470733a2f2f6f70656e74656c656d657472792e696f2f736368656d61732f312e392e30";
+
+    const TRACE_ID: &str = "01020304-0506-0708-090a-0b0c0d0e0f10";
+
+    fn query(sql: &str) -> serde_json::Value {
+        Spi::get_one::<Json>(sql).expect("SQL query failed").0
+    }
+
+    fn insert_payload() {
+        Spi::run(&format!(
+            "CALL ps_trace.insert_otlp_traces('\\x{}'::bytea)",
+            PAYLOAD
+        ));
+    }
+
+    #[pg_test]
+    fn test_trace_to_otlp_json() {
+        insert_payload();
+        let request = query(&format!(
+            "SELECT ps_trace.trace_to_otlp_json('{}')::json",
+            TRACE_ID
+        ));
+        let resource_spans = request["resourceSpans"].as_array().unwrap();
+        assert_eq!(resource_spans.len(), 1);
+        assert_eq!(
+            resource_spans[0]["resource"],
+            serde_json::json!({"attributes": [
+                {"key": "host.name", "value": {"stringValue": "host-1"}},
+                {"key": "service.name", "value": {"stringValue": "api"}}
+            ]})
+        );
+        assert_eq!(
+            resource_spans[0]["schemaUrl"],
+            "https://opentelemetry.io/schemas/1.9.0"
+        );
+        let scope_spans = resource_spans[0]["scopeSpans"].as_array().unwrap();
+        assert_eq!(scope_spans.len(), 1);
+        assert_eq!(
+            scope_spans[0]["scope"],
+            serde_json::json!({"name": "io.opentelemetry.http", "version": "1.2.0"})
+        );
+        assert_eq!(
+            scope_spans[0]["spans"],
+            serde_json::json!([
+                {
+                    "traceId": "0102030405060708090a0b0c0d0e0f10",
+                    "spanId": "0000000000000001",
+                    "name": "GET /users",
+                    "kind": 2,
+                    "startTimeUnixNano": "1672531200000000000",
+                    "endTimeUnixNano": "1672531200250000000",
+                    "attributes": [
+                        {"key": "http.method", "value": {"stringValue": "GET"}},
+                        {"key": "http.status_code", "value": {"intValue": "200"}}
+                    ],
+                    "events": [{
+                        "timeUnixNano": "1672531200010000000",
+                        "name": "cache miss",
+                        "attributes": [{"key": "cache", "value": {"stringValue": "users"}}]
+                    }],
+                    "links": [],
+                    "status": {"code": 1}
+                },
+                {
+                    "traceId": "0102030405060708090a0b0c0d0e0f10",
+                    "spanId": "0000000000000002",
+                    "traceState": "k=v",
+                    "parentSpanId": "0000000000000001",
+                    "name": "SELECT users",
+                    "kind": 3,
+                    "startTimeUnixNano": "1672531200020000000",
+                    "endTimeUnixNano": "1672531200120000000",
+                    "attributes": [{"key": "db.system", "value": {"stringValue": "postgresql"}}],
+                    "droppedAttributesCount": 2,
+                    "events": [],
+                    "links": [{
+                        "traceId": "ffffffffffffffffffffffffffffffff",
+                        "spanId": "0000000000000009",
+                        "attributes": [{"key": "reason", "value": {"stringValue": "retry"}}]
+                    }],
+                    "status": {"code": 2, "message": "timeout"}
+                }
+            ])
+        );
+    }
+
+    #[pg_test]
+    fn test_trace_to_jaeger_json_round_trip() {
+        insert_payload();
+        let columns = "trace_id::text, span_id, parent_span_id, trace_state, name, span_kind,
+            start_time, end_time, status_code, status_message, span_tags, resource_tags,
+            instrumentation_lib_name, instrumentation_lib_version";
+        assert_eq!(
+            query(&format!(
+                "SELECT json_agg(json_build_array({}) ORDER BY span_id)
+                 FROM ps_trace.decode_jaeger_json(ps_trace.trace_to_jaeger_json('{}'))",
+                columns, TRACE_ID
+            )),
+            query(&format!(
+                "SELECT json_agg(json_build_array({}) ORDER BY span_id)
+                 FROM ps_trace.decode_otlp_spans('\\x{}'::bytea)",
+                columns, PAYLOAD
+            ))
+        );
+    }
+
+    #[pg_test]
+    fn test_trace_to_otlp_json_unknown_trace() {
+        insert_payload();
+        assert!(Spi::get_one::<JsonB>(
+            "SELECT ps_trace.trace_to_otlp_json('ffffffff-ffff-ffff-ffff-ffffffffffff')"
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,388 @@
+//! Reads blocks in the on-disk format of the Prometheus TSDB, see
+//! [`tsdb/docs/format`](https://github.com/prometheus/prometheus/tree/main/tsdb/docs/format).
+//!
+//! Only version 2 indexes are supported, as written by Prometheus 2.x.
+use std::convert::TryInto;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::chunkenc::decode_xor_chunk;
+use crate::remote_read::crc32c;
+
+pub(crate) const INDEX_MAGIC: u32 = 0xBAAA_D700;
+pub(crate) const INDEX_VERSION: u8 = 2;
+pub(crate) const SEGMENT_MAGIC: u32 = 0x85BD_40DD;
+pub(crate) const SEGMENT_VERSION: u8 = 1;
+pub(crate) const SEGMENT_HEADER_SIZE: u64 = 8;
+pub(crate) const CHUNK_ENCODING_XOR: u8 = 1;
+/// Series are 16-byte aligned, and referenced by their offset divided by 16.
+pub(crate) const SERIES_ALIGNMENT: u64 = 16;
+/// Six section offsets and a checksum.
+pub(crate) const TOC_SIZE: u64 = 6 * 8 + 4;
+
+/// The contents of `meta.json`. Times are in milliseconds, `max_time` is
+/// exclusive.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BlockMeta {
+    pub(crate) ulid: String,
+    pub(crate) min_time: i64,
+    pub(crate) max_time: i64,
+    #[serde(default)]
+    pub(crate) stats: BlockStats,
+    #[serde(default)]
+    pub(crate) compaction: BlockCompaction,
+    pub(crate) version: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BlockStats {
+    #[serde(default)]
+    pub(crate) num_samples: u64,
+    #[serde(default)]
+    pub(crate) num_series: u64,
+    #[serde(default)]
+    pub(crate) num_chunks: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct BlockCompaction {
+    #[serde(default)]
+    pub(crate) level: u32,
+    #[serde(default)]
+    pub(crate) sources: Vec<String>,
+}
+
+impl BlockMeta {
+    pub(crate) fn read(dir: &Path) -> Result<Self, String> {
+        let path = dir.join("meta.json");
+        let bytes = std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let meta: Self =
+            serde_json::from_slice(&bytes).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if meta.version != 1 {
+            return Err(format!(
+                "{}: unsupported version {}",
+                path.display(),
+                meta.version
+            ));
+        }
+        Ok(meta)
+    }
+}
+
+/// A series of the index, with labels sorted by name.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Series {
+    pub(crate) labels: Vec<(String, String)>,
+    pub(crate) chunks: Vec<ChunkMeta>,
+}
+
+/// Where a chunk is stored: the segment file in the upper 32 bits of `chunk_ref`,
+/// the offset into it in the lower.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ChunkMeta {
+    pub(crate) min_time: i64,
+    pub(crate) max_time: i64,
+    pub(crate) chunk_ref: u64,
+}
+
+/// An open block. Symbols are read upfront, series and chunks on demand.
+pub(crate) struct Block {
+    pub(crate) dir: PathBuf,
+    pub(crate) meta: BlockMeta,
+    index: File,
+    symbols: Vec<String>,
+    /// The offsets of the series section into the index, whose series are
+    /// stored one after another in label order.
+    series_start: u64,
+    series_end: u64,
+    segments: Vec<File>,
+}
+
+impl Block {
+    pub(crate) fn open(dir: &Path, meta: BlockMeta) -> Result<Self, String> {
+        let index_path = dir.join("index");
+        let index =
+            File::open(&index_path).map_err(|e| format!("{}: {}", index_path.display(), e))?;
+        let (symbols, series_start, series_end) =
+            read_index(&index).map_err(|e| format!("{}: {}", index_path.display(), e))?;
+
+        let chunks_dir = dir.join("chunks");
+        let mut segment_paths = vec![];
+        for entry in std::fs::read_dir(&chunks_dir)
+            .map_err(|e| format!("{}: {}", chunks_dir.display(), e))?
+        {
+            let path = entry
+                .map_err(|e| format!("{}: {}", chunks_dir.display(), e))?
+                .path();
+            // segments are numbered from 000001
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit()) {
+                segment_paths.push(path);
+            }
+        }
+        segment_paths.sort();
+        let segments = segment_paths
+            .iter()
+            .map(|path| {
+                let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                let mut header = [0; SEGMENT_HEADER_SIZE as usize];
+                file.read_exact_at(&mut header, 0)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                if u32::from_be_bytes(header[..4].try_into().unwrap()) != SEGMENT_MAGIC {
+                    return Err(format!("{}: invalid magic number", path.display()));
+                }
+                if header[4] != SEGMENT_VERSION {
+                    return Err(format!(
+                        "{}: unsupported version {}",
+                        path.display(),
+                        header[4]
+                    ));
+                }
+                Ok(file)
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            meta,
+            index,
+            symbols,
+            series_start,
+            series_end,
+            segments,
+        })
+    }
+
+    /// The series at `offset` into the index, or the first one at or after
+    /// it in label order, with the offset of the next series. `None` past
+    /// the last series.
+    pub(crate) fn series_at(&self, offset: u64) -> Result<Option<(Series, u64)>, String> {
+        let offset = align(offset.max(self.series_start), SERIES_ALIGNMENT);
+        if offset >= self.series_end {
+            return Ok(None);
+        }
+        let (len, len_size) = read_uvarint_at(&self.index, offset)
+            .map_err(|e| format!("{}: series at {}: {}", self.index_path(), offset, e))?;
+        let content = read_checksummed(&self.index, offset + len_size, len)
+            .map_err(|e| format!("{}: series at {}: {}", self.index_path(), offset, e))?;
+        let series = self
+            .decode_series(&content)
+            .map_err(|e| format!("{}: series at {}: {}", self.index_path(), offset, e))?;
+        Ok(Some((series, offset + len_size + len + 4)))
+    }
+
+    fn decode_series(&self, content: &[u8]) -> Result<Series, String> {
+        let mut buf = Decbuf::new(content);
+        let symbol = |buf: &mut Decbuf| -> Result<String, String> {
+            let i = buf.uvarint()? as usize;
+            self.symbols
+                .get(i)
+                .cloned()
+                .ok_or_else(|| format!("unknown symbol {}", i))
+        };
+        let num_labels = buf.uvarint()?;
+        let mut labels = Vec::with_capacity(num_labels as usize);
+        for _ in 0..num_labels {
+            let name = symbol(&mut buf)?;
+            let value = symbol(&mut buf)?;
+            labels.push((name, value));
+        }
+        let num_chunks = buf.uvarint()?;
+        let mut chunks: Vec<ChunkMeta> = Vec::with_capacity(num_chunks as usize);
+        for _ in 0..num_chunks {
+            // the first chunk is stored as is, the others relative to the previous one
+            let chunk = match chunks.last() {
+                None => {
+                    let min_time = buf.varint()?;
+                    ChunkMeta {
+                        min_time,
+                        max_time: min_time.wrapping_add(buf.uvarint()? as i64),
+                        chunk_ref: buf.uvarint()?,
+                    }
+                }
+                Some(prev) => {
+                    let min_time = prev.max_time.wrapping_add(buf.uvarint()? as i64);
+                    ChunkMeta {
+                        min_time,
+                        max_time: min_time.wrapping_add(buf.uvarint()? as i64),
+                        chunk_ref: (prev.chunk_ref as i64).wrapping_add(buf.varint()?) as u64,
+                    }
+                }
+            };
+            chunks.push(chunk);
+        }
+        Ok(Series { labels, chunks })
+    }
+
+    /// The float samples of a series. Chunks of other encodings, i.e. native
+    /// histograms, are skipped.
+    pub(crate) fn samples(&self, series: &Series) -> Result<Vec<(i64, f64)>, String> {
+        let mut samples = vec![];
+        for chunk in &series.chunks {
+            let (encoding, data) = self.chunk(chunk.chunk_ref)?;
+            if encoding == CHUNK_ENCODING_XOR {
+                samples.extend(decode_xor_chunk(&data).map_err(|e| {
+                    format!("{}: chunk {}: {}", self.dir.display(), chunk.chunk_ref, e)
+                })?);
+            }
+        }
+        Ok(samples)
+    }
+
+    fn chunk(&self, chunk_ref: u64) -> Result<(u8, Vec<u8>), String> {
+        let (segment, offset) = ((chunk_ref >> 32) as usize, chunk_ref & 0xffff_ffff);
+        let error = |e: String| format!("{}: chunk {}: {}", self.dir.display(), chunk_ref, e);
+        let file = self
+            .segments
+            .get(segment)
+            .ok_or_else(|| error(format!("missing segment {}", segment + 1)))?;
+        // a length, the encoding, the data and a checksum of encoding and data
+        let (len, len_size) = read_uvarint_at(file, offset).map_err(error)?;
+        let mut bytes =
+            read_checksummed(file, offset + len_size, len.saturating_add(1)).map_err(error)?;
+        let data = bytes.split_off(1);
+        Ok((bytes[0], data))
+    }
+
+    fn index_path(&self) -> String {
+        self.dir.join("index").display().to_string()
+    }
+}
+
+/// Reads the symbol table and the bounds of the series section from the index.
+fn read_index(index: &File) -> Result<(Vec<String>, u64, u64), String> {
+    let mut header = [0; 5];
+    index
+        .read_exact_at(&mut header, 0)
+        .map_err(|e| e.to_string())?;
+    if u32::from_be_bytes(header[..4].try_into().unwrap()) != INDEX_MAGIC {
+        return Err("invalid magic number".to_string());
+    }
+    if header[4] != INDEX_VERSION {
+        return Err(format!("unsupported version {}", header[4]));
+    }
+
+    let len = index.metadata().map_err(|e| e.to_string())?.len();
+    let toc_offset = len
+        .checked_sub(TOC_SIZE)
+        .ok_or_else(|| "missing table of contents".to_string())?;
+    let mut toc = [0; TOC_SIZE as usize];
+    index
+        .read_exact_at(&mut toc, toc_offset)
+        .map_err(|e| e.to_string())?;
+    let (toc, checksum) = toc.split_at(TOC_SIZE as usize - 4);
+    if crc32c(toc).to_be_bytes()[..] != checksum[..] {
+        return Err("table of contents: checksum mismatch".to_string());
+    }
+    let toc_entry = |i: usize| u64::from_be_bytes(toc[i * 8..i * 8 + 8].try_into().unwrap());
+    let (symbols_offset, series_start) = (toc_entry(0), toc_entry(1));
+    // the series section ends where the next one present starts
+    let series_end = (2..6)
+        .map(toc_entry)
+        .filter(|&offset| offset > series_start)
+        .min()
+        .unwrap_or(toc_offset);
+
+    let content = read_section(index, symbols_offset).map_err(|e| format!("symbols: {}", e))?;
+    let mut buf = Decbuf::new(&content);
+    let num_symbols = buf.be_u32()?;
+    let symbols = (0..num_symbols)
+        .map(|_| buf.uvarint_str().map(String::from))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("symbols: {}", e))?;
+    Ok((symbols, series_start, series_end))
+}
+
+/// Rounds `offset` up to a multiple of `alignment`.
+fn align(offset: u64, alignment: u64) -> u64 {
+    offset.saturating_add(alignment - 1) / alignment * alignment
+}
+
+/// Reads a section with a big-endian 4-byte length prefix and a trailing
+/// checksum, returning its content.
+fn read_section(file: &File, offset: u64) -> Result<Vec<u8>, String> {
+    let mut len = [0; 4];
+    file.read_exact_at(&mut len, offset)
+        .map_err(|e| e.to_string())?;
+    read_checksummed(file, offset + 4, u32::from_be_bytes(len) as u64)
+}
+
+fn read_checksummed(file: &File, offset: u64, len: u64) -> Result<Vec<u8>, String> {
+    let file_len = file.metadata().map_err(|e| e.to_string())?.len();
+    if offset.saturating_add(len).saturating_add(4) > file_len {
+        return Err("unexpected end of file".to_string());
+    }
+    let mut content = vec![0; len as usize + 4];
+    file.read_exact_at(&mut content, offset)
+        .map_err(|e| e.to_string())?;
+    let checksum = content.split_off(len as usize);
+    if crc32c(&content).to_be_bytes()[..] != checksum[..] {
+        return Err("checksum mismatch".to_string());
+    }
+    Ok(content)
+}
+
+/// Reads a varint at `offset`, returning it with its size in bytes.
+fn read_uvarint_at(file: &File, offset: u64) -> Result<(u64, u64), String> {
+    let mut bytes = [0; 10];
+    let n = file
+        .read_at(&mut bytes, offset)
+        .map_err(|e| e.to_string())?;
+    let mut buf = Decbuf::new(&bytes[..n]);
+    let value = buf.uvarint()?;
+    Ok((value, buf.pos as u64))
+}
+
+/// Decodes the primitives of the index format.
+struct Decbuf<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decbuf<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.buf.len())
+            .ok_or_else(|| "unexpected end of data".to_string())?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn be_u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn uvarint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.bytes(1)?[0];
+            value |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("varint too long".to_string())
+    }
+
+    fn varint(&mut self) -> Result<i64, String> {
+        let v = self.uvarint()?;
+        Ok((v >> 1) as i64 ^ -((v & 1) as i64))
+    }
+
+    fn uvarint_str(&mut self) -> Result<&'a str, String> {
+        let len = self.uvarint()? as usize;
+        std::str::from_utf8(self.bytes(len)?).map_err(|e| e.to_string())
+    }
+}
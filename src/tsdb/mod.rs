@@ -0,0 +1,607 @@
+//! # Prometheus TSDB blocks
+//!
+//! Imports the blocks of a Prometheus TSDB, i.e. the directories under its
+//! `--storage.tsdb.path`, from the database server's filesystem:
+//!
+//! ```sql
+//! CALL prom_api.import_tsdb_block('/var/lib/prometheus/01GQ5Z8NB8YJ4KQ3V2B0M1XTSD');
+//! ```
+//!
+//! The series of the index are imported in batches through the same catalog
+//! functions the connector uses, with a commit and a progress notice after
+//! every batch. The progress, including the offset of the next series into
+//! the index, is recorded by block ULID in `_ps_catalog.tsdb_block_import`,
+//! so every batch, also of an interrupted import, continues right after the
+//! last committed one, and a completed block is not imported twice.
+//! The procedure therefore has to be called outside of a transaction block.
+//!
+//! Blocks are also written, e.g. to offload cold data to Thanos or to inspect
+//! it with `promtool tsdb`. `prom_api.export_tsdb_block` exports a time range
+//! of metrics into a new block in the given directory and returns its
+//! `meta.json`:
+//!
+//! ```sql
+//! SELECT prom_api.export_tsdb_block('/var/lib/export', ARRAY['up'], now() - interval '2 hours', now());
+//! ```
+//!
+//! It is built on the `prom_api.write_tsdb_block` aggregate, which writes the
+//! aggregated series into a block of their own:
+//!
+//! ```sql
+//! SELECT prom_api.write_tsdb_block('/var/lib/export', prom_api.jsonb(prom_api.labels(series_id)), times, vals)
+//! FROM (
+//!     SELECT series_id, array_agg(time ORDER BY time) times, array_agg(value ORDER BY time) vals
+//!     FROM prom_data.up
+//!     GROUP BY series_id
+//! ) d;
+//! ```
+//!
+//! Native histograms are not supported and are silently skipped on import.
+//! Reading blocks requires superuser or membership in `pg_read_server_files`,
+//! writing them `pg_write_server_files`; relative paths are resolved against
+//! the data directory.
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use pgx::*;
+
+use block::{Block, BlockMeta};
+
+mod block;
+mod writer;
+
+thread_local! {
+    /// The block read last, as imports open a block once per batch.
+    static OPEN_BLOCK: RefCell<Option<Rc<Block>>> = RefCell::default();
+}
+
+#[pg_schema]
+mod _prom_ext {
+    use std::path::Path;
+
+    use pgx::*;
+    use serde_json::Value;
+
+    use super::block::{Block, Series};
+    use super::writer::{new_ulid, BlockWriter};
+    use super::{open_block, BlockMeta};
+    use crate::aggregate_utils::in_aggregate_context;
+    use crate::palloc::{Inner, InternalAsValue, ToInternal};
+    use crate::prompb::{from_pg_timestamp, to_pg_timestamp};
+    use crate::util::check_privileges;
+
+    #[pg_extern(strict, create_or_replace)]
+    pub fn tsdb_block_meta(path: &str) -> JsonB {
+        check_privileges("pg_read_server_files", "read TSDB blocks");
+        let meta = BlockMeta::read(Path::new(path))
+            .unwrap_or_else(|e| error!("cannot read TSDB block: {}", e));
+        JsonB(serde_json::to_value(meta).expect("block metadata is serializable"))
+    }
+
+    /// At most `max_series` series of a block in index order, starting with
+    /// the series at `series_offset` into the index, or the first one after
+    /// it. Each series comes with the offset to continue from.
+    #[pg_extern(strict, create_or_replace)]
+    pub fn read_tsdb_block(
+        path: &str,
+        series_offset: i64,
+        max_series: i64,
+    ) -> TableIterator<
+        'static,
+        (
+            name!(series_offset, i64),
+            name!(next_series_offset, i64),
+            name!(metric_name, Option<String>),
+            name!(labels, JsonB),
+            name!(times, Vec<TimestampWithTimeZone>),
+            name!(values, Vec<f64>),
+        ),
+    > {
+        check_privileges("pg_read_server_files", "read TSDB blocks");
+        let block = open_block(path).unwrap_or_else(|e| error!("cannot read TSDB block: {}", e));
+        let mut rows = vec![];
+        let mut offset = series_offset.max(0) as u64;
+        while rows.len() < max_series.max(0) as usize {
+            let (series, next_offset) = match block
+                .series_at(offset)
+                .unwrap_or_else(|e| error!("cannot read TSDB block: {}", e))
+            {
+                Some(series) => series,
+                None => break,
+            };
+            rows.push(
+                to_row(&block, offset, next_offset, series)
+                    .unwrap_or_else(|e| error!("cannot read TSDB block: {}", e)),
+            );
+            offset = next_offset;
+        }
+        TableIterator::new(rows.into_iter())
+    }
+
+    type SeriesRow = (
+        i64,
+        i64,
+        Option<String>,
+        JsonB,
+        Vec<TimestampWithTimeZone>,
+        Vec<f64>,
+    );
+
+    fn to_row(
+        block: &Block,
+        offset: u64,
+        next_offset: u64,
+        series: Series,
+    ) -> Result<SeriesRow, String> {
+        let samples = block.samples(&series)?;
+        let metric_name = series
+            .labels
+            .iter()
+            .find(|(name, _)| name == "__name__")
+            .map(|(_, value)| value.clone());
+        let labels = series
+            .labels
+            .into_iter()
+            .map(|(name, value)| (name, serde_json::Value::String(value)))
+            .collect();
+        let times: Vec<TimestampWithTimeZone> = samples
+            .iter()
+            .map(|(t, _)| to_pg_timestamp(*t).map(TimestampWithTimeZone::from))
+            .collect::<Result<_, String>>()?;
+        let values: Vec<f64> = samples.into_iter().map(|(_, v)| v).collect();
+        Ok((
+            offset as i64,
+            next_offset as i64,
+            metric_name,
+            JsonB(serde_json::Value::Object(labels)),
+            times,
+            values,
+        ))
+    }
+
+    #[pg_extern(create_or_replace)]
+    pub fn write_tsdb_block_transition(
+        state: Internal,
+        path: Option<&str>,
+        labels: Option<JsonB>,
+        sample_times: Option<Vec<Option<TimestampWithTimeZone>>>,
+        sample_values: Option<Vec<Option<f64>>>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Internal {
+        write_tsdb_block_transition_inner(
+            unsafe { state.to_inner() },
+            path,
+            labels,
+            sample_times,
+            sample_values,
+            fcinfo,
+        )
+        .internal()
+    }
+
+    fn write_tsdb_block_transition_inner(
+        state: Option<Inner<BlockWriter>>,
+        path: Option<&str>,
+        labels: Option<JsonB>,
+        sample_times: Option<Vec<Option<TimestampWithTimeZone>>>,
+        sample_values: Option<Vec<Option<f64>>>,
+        fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Option<Inner<BlockWriter>> {
+        unsafe {
+            in_aggregate_context(fcinfo, || {
+                // the directory of the first row is used for all of them
+                let mut state = state.unwrap_or_else(|| {
+                    check_privileges("pg_write_server_files", "write TSDB blocks");
+                    let path = path.unwrap_or_else(|| error!("path must not be NULL"));
+                    BlockWriter::new(Path::new(path), new_ulid())
+                        .unwrap_or_else(|e| error!("cannot write TSDB block: {}", e))
+                        .into()
+                });
+                // a series without labels can't be identified, skip it like NULL input
+                if let Some(labels) = labels {
+                    let samples = to_samples(
+                        sample_times.unwrap_or_default(),
+                        sample_values.unwrap_or_default(),
+                    );
+                    state
+                        .add_series(to_labels(labels), &samples)
+                        .unwrap_or_else(|e| error!("cannot write TSDB block: {}", e));
+                }
+                Some(state)
+            })
+        }
+    }
+
+    /// Writes the index and the metadata, returning the metadata. A block
+    /// without samples is not written, and `NULL` returned.
+    #[pg_extern(create_or_replace)]
+    pub fn write_tsdb_block_final(
+        state: Internal, /* Option<Inner<BlockWriter>> */
+    ) -> Option<JsonB> {
+        let mut state: Inner<BlockWriter> = unsafe { state.to_inner() }?;
+        let meta = state
+            .finish()
+            .unwrap_or_else(|e| error!("cannot write TSDB block: {}", e))?;
+        Some(JsonB(
+            serde_json::to_value(meta).expect("block metadata is serializable"),
+        ))
+    }
+
+    fn to_labels(labels: JsonB) -> Vec<(String, String)> {
+        let labels = match labels.0 {
+            Value::Object(map) => map,
+            _ => error!("labels must be a JSON object"),
+        };
+        labels
+            .into_iter()
+            .map(|(name, value)| match value {
+                Value::String(value) => (name, value),
+                _ => error!("value of label \"{}\" must be a string", name),
+            })
+            .collect()
+    }
+
+    /// Samples sorted by time, with the first of samples of the same time.
+    fn to_samples(
+        sample_times: Vec<Option<TimestampWithTimeZone>>,
+        sample_values: Vec<Option<f64>>,
+    ) -> Vec<(i64, f64)> {
+        if sample_times.len() != sample_values.len() {
+            error!(
+                "sample_times and sample_values must have the same length, got {} and {}",
+                sample_times.len(),
+                sample_values.len()
+            );
+        }
+        let mut samples: Vec<(i64, f64)> = sample_times
+            .into_iter()
+            .zip(sample_values)
+            .map(|(time, value)| match (time, value) {
+                (Some(time), Some(value)) => (from_pg_timestamp(time.into()), value),
+                _ => error!("sample_times and sample_values must not contain NULLs"),
+            })
+            .collect();
+        samples.sort_by_key(|&(t, _)| t);
+        samples.dedup_by_key(|&mut (t, _)| t);
+        samples
+    }
+}
+
+/// Opens the block at `path`, reusing the last one if its ULID is unchanged.
+fn open_block(path: &str) -> Result<Rc<Block>, String> {
+    let dir = Path::new(path);
+    let meta = BlockMeta::read(dir)?;
+    let cached = OPEN_BLOCK.with(|block| {
+        block
+            .borrow()
+            .as_ref()
+            .filter(|block| block.dir == dir && block.meta.ulid == meta.ulid)
+            .cloned()
+    });
+    if let Some(block) = cached {
+        return Ok(block);
+    }
+    let block = Rc::new(Block::open(dir, meta)?);
+    OPEN_BLOCK.with(|cached| *cached.borrow_mut() = Some(block.clone()));
+    Ok(block)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use std::path::PathBuf;
+
+    use pgx::*;
+    use serde_json::json;
+
+    use super::block::{Block, BlockMeta};
+    use super::writer::{new_ulid, BlockWriter};
+    use crate::aggregates::STALE_NAN;
+
+    const ULID: &str = "01GQ5Z8NB8YJ4KQ3V2B0M1XTSD";
+
+    /// The index of a block with the series `http_requests_total{code="200", job="api"}`
+    /// and `up{instance="a"|"b", job="api"}`, written from the format specification.
+    const INDEX: &str = concat!(
+        "baaad70002000000420000000a03323030085f5f6e616d655f5f016103617069016204636f64651368747470",
+        "5f72657175657374735f746f74616c08696e7374616e6365036a6f62027570f35d4293001203010605000803",
+        "018eb0fdac8d37b0ea010832d1c8f000000000000000000012030109070208030180b0fdac8d37c8df02268e",
+        "d4d45e00000000000000000012030109070408030180b0fdac8d37c8df023f1bc74198000000001000000001",
+        "00000002000000060000000977e6c2ca0000000c000000010000000100000000e74f0cb90000001000000001",
+        "000000020000000200000004288b30a90000000c000000010000000100000003f41fff4d0000002b00000004",
+        "01085f5f6e616d655f5fa8010104636f6465c0010108696e7374616e6365d40101036a6f62ec01f317b42a00",
+        "0000001000000003000000050000000700000009e93313b600000008000000010000000581c8c93a0000000c",
+        "0000000200000007000000091635bab400000008000000010000000581c8c93a000000080000000100000007",
+        "60f3b9cd000000080000000100000009cc8b06ea0000001000000003000000050000000700000009e93313b6",
+        "0000006b00000007020000b40202085f5f6e616d655f5f13687474705f72657175657374735f746f74616ccc",
+        "0202085f5f6e616d655f5f027570dc020204636f646503323030f0020208696e7374616e6365016180030208",
+        "696e7374616e63650162900302036a6f6203617069a003233402ce0000000000000005000000000000004f00",
+        "000000000000a70000000000000100000000000000013300000000000001b8c2231a8d",
+    );
+
+    const SEGMENT: &str = concat!(
+        "85bd40dd01000000180100038eb0fdac8d3700000000000000009875c27c02540040e88d45841301000480b0",
+        "fdac8d373ff0000000000000987500dadeb7f81901000480b0fdac8d3700000000000000009875c457febff5",
+        "ff8069029935",
+    );
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Writes the test block into a temporary directory of its own.
+    fn write_block(name: &str) -> String {
+        let dir: PathBuf = std::env::temp_dir().join(name).join(ULID);
+        std::fs::create_dir_all(dir.join("chunks")).unwrap();
+        let meta = json!({
+            "ulid": ULID,
+            "minTime": 946684800000i64,
+            "maxTime": 946684845001i64,
+            "stats": {"numSamples": 11, "numSeries": 3, "numChunks": 3},
+            "compaction": {"level": 1, "sources": [ULID]},
+            "version": 1,
+        });
+        std::fs::write(dir.join("meta.json"), meta.to_string()).unwrap();
+        std::fs::write(dir.join("index"), hex(INDEX)).unwrap();
+        std::fs::write(dir.join("chunks").join("000001"), hex(SEGMENT)).unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+
+    fn query(sql: &str, path: &str) -> serde_json::Value {
+        Spi::get_one_with_args::<Json>(sql, vec![(PgBuiltInOids::TEXTOID.oid(), path.into_datum())])
+            .expect("SQL query failed")
+            .0
+    }
+
+    #[pg_test]
+    fn test_read_tsdb_block() {
+        let path = write_block("promscale_test_read_tsdb_block");
+        assert_eq!(
+            query(
+                "SELECT to_json(_prom_ext.tsdb_block_meta($1)->'ulid')",
+                &path
+            ),
+            json!(ULID)
+        );
+        assert_eq!(
+            query(
+                r#"SELECT json_agg(json_build_array(
+                     b.series_offset,
+                     b.metric_name,
+                     b.labels,
+                     (SELECT array_agg(extract(epoch FROM t)::int) FROM unnest(b.times) t),
+                     b."values"))
+                 FROM _prom_ext.read_tsdb_block(
+                     $1, (SELECT next_series_offset FROM _prom_ext.read_tsdb_block($1, 0, 1)), 5) b"#,
+                &path
+            ),
+            json!([
+                [
+                    112,
+                    "up",
+                    {"__name__": "up", "instance": "a", "job": "api"},
+                    [946684800, 946684815, 946684830, 946684845],
+                    [1, 1, 1, 1]
+                ],
+                [
+                    144,
+                    "up",
+                    {"__name__": "up", "instance": "b", "job": "api"},
+                    [946684800, 946684815, 946684830, 946684845],
+                    [0, 1, 0, 1]
+                ],
+            ])
+        );
+    }
+
+    #[pg_test]
+    fn test_import_tsdb_block_batches() {
+        let path = write_block("promscale_test_import_tsdb_block_batches");
+        let batch = || {
+            query(
+                "SELECT json_build_array(imported_series, imported_samples, completed_at IS NOT NULL)
+                 FROM _prom_catalog.import_tsdb_block_batch($1, 2)",
+                &path,
+            )
+        };
+        assert_eq!(batch(), json!([2, 7, false]));
+        assert_eq!(batch(), json!([3, 11, true]));
+        // completed blocks are not imported again
+        assert_eq!(batch(), json!([3, 11, true]));
+
+        let samples = Spi::get_one::<i64>(
+            "SELECT count(*) FROM prom_metric.up WHERE prom_api.jsonb(labels)->>'instance' = 'b' AND value = 1",
+        )
+        .expect("SQL query failed");
+        assert_eq!(samples, 2);
+        let sum = Spi::get_one::<f64>("SELECT sum(value) FROM prom_data.http_requests_total")
+            .expect("SQL query failed");
+        assert_eq!(sum, 31.5);
+    }
+
+    /// A fresh parent directory for written blocks.
+    fn empty_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn label_pairs(labels: &[(&str, &str)]) -> Vec<(String, String)> {
+        labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[pg_test]
+    fn test_write_tsdb_block_matches_fixture() {
+        let parent = empty_dir("promscale_test_write_tsdb_block_matches_fixture");
+        let mut writer = BlockWriter::new(&parent, ULID.to_string()).unwrap();
+        let base = 946684800000i64;
+        let up_b: Vec<_> = (0..4).map(|i| (base + i * 15000, (i % 2) as f64)).collect();
+        let up_a: Vec<_> = (0..4).map(|i| (base + i * 15000, 1.0)).collect();
+        let requests: Vec<_> = (0..3)
+            .map(|i| (base + i * 15000 + 7, 10.5 * i as f64))
+            .collect();
+        writer
+            .add_series(
+                label_pairs(&[("__name__", "up"), ("job", "api"), ("instance", "b")]),
+                &up_b,
+            )
+            .unwrap();
+        writer
+            .add_series(
+                label_pairs(&[("__name__", "up"), ("job", "api"), ("instance", "a")]),
+                &up_a,
+            )
+            .unwrap();
+        writer
+            .add_series(
+                label_pairs(&[
+                    ("__name__", "http_requests_total"),
+                    ("job", "api"),
+                    ("code", "200"),
+                ]),
+                &requests,
+            )
+            .unwrap();
+        let meta = writer.finish().unwrap().expect("block has series");
+
+        let dir = parent.join(ULID);
+        assert_eq!(std::fs::read(dir.join("index")).unwrap(), hex(INDEX));
+        assert_eq!(
+            std::fs::read(dir.join("chunks").join("000001")).unwrap(),
+            hex(SEGMENT)
+        );
+        assert_eq!(meta, BlockMeta::read(&dir).unwrap());
+        assert_eq!(
+            serde_json::to_value(&meta).unwrap(),
+            json!({
+                "ulid": ULID,
+                "minTime": 946684800000i64,
+                "maxTime": 946684845001i64,
+                "stats": {"numSamples": 11, "numSeries": 3, "numChunks": 3},
+                "compaction": {"level": 1, "sources": [ULID]},
+                "version": 1,
+            })
+        );
+    }
+
+    #[pg_test]
+    fn test_write_tsdb_block_round_trip() {
+        let parent = empty_dir("promscale_test_write_tsdb_block_round_trip");
+        let ulid = new_ulid();
+        let mut writer = BlockWriter::new(&parent, ulid.clone()).unwrap();
+        let mut expected = vec![];
+        for i in (0..50i64).rev() {
+            let labels = label_pairs(&[
+                ("__name__", "load"),
+                ("instance", &format!("host-{:02}", i)),
+            ]);
+            let samples: Vec<_> = (0..i * 7)
+                .map(|j| {
+                    let value = if j == 100 {
+                        f64::from_bits(STALE_NAN)
+                    } else {
+                        (i * j) as f64 / 3.0
+                    };
+                    (1_600_000_000_000 + j * 10_000 + j % 3, value)
+                })
+                .collect();
+            writer.add_series(labels.clone(), &samples).unwrap();
+            if !samples.is_empty() {
+                expected.push((labels, samples));
+            }
+        }
+        writer.finish().unwrap().expect("block has series");
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let dir = parent.join(&ulid);
+        let block = Block::open(&dir, BlockMeta::read(&dir).unwrap()).unwrap();
+        assert_eq!(block.meta.stats.num_series, 49);
+        let mut offset = 0;
+        for (labels, samples) in &expected {
+            let (series, next_offset) = block.series_at(offset).unwrap().expect("series left");
+            offset = next_offset;
+            assert_eq!(&series.labels, labels);
+            let read = block.samples(&series).unwrap();
+            assert_eq!(read.len(), samples.len());
+            for (read, sample) in read.iter().zip(samples) {
+                assert_eq!(read.0, sample.0);
+                assert_eq!(read.1.to_bits(), sample.1.to_bits());
+            }
+        }
+        assert!(block.series_at(offset).unwrap().is_none());
+    }
+
+    #[pg_test]
+    fn test_export_tsdb_block() {
+        Spi::run(
+            r#"SELECT _prom_catalog.get_or_create_metric_table_name('up');
+            INSERT INTO prom_data.up(time, value, series_id)
+            SELECT to_timestamp(1600000000 + 15 * i), s.v, s.id
+            FROM generate_series(0, 199) i,
+            (VALUES (_prom_catalog.get_or_create_series_id('{"__name__": "up", "job": "api", "instance": "a"}'), 1),
+                    (_prom_catalog.get_or_create_series_id('{"__name__": "up", "job": "api", "instance": "b"}'), 0))
+                AS s(id, v);"#,
+        );
+        let parent = empty_dir("promscale_test_export_tsdb_block");
+        let path = parent.to_str().unwrap();
+        let meta = query(
+            "SELECT to_json(prom_api.export_tsdb_block($1, ARRAY['up', 'missing'],
+                to_timestamp(1600000015), to_timestamp(1600001500)))",
+            path,
+        );
+        let ulid = meta["ulid"].as_str().expect("block has a ULID");
+        assert_eq!(meta["minTime"], json!(1600000015000i64));
+        assert_eq!(meta["maxTime"], json!(1600001485001i64));
+        assert_eq!(
+            meta["stats"],
+            json!({"numSamples": 198, "numSeries": 2, "numChunks": 2})
+        );
+
+        let block = query(
+            r#"SELECT json_agg(json_build_array(
+                labels, array_length(times, 1), extract(epoch from times[1]), "values"[1])
+                ORDER BY series_offset)
+            FROM _prom_ext.read_tsdb_block($1, 0, 10)"#,
+            &format!("{}/{}", path, ulid),
+        );
+        assert_eq!(
+            block,
+            json!([
+                [{"__name__": "up", "instance": "a", "job": "api"}, 99, 1600000015, 1],
+                [{"__name__": "up", "instance": "b", "job": "api"}, 99, 1600000015, 0],
+            ])
+        );
+    }
+
+    #[pg_test]
+    fn test_export_tsdb_block_no_metrics() {
+        let parent = empty_dir("promscale_test_export_tsdb_block_no_metrics");
+        let meta = Spi::get_one_with_args::<JsonB>(
+            "SELECT prom_api.export_tsdb_block($1, ARRAY['missing'], '-infinity', 'infinity')",
+            vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                parent.to_str().unwrap().into_datum(),
+            )],
+        );
+        assert!(meta.is_none());
+        assert_eq!(std::fs::read_dir(&parent).unwrap().count(), 0);
+    }
+
+    #[pg_test(
+        error = "cannot read TSDB block: /nonexistent/block/meta.json: No such file or directory (os error 2)"
+    )]
+    fn test_read_tsdb_block_missing() {
+        query(
+            "SELECT to_json(_prom_ext.tsdb_block_meta($1))",
+            "/nonexistent/block",
+        );
+    }
+}
@@ -0,0 +1,368 @@
+//! Writes blocks in the on-disk format of the Prometheus TSDB, laid out like
+//! Prometheus' own writer so that `promtool tsdb analyze` and Thanos accept
+//! them.
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::block::{
+    BlockCompaction, BlockMeta, BlockStats, ChunkMeta, Series, CHUNK_ENCODING_XOR, INDEX_MAGIC,
+    INDEX_VERSION, SEGMENT_HEADER_SIZE, SEGMENT_MAGIC, SEGMENT_VERSION, SERIES_ALIGNMENT,
+};
+use crate::chunkenc::{XorChunkBuilder, SAMPLES_PER_CHUNK};
+use crate::remote_read::crc32c;
+
+/// The size after which Prometheus starts a new chunk segment.
+const SEGMENT_MAX_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Writes a block into `<ulid>.tmp` in the parent directory, which is renamed
+/// to `<ulid>` once the block is complete. Chunks are written as series are
+/// added, the index when finishing.
+#[derive(Debug)]
+pub(crate) struct BlockWriter {
+    parent: PathBuf,
+    ulid: String,
+    series: Vec<Series>,
+    /// The number of segment files, the last of which is appended to.
+    segments: u64,
+    segment_size: u64,
+    stats: BlockStats,
+    min_time: i64,
+    max_time: i64,
+    finished: Option<BlockMeta>,
+}
+
+impl BlockWriter {
+    pub(crate) fn new(parent: &Path, ulid: String) -> Result<Self, String> {
+        let writer = Self {
+            parent: parent.to_path_buf(),
+            ulid,
+            series: vec![],
+            segments: 0,
+            segment_size: 0,
+            stats: BlockStats::default(),
+            min_time: i64::MAX,
+            max_time: i64::MIN,
+            finished: None,
+        };
+        let chunks_dir = writer.tmp_dir().join("chunks");
+        std::fs::create_dir_all(&chunks_dir)
+            .map_err(|e| format!("{}: {}", chunks_dir.display(), e))?;
+        Ok(writer)
+    }
+
+    fn tmp_dir(&self) -> PathBuf {
+        self.parent.join(format!("{}.tmp", self.ulid))
+    }
+
+    /// Adds a series with samples sorted by time. Series without samples are
+    /// skipped.
+    pub(crate) fn add_series(
+        &mut self,
+        mut labels: Vec<(String, String)>,
+        samples: &[(i64, f64)],
+    ) -> Result<(), String> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        labels.sort();
+        let mut chunks = Vec::with_capacity((samples.len() - 1) / SAMPLES_PER_CHUNK + 1);
+        for samples in samples.chunks(SAMPLES_PER_CHUNK) {
+            let mut builder = XorChunkBuilder::new();
+            samples.iter().for_each(|&(t, v)| builder.append(t, v));
+            let chunk_ref = self.write_chunk(&builder.finish())?;
+            chunks.push(ChunkMeta {
+                min_time: samples[0].0,
+                max_time: samples[samples.len() - 1].0,
+                chunk_ref,
+            });
+        }
+        self.min_time = self.min_time.min(samples[0].0);
+        self.max_time = self.max_time.max(samples[samples.len() - 1].0);
+        self.stats.num_samples += samples.len() as u64;
+        self.stats.num_chunks += chunks.len() as u64;
+        self.stats.num_series += 1;
+        self.series.push(Series { labels, chunks });
+        Ok(())
+    }
+
+    /// Appends an XOR chunk to the last segment, returning its reference.
+    fn write_chunk(&mut self, data: &[u8]) -> Result<u64, String> {
+        let mut bytes = Vec::with_capacity(data.len() + 10);
+        crate::protobuf::write_varint(&mut bytes, data.len() as u64);
+        let checksummed = bytes.len();
+        bytes.push(CHUNK_ENCODING_XOR);
+        bytes.extend_from_slice(data);
+        let checksum = crc32c(&bytes[checksummed..]);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+
+        let new_segment =
+            self.segments == 0 || self.segment_size + bytes.len() as u64 > SEGMENT_MAX_SIZE;
+        if new_segment {
+            self.segments += 1;
+            self.segment_size = SEGMENT_HEADER_SIZE;
+        }
+        let path = self
+            .tmp_dir()
+            .join("chunks")
+            .join(format!("{:06}", self.segments));
+        let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(error)?;
+        if new_segment {
+            let mut header = SEGMENT_MAGIC.to_be_bytes().to_vec();
+            header.extend_from_slice(&[SEGMENT_VERSION, 0, 0, 0]);
+            file.write_all(&header).map_err(error)?;
+        }
+        file.write_all(&bytes).map_err(error)?;
+
+        let chunk_ref = (self.segments - 1) << 32 | self.segment_size;
+        self.segment_size += bytes.len() as u64;
+        Ok(chunk_ref)
+    }
+
+    /// Writes the index and `meta.json`, and moves the block into place.
+    /// Returns `None` for blocks without series, which are removed instead.
+    pub(crate) fn finish(&mut self) -> Result<Option<BlockMeta>, String> {
+        if let Some(meta) = &self.finished {
+            return Ok(Some(meta.clone()));
+        }
+        let tmp_dir = self.tmp_dir();
+        if self.series.is_empty() {
+            std::fs::remove_dir_all(&tmp_dir)
+                .map_err(|e| format!("{}: {}", tmp_dir.display(), e))?;
+            return Ok(None);
+        }
+
+        self.series.sort_by(|a, b| a.labels.cmp(&b.labels));
+        if let Some(w) = self.series.windows(2).find(|w| w[0].labels == w[1].labels) {
+            return Err(format!("duplicate series {:?}", w[0].labels));
+        }
+        let index = encode_index(&self.series)?;
+        write_file(&tmp_dir.join("index"), &index)?;
+
+        let meta = BlockMeta {
+            ulid: self.ulid.clone(),
+            min_time: self.min_time,
+            // exclusive
+            max_time: self.max_time + 1,
+            stats: self.stats.clone(),
+            compaction: BlockCompaction {
+                level: 1,
+                sources: vec![self.ulid.clone()],
+            },
+            version: 1,
+        };
+        let json = serde_json::to_vec_pretty(&meta).expect("block metadata is serializable");
+        write_file(&tmp_dir.join("meta.json"), &json)?;
+
+        let dir = self.parent.join(&self.ulid);
+        std::fs::rename(&tmp_dir, &dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        self.finished = Some(meta.clone());
+        Ok(Some(meta))
+    }
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    let mut file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    file.write_all(bytes)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Encodes the index of series sorted by labels: the symbol table, the
+/// series, the label indices, the postings and their offset tables, and the
+/// table of contents.
+fn encode_index(series: &[Series]) -> Result<Vec<u8>, String> {
+    let mut buf = Encbuf::default();
+    buf.be_u32(INDEX_MAGIC);
+    buf.bytes.push(INDEX_VERSION);
+
+    let mut label_values: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for (name, value) in series.iter().flat_map(|s| &s.labels) {
+        label_values.entry(name).or_default().insert(value);
+    }
+    let symbols: BTreeSet<&str> = label_values
+        .iter()
+        .flat_map(|(name, values)| std::iter::once(*name).chain(values.iter().copied()))
+        .collect();
+    let symbol_refs: HashMap<&str, u32> = symbols.iter().zip(0..).map(|(s, i)| (*s, i)).collect();
+
+    let symbols_offset = buf.len();
+    buf.section(|buf| {
+        buf.be_u32(symbols.len() as u32);
+        symbols.iter().for_each(|s| buf.uvarint_str(s));
+    });
+
+    let series_offset = buf.len();
+    // the series of every label pair, and of the empty pair for all series
+    let mut postings: BTreeMap<(&str, &str), Vec<u32>> = BTreeMap::new();
+    for s in series {
+        buf.align(SERIES_ALIGNMENT as usize);
+        let series_ref = u32::try_from(buf.len() / SERIES_ALIGNMENT)
+            .map_err(|_| "index exceeds the maximum size".to_string())?;
+        let mut entry = Encbuf::default();
+        entry.uvarint(s.labels.len() as u64);
+        for (name, value) in &s.labels {
+            entry.uvarint(symbol_refs[name.as_str()] as u64);
+            entry.uvarint(symbol_refs[value.as_str()] as u64);
+            postings.entry((name, value)).or_default().push(series_ref);
+        }
+        postings.entry(("", "")).or_default().push(series_ref);
+        entry.uvarint(s.chunks.len() as u64);
+        let mut prev: Option<&ChunkMeta> = None;
+        for chunk in &s.chunks {
+            match prev {
+                None => {
+                    entry.varint(chunk.min_time);
+                    entry.uvarint((chunk.max_time - chunk.min_time) as u64);
+                    entry.uvarint(chunk.chunk_ref);
+                }
+                Some(prev) => {
+                    entry.uvarint((chunk.min_time - prev.max_time) as u64);
+                    entry.uvarint((chunk.max_time - chunk.min_time) as u64);
+                    entry.varint(chunk.chunk_ref as i64 - prev.chunk_ref as i64);
+                }
+            }
+            prev = Some(chunk);
+        }
+        buf.uvarint(entry.bytes.len() as u64);
+        let checksum = crc32c(&entry.bytes);
+        buf.bytes.extend_from_slice(&entry.bytes);
+        buf.be_u32(checksum);
+    }
+
+    let label_indices_offset = buf.len();
+    let mut label_offsets = vec![];
+    for (name, values) in &label_values {
+        buf.align(4);
+        label_offsets.push((*name, buf.len()));
+        buf.section(|buf| {
+            buf.be_u32(1);
+            buf.be_u32(values.len() as u32);
+            values.iter().for_each(|v| buf.be_u32(symbol_refs[v]));
+        });
+    }
+
+    let label_table_offset = buf.len();
+    buf.section(|buf| {
+        buf.be_u32(label_offsets.len() as u32);
+        for (name, offset) in &label_offsets {
+            buf.uvarint(1);
+            buf.uvarint_str(name);
+            buf.uvarint(*offset);
+        }
+    });
+
+    let postings_offset = buf.len();
+    let mut postings_offsets = vec![];
+    for ((name, value), refs) in &postings {
+        buf.align(4);
+        postings_offsets.push((*name, *value, buf.len()));
+        buf.section(|buf| {
+            buf.be_u32(refs.len() as u32);
+            refs.iter().for_each(|r| buf.be_u32(*r));
+        });
+    }
+
+    let postings_table_offset = buf.len();
+    buf.section(|buf| {
+        buf.be_u32(postings_offsets.len() as u32);
+        for (name, value, offset) in &postings_offsets {
+            buf.uvarint(2);
+            buf.uvarint_str(name);
+            buf.uvarint_str(value);
+            buf.uvarint(*offset);
+        }
+    });
+
+    let mut toc = Encbuf::default();
+    for offset in [
+        symbols_offset,
+        series_offset,
+        label_indices_offset,
+        label_table_offset,
+        postings_offset,
+        postings_table_offset,
+    ] {
+        toc.bytes.extend_from_slice(&offset.to_be_bytes());
+    }
+    let checksum = crc32c(&toc.bytes);
+    buf.bytes.extend_from_slice(&toc.bytes);
+    buf.be_u32(checksum);
+    Ok(buf.bytes)
+}
+
+/// Encodes the primitives of the index format.
+#[derive(Default)]
+struct Encbuf {
+    bytes: Vec<u8>,
+}
+
+impl Encbuf {
+    fn len(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+
+    fn be_u32(&mut self, v: u32) {
+        self.bytes.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn uvarint(&mut self, v: u64) {
+        crate::protobuf::write_varint(&mut self.bytes, v);
+    }
+
+    fn varint(&mut self, v: i64) {
+        self.uvarint(((v << 1) ^ (v >> 63)) as u64);
+    }
+
+    fn uvarint_str(&mut self, s: &str) {
+        self.uvarint(s.len() as u64);
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    /// Pads with zeros to a multiple of `alignment`.
+    fn align(&mut self, alignment: usize) {
+        let padding = (alignment - self.bytes.len() % alignment) % alignment;
+        self.bytes.resize(self.bytes.len() + padding, 0);
+    }
+
+    /// Writes a section with a 4-byte length prefix and a trailing checksum.
+    fn section(&mut self, f: impl FnOnce(&mut Encbuf)) {
+        let mut content = Encbuf::default();
+        f(&mut content);
+        self.be_u32(content.bytes.len() as u32);
+        let checksum = crc32c(&content.bytes);
+        self.bytes.extend_from_slice(&content.bytes);
+        self.be_u32(checksum);
+    }
+}
+
+/// A new [ULID](https://github.com/ulid/spec): the current time in
+/// milliseconds followed by 80 random bits, in Crockford's base32.
+pub(crate) fn new_ulid() -> String {
+    const ALPHABET: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("the clock is after 1970");
+    // randomly seeded hashers are good enough to tell blocks apart
+    let random = |seed: u128| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(seed);
+        hasher.finish() as u128
+    };
+    let random = (random(now.as_nanos()) << 64 | random(now.as_nanos() + 1)) & ((1 << 80) - 1);
+    let bits = now.as_millis() << 80 | random;
+    (0..26)
+        .rev()
+        .map(|i| ALPHABET[(bits >> (i * 5)) as usize & 0x1f] as char)
+        .collect()
+}
@@ -10,3 +10,20 @@ mod _prom_ext {
         get() as i32
     }
 }
+
+/// Checks that the user may `action` on the server, e.g. read or write files,
+/// i.e. is a member of `role`, which superusers always are.
+pub(crate) fn check_privileges(role: &str, action: &str) {
+    let allowed = Spi::get_one_with_args::<bool>(
+        "SELECT pg_catalog.pg_has_role($1, 'USAGE')",
+        vec![(PgBuiltInOids::TEXTOID.oid(), role.into_datum())],
+    );
+    if allowed != Some(true) {
+        error!("must be superuser or a member of {} to {}", role, action);
+    }
+}
+
+/// Quotes an identifier for use in SQL, unlike `quote_ident` even when not needed.
+pub(crate) fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
@@ -0,0 +1,267 @@
+//! # Zipkin v2 JSON decoding
+//!
+//! Decodes spans in the [Zipkin v2 JSON model][zipkin] (a JSON array of spans,
+//! as sent to `/api/v2/spans`) into span rows, following the OpenTelemetry
+//! collector's Zipkin translator, so that spans look the same whether they
+//! arrive as Zipkin or as OTLP:
+//!
+//! * `kind` becomes the span kind, `unspecified` when absent,
+//! * `localEndpoint.serviceName` becomes the `service.name` resource tag,
+//!   the endpoints' addresses and ports the `net.host.*` and `net.peer.*`
+//!   span tags, and `remoteEndpoint.serviceName` the `peer.service` tag,
+//! * annotations become events,
+//! * the `otel.status_code`, `otel.status_description`, `error`,
+//!   `otel.scope.*` and `w3c.tracestate` tags become the corresponding span
+//!   fields.
+//!
+//! The events of a span are returned as a JSON array of objects with the
+//! columns of `_ps_trace.event`. Zipkin spans have no links.
+//!
+//! [zipkin]: https://zipkin.io/zipkin-api/#/default/post_spans
+use pgx::*;
+
+use serde_json::{Map, Value};
+
+use crate::otlp::micros_to_pg_timestamp;
+use crate::otlp_traces::{
+    apply_otel_tags, events_to_json, int_field, non_empty, span_id_from_hex, str_field,
+    trace_id_from_hex, EventRow, SpanRow,
+};
+
+#[pg_schema]
+mod _prom_ext {
+    use pgx::*;
+    use serde_json::Value;
+
+    use super::{decode, events_to_json};
+
+    #[pg_extern(immutable, strict, parallel_safe, create_or_replace)]
+    pub fn decode_zipkin_json(
+        spans: JsonB,
+    ) -> TableIterator<
+        'static,
+        (
+            name!(trace_id, Uuid),
+            name!(span_id, i64),
+            name!(parent_span_id, Option<i64>),
+            name!(trace_state, Option<String>),
+            name!(name, String),
+            name!(span_kind, String),
+            name!(start_time, TimestampWithTimeZone),
+            name!(end_time, TimestampWithTimeZone),
+            name!(status_code, String),
+            name!(status_message, Option<String>),
+            name!(span_tags, JsonB),
+            name!(resource_tags, JsonB),
+            name!(instrumentation_lib_name, Option<String>),
+            name!(instrumentation_lib_version, Option<String>),
+            name!(events, JsonB),
+        ),
+    > {
+        let spans = decode(&spans.0);
+        TableIterator::new(spans.into_iter().map(|(span, events)| {
+            (
+                Uuid::from_bytes(span.trace_id),
+                span.span_id,
+                span.parent_span_id,
+                span.trace_state,
+                span.name,
+                span.span_kind.to_string(),
+                TimestampWithTimeZone::from(span.start_time),
+                TimestampWithTimeZone::from(span.end_time),
+                span.status_code.to_string(),
+                span.status_message,
+                JsonB(Value::Object(span.span_tags)),
+                JsonB(Value::Object(span.resource_tags)),
+                span.instrumentation_lib_name,
+                span.instrumentation_lib_version,
+                JsonB(events_to_json(events)),
+            )
+        }))
+    }
+}
+
+fn decode(spans: &Value) -> Vec<(SpanRow, Vec<EventRow>)> {
+    let spans = match spans {
+        Value::Array(spans) => spans.iter().collect(),
+        span => vec![span],
+    };
+    spans
+        .into_iter()
+        .map(to_row)
+        .collect::<Result<_, _>>()
+        .unwrap_or_else(|e| error!("invalid Zipkin spans: {}", e))
+}
+
+fn to_row(span: &Value) -> Result<(SpanRow, Vec<EventRow>), String> {
+    let span = span
+        .as_object()
+        .ok_or_else(|| format!("expected a span object, got {}", span))?;
+    let trace_id = trace_id_from_hex(str_field(span, "traceId")?.unwrap_or_default())?;
+    let span_id = span_id_from_hex(str_field(span, "id")?.unwrap_or_default())?;
+    let start = int_field(span, "timestamp")?.unwrap_or(0);
+    let mut row = SpanRow {
+        trace_id,
+        span_id,
+        parent_span_id: str_field(span, "parentId")?
+            .map(span_id_from_hex)
+            .transpose()?,
+        name: str_field(span, "name")?.unwrap_or_default().to_string(),
+        span_kind: match str_field(span, "kind")? {
+            None => "unspecified",
+            Some("CLIENT") => "client",
+            Some("SERVER") => "server",
+            Some("PRODUCER") => "producer",
+            Some("CONSUMER") => "consumer",
+            Some(kind) => return Err(format!("invalid span kind {:?}", kind)),
+        },
+        start_time: micros_to_pg_timestamp(start),
+        end_time: micros_to_pg_timestamp(start + int_field(span, "duration")?.unwrap_or(0)),
+        status_code: "unset",
+        ..Default::default()
+    };
+
+    if let Some(tags) = span.get("tags") {
+        let tags = tags
+            .as_object()
+            .ok_or_else(|| format!("invalid tags {}", tags))?;
+        row.span_tags = tags.clone();
+    }
+    if let Some(endpoint) = endpoint(span, "localEndpoint")? {
+        if let Some(service) = str_field(endpoint, "serviceName")?.and_then(non_empty) {
+            row.resource_tags
+                .insert("service.name".to_string(), Value::String(service));
+        }
+        endpoint_tags(endpoint, "net.host", &mut row.span_tags);
+    }
+    if let Some(endpoint) = endpoint(span, "remoteEndpoint")? {
+        if let Some(service) = str_field(endpoint, "serviceName")?.and_then(non_empty) {
+            row.span_tags
+                .insert("peer.service".to_string(), Value::String(service));
+        }
+        endpoint_tags(endpoint, "net.peer", &mut row.span_tags);
+    }
+    apply_otel_tags(&mut row);
+
+    let annotations = match span.get("annotations") {
+        None => vec![],
+        Some(Value::Array(annotations)) => annotations.iter().collect(),
+        Some(other) => return Err(format!("invalid annotations {}", other)),
+    };
+    let events = annotations
+        .into_iter()
+        .enumerate()
+        .map(|(i, annotation)| {
+            let annotation = annotation
+                .as_object()
+                .ok_or_else(|| format!("invalid annotation {}", annotation))?;
+            Ok(EventRow {
+                time: micros_to_pg_timestamp(int_field(annotation, "timestamp")?.unwrap_or(0)),
+                trace_id,
+                span_id,
+                event_nbr: i as i32,
+                name: str_field(annotation, "value")?
+                    .unwrap_or_default()
+                    .to_string(),
+                tags: Map::new(),
+                dropped_tags_count: 0,
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    Ok((row, events))
+}
+
+fn endpoint<'a>(
+    span: &'a Map<String, Value>,
+    field: &str,
+) -> Result<Option<&'a Map<String, Value>>, String> {
+    match span.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Object(endpoint)) => Ok(Some(endpoint)),
+        Some(other) => Err(format!("invalid {} {}", field, other)),
+    }
+}
+
+/// `ipv4` (or `ipv6`) and `port` as `<prefix>.ip` and `<prefix>.port`.
+fn endpoint_tags(endpoint: &Map<String, Value>, prefix: &str, tags: &mut Map<String, Value>) {
+    if let Some(ip) = endpoint.get("ipv4").or_else(|| endpoint.get("ipv6")) {
+        tags.insert(format!("{}.ip", prefix), ip.clone());
+    }
+    if let Some(port) = endpoint.get("port") {
+        tags.insert(format!("{}.port", prefix), port.clone());
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgx::*;
+
+    #[pg_test]
+    fn test_decode_zipkin_json() {
+        Spi::run("SET TIME ZONE 'UTC'");
+        let spans = Spi::get_one::<Json>(
+            r#"SELECT json_agg(json_build_array(
+                trace_id::text, span_id, parent_span_id, name, span_kind, start_time::text,
+                (extract(epoch FROM end_time - start_time) * 1000)::bigint, status_code,
+                status_message, span_tags, resource_tags, instrumentation_lib_name, events
+               ))
+               FROM ps_trace.decode_zipkin_json('[{
+                "traceId": "5af7183fb1d4cf5f",
+                "id": "352bff9a74ca9ad2",
+                "parentId": "6b221d5bc9e6496c",
+                "name": "get /api",
+                "kind": "SERVER",
+                "timestamp": 1672531200000000,
+                "duration": 250000,
+                "localEndpoint": {"serviceName": "frontend", "ipv4": "10.0.0.1", "port": 8080},
+                "remoteEndpoint": {"serviceName": "browser", "ipv4": "10.0.0.9"},
+                "annotations": [{"timestamp": 1672531200010000, "value": "ws"}],
+                "tags": {
+                    "http.method": "GET",
+                    "error": "connection reset",
+                    "otel.scope.name": "io.opentelemetry.http"
+                }
+               }]')"#,
+        )
+        .expect("SQL query failed")
+        .0;
+        assert_eq!(
+            spans,
+            serde_json::json!([[
+                "00000000-0000-0000-5af7-183fb1d4cf5f",
+                3831436946858220242_i64,
+                7719764991332993388_i64,
+                "get /api",
+                "server",
+                "2023-01-01 00:00:00+00",
+                250,
+                "error",
+                "connection reset",
+                {
+                    "http.method": "GET",
+                    "net.host.ip": "10.0.0.1",
+                    "net.host.port": 8080,
+                    "net.peer.ip": "10.0.0.9",
+                    "peer.service": "browser"
+                },
+                {"service.name": "frontend"},
+                "io.opentelemetry.http",
+                [{
+                    "time": "2023-01-01T00:00:00.010000Z",
+                    "event_nbr": 0,
+                    "name": "ws",
+                    "tags": {},
+                    "dropped_tags_count": 0
+                }]
+            ]])
+        );
+    }
+
+    #[pg_test(error = "invalid Zipkin spans: invalid span id \"xyz\"")]
+    fn test_decode_zipkin_json_invalid_span_id() {
+        Spi::run(
+            r#"SELECT * FROM ps_trace.decode_zipkin_json('[{"traceId": "5af7183fb1d4cf5f", "id": "xyz"}]')"#,
+        );
+    }
+}